@@ -0,0 +1,92 @@
+-- Initial schema for the tnnl coordination server (Postgres)
+-- Also upgrades databases created from the old supabase-schema.sql, which
+-- referenced Supabase's auth.users table
+
+-- Users are keyed by the `sub` claim of their auth token
+CREATE TABLE IF NOT EXISTS users (
+    id uuid PRIMARY KEY,
+    email text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_seen_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- SSH keys and other tnnl-specific user data
+CREATE TABLE IF NOT EXISTS user_profiles (
+    id uuid PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    ssh_public_key text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Tunnels; rows are kept after a tunnel closes (closed_at set) as session history
+CREATE TABLE IF NOT EXISTS tunnels (
+    id uuid PRIMARY KEY,
+    subdomain text NOT NULL,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    is_custom boolean NOT NULL DEFAULT false,
+    port integer NOT NULL,
+    password text, -- Optional HTTP Basic Auth password
+    created_at timestamptz NOT NULL DEFAULT now(),
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    last_connected_at timestamptz,
+    closed_at timestamptz, -- NULL while the tunnel is active
+    close_reason text,
+    duration_seconds bigint,
+    viewer_sessions integer NOT NULL DEFAULT 0
+);
+
+-- Append-only record of auth, SSH key, tunnel and admin events
+-- user_id is not a foreign key so failed auth attempts and deleted users stay in the log
+CREATE TABLE IF NOT EXISTS audit_events (
+    id uuid PRIMARY KEY,
+    action text NOT NULL,
+    user_id uuid,
+    subdomain text,
+    client_ip text,
+    details text, -- JSON-encoded event details
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Upgrade tables created by supabase-schema.sql
+ALTER TABLE tunnels DROP CONSTRAINT IF EXISTS tunnels_subdomain_key;
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS closed_at timestamptz;
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS close_reason text;
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS duration_seconds bigint;
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS viewer_sessions integer NOT NULL DEFAULT 0;
+
+-- Re-point foreign keys from auth.users to users, backfilling users that
+-- already own tunnels or profiles (their email is filled in on next login)
+INSERT INTO users (id, email) SELECT DISTINCT user_id, '' FROM tunnels ON CONFLICT (id) DO NOTHING;
+INSERT INTO users (id, email) SELECT id, '' FROM user_profiles ON CONFLICT (id) DO NOTHING;
+
+ALTER TABLE tunnels DROP CONSTRAINT IF EXISTS tunnels_user_id_fkey;
+ALTER TABLE tunnels ADD CONSTRAINT tunnels_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE user_profiles DROP CONSTRAINT IF EXISTS user_profiles_id_fkey;
+ALTER TABLE user_profiles ADD CONSTRAINT user_profiles_id_fkey
+    FOREIGN KEY (id) REFERENCES users(id) ON DELETE CASCADE;
+
+-- Indexes
+CREATE INDEX IF NOT EXISTS idx_tunnels_subdomain ON tunnels(subdomain);
+CREATE INDEX IF NOT EXISTS idx_tunnels_user_id ON tunnels(user_id);
+CREATE INDEX IF NOT EXISTS idx_tunnels_user_history ON tunnels(user_id, created_at DESC);
+-- A subdomain may only be held by one active tunnel at a time
+CREATE UNIQUE INDEX IF NOT EXISTS idx_tunnels_active_subdomain ON tunnels(subdomain) WHERE closed_at IS NULL;
+CREATE INDEX IF NOT EXISTS idx_tunnels_port ON tunnels(port);
+CREATE INDEX IF NOT EXISTS idx_audit_events_created_at ON audit_events(created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_events_user_id ON audit_events(user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_events_subdomain ON audit_events(subdomain, created_at);
+
+-- Reject any modification of existing audit events
+CREATE OR REPLACE FUNCTION reject_audit_modification()
+RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_events_append_only ON audit_events;
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW
+    EXECUTE FUNCTION reject_audit_modification();
@@ -0,0 +1,13 @@
+-- Custom hostnames users route to their tunnels
+-- A hostname is claimed when added and only served once ownership is verified
+CREATE TABLE IF NOT EXISTS custom_domains (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    hostname text NOT NULL UNIQUE,
+    verification_method text NOT NULL, -- 'txt' or 'http'
+    verification_token text NOT NULL,
+    verified_at timestamptz, -- NULL until ownership is proven and a certificate issued
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_custom_domains_user_id ON custom_domains(user_id, created_at);
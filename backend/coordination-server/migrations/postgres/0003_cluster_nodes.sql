@@ -0,0 +1,19 @@
+-- Cluster mode: several coordination servers share this database
+-- Each tunnel is owned by the node holding its client connection and SSH forward
+
+-- Live nodes report in every heartbeat interval; silent nodes are failed over
+CREATE TABLE IF NOT EXISTS cluster_nodes (
+    id text PRIMARY KEY,
+    region text,
+    public_url text,
+    base_domain text NOT NULL,
+    started_at timestamptz NOT NULL DEFAULT now(),
+    last_heartbeat_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Standalone servers record their tunnels as node 'local'
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS node_id text NOT NULL DEFAULT 'local';
+
+CREATE INDEX IF NOT EXISTS idx_tunnels_active_node ON tunnels(node_id) WHERE closed_at IS NULL;
+-- Forward ports are local to a node, so only unique per node
+CREATE UNIQUE INDEX IF NOT EXISTS idx_tunnels_active_node_port ON tunnels(node_id, port) WHERE closed_at IS NULL;
@@ -0,0 +1,9 @@
+-- Tunnel kinds: screen sharing, or any local HTTP or TCP service
+-- TCP tunnels are exposed on a public port of the node holding them
+
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS kind text NOT NULL DEFAULT 'screen';
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS public_port integer;
+
+-- A public port may only be held by one active tunnel per node
+CREATE UNIQUE INDEX IF NOT EXISTS idx_tunnels_active_public_port ON tunnels(node_id, public_port)
+    WHERE closed_at IS NULL AND public_port IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Tunnels can be given a time to live; the expiry scheduler closes them when
+-- expires_at passes, also after a restart
+
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS expires_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS idx_tunnels_active_expiry ON tunnels(node_id, expires_at)
+    WHERE closed_at IS NULL AND expires_at IS NOT NULL;
@@ -0,0 +1,27 @@
+-- Outbound webhooks: endpoints users register for tunnel and viewer events,
+-- and a log of every delivery attempt
+CREATE TABLE IF NOT EXISTS webhooks (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    url text NOT NULL,
+    secret text NOT NULL, -- HMAC-SHA256 signing key, shown to the user once
+    events text NOT NULL, -- comma-separated event names
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhooks_user_id ON webhooks(user_id, created_at);
+
+-- One row per attempt; retries of an event share its event_id
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id uuid PRIMARY KEY,
+    webhook_id uuid NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event_id uuid NOT NULL,
+    event text NOT NULL,
+    attempt integer NOT NULL,
+    status_code integer, -- NULL when no response was received
+    error text,
+    delivered boolean NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id, created_at);
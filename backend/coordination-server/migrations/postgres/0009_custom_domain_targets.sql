@@ -0,0 +1,4 @@
+-- The tunnel a custom domain is served from, by reserved subdomain or label
+-- Domains without a target stay on the offline page
+
+ALTER TABLE custom_domains ADD COLUMN IF NOT EXISTS target text;
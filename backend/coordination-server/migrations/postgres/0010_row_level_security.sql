@@ -0,0 +1,22 @@
+-- Lock the tables away from Supabase's auto-generated REST API
+-- Supabase serves every table in the public schema to its anon and
+-- authenticated roles, and these tables hold SSH keys, webhook secrets and
+-- client IPs. Row level security without any policy denies those roles
+-- everything, while the server keeps full access: it connects as the table
+-- owner or the service role, both of which bypass RLS.
+-- Plain Postgres, without the Supabase roles, is left alone.
+CREATE OR REPLACE FUNCTION enable_supabase_rls()
+RETURNS void AS $$
+DECLARE
+    t record;
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_roles WHERE rolname IN ('anon', 'authenticated')) THEN
+        FOR t IN SELECT tablename FROM pg_tables WHERE schemaname = current_schema() LOOP
+            EXECUTE format('ALTER TABLE %I ENABLE ROW LEVEL SECURITY', t.tablename);
+        END LOOP;
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Migrations adding tables call this again
+SELECT enable_supabase_rls();
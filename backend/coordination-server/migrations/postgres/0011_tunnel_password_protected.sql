@@ -0,0 +1,7 @@
+-- Closed tunnel records are kept as history, which only needs to know whether
+-- a tunnel had a password; the Basic Auth password itself is dropped on close
+
+ALTER TABLE tunnels ADD COLUMN IF NOT EXISTS password_protected boolean NOT NULL DEFAULT false;
+
+UPDATE tunnels SET password_protected = true WHERE password IS NOT NULL;
+UPDATE tunnels SET password = NULL WHERE closed_at IS NOT NULL;
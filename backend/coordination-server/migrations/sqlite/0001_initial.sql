@@ -0,0 +1,70 @@
+-- Initial schema for the tnnl coordination server (SQLite)
+-- UUIDs are stored as 16-byte blobs and timestamps as RFC 3339 text
+
+-- Users are keyed by the `sub` claim of their auth token
+CREATE TABLE IF NOT EXISTS users (
+    id BLOB PRIMARY KEY,
+    email TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL
+);
+
+-- SSH keys and other tnnl-specific user data
+CREATE TABLE IF NOT EXISTS user_profiles (
+    id BLOB PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    ssh_public_key TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- Tunnels; rows are kept after a tunnel closes (closed_at set) as session history
+CREATE TABLE IF NOT EXISTS tunnels (
+    id BLOB PRIMARY KEY,
+    subdomain TEXT NOT NULL,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    is_custom BOOLEAN NOT NULL DEFAULT 0,
+    port INTEGER NOT NULL,
+    password TEXT, -- Optional HTTP Basic Auth password
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    last_connected_at TEXT,
+    closed_at TEXT, -- NULL while the tunnel is active
+    close_reason TEXT,
+    duration_seconds INTEGER,
+    viewer_sessions INTEGER NOT NULL DEFAULT 0
+);
+
+-- Append-only record of auth, SSH key, tunnel and admin events
+-- user_id is not a foreign key so failed auth attempts and deleted users stay in the log
+CREATE TABLE IF NOT EXISTS audit_events (
+    id BLOB PRIMARY KEY,
+    action TEXT NOT NULL,
+    user_id BLOB,
+    subdomain TEXT,
+    client_ip TEXT,
+    details TEXT, -- JSON-encoded event details
+    created_at TEXT NOT NULL
+);
+
+-- Indexes
+CREATE INDEX IF NOT EXISTS idx_tunnels_subdomain ON tunnels(subdomain);
+CREATE INDEX IF NOT EXISTS idx_tunnels_user_history ON tunnels(user_id, created_at DESC);
+-- A subdomain may only be held by one active tunnel at a time
+CREATE UNIQUE INDEX IF NOT EXISTS idx_tunnels_active_subdomain ON tunnels(subdomain) WHERE closed_at IS NULL;
+CREATE INDEX IF NOT EXISTS idx_tunnels_port ON tunnels(port);
+CREATE INDEX IF NOT EXISTS idx_audit_events_created_at ON audit_events(created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_events_user_id ON audit_events(user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_events_subdomain ON audit_events(subdomain, created_at);
+
+-- Reject any modification of existing audit events
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update
+    BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete
+    BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
@@ -0,0 +1,13 @@
+-- Custom hostnames users route to their tunnels
+-- A hostname is claimed when added and only served once ownership is verified
+CREATE TABLE IF NOT EXISTS custom_domains (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    hostname TEXT NOT NULL UNIQUE,
+    verification_method TEXT NOT NULL, -- 'txt' or 'http'
+    verification_token TEXT NOT NULL,
+    verified_at TEXT, -- NULL until ownership is proven and a certificate issued
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_custom_domains_user_id ON custom_domains(user_id, created_at);
@@ -0,0 +1,19 @@
+-- Cluster mode: several coordination servers share this database
+-- SQLite deployments are always standalone, but keep the same schema
+
+-- Live nodes report in every heartbeat interval; silent nodes are failed over
+CREATE TABLE IF NOT EXISTS cluster_nodes (
+    id TEXT PRIMARY KEY,
+    region TEXT,
+    public_url TEXT,
+    base_domain TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    last_heartbeat_at TEXT NOT NULL
+);
+
+-- Standalone servers record their tunnels as node 'local'
+ALTER TABLE tunnels ADD COLUMN node_id TEXT NOT NULL DEFAULT 'local';
+
+CREATE INDEX IF NOT EXISTS idx_tunnels_active_node ON tunnels(node_id) WHERE closed_at IS NULL;
+-- Forward ports are local to a node, so only unique per node
+CREATE UNIQUE INDEX IF NOT EXISTS idx_tunnels_active_node_port ON tunnels(node_id, port) WHERE closed_at IS NULL;
@@ -0,0 +1,27 @@
+-- Bandwidth accounting: bytes moved through the proxy, collected from nginx
+-- traffic logs per tunnel, per viewer connection and per user per month
+
+ALTER TABLE tunnels ADD COLUMN bytes_in INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tunnels ADD COLUMN bytes_out INTEGER NOT NULL DEFAULT 0;
+
+-- Monthly totals per user, checked against the transfer cap
+CREATE TABLE IF NOT EXISTS user_traffic (
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    month TEXT NOT NULL, -- UTC 'YYYY-MM'
+    bytes_in INTEGER NOT NULL DEFAULT 0,
+    bytes_out INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, month)
+);
+
+-- One row per finished viewer WebSocket connection
+CREATE TABLE IF NOT EXISTS viewer_traffic (
+    id BLOB PRIMARY KEY,
+    tunnel_id BLOB NOT NULL REFERENCES tunnels(id) ON DELETE CASCADE,
+    request_id TEXT NOT NULL, -- nginx $request_id
+    remote_ip TEXT NOT NULL,
+    bytes_in INTEGER NOT NULL,
+    bytes_out INTEGER NOT NULL,
+    ended_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_viewer_traffic_tunnel_id ON viewer_traffic(tunnel_id, ended_at);
@@ -0,0 +1,3 @@
+-- Labels clients give their tunnels, to tell several tunnels of one app apart
+
+ALTER TABLE tunnels ADD COLUMN label TEXT;
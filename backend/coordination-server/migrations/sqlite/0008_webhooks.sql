@@ -0,0 +1,27 @@
+-- Outbound webhooks: endpoints users register for tunnel and viewer events,
+-- and a log of every delivery attempt
+CREATE TABLE IF NOT EXISTS webhooks (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL, -- HMAC-SHA256 signing key, shown to the user once
+    events TEXT NOT NULL, -- comma-separated event names
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhooks_user_id ON webhooks(user_id, created_at);
+
+-- One row per attempt; retries of an event share its event_id
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BLOB PRIMARY KEY,
+    webhook_id BLOB NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    event_id BLOB NOT NULL,
+    event TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER, -- NULL when no response was received
+    error TEXT,
+    delivered BOOLEAN NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries(webhook_id, created_at);
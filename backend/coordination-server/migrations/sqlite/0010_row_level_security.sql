@@ -0,0 +1,4 @@
+-- Counterpart of the Postgres migration locking tables away from Supabase's
+-- REST API; SQLite has no such API and no row level security
+
+SELECT 1;
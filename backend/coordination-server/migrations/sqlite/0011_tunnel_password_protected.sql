@@ -0,0 +1,7 @@
+-- Closed tunnel records are kept as history, which only needs to know whether
+-- a tunnel had a password; the Basic Auth password itself is dropped on close
+
+ALTER TABLE tunnels ADD COLUMN password_protected BOOLEAN NOT NULL DEFAULT 0;
+
+UPDATE tunnels SET password_protected = 1 WHERE password IS NOT NULL;
+UPDATE tunnels SET password = NULL WHERE closed_at IS NOT NULL;
@@ -0,0 +1,204 @@
+// Admin HTTP API
+// Bound to a separate (by default loopback-only) address and protected by a bearer token
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::audit::{self, AuditAction, AuditEvent, AuditFilter};
+use crate::{certs, limits, reconcile};
+use crate::AppState;
+
+#[derive(Clone)]
+struct AdminState {
+    app: Arc<AppState>,
+    token: Arc<String>,
+}
+
+/// Serve the admin API on an already-bound listener
+pub async fn serve(listener: TcpListener, app: Arc<AppState>, token: String) {
+    let state = AdminState {
+        app,
+        token: Arc::new(token),
+    };
+
+    let router = Router::new()
+        .route("/admin/audit", get(list_audit_events))
+        .route("/admin/tunnels/:subdomain", delete(close_tunnel))
+        .route("/admin/metrics", get(metrics))
+        .route("/admin/reconcile", get(last_reconcile_report).post(run_reconcile))
+        .route("/admin/certificates", get(list_certificates))
+        .route("/admin/certificates/:domain/renew", post(renew_certificate))
+        .with_state(state);
+
+    if let Err(e) = axum::serve(listener, router).await {
+        error!("Admin API server error: {}", e);
+    }
+}
+
+/// Check the `Authorization: Bearer <token>` header
+/// Compared in constant time so response timing does not leak the token
+fn is_authorized(headers: &HeaderMap, state: &AdminState) -> bool {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| bool::from(token.as_bytes().ct_eq(state.token.as_bytes())))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// GET /admin/audit?user_id=&subdomain=&since=&until=&limit=
+async fn list_audit_events(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Query(filter): Query<AuditFilter>,
+) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    match state.app.store.query_audit_events(&filter).await {
+        Ok(events) => Json(serde_json::json!({ "events": events })).into_response(),
+        Err(e) => {
+            error!("Failed to query audit events: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        }
+    }
+}
+
+/// DELETE /admin/tunnels/:subdomain
+/// Force-closes a live tunnel and notifies its owner, on whichever node holds it
+async fn close_tunnel(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(subdomain): Path<String>,
+) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    let client_ip = limits::proxied_client_ip(&headers);
+    audit::record(
+        state.app.store.as_ref(),
+        AuditEvent::new(AuditAction::AdminAction)
+            .subdomain(&subdomain)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "operation": "force_close_tunnel" })),
+    )
+    .await;
+
+    if crate::close_tunnel_anywhere(&state.app, &subdomain, "admin_force_close").await {
+        info!("Admin force-closed tunnel {}", subdomain);
+        Json(serde_json::json!({ "closed": subdomain })).into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, "Tunnel not found")
+    }
+}
+
+/// GET /admin/metrics
+/// Connected apps, connections closed for breaking a limit, drift found by the
+/// reconciler and certificate renewals since startup, and days to expiry of
+/// every certificate
+async fn metrics(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    let connections = state.app.clients.read().await.len();
+    Json(serde_json::json!({
+        "connections": connections,
+        "limits": state.app.limits.metrics(),
+        "reconciler": state.app.reconciler.metrics(),
+        "certificates": state.app.certificates.metrics(),
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct ReconcileQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// GET /admin/reconcile
+/// Report of the last reconciliation pass, null before the first
+async fn last_reconcile_report(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    Json(serde_json::json!({ "report": state.app.reconciler.last_report() })).into_response()
+}
+
+/// POST /admin/reconcile?dry_run=
+/// Run a reconciliation pass now, waiting for one already running to finish
+async fn run_reconcile(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Query(query): Query<ReconcileQuery>,
+) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    let client_ip = limits::proxied_client_ip(&headers);
+    audit::record(
+        state.app.store.as_ref(),
+        AuditEvent::new(AuditAction::AdminAction)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "operation": "reconcile", "dry_run": query.dry_run })),
+    )
+    .await;
+
+    let report = reconcile::reconcile(&state.app, query.dry_run).await;
+    Json(serde_json::json!({ "report": report })).into_response()
+}
+
+/// GET /admin/certificates
+/// Certificates as of the last check, with days to expiry and issuer
+async fn list_certificates(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    Json(serde_json::json!({ "certificates": state.app.certificates.inventory() })).into_response()
+}
+
+/// POST /admin/certificates/:domain/renew
+/// Renew a certificate now, whether or not it is due
+async fn renew_certificate(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(domain): Path<String>,
+) -> Response {
+    if !is_authorized(&headers, &state) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+
+    let client_ip = limits::proxied_client_ip(&headers);
+    audit::record(
+        state.app.store.as_ref(),
+        AuditEvent::new(AuditAction::AdminAction)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "operation": "renew_certificate", "domain": domain })),
+    )
+    .await;
+
+    match certs::force_renew(&state.app, &domain).await {
+        Ok(Some(status)) => Json(serde_json::json!({ "certificate": status })).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Certificate not found"),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, &format!("Renewal failed: {}", e)),
+    }
+}
@@ -0,0 +1,198 @@
+// Append-only audit log of account, tunnel, custom domain, viewer access,
+// bandwidth and webhook events
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::store::TunnelStore;
+
+/// Kinds of events recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    AuthSuccess,
+    AuthFailure,
+    SshKeyRegistered,
+    SshKeyRevoked,
+    TunnelCreated,
+    TunnelClosed,
+    CustomDomainAdded,
+    CustomDomainVerified,
+    CustomDomainRemoved,
+    NodeFailover,
+    ViewerAllowlistUpdated,
+    ViewerSignedIn,
+    TransferCapExceeded,
+    WebhookAdded,
+    WebhookRemoved,
+    AdminAction,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::AuthSuccess => "auth_success",
+            AuditAction::AuthFailure => "auth_failure",
+            AuditAction::SshKeyRegistered => "ssh_key_registered",
+            AuditAction::SshKeyRevoked => "ssh_key_revoked",
+            AuditAction::TunnelCreated => "tunnel_created",
+            AuditAction::TunnelClosed => "tunnel_closed",
+            AuditAction::CustomDomainAdded => "custom_domain_added",
+            AuditAction::CustomDomainVerified => "custom_domain_verified",
+            AuditAction::CustomDomainRemoved => "custom_domain_removed",
+            AuditAction::NodeFailover => "node_failover",
+            AuditAction::ViewerAllowlistUpdated => "viewer_allowlist_updated",
+            AuditAction::ViewerSignedIn => "viewer_signed_in",
+            AuditAction::TransferCapExceeded => "transfer_cap_exceeded",
+            AuditAction::WebhookAdded => "webhook_added",
+            AuditAction::WebhookRemoved => "webhook_removed",
+            AuditAction::AdminAction => "admin_action",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auth_success" => Some(AuditAction::AuthSuccess),
+            "auth_failure" => Some(AuditAction::AuthFailure),
+            "ssh_key_registered" => Some(AuditAction::SshKeyRegistered),
+            "ssh_key_revoked" => Some(AuditAction::SshKeyRevoked),
+            "tunnel_created" => Some(AuditAction::TunnelCreated),
+            "tunnel_closed" => Some(AuditAction::TunnelClosed),
+            "custom_domain_added" => Some(AuditAction::CustomDomainAdded),
+            "custom_domain_verified" => Some(AuditAction::CustomDomainVerified),
+            "custom_domain_removed" => Some(AuditAction::CustomDomainRemoved),
+            "node_failover" => Some(AuditAction::NodeFailover),
+            "viewer_allowlist_updated" => Some(AuditAction::ViewerAllowlistUpdated),
+            "viewer_signed_in" => Some(AuditAction::ViewerSignedIn),
+            "transfer_cap_exceeded" => Some(AuditAction::TransferCapExceeded),
+            "webhook_added" => Some(AuditAction::WebhookAdded),
+            "webhook_removed" => Some(AuditAction::WebhookRemoved),
+            "admin_action" => Some(AuditAction::AdminAction),
+            _ => None,
+        }
+    }
+}
+
+/// A single row in the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub action: AuditAction,
+    pub user_id: Option<Uuid>,
+    pub subdomain: Option<String>,
+    pub client_ip: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            action,
+            user_id: None,
+            subdomain: None,
+            client_ip: None,
+            details: serde_json::Value::Null,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn user(mut self, user_id: Option<Uuid>) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    pub fn subdomain(mut self, subdomain: &str) -> Self {
+        self.subdomain = Some(subdomain.to_string());
+        self
+    }
+
+    pub fn client_ip(mut self, client_ip: Option<&str>) -> Self {
+        self.client_ip = client_ip.map(String::from);
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Filter for querying the audit log
+/// All fields are optional; unset fields match everything
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    pub user_id: Option<Uuid>,
+    pub subdomain: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+pub const DEFAULT_QUERY_LIMIT: i64 = 100;
+pub const MAX_QUERY_LIMIT: i64 = 1000;
+
+impl AuditFilter {
+    /// Effective row limit, clamped to MAX_QUERY_LIMIT
+    pub fn effective_limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .clamp(1, MAX_QUERY_LIMIT)
+    }
+}
+
+/// Record an audit event
+/// Failures are logged rather than returned so auditing never blocks the
+/// action being audited
+pub async fn record(store: &dyn TunnelStore, event: AuditEvent) {
+    if let Err(e) = store.insert_audit_event(&event).await {
+        error!("Failed to record audit event {}: {}", event.action.as_str(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_round_trip() {
+        let actions = [
+            AuditAction::AuthSuccess,
+            AuditAction::AuthFailure,
+            AuditAction::SshKeyRegistered,
+            AuditAction::SshKeyRevoked,
+            AuditAction::TunnelCreated,
+            AuditAction::TunnelClosed,
+            AuditAction::CustomDomainAdded,
+            AuditAction::CustomDomainVerified,
+            AuditAction::CustomDomainRemoved,
+            AuditAction::NodeFailover,
+            AuditAction::ViewerAllowlistUpdated,
+            AuditAction::ViewerSignedIn,
+            AuditAction::TransferCapExceeded,
+            AuditAction::WebhookAdded,
+            AuditAction::WebhookRemoved,
+            AuditAction::AdminAction,
+        ];
+
+        for action in actions {
+            assert_eq!(AuditAction::parse(action.as_str()), Some(action));
+        }
+
+        assert_eq!(AuditAction::parse("unknown"), None);
+    }
+
+    #[test]
+    fn test_filter_limit_is_clamped() {
+        let mut filter = AuditFilter::default();
+        assert_eq!(filter.effective_limit(), DEFAULT_QUERY_LIMIT);
+
+        filter.limit = Some(0);
+        assert_eq!(filter.effective_limit(), 1);
+
+        filter.limit = Some(50_000);
+        assert_eq!(filter.effective_limit(), MAX_QUERY_LIMIT);
+    }
+}
@@ -0,0 +1,455 @@
+// Bandwidth accounting and monthly transfer caps
+// The proxy reports the bytes each request moved (nginx writes a traffic log
+// per tunnel). collect() adds them to the tunnel, to the viewer connection and
+// to the owner's monthly total, then warns, throttles or closes the tunnels of
+// users past their cap
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::config::{BandwidthConfig, OverCapAction};
+use crate::tunnel::Tunnel;
+use crate::AppState;
+
+/// Close reason of tunnels closed for using up the monthly cap
+pub const CAP_CLOSE_REASON: &str = "transfer_cap_exceeded";
+
+/// nginx log_format of the traffic logs; parse_log_line reads it back
+pub const LOG_FORMAT: &str = "$msec $request_id $remote_addr $request_length $bytes_sent $http_upgrade";
+
+/// The same fields for the connections of TCP tunnels, logged by the stream module
+pub const STREAM_LOG_FORMAT: &str = "$msec $connection $remote_addr $bytes_received $bytes_sent tcp";
+
+/// Bytes moved by one proxied request, as reported by the proxy
+/// WebSocket and TCP connections are reported once, when they close
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficSample {
+    pub tunnel_id: Uuid,
+    pub request_id: String,
+    pub remote_ip: String,
+    /// Bytes received from the viewer (for WebSockets, only the handshake)
+    pub bytes_in: u64,
+    /// Bytes sent to the viewer
+    pub bytes_out: u64,
+    /// A viewer's WebSocket or TCP connection rather than a page request
+    pub viewer_session: bool,
+    pub ended_at: DateTime<Utc>,
+}
+
+/// Traffic of one finished viewer connection
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewerTraffic {
+    pub id: Uuid,
+    pub tunnel_id: Uuid,
+    pub request_id: String,
+    pub remote_ip: String,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub ended_at: DateTime<Utc>,
+}
+
+/// Bytes moved in each direction over some period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficTotals {
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+}
+
+impl TrafficTotals {
+    /// Transfer counted against the cap
+    pub fn total(&self) -> i64 {
+        self.bytes_in.saturating_add(self.bytes_out)
+    }
+}
+
+/// Month traffic is totalled under, as UTC "YYYY-MM"
+pub fn month_key(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m").to_string()
+}
+
+/// Parse a line of a tunnel's traffic log; None if it is malformed
+pub fn parse_log_line(tunnel_id: Uuid, line: &str) -> Option<TrafficSample> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [msec, request_id, remote_addr, request_length, bytes_sent, upgrade] = fields[..] else {
+        return None;
+    };
+
+    let millis = (msec.parse::<f64>().ok()? * 1000.0) as i64;
+    Some(TrafficSample {
+        tunnel_id,
+        request_id: request_id.to_string(),
+        remote_ip: remote_addr.parse::<std::net::IpAddr>().ok()?.to_string(),
+        bytes_in: request_length.parse().ok()?,
+        bytes_out: bytes_sent.parse().ok()?,
+        viewer_session: upgrade.eq_ignore_ascii_case("websocket") || upgrade == "tcp",
+        ended_at: Utc.timestamp_millis_opt(millis).single()?,
+    })
+}
+
+/// How much of their monthly cap a user has used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageLevel {
+    Normal,
+    /// Past the warning threshold
+    Warning,
+    /// Cap used up
+    OverCap,
+}
+
+pub fn usage_level(config: &BandwidthConfig, used_bytes: i64) -> UsageLevel {
+    match config.monthly_cap_bytes() {
+        Some(cap) if used_bytes >= cap => UsageLevel::OverCap,
+        Some(cap) if used_bytes.saturating_mul(100) >= cap.saturating_mul(config.warn_percent as i64) => {
+            UsageLevel::Warning
+        }
+        _ => UsageLevel::Normal,
+    }
+}
+
+/// Level each user was last acted on at, so a warning or limit is applied once
+/// per month instead of on every collection
+#[derive(Default)]
+pub struct UsageTracker {
+    levels: Mutex<HashMap<Uuid, (String, UsageLevel)>>,
+}
+
+impl UsageTracker {
+    /// Record a user's level for `month`, returning the previous record
+    async fn update(&self, user_id: Uuid, month: &str, level: UsageLevel) -> Option<(String, UsageLevel)> {
+        self.levels.lock().await.insert(user_id, (month.to_string(), level))
+    }
+}
+
+/// A user's transfer this month
+pub async fn monthly_usage(state: &AppState, user_id: Uuid) -> Result<TrafficTotals> {
+    state.store.get_user_traffic(user_id, &month_key(Utc::now())).await
+}
+
+/// Usage summary sent to the client in reply to get_bandwidth_usage
+pub async fn usage_report(state: &AppState, user_id: Uuid) -> Result<serde_json::Value> {
+    let config = &state.config.bandwidth;
+    let usage = monthly_usage(state, user_id).await?;
+    Ok(serde_json::json!({
+        "type": "bandwidth_usage",
+        "month": month_key(Utc::now()),
+        "bytes_in": usage.bytes_in,
+        "bytes_out": usage.bytes_out,
+        "used_bytes": usage.total(),
+        "cap_bytes": config.monthly_cap_bytes(),
+        "level": usage_level(config, usage.total()),
+        "over_cap_action": config.over_cap_action.as_str()
+    }))
+}
+
+/// Collect traffic every collect_interval_secs
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.bandwidth.collect_interval_secs));
+    loop {
+        interval.tick().await;
+        collect(&state).await;
+    }
+}
+
+/// Record the traffic proxied since the last collection and enforce caps for
+/// the users it belongs to
+pub async fn collect(state: &Arc<AppState>) {
+    let samples = match state.proxy.collect_traffic().await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to collect traffic: {}", e);
+            return;
+        }
+    };
+    if samples.is_empty() {
+        return;
+    }
+
+    let month = month_key(Utc::now());
+    let mut per_tunnel: HashMap<Uuid, TrafficTotals> = HashMap::new();
+    for sample in &samples {
+        let totals = per_tunnel.entry(sample.tunnel_id).or_default();
+        totals.bytes_in = totals.bytes_in.saturating_add(sample.bytes_in as i64);
+        totals.bytes_out = totals.bytes_out.saturating_add(sample.bytes_out as i64);
+
+        if sample.viewer_session {
+            let viewer = ViewerTraffic {
+                id: Uuid::new_v4(),
+                tunnel_id: sample.tunnel_id,
+                request_id: sample.request_id.clone(),
+                remote_ip: sample.remote_ip.clone(),
+                bytes_in: sample.bytes_in as i64,
+                bytes_out: sample.bytes_out as i64,
+                ended_at: sample.ended_at,
+            };
+            if let Err(e) = state.store.record_viewer_traffic(&viewer).await {
+                error!("Failed to record viewer traffic for tunnel {}: {}", sample.tunnel_id, e);
+            }
+        }
+    }
+
+    let mut users = HashSet::new();
+    for (tunnel_id, totals) in per_tunnel {
+        match state.store.add_tunnel_traffic(tunnel_id, &month, totals).await {
+            Ok(Some(user_id)) => {
+                users.insert(user_id);
+            }
+            Ok(None) => warn!("Dropping traffic of unknown tunnel {}", tunnel_id),
+            Err(e) => error!("Failed to record traffic for tunnel {}: {}", tunnel_id, e),
+        }
+    }
+
+    for user_id in users {
+        match state.store.get_user_traffic(user_id, &month).await {
+            Ok(usage) => enforce(state, user_id, &month, usage).await,
+            Err(e) => error!("Failed to load traffic for user {}: {}", user_id, e),
+        }
+    }
+}
+
+/// Warn or limit a user whose usage reached a new level this month
+async fn enforce(state: &Arc<AppState>, user_id: Uuid, month: &str, usage: TrafficTotals) {
+    let config = &state.config.bandwidth;
+    let level = usage_level(config, usage.total());
+    let previous = state.bandwidth.update(user_id, month, level).await;
+
+    // Throttling lasts until the month the cap was hit is over
+    let was_over_cap = matches!(previous, Some((_, UsageLevel::OverCap)));
+    if was_over_cap && level < UsageLevel::OverCap && config.over_cap_action == OverCapAction::Throttle {
+        info!("Lifting bandwidth throttle of user {}", user_id);
+        set_rate_limit(state, user_id, None).await;
+    }
+
+    let previous_level = match previous {
+        Some((m, l)) if m == month => l,
+        _ => UsageLevel::Normal,
+    };
+    if level <= previous_level {
+        return;
+    }
+
+    let cap = config.monthly_cap_bytes().unwrap_or_default();
+    match level {
+        UsageLevel::Normal => {}
+        UsageLevel::Warning => {
+            info!("User {} has used {} of {} bytes this month", user_id, usage.total(), cap);
+            notify_user(
+                state,
+                user_id,
+                serde_json::json!({
+                    "type": "bandwidth_warning",
+                    "month": month,
+                    "used_bytes": usage.total(),
+                    "cap_bytes": cap
+                }),
+            )
+            .await;
+        }
+        UsageLevel::OverCap => {
+            warn!("User {} exceeded the monthly transfer cap", user_id);
+            let mut notice = serde_json::json!({
+                "type": "bandwidth_limited",
+                "action": config.over_cap_action.as_str(),
+                "month": month,
+                "used_bytes": usage.total(),
+                "cap_bytes": cap
+            });
+            if config.over_cap_action == OverCapAction::Throttle {
+                notice["rate_bytes_per_sec"] = config.throttle_rate_bytes().into();
+            }
+            notify_user(state, user_id, notice).await;
+
+            audit::record(
+                state.store.as_ref(),
+                AuditEvent::new(AuditAction::TransferCapExceeded)
+                    .user(Some(user_id))
+                    .details(serde_json::json!({
+                        "month": month,
+                        "used_bytes": usage.total(),
+                        "cap_bytes": cap,
+                        "action": config.over_cap_action.as_str()
+                    })),
+            )
+            .await;
+
+            match config.over_cap_action {
+                OverCapAction::Throttle => {
+                    set_rate_limit(state, user_id, Some(config.throttle_rate_bytes())).await;
+                }
+                OverCapAction::Close => {
+                    for tunnel in user_tunnels(state, user_id).await {
+                        crate::force_close_tunnel(state, &tunnel.subdomain, CAP_CLOSE_REASON).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Live tunnels of a user on this node
+async fn user_tunnels(state: &AppState, user_id: Uuid) -> Vec<Tunnel> {
+    let clients = state.clients.read().await;
+    clients
+        .values()
+        .filter(|c| c.user_id == Some(user_id))
+        .flat_map(|c| c.tunnels.iter().cloned())
+        .collect()
+}
+
+/// Throttle or unthrottle every live tunnel of a user on this node, including
+/// the custom domains routed to them
+async fn set_rate_limit(state: &Arc<AppState>, user_id: Uuid, bytes_per_sec: Option<u64>) {
+    for tunnel in user_tunnels(state, user_id).await {
+        if let Err(e) = state.proxy.set_tunnel_rate_limit(&tunnel, bytes_per_sec).await {
+            error!("Failed to set rate limit of {}: {}", tunnel.subdomain, e);
+        }
+    }
+
+    if !user_tunnels(state, user_id).await.is_empty() {
+        crate::route_custom_domains(state, user_id, None).await;
+    }
+}
+
+/// Send a message to every client of a user on this node
+async fn notify_user(state: &AppState, user_id: Uuid, message: serde_json::Value) {
+    let clients = state.clients.read().await;
+    for client in clients.values().filter(|c| c.user_id == Some(user_id)) {
+        let _ = client.sender.send(Message::Text(message.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_parse_log_line() {
+        let tunnel_id = Uuid::new_v4();
+        let sample = parse_log_line(
+            tunnel_id,
+            "1767225600.250 4f3c2a1b9e8d7c6b5a4f3e2d1c0b9a8f 203.0.113.9 412 1048576 websocket",
+        )
+        .unwrap();
+        assert_eq!(sample.tunnel_id, tunnel_id);
+        assert_eq!(sample.remote_ip, "203.0.113.9");
+        assert_eq!((sample.bytes_in, sample.bytes_out), (412, 1_048_576));
+        assert!(sample.viewer_session);
+        assert_eq!(sample.ended_at.timestamp_millis(), 1_767_225_600_250);
+        assert_eq!(month_key(sample.ended_at), "2026-01");
+
+        let page = parse_log_line(tunnel_id, "1767225600.000 abc 2001:db8::1 380 5120 -").unwrap();
+        assert!(!page.viewer_session);
+
+        let connection = parse_log_line(tunnel_id, "1767225600.000 1042 203.0.113.9 2048 4096 tcp").unwrap();
+        assert!(connection.viewer_session);
+        assert_eq!((connection.bytes_in, connection.bytes_out), (2048, 4096));
+
+        assert!(parse_log_line(tunnel_id, "1767225600.000 abc 203.0.113.9 380 -").is_none());
+        assert!(parse_log_line(tunnel_id, "1767225600.000 abc not-an-ip 380 5120 -").is_none());
+    }
+
+    #[test]
+    fn test_usage_level() {
+        let mut config = BandwidthConfig::default();
+        assert_eq!(usage_level(&config, i64::MAX), UsageLevel::Normal);
+
+        config.monthly_cap_mb = 100;
+        let cap = config.monthly_cap_bytes().unwrap();
+        assert_eq!(usage_level(&config, cap / 2), UsageLevel::Normal);
+        assert_eq!(usage_level(&config, cap * 8 / 10), UsageLevel::Warning);
+        assert_eq!(usage_level(&config, cap), UsageLevel::OverCap);
+    }
+
+    fn traffic_sample(tunnel_id: Uuid, bytes_out: u64, viewer_session: bool) -> TrafficSample {
+        TrafficSample {
+            tunnel_id,
+            request_id: Uuid::new_v4().simple().to_string(),
+            remote_ip: "203.0.113.9".to_string(),
+            bytes_in: 0,
+            bytes_out,
+            viewer_session,
+            ended_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_cap_warns_then_throttles() {
+        let mut config = test_config();
+        config.bandwidth.monthly_cap_mb = 1;
+        let (state, proxy) = test_state_with_config(config);
+        let (client_id, mut rx, tunnel) = authed_tunnel(&state, serde_json::json!({})).await;
+        let (tunnel_id, subdomain) = (tunnel.id, tunnel.subdomain);
+        assert!(proxy.rate_limits.lock().unwrap().is_empty());
+
+        // 880 KiB of a 1 MiB cap: warned once
+        proxy.traffic.lock().unwrap().push(traffic_sample(tunnel_id, 880 * 1024, false));
+        collect(&state).await;
+        let warning = next_message(&mut rx);
+        assert_eq!(warning["type"], "bandwidth_warning");
+        assert_eq!(warning["cap_bytes"], 1024 * 1024);
+        proxy.traffic.lock().unwrap().push(traffic_sample(tunnel_id, 1024, false));
+        collect(&state).await;
+        assert!(rx.try_recv().is_err());
+
+        // A viewer connection pushes the user over the cap
+        proxy.traffic.lock().unwrap().push(traffic_sample(tunnel_id, 200 * 1024, true));
+        collect(&state).await;
+        let limited = next_message(&mut rx);
+        assert_eq!(limited["type"], "bandwidth_limited");
+        assert_eq!(limited["action"], "throttle");
+        assert_eq!(limited["rate_bytes_per_sec"], 64 * 1024);
+        assert_eq!(*proxy.rate_limits.lock().unwrap(), vec![format!("{} 65536", subdomain)]);
+
+        send(client_id, serde_json::json!({ "type": "get_bandwidth_usage" }), &state).await;
+        let usage = next_message(&mut rx);
+        assert_eq!(usage["type"], "bandwidth_usage");
+        assert_eq!(usage["used_bytes"], (880 + 1 + 200) * 1024);
+        assert_eq!(usage["level"], "over_cap");
+
+        send(client_id, serde_json::json!({ "type": "get_viewer_traffic", "tunnel_id": tunnel_id }), &state).await;
+        let viewers = next_message(&mut rx);
+        assert_eq!(viewers["sessions"].as_array().unwrap().len(), 1);
+        assert_eq!(viewers["sessions"][0]["bytes_out"], 200 * 1024);
+
+        send(client_id, serde_json::json!({ "type": "get_tunnel_history" }), &state).await;
+        assert_eq!(next_message(&mut rx)["tunnels"][0]["bytes_out"], (880 + 1 + 200) * 1024);
+
+        // New tunnels start out throttled
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &state).await;
+        let second = next_message(&mut rx);
+        assert_eq!(second["tunnel"]["throttled"], true);
+        assert_eq!(proxy.rate_limits.lock().unwrap().len(), 2);
+
+        let events = state.store.query_audit_events(&audit::AuditFilter::default()).await.unwrap();
+        assert!(events.iter().any(|e| e.action == AuditAction::TransferCapExceeded));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_cap_closes_tunnels() {
+        let mut config = test_config();
+        config.bandwidth.monthly_cap_mb = 1;
+        config.bandwidth.over_cap_action = OverCapAction::Close;
+        let (state, proxy) = test_state_with_config(config);
+        let (client_id, mut rx, tunnel) = authed_tunnel(&state, serde_json::json!({})).await;
+
+        proxy.traffic.lock().unwrap().push(traffic_sample(tunnel.id, 2 * 1024 * 1024, true));
+        collect(&state).await;
+        assert_eq!(next_message(&mut rx)["action"], "close");
+        let closed = next_message(&mut rx);
+        assert_eq!(closed["type"], "tunnel_closed");
+        assert_eq!(closed["reason"], CAP_CLOSE_REASON);
+        assert_eq!(*proxy.removed.lock().unwrap(), vec![tunnel.subdomain]);
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "Monthly transfer cap reached");
+    }
+}
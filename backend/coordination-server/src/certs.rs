@@ -0,0 +1,288 @@
+// Certificate inventory and renewal
+// certbot issues a certificate when a tunnel or custom domain is first served,
+// and nothing looked at it again, so a long-lived reserved subdomain could
+// quietly expire. The monitor periodically reads every certificate the proxy
+// holds, renews those still in use before they expire and keeps the inventory
+// for the admin API and metrics
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::AppState;
+
+/// A certificate the proxy serves a hostname with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Certificate {
+    pub domain: String,
+    pub not_after: DateTime<Utc>,
+    pub issuer: String,
+}
+
+impl Certificate {
+    /// Read `openssl x509 -noout -enddate -issuer` output, e.g.
+    /// `notAfter=Jan  1 00:00:00 2027 GMT` and `issuer=C = US, O = Let's Encrypt, CN = R11`
+    pub fn from_openssl(domain: &str, output: &str) -> anyhow::Result<Self> {
+        let mut not_after = None;
+        let mut issuer = None;
+        for line in output.lines() {
+            if let Some(date) = line.strip_prefix("notAfter=") {
+                let date = NaiveDateTime::parse_from_str(date.trim(), "%b %e %H:%M:%S %Y GMT")
+                    .map_err(|e| anyhow::anyhow!("Unreadable expiry {:?}: {}", date, e))?;
+                not_after = Some(date.and_utc());
+            } else if let Some(name) = line.strip_prefix("issuer=") {
+                issuer = Some(name.trim().to_string());
+            }
+        }
+        Ok(Self {
+            domain: domain.to_string(),
+            not_after: not_after.ok_or_else(|| anyhow::anyhow!("No expiry in certificate of {}", domain))?,
+            issuer: issuer.unwrap_or_default(),
+        })
+    }
+}
+
+/// A certificate as of the last check
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateStatus {
+    #[serde(flatten)]
+    pub certificate: Certificate,
+    pub days_to_expiry: i64,
+    /// Whether a live tunnel, reserved name or custom domain uses it; only
+    /// these are renewed
+    pub in_use: bool,
+    /// Why the last renewal failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renewal_error: Option<String>,
+}
+
+/// Renewal counts since startup and days to expiry by domain
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct CertificateMetrics {
+    pub renewals: u64,
+    pub renewal_failures: u64,
+    pub days_to_expiry: BTreeMap<String, i64>,
+}
+
+/// Inventory from the last check and renewal counters
+#[derive(Default)]
+pub struct CertificateMonitor {
+    /// Held while checking or renewing so certbot never runs twice for a domain
+    busy: tokio::sync::Mutex<()>,
+    inventory: Mutex<Vec<CertificateStatus>>,
+    renewals: AtomicU64,
+    renewal_failures: AtomicU64,
+}
+
+impl CertificateMonitor {
+    pub fn inventory(&self) -> Vec<CertificateStatus> {
+        self.inventory.lock().unwrap().clone()
+    }
+
+    pub fn metrics(&self) -> CertificateMetrics {
+        CertificateMetrics {
+            renewals: self.renewals.load(Ordering::Relaxed),
+            renewal_failures: self.renewal_failures.load(Ordering::Relaxed),
+            days_to_expiry: self
+                .inventory
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|s| (s.certificate.domain.clone(), s.days_to_expiry))
+                .collect(),
+        }
+    }
+}
+
+/// Check certificates until the server stops
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.certbot.check_interval_secs));
+    loop {
+        interval.tick().await;
+        check(&state, Utc::now()).await;
+    }
+}
+
+/// Whether a live tunnel on this node, a reserved name or a custom domain is
+/// served with the certificate for `domain`
+async fn in_use(state: &AppState, domain: &str) -> bool {
+    let suffix = format!(".{}", state.config.server.base_domain);
+    if let Some(subdomain) = domain.strip_suffix(&suffix) {
+        return state.tunnel_manager.get_tunnel(subdomain).await.is_some()
+            || state.tunnel_manager.is_reserved(subdomain);
+    }
+    state.store.get_custom_domain(domain).await.ok().flatten().is_some()
+}
+
+/// Ask the proxy for a certificate's renewal and count the outcome
+async fn renew_one(state: &AppState, domain: &str) -> anyhow::Result<()> {
+    match state.proxy.renew_certificate(domain).await {
+        Ok(()) => {
+            info!("Renewed certificate for {}", domain);
+            state.certificates.renewals.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to renew certificate for {}: {}", domain, e);
+            state.certificates.renewal_failures.fetch_add(1, Ordering::Relaxed);
+            Err(e)
+        }
+    }
+}
+
+/// Read the inventory as of `now`, with the given renewal errors
+async fn take_inventory(
+    state: &AppState,
+    now: DateTime<Utc>,
+    errors: &BTreeMap<String, String>,
+) -> anyhow::Result<Vec<CertificateStatus>> {
+    let mut inventory = Vec::new();
+    for certificate in state.proxy.certificates().await? {
+        inventory.push(CertificateStatus {
+            days_to_expiry: (certificate.not_after - now).num_days(),
+            in_use: in_use(state, &certificate.domain).await,
+            renewal_error: errors.get(&certificate.domain).cloned(),
+            certificate,
+        });
+    }
+    inventory.sort_by(|a, b| a.certificate.domain.cmp(&b.certificate.domain));
+    Ok(inventory)
+}
+
+/// Renew the certificates in use that expire within the renewal window as of
+/// `now`, then record the inventory
+pub async fn check(state: &Arc<AppState>, now: DateTime<Utc>) {
+    let _busy = state.certificates.busy.lock().await;
+    let inventory = match take_inventory(state, now, &BTreeMap::new()).await {
+        Ok(inventory) => inventory,
+        Err(e) => {
+            error!("Failed to read certificates: {}", e);
+            return;
+        }
+    };
+
+    let window = chrono::Duration::days(state.config.certbot.renew_before_days as i64);
+    let mut errors = BTreeMap::new();
+    let mut renewed = false;
+    for status in inventory.iter().filter(|s| s.in_use && s.certificate.not_after - now <= window) {
+        let domain = &status.certificate.domain;
+        if status.certificate.not_after <= now {
+            warn!("Certificate for {} expired at {}", domain, status.certificate.not_after);
+        }
+        match renew_one(state, domain).await {
+            Ok(()) => renewed = true,
+            Err(e) => {
+                errors.insert(domain.clone(), e.to_string());
+            }
+        }
+    }
+
+    let inventory = if renewed || !errors.is_empty() {
+        match take_inventory(state, now, &errors).await {
+            Ok(inventory) => inventory,
+            Err(e) => {
+                error!("Failed to read certificates after renewal: {}", e);
+                return;
+            }
+        }
+    } else {
+        inventory
+    };
+    *state.certificates.inventory.lock().unwrap() = inventory;
+}
+
+/// Renew one certificate of the inventory now, whether or not it is due
+/// None when the proxy holds no certificate for `domain`
+pub async fn force_renew(state: &Arc<AppState>, domain: &str) -> anyhow::Result<Option<CertificateStatus>> {
+    let _busy = state.certificates.busy.lock().await;
+    let now = Utc::now();
+    let inventory = take_inventory(state, now, &BTreeMap::new()).await?;
+    if !inventory.iter().any(|s| s.certificate.domain == domain) {
+        return Ok(None);
+    }
+
+    let mut errors = BTreeMap::new();
+    let result = renew_one(state, domain).await;
+    if let Err(e) = &result {
+        errors.insert(domain.to_string(), e.to_string());
+    }
+    let inventory = take_inventory(state, now, &errors).await?;
+    let status = inventory.iter().find(|s| s.certificate.domain == domain).cloned();
+    *state.certificates.inventory.lock().unwrap() = inventory;
+
+    result?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use std::collections::HashMap;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_read_openssl_output() {
+        let output = "notAfter=Jan  9 08:30:00 2027 GMT\nissuer=C = US, O = Let's Encrypt, CN = R11\n";
+        let certificate = Certificate::from_openssl("calm-elk-1234.tnnl.to", output).unwrap();
+        assert_eq!(certificate.domain, "calm-elk-1234.tnnl.to");
+        assert_eq!(certificate.not_after, Utc.with_ymd_and_hms(2027, 1, 9, 8, 30, 0).unwrap());
+        assert_eq!(certificate.issuer, "C = US, O = Let's Encrypt, CN = R11");
+
+        let output = "notAfter=Dec 31 23:59:59 2026 GMT\n";
+        assert_eq!(
+            Certificate::from_openssl("app.example.com", output).unwrap().not_after,
+            Utc.with_ymd_and_hms(2026, 12, 31, 23, 59, 59).unwrap()
+        );
+
+        assert!(Certificate::from_openssl("app.example.com", "issuer=CN = R11\n").is_err());
+        assert!(Certificate::from_openssl("app.example.com", "notAfter=soon\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_certificates_in_use_are_renewed_ahead_of_expiry() {
+        let (state, proxy) = test_state();
+        let (_, _rx, tunnel) = authed_tunnel(&state, serde_json::json!({})).await;
+        let subdomain = tunnel.subdomain;
+
+        let now = chrono::Utc::now();
+        let certificate = |domain: String, days: i64| Certificate {
+            domain,
+            not_after: now + chrono::Duration::days(days),
+            issuer: "CN = R11".to_string(),
+        };
+        let live = format!("{}.tunnels.example.com", subdomain);
+        let reserved = "status.tunnels.example.com".to_string();
+        let orphan = "lost-fox-1234.tunnels.example.com".to_string();
+        *proxy.certificates.lock().unwrap() = vec![
+            certificate(live.clone(), 10),
+            certificate(reserved.clone(), 60),
+            certificate(orphan.clone(), 5),
+        ];
+
+        // Only certificates in use and within the 30-day window are renewed
+        check(&state, now).await;
+        assert_eq!(*proxy.renewed.lock().unwrap(), vec![live.clone()]);
+        let inventory: HashMap<String, (i64, bool)> = state
+            .certificates
+            .inventory()
+            .into_iter()
+            .map(|s| (s.certificate.domain, (s.days_to_expiry, s.in_use)))
+            .collect();
+        assert_eq!(inventory[&live], (90, true));
+        assert_eq!(inventory[&reserved], (60, true));
+        assert_eq!(inventory[&orphan], (5, false));
+        let metrics = state.certificates.metrics();
+        assert_eq!((metrics.renewals, metrics.renewal_failures), (1, 0));
+        assert_eq!(metrics.days_to_expiry[&reserved], 60);
+
+        // An admin can renew any certificate the proxy holds, due or not
+        let status = force_renew(&state, &reserved).await.unwrap().unwrap();
+        assert_eq!(status.days_to_expiry, 90);
+        assert!(force_renew(&state, "nowhere.example.com").await.unwrap().is_none());
+        assert_eq!(*proxy.renewed.lock().unwrap(), vec![live, reserved]);
+    }
+}
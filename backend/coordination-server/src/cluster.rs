@@ -0,0 +1,389 @@
+// Cluster mode: several coordination servers sharing one Postgres database
+// Each node registers itself and heartbeats in cluster_nodes, owns the tunnels
+// of the clients connected to it, and receives events addressed to it (such as
+// an admin force-close) over Postgres LISTEN/NOTIFY. Nodes that stop
+// heartbeating are failed over: a surviving node closes their tunnels so the
+// clients can reconnect elsewhere
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::AppState;
+
+/// Postgres NOTIFY channel carrying cluster events
+pub const CLUSTER_CHANNEL: &str = "tnnl_cluster";
+
+/// A coordination server as registered in cluster_nodes
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterNode {
+    pub id: String,
+    pub region: Option<String>,
+    pub public_url: Option<String>,
+    pub base_domain: String,
+    pub started_at: DateTime<Utc>,
+    pub last_heartbeat_at: DateTime<Utc>,
+}
+
+impl ClusterNode {
+    /// Whether the node has heartbeated within `timeout_secs`
+    pub fn is_live(&self, timeout_secs: u64) -> bool {
+        Utc::now() - self.last_heartbeat_at <= chrono::Duration::seconds(timeout_secs as i64)
+    }
+}
+
+/// Events sent between nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    /// Close a tunnel owned by `node_id` and notify its client
+    ForceCloseTunnel {
+        node_id: String,
+        subdomain: String,
+        reason: String,
+    },
+}
+
+impl ClusterEvent {
+    /// Node the event is addressed to
+    pub fn target_node(&self) -> &str {
+        match self {
+            ClusterEvent::ForceCloseTunnel { node_id, .. } => node_id,
+        }
+    }
+}
+
+/// Delivers events between nodes
+/// PgBus is used in cluster mode; LocalBus keeps a standalone server (and
+/// tests running several nodes in one process) on the same code path
+#[async_trait]
+pub trait ClusterBus: Send + Sync {
+    /// Send an event to every node
+    async fn publish(&self, event: &ClusterEvent) -> Result<()>;
+
+    /// Receive events published by any node, including this one
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<ClusterEvent>>;
+}
+
+/// In-process bus
+pub struct LocalBus {
+    sender: broadcast::Sender<ClusterEvent>,
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl ClusterBus for LocalBus {
+    async fn publish(&self, event: &ClusterEvent) -> Result<()> {
+        // No subscribers is fine: nobody is listening yet
+        let _ = self.sender.send(event.clone());
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<ClusterEvent>> {
+        let mut events = self.sender.subscribe();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Cluster bus dropped {} event(s)", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// Bus over Postgres LISTEN/NOTIFY on CLUSTER_CHANNEL
+pub struct PgBus {
+    pool: sqlx::PgPool,
+}
+
+impl PgBus {
+    /// Open a small dedicated pool; the listener holds one connection
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(2)
+            .connect(database_url)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ClusterBus for PgBus {
+    async fn publish(&self, event: &ClusterEvent) -> Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CLUSTER_CHANNEL)
+            .bind(serde_json::to_string(event)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<ClusterEvent>> {
+        let mut listener = sqlx::postgres::PgListener::connect_with(&self.pool).await?;
+        listener.listen(CLUSTER_CHANNEL).await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                // PgListener reconnects and re-listens on the next recv after an error
+                match listener.recv().await {
+                    Ok(notification) => match serde_json::from_str(notification.payload()) {
+                        Ok(event) => {
+                            if tx.send(event).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("Ignoring malformed cluster event: {}", e),
+                    },
+                    Err(e) => {
+                        error!("Cluster listener error: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// Open the bus for the configured mode
+pub async fn open_bus(config: &crate::config::Config) -> Result<Box<dyn ClusterBus>> {
+    if config.cluster.enabled {
+        Ok(Box::new(PgBus::connect(&config.server.database_url).await?))
+    } else {
+        Ok(Box::new(LocalBus::new()))
+    }
+}
+
+/// This node's registry entry
+pub fn local_node(state: &AppState) -> ClusterNode {
+    let now = Utc::now();
+    ClusterNode {
+        id: state.config.cluster.node_id().to_string(),
+        region: state.config.cluster.region.clone(),
+        public_url: state.config.cluster.public_url.clone(),
+        base_domain: state.config.server.base_domain.clone(),
+        started_at: now,
+        last_heartbeat_at: now,
+    }
+}
+
+/// Live nodes ordered for a client: its region first, then this node, then the rest
+pub async fn nodes_for_client(state: &AppState, client_region: Option<&str>) -> Vec<ClusterNode> {
+    let timeout = state.config.cluster.node_timeout_secs;
+    let local_id = state.config.cluster.node_id();
+
+    let mut nodes: Vec<ClusterNode> = match state.store.list_cluster_nodes().await {
+        Ok(nodes) => nodes.into_iter().filter(|n| n.is_live(timeout)).collect(),
+        Err(e) => {
+            error!("Failed to list cluster nodes: {}", e);
+            Vec::new()
+        }
+    };
+
+    nodes.sort_by_key(|n| {
+        let same_region = client_region.is_some() && n.region.as_deref() == client_region;
+        (!same_region, n.id != local_id, n.id.clone())
+    });
+    nodes
+}
+
+/// Run this node's membership: register, subscribe to events, heartbeat and
+/// fail over dead nodes. Only started in cluster mode
+pub async fn run(state: Arc<AppState>) {
+    let node = local_node(&state);
+    if let Err(e) = state.store.register_node(&node).await {
+        error!("Failed to register cluster node {}: {}", node.id, e);
+    }
+    info!("Registered cluster node {} ({})", node.id, node.region.as_deref().unwrap_or("no region"));
+
+    match state.bus.subscribe().await {
+        Ok(events) => {
+            tokio::spawn(handle_events(state.clone(), events));
+        }
+        Err(e) => error!("Failed to subscribe to cluster events: {}", e),
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.cluster.heartbeat_interval_secs));
+    loop {
+        interval.tick().await;
+        heartbeat(&state).await;
+    }
+}
+
+/// Act on events addressed to this node
+pub async fn handle_events(state: Arc<AppState>, mut events: mpsc::UnboundedReceiver<ClusterEvent>) {
+    while let Some(event) = events.recv().await {
+        if event.target_node() != state.config.cluster.node_id() {
+            continue;
+        }
+
+        match event {
+            ClusterEvent::ForceCloseTunnel { subdomain, reason, .. } => {
+                info!("Closing tunnel {} on request from another node", subdomain);
+                if !crate::force_close_tunnel(&state, &subdomain, &reason).await {
+                    warn!("Tunnel {} to close is not connected to this node", subdomain);
+                }
+            }
+        }
+    }
+}
+
+/// Report this node alive and close the tunnels of nodes that are not
+pub async fn heartbeat(state: &AppState) {
+    let local_id = state.config.cluster.node_id();
+    if let Err(e) = state.store.heartbeat_node(local_id).await {
+        error!("Failed to record heartbeat for node {}: {}", local_id, e);
+    }
+
+    let nodes = match state.store.list_cluster_nodes().await {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("Failed to list cluster nodes: {}", e);
+            return;
+        }
+    };
+
+    for node in nodes {
+        if node.id == local_id || node.is_live(state.config.cluster.node_timeout_secs) {
+            continue;
+        }
+
+        // Closing is idempotent, so nodes racing to fail over the same node is harmless
+        warn!("Cluster node {} stopped heartbeating, closing its tunnels", node.id);
+        match state.store.close_stale_tunnel_records(&node.id, "node_failed").await {
+            Ok(n) => info!("Closed {} tunnel(s) of failed node {}", n, node.id),
+            Err(e) => {
+                error!("Failed to close tunnels of node {}: {}", node.id, e);
+                continue;
+            }
+        }
+        if let Err(e) = state.store.remove_node(&node.id).await {
+            error!("Failed to remove node {}: {}", node.id, e);
+        }
+
+        audit::record(
+            state.store.as_ref(),
+            AuditEvent::new(AuditAction::NodeFailover).details(serde_json::json!({
+                "node_id": node.id,
+                "detected_by": local_id
+            })),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::store::TunnelStore;
+    use crate::{close_tunnel_anywhere, db};
+    use tokio_tungstenite::tungstenite::Message;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_event_wire_format() {
+        let event = ClusterEvent::ForceCloseTunnel {
+            node_id: "eu-1".to_string(),
+            subdomain: "happy-fox-1234".to_string(),
+            reason: "admin_force_close".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "force_close_tunnel");
+        assert_eq!(serde_json::from_value::<ClusterEvent>(json).unwrap(), event);
+        assert_eq!(event.target_node(), "eu-1");
+    }
+
+    #[tokio::test]
+    async fn test_local_bus_delivers_to_every_subscriber() {
+        let bus = LocalBus::new();
+        let mut a = bus.subscribe().await.unwrap();
+        let mut b = bus.subscribe().await.unwrap();
+
+        let event = ClusterEvent::ForceCloseTunnel {
+            node_id: "us-1".to_string(),
+            subdomain: "happy-fox-1234".to_string(),
+            reason: "admin_force_close".to_string(),
+        };
+        bus.publish(&event).await.unwrap();
+
+        assert_eq!(a.recv().await.unwrap(), event);
+        assert_eq!(b.recv().await.unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_cluster_steering_forwarded_close_and_failover() {
+        let pool = db::init_pool("sqlite::memory:").await.unwrap();
+        let bus = Arc::new(LocalBus::new());
+        let eu = test_node("eu-1", "eu-west", &pool, &bus);
+        let us = test_node("us-1", "us-east", &pool, &bus);
+        for node in [&eu, &us] {
+            pool.register_node(&local_node(node)).await.unwrap();
+        }
+        tokio::spawn(handle_events(us.clone(), bus.subscribe().await.unwrap()));
+
+        // A client on the EU node hinting it is in us-east is pointed at the US node first
+        let (client_id, mut rx) = connect_client(&eu).await;
+        let token = test_token(Uuid::new_v4(), "dev@example.com");
+        send(client_id, serde_json::json!({ "type": "auth", "token": token, "region": "us-east" }), &eu).await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["node"]["id"], "eu-1");
+        assert_eq!(response["nodes"][0]["id"], "us-1");
+        assert_eq!(response["nodes"][0]["url"], "wss://us-1.example.com");
+        assert_eq!(response["nodes"][1]["id"], "eu-1");
+
+        // A tunnel on the US node can be closed from the EU node
+        let (client_id, mut rx, tunnel) = authed_tunnel(&us, serde_json::json!({})).await;
+        let subdomain = tunnel.subdomain;
+        assert_eq!(
+            pool.get_tunnel_by_subdomain(&subdomain).await.unwrap().unwrap().node_id,
+            "us-1"
+        );
+
+        assert!(close_tunnel_anywhere(&eu, &subdomain, "admin_force_close").await);
+        let notice = match tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await {
+            Ok(Some(Message::Text(text))) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("expected tunnel_closed, got {:?}", other),
+        };
+        assert_eq!(notice["type"], "tunnel_closed");
+        assert!(pool.get_tunnel_by_subdomain(&subdomain).await.unwrap().is_none());
+        assert!(!close_tunnel_anywhere(&eu, "no-such-tunnel", "admin_force_close").await);
+
+        // When the US node stops heartbeating, the EU node closes its tunnels
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &us).await;
+        let subdomain = next_message(&mut rx)["tunnel"]["subdomain"].as_str().unwrap().to_string();
+        let mut dead = local_node(&us);
+        dead.last_heartbeat_at = chrono::Utc::now() - chrono::Duration::minutes(5);
+        pool.register_node(&dead).await.unwrap();
+
+        heartbeat(&eu).await;
+
+        assert!(pool.get_tunnel_by_subdomain(&subdomain).await.unwrap().is_none());
+        let history = pool.get_user_tunnels(us.clients.read().await[&client_id].user_id.unwrap(), 10).await.unwrap();
+        assert_eq!(history[0].close_reason.as_deref(), Some("node_failed"));
+        let nodes = pool.list_cluster_nodes().await.unwrap();
+        assert_eq!(nodes.iter().map(|n| n.id.as_str()).collect::<Vec<_>>(), vec!["eu-1"]);
+    }
+}
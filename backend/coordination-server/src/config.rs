@@ -0,0 +1,1136 @@
+// Server configuration
+// Loaded from a TOML file (TNNL_CONFIG, default ./tnnl.toml if present), then
+// overridden by environment variables and validated before anything starts
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::subdomains;
+
+/// Config file read when TNNL_CONFIG is not set
+pub const DEFAULT_CONFIG_PATH: &str = "tnnl.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub admin: AdminConfig,
+    pub tunnels: TunnelConfig,
+    pub nginx: NginxConfig,
+    pub certbot: CertbotConfig,
+    pub ssh: SshConfig,
+    pub cluster: ClusterConfig,
+    pub viewer_auth: ViewerAuthConfig,
+    pub bandwidth: BandwidthConfig,
+    pub webhooks: WebhooksConfig,
+    pub logging: LoggingConfig,
+    pub limits: LimitsConfig,
+    pub reconciler: ReconcilerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the WebSocket server listens on
+    pub bind_address: SocketAddr,
+    /// `postgresql://...`, `sqlite:<path>` or `memory:`
+    pub database_url: String,
+    /// HS256 secret used to verify auth tokens
+    pub jwt_secret: String,
+    /// Accept auth tokens without verifying their signature (development only)
+    pub dev_mode: bool,
+    /// Domain tunnels are published under as <subdomain>.<base_domain>
+    pub base_domain: String,
+    /// Host desktop apps open SSH reverse tunnels to; defaults to base_domain
+    pub ssh_host: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            database_url: String::new(),
+            jwt_secret: String::new(),
+            dev_mode: false,
+            base_domain: "tnnl.to".to_string(),
+            ssh_host: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the admin API; the API is disabled when unset
+    pub token: Option<String>,
+    pub bind_address: SocketAddr,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 8081)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// First local port handed out for SSH reverse forwards
+    pub port_base: u16,
+    /// Number of local ports for SSH reverse forwards, i.e. most tunnels open
+    /// on a node at once
+    pub port_count: u16,
+    /// First public port TCP tunnels are exposed on
+    pub tcp_port_base: u16,
+    /// Number of public ports reserved for TCP tunnels
+    pub tcp_port_count: u16,
+    /// Most tunnels one client connection may hold at once
+    pub max_per_client: usize,
+    /// Words in generated subdomains: adjectives followed by one noun
+    pub subdomain_words: usize,
+    /// Digits in the numeric suffix of generated subdomains; 0 for none
+    pub subdomain_digits: u32,
+    /// Subdomains never handed out, on top of the built-in ones (www, api, ...)
+    pub reserved_subdomains: Vec<String>,
+    /// Longest time to live a client may give a tunnel
+    pub max_ttl_secs: u64,
+    /// How long before expiry the host is warned
+    pub expiry_warning_secs: u64,
+    /// How often the expiry scheduler runs
+    pub expiry_check_interval_secs: u64,
+    /// Time the app has to bring up a new tunnel's SSH forward
+    pub ready_timeout_secs: u64,
+}
+
+impl TunnelConfig {
+    /// Local ports SSH reverse forwards are bound to
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        let last = self.port_base.saturating_add(self.port_count.saturating_sub(1));
+        self.port_base..=last
+    }
+
+    /// Public ports TCP tunnels are exposed on
+    pub fn tcp_ports(&self) -> RangeInclusive<u16> {
+        let last = self.tcp_port_base.saturating_add(self.tcp_port_count.saturating_sub(1));
+        self.tcp_port_base..=last
+    }
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            port_base: 10000,
+            port_count: 10000,
+            tcp_port_base: 30000,
+            tcp_port_count: 1000,
+            max_per_client: 5,
+            subdomain_words: 2,
+            subdomain_digits: 4,
+            reserved_subdomains: Vec::new(),
+            max_ttl_secs: 7 * 24 * 3600,
+            expiry_warning_secs: 300,
+            expiry_check_interval_secs: 10,
+            ready_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NginxConfig {
+    pub sites_available_dir: PathBuf,
+    pub sites_enabled_dir: PathBuf,
+    /// Directory for tunnel snippets included by the main config
+    pub conf_dir: PathBuf,
+    /// Directory for TCP tunnel snippets included in the main config's stream block
+    pub stream_conf_dir: PathBuf,
+    /// Directory for per-tunnel htpasswd files
+    pub passwd_dir: PathBuf,
+    /// Document root of the HTTP-only and offline sites
+    pub web_root: PathBuf,
+    /// Viewer client page to serve instead of the one built into the server
+    pub client_template: Option<PathBuf>,
+    /// Address the viewer client is served on, reached through nginx
+    pub client_bind_address: SocketAddr,
+}
+
+impl Default for NginxConfig {
+    fn default() -> Self {
+        Self {
+            sites_available_dir: PathBuf::from("/etc/nginx/sites-available"),
+            sites_enabled_dir: PathBuf::from("/etc/nginx/sites-enabled"),
+            conf_dir: PathBuf::from("/etc/nginx/tunnels"),
+            stream_conf_dir: PathBuf::from("/etc/nginx/tnnl-streams"),
+            passwd_dir: PathBuf::from("/etc/nginx/passwd"),
+            web_root: PathBuf::from("/var/www/html"),
+            client_template: None,
+            client_bind_address: SocketAddr::from(([127, 0, 0, 1], 8083)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertbotConfig {
+    /// Webroot served for ACME HTTP-01 challenges
+    pub webroot: PathBuf,
+    /// Contact email registered with Let's Encrypt
+    pub email: String,
+    /// Directory certbot stores issued certificates in
+    pub live_dir: PathBuf,
+    /// How often issued certificates are checked for expiry
+    pub check_interval_secs: u64,
+    /// Certificates expiring within this many days are renewed
+    pub renew_before_days: u32,
+}
+
+impl Default for CertbotConfig {
+    fn default() -> Self {
+        Self {
+            webroot: PathBuf::from("/var/www/certbot"),
+            email: "admin@tnnl.to".to_string(),
+            live_dir: PathBuf::from("/etc/letsencrypt/live"),
+            check_interval_secs: 6 * 3600,
+            renew_before_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    /// authorized_keys file of the account tunnels SSH into
+    pub authorized_keys_path: PathBuf,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            authorized_keys_path: PathBuf::from("/home/tnnl/.ssh/authorized_keys"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Run as one node of a cluster sharing a Postgres database
+    pub enabled: bool,
+    /// Unique name of this node; required in cluster mode
+    pub node_id: Option<String>,
+    /// Region clients in the same region are steered to, e.g. "eu-west"
+    pub region: Option<String>,
+    /// WebSocket URL clients use to reach this node, e.g. wss://eu.tnnl.to
+    pub public_url: Option<String>,
+    /// How often this node reports that it is alive
+    pub heartbeat_interval_secs: u64,
+    /// Nodes silent for longer are considered dead and their tunnels closed
+    pub node_timeout_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_id: None,
+            region: None,
+            public_url: None,
+            heartbeat_interval_secs: 10,
+            node_timeout_secs: 30,
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Node ID tunnels are recorded under; standalone servers use "local"
+    pub fn node_id(&self) -> &str {
+        self.node_id.as_deref().unwrap_or("local")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewerAuthConfig {
+    /// Serve the forward-auth endpoint and sign-in pages for email allowlists
+    pub enabled: bool,
+    /// Address nginx reaches the endpoint on; keep it loopback-only
+    pub bind_address: SocketAddr,
+    /// HS256 secret signing viewer session cookies, at least 32 characters
+    pub cookie_secret: String,
+    /// Sender of sign-in code emails
+    pub email_from: String,
+    /// sendmail-compatible binary used to deliver sign-in codes
+    pub sendmail_path: PathBuf,
+    /// How long a sign-in code is valid
+    pub code_ttl_minutes: u64,
+    /// How long a viewer stays signed in
+    pub session_ttl_hours: u64,
+}
+
+impl Default for ViewerAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 8082)),
+            cookie_secret: String::new(),
+            email_from: "tnnl <no-reply@tnnl.to>".to_string(),
+            sendmail_path: PathBuf::from("/usr/sbin/sendmail"),
+            code_ttl_minutes: 10,
+            session_ttl_hours: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthConfig {
+    /// Directory nginx writes per-tunnel traffic logs to
+    pub log_dir: PathBuf,
+    /// How often traffic logs are collected and caps enforced
+    pub collect_interval_secs: u64,
+    /// Monthly transfer (in + out) allowed per user, in megabytes; 0 is unlimited
+    pub monthly_cap_mb: u64,
+    /// Warn the user once this share of their cap is used
+    pub warn_percent: u8,
+    /// What happens to a user's tunnels once the cap is used up
+    pub over_cap_action: OverCapAction,
+    /// Rate throttled tunnels are limited to, in kilobytes per second
+    pub throttle_rate_kbps: u64,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            log_dir: PathBuf::from("/var/log/nginx/tnnl-traffic"),
+            collect_interval_secs: 60,
+            monthly_cap_mb: 0,
+            warn_percent: 80,
+            over_cap_action: OverCapAction::Throttle,
+            throttle_rate_kbps: 64,
+        }
+    }
+}
+
+impl BandwidthConfig {
+    /// Monthly cap in bytes, None when unlimited
+    pub fn monthly_cap_bytes(&self) -> Option<i64> {
+        (self.monthly_cap_mb > 0).then(|| self.monthly_cap_mb as i64 * 1024 * 1024)
+    }
+
+    /// Rate limit applied to throttled tunnels, in bytes per second
+    pub fn throttle_rate_bytes(&self) -> u64 {
+        self.throttle_rate_kbps * 1024
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Let users register webhook endpoints
+    pub enabled: bool,
+    /// Endpoints one user may register
+    pub max_per_user: usize,
+    /// Attempts per event before a delivery is given up
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubles with every further attempt
+    pub retry_base_secs: u64,
+    /// Time allowed for one delivery attempt
+    pub timeout_secs: u64,
+    /// Accept plain http:// endpoints (e.g. for local testing)
+    pub allow_http: bool,
+    /// Accept endpoints on loopback, private and link-local addresses (e.g.
+    /// for local testing); otherwise they would let users probe the network
+    /// the server sits in
+    pub allow_private_addresses: bool,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_per_user: 5,
+            max_attempts: 5,
+            retry_base_secs: 10,
+            timeout_secs: 10,
+            allow_http: false,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Open app connections allowed from one client IP (0 is unlimited)
+    pub max_connections_per_ip: usize,
+    /// Largest WebSocket message accepted from an app, in bytes
+    pub max_frame_bytes: usize,
+    /// Messages per second a connection may keep sending
+    pub messages_per_sec: u32,
+    /// Messages a connection may send in a burst above that rate
+    pub message_burst: u32,
+    /// Time an app has to authenticate after connecting
+    pub auth_timeout_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: 20,
+            max_frame_bytes: 64 * 1024,
+            messages_per_sec: 10,
+            message_burst: 30,
+            auth_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconcilerConfig {
+    /// Periodically check the proxy and database against the live tunnels
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Only report drift, fix nothing; on until the operator has checked a report
+    pub dry_run: bool,
+    /// Tunnels younger than this are still being set up and not checked for
+    /// missing config
+    pub grace_secs: u64,
+}
+
+impl Default for ReconcilerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 300,
+            dry_run: true,
+            grace_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// tracing filter directives: a default level, optionally followed by
+    /// per-module levels, e.g. "info,tnnl_coordination_server::nginx=debug"
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("expected \"text\" or \"json\", got {:?}", s)),
+        }
+    }
+}
+
+/// What happens to a user's tunnels past their monthly cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverCapAction {
+    /// Keep tunnels up but rate-limit new viewer connections
+    Throttle,
+    /// Close the user's tunnels and refuse new ones until the month ends
+    Close,
+}
+
+impl OverCapAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverCapAction::Throttle => "throttle",
+            OverCapAction::Close => "close",
+        }
+    }
+}
+
+impl FromStr for OverCapAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "throttle" => Ok(OverCapAction::Throttle),
+            "close" => Ok(OverCapAction::Close),
+            _ => Err(anyhow!("expected \"throttle\" or \"close\", got {:?}", s)),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Host desktop apps should SSH to
+    pub fn ssh_host(&self) -> &str {
+        self.ssh_host.as_deref().unwrap_or(&self.base_domain)
+    }
+
+    /// Public hostname of a tunnel
+    pub fn tunnel_host(&self, subdomain: &str) -> String {
+        format!("{}.{}", subdomain, self.base_domain)
+    }
+}
+
+impl Config {
+    /// Load the config file (if any), apply environment overrides and validate
+    pub fn load() -> Result<Self> {
+        let env = |name: &str| std::env::var(name).ok();
+
+        let mut config = match env("TNNL_CONFIG") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env(&env)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Override values from environment variables
+    /// Every key can be set as TNNL_<SECTION>_<KEY>; the variables the server
+    /// has always read (DATABASE_URL, JWT_SECRET, ...) still work as aliases
+    pub fn apply_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+        override_from_env(&mut self.server.bind_address, &["TNNL_SERVER_BIND_ADDRESS", "BIND_ADDRESS"], env)?;
+        override_from_env(&mut self.server.database_url, &["TNNL_SERVER_DATABASE_URL", "DATABASE_URL"], env)?;
+        override_from_env(&mut self.server.jwt_secret, &["TNNL_SERVER_JWT_SECRET", "JWT_SECRET"], env)?;
+        override_from_env(&mut self.server.dev_mode, &["TNNL_SERVER_DEV_MODE", "DEV_MODE"], env)?;
+        override_from_env(&mut self.server.base_domain, &["TNNL_SERVER_BASE_DOMAIN", "BASE_DOMAIN"], env)?;
+        if let Some((_, host)) = first_env(&["TNNL_SERVER_SSH_HOST"], env) {
+            self.server.ssh_host = Some(host);
+        }
+
+        if let Some((_, token)) = first_env(&["TNNL_ADMIN_TOKEN", "ADMIN_TOKEN"], env) {
+            self.admin.token = Some(token);
+        }
+        override_from_env(&mut self.admin.bind_address, &["TNNL_ADMIN_BIND_ADDRESS", "ADMIN_BIND_ADDRESS"], env)?;
+
+        override_from_env(&mut self.tunnels.port_base, &["TNNL_TUNNELS_PORT_BASE"], env)?;
+        override_from_env(&mut self.tunnels.port_count, &["TNNL_TUNNELS_PORT_COUNT"], env)?;
+        override_from_env(&mut self.tunnels.tcp_port_base, &["TNNL_TUNNELS_TCP_PORT_BASE"], env)?;
+        override_from_env(&mut self.tunnels.tcp_port_count, &["TNNL_TUNNELS_TCP_PORT_COUNT"], env)?;
+        override_from_env(&mut self.tunnels.max_per_client, &["TNNL_TUNNELS_MAX_PER_CLIENT"], env)?;
+        override_from_env(&mut self.tunnels.subdomain_words, &["TNNL_TUNNELS_SUBDOMAIN_WORDS"], env)?;
+        override_from_env(&mut self.tunnels.subdomain_digits, &["TNNL_TUNNELS_SUBDOMAIN_DIGITS"], env)?;
+        override_from_env(&mut self.tunnels.max_ttl_secs, &["TNNL_TUNNELS_MAX_TTL_SECS"], env)?;
+        override_from_env(&mut self.tunnels.expiry_warning_secs, &["TNNL_TUNNELS_EXPIRY_WARNING_SECS"], env)?;
+        override_from_env(&mut self.tunnels.expiry_check_interval_secs, &["TNNL_TUNNELS_EXPIRY_CHECK_INTERVAL_SECS"], env)?;
+        override_from_env(&mut self.tunnels.ready_timeout_secs, &["TNNL_TUNNELS_READY_TIMEOUT_SECS"], env)?;
+        if let Some((_, reserved)) = first_env(&["TNNL_TUNNELS_RESERVED_SUBDOMAINS"], env) {
+            self.tunnels.reserved_subdomains = reserved
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+
+        override_from_env(&mut self.nginx.sites_available_dir, &["TNNL_NGINX_SITES_AVAILABLE_DIR"], env)?;
+        override_from_env(&mut self.nginx.sites_enabled_dir, &["TNNL_NGINX_SITES_ENABLED_DIR"], env)?;
+        override_from_env(&mut self.nginx.conf_dir, &["TNNL_NGINX_CONF_DIR"], env)?;
+        override_from_env(&mut self.nginx.stream_conf_dir, &["TNNL_NGINX_STREAM_CONF_DIR"], env)?;
+        override_from_env(&mut self.nginx.passwd_dir, &["TNNL_NGINX_PASSWD_DIR"], env)?;
+        override_from_env(&mut self.nginx.web_root, &["TNNL_NGINX_WEB_ROOT"], env)?;
+        if let Some((_, path)) = first_env(&["TNNL_NGINX_CLIENT_TEMPLATE"], env) {
+            self.nginx.client_template = (!path.trim().is_empty()).then(|| PathBuf::from(path.trim()));
+        }
+        override_from_env(&mut self.nginx.client_bind_address, &["TNNL_NGINX_CLIENT_BIND_ADDRESS"], env)?;
+
+        override_from_env(&mut self.certbot.webroot, &["TNNL_CERTBOT_WEBROOT"], env)?;
+        override_from_env(&mut self.certbot.email, &["TNNL_CERTBOT_EMAIL"], env)?;
+        override_from_env(&mut self.certbot.live_dir, &["TNNL_CERTBOT_LIVE_DIR"], env)?;
+        override_from_env(&mut self.certbot.check_interval_secs, &["TNNL_CERTBOT_CHECK_INTERVAL_SECS"], env)?;
+        override_from_env(&mut self.certbot.renew_before_days, &["TNNL_CERTBOT_RENEW_BEFORE_DAYS"], env)?;
+
+        override_from_env(&mut self.ssh.authorized_keys_path, &["TNNL_SSH_AUTHORIZED_KEYS_PATH"], env)?;
+
+        override_from_env(&mut self.cluster.enabled, &["TNNL_CLUSTER_ENABLED"], env)?;
+        if let Some((_, node_id)) = first_env(&["TNNL_CLUSTER_NODE_ID"], env) {
+            self.cluster.node_id = Some(node_id);
+        }
+        if let Some((_, region)) = first_env(&["TNNL_CLUSTER_REGION"], env) {
+            self.cluster.region = Some(region);
+        }
+        if let Some((_, url)) = first_env(&["TNNL_CLUSTER_PUBLIC_URL"], env) {
+            self.cluster.public_url = Some(url);
+        }
+        override_from_env(&mut self.cluster.heartbeat_interval_secs, &["TNNL_CLUSTER_HEARTBEAT_INTERVAL_SECS"], env)?;
+        override_from_env(&mut self.cluster.node_timeout_secs, &["TNNL_CLUSTER_NODE_TIMEOUT_SECS"], env)?;
+
+        override_from_env(&mut self.viewer_auth.enabled, &["TNNL_VIEWER_AUTH_ENABLED"], env)?;
+        override_from_env(&mut self.viewer_auth.bind_address, &["TNNL_VIEWER_AUTH_BIND_ADDRESS"], env)?;
+        override_from_env(&mut self.viewer_auth.cookie_secret, &["TNNL_VIEWER_AUTH_COOKIE_SECRET"], env)?;
+        override_from_env(&mut self.viewer_auth.email_from, &["TNNL_VIEWER_AUTH_EMAIL_FROM"], env)?;
+        override_from_env(&mut self.viewer_auth.sendmail_path, &["TNNL_VIEWER_AUTH_SENDMAIL_PATH"], env)?;
+        override_from_env(&mut self.viewer_auth.code_ttl_minutes, &["TNNL_VIEWER_AUTH_CODE_TTL_MINUTES"], env)?;
+        override_from_env(&mut self.viewer_auth.session_ttl_hours, &["TNNL_VIEWER_AUTH_SESSION_TTL_HOURS"], env)?;
+
+        override_from_env(&mut self.bandwidth.log_dir, &["TNNL_BANDWIDTH_LOG_DIR"], env)?;
+        override_from_env(&mut self.bandwidth.collect_interval_secs, &["TNNL_BANDWIDTH_COLLECT_INTERVAL_SECS"], env)?;
+        override_from_env(&mut self.bandwidth.monthly_cap_mb, &["TNNL_BANDWIDTH_MONTHLY_CAP_MB"], env)?;
+        override_from_env(&mut self.bandwidth.warn_percent, &["TNNL_BANDWIDTH_WARN_PERCENT"], env)?;
+        override_from_env(&mut self.bandwidth.over_cap_action, &["TNNL_BANDWIDTH_OVER_CAP_ACTION"], env)?;
+        override_from_env(&mut self.bandwidth.throttle_rate_kbps, &["TNNL_BANDWIDTH_THROTTLE_RATE_KBPS"], env)?;
+
+        override_from_env(&mut self.webhooks.enabled, &["TNNL_WEBHOOKS_ENABLED"], env)?;
+        override_from_env(&mut self.webhooks.max_per_user, &["TNNL_WEBHOOKS_MAX_PER_USER"], env)?;
+        override_from_env(&mut self.webhooks.max_attempts, &["TNNL_WEBHOOKS_MAX_ATTEMPTS"], env)?;
+        override_from_env(&mut self.webhooks.retry_base_secs, &["TNNL_WEBHOOKS_RETRY_BASE_SECS"], env)?;
+        override_from_env(&mut self.webhooks.timeout_secs, &["TNNL_WEBHOOKS_TIMEOUT_SECS"], env)?;
+        override_from_env(&mut self.webhooks.allow_http, &["TNNL_WEBHOOKS_ALLOW_HTTP"], env)?;
+        override_from_env(&mut self.webhooks.allow_private_addresses, &["TNNL_WEBHOOKS_ALLOW_PRIVATE_ADDRESSES"], env)?;
+
+        override_from_env(&mut self.logging.format, &["TNNL_LOGGING_FORMAT"], env)?;
+        override_from_env(&mut self.logging.level, &["TNNL_LOGGING_LEVEL", "RUST_LOG"], env)?;
+
+        override_from_env(&mut self.limits.max_connections_per_ip, &["TNNL_LIMITS_MAX_CONNECTIONS_PER_IP"], env)?;
+        override_from_env(&mut self.limits.max_frame_bytes, &["TNNL_LIMITS_MAX_FRAME_BYTES"], env)?;
+        override_from_env(&mut self.limits.messages_per_sec, &["TNNL_LIMITS_MESSAGES_PER_SEC"], env)?;
+        override_from_env(&mut self.limits.message_burst, &["TNNL_LIMITS_MESSAGE_BURST"], env)?;
+        override_from_env(&mut self.limits.auth_timeout_secs, &["TNNL_LIMITS_AUTH_TIMEOUT_SECS"], env)?;
+
+        override_from_env(&mut self.reconciler.enabled, &["TNNL_RECONCILER_ENABLED"], env)?;
+        override_from_env(&mut self.reconciler.interval_secs, &["TNNL_RECONCILER_INTERVAL_SECS"], env)?;
+        override_from_env(&mut self.reconciler.dry_run, &["TNNL_RECONCILER_DRY_RUN"], env)?;
+        override_from_env(&mut self.reconciler.grace_secs, &["TNNL_RECONCILER_GRACE_SECS"], env)?;
+
+        // An empty token means "disabled", same as leaving it out
+        if self.admin.token.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            self.admin.token = None;
+        }
+        if self.server.ssh_host.as_deref().is_some_and(|h| h.trim().is_empty()) {
+            self.server.ssh_host = None;
+        }
+        for value in [&mut self.cluster.node_id, &mut self.cluster.region, &mut self.cluster.public_url] {
+            if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+                *value = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the config, reporting every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.server.database_url.trim().is_empty() {
+            problems.push("server.database_url is not set (or set DATABASE_URL)".to_string());
+        }
+        if self.server.jwt_secret.trim().is_empty() {
+            problems.push("server.jwt_secret is not set (or set JWT_SECRET)".to_string());
+        }
+        if !is_valid_hostname(&self.server.base_domain) {
+            problems.push(format!(
+                "server.base_domain is not a valid domain name: {:?}",
+                self.server.base_domain
+            ));
+        }
+        if let Some(host) = &self.server.ssh_host {
+            if !is_valid_hostname(host) {
+                problems.push(format!("server.ssh_host is not a valid host name: {:?}", host));
+            }
+        }
+        let ports_valid = self.tunnels.port_base >= 1024
+            && self.tunnels.port_count > 0
+            && self.tunnels.port_base.checked_add(self.tunnels.port_count - 1).is_some();
+        if !ports_valid {
+            problems.push(format!(
+                "tunnels.port_base ({}) and tunnels.port_count ({}) must give a non-empty range of ports from 1024 to 65535",
+                self.tunnels.port_base, self.tunnels.port_count
+            ));
+        }
+        let tcp_ports_valid = self.tunnels.tcp_port_base >= 1024
+            && self.tunnels.tcp_port_count > 0
+            && self.tunnels.tcp_port_base.checked_add(self.tunnels.tcp_port_count - 1).is_some();
+        if !tcp_ports_valid {
+            problems.push(format!(
+                "tunnels.tcp_port_base ({}) and tunnels.tcp_port_count ({}) must give a non-empty range of ports from 1024 to 65535",
+                self.tunnels.tcp_port_base, self.tunnels.tcp_port_count
+            ));
+        }
+        // sshd and nginx's stream listeners bind on the same host
+        let (ports, tcp_ports) = (self.tunnels.ports(), self.tunnels.tcp_ports());
+        if ports_valid && tcp_ports_valid && ports.start() <= tcp_ports.end() && tcp_ports.start() <= ports.end() {
+            problems.push(format!(
+                "tunnels.port_base/port_count ({}-{}) overlaps tunnels.tcp_port_base/tcp_port_count ({}-{})",
+                ports.start(),
+                ports.end(),
+                tcp_ports.start(),
+                tcp_ports.end()
+            ));
+        }
+        if self.tunnels.max_per_client == 0 {
+            problems.push("tunnels.max_per_client must be non-zero".to_string());
+        }
+        let (words, digits) = (self.tunnels.subdomain_words, self.tunnels.subdomain_digits);
+        if !subdomains::WORDS_RANGE.contains(&words) || !subdomains::DIGITS_RANGE.contains(&digits) {
+            problems.push(format!(
+                "tunnels.subdomain_words must be 1-4 and tunnels.subdomain_digits 0-8, got {} and {}",
+                words, digits
+            ));
+        } else if subdomains::entropy_bits(words, digits) < subdomains::MIN_ENTROPY_BITS {
+            problems.push(format!(
+                "tunnels.subdomain_words ({}) and tunnels.subdomain_digits ({}) give {:.1} bits of entropy, at least {} needed",
+                words, digits, subdomains::entropy_bits(words, digits), subdomains::MIN_ENTROPY_BITS
+            ));
+        }
+        if self.tunnels.max_ttl_secs == 0 || self.tunnels.max_ttl_secs > i32::MAX as u64 {
+            problems.push(format!(
+                "tunnels.max_ttl_secs must be between 1 and {}, got {}",
+                i32::MAX, self.tunnels.max_ttl_secs
+            ));
+        }
+        if self.tunnels.expiry_check_interval_secs == 0 {
+            problems.push("tunnels.expiry_check_interval_secs must be non-zero".to_string());
+        }
+        if self.tunnels.ready_timeout_secs == 0 {
+            problems.push("tunnels.ready_timeout_secs must be non-zero".to_string());
+        }
+        for name in &self.tunnels.reserved_subdomains {
+            if !is_valid_node_id(name) {
+                problems.push(format!(
+                    "tunnels.reserved_subdomains must be lowercase letters, digits and hyphens, got {:?}",
+                    name
+                ));
+            }
+        }
+        if !self.certbot.email.contains('@') {
+            problems.push(format!("certbot.email is not an email address: {:?}", self.certbot.email));
+        }
+        // Let's Encrypt certificates last 90 days
+        if self.certbot.check_interval_secs == 0 || !(1..90).contains(&self.certbot.renew_before_days) {
+            problems.push(format!(
+                "certbot.check_interval_secs must be non-zero and certbot.renew_before_days between 1 and 89, got {} and {}",
+                self.certbot.check_interval_secs, self.certbot.renew_before_days
+            ));
+        }
+
+        if let Some(node_id) = &self.cluster.node_id {
+            if !is_valid_node_id(node_id) {
+                problems.push(format!(
+                    "cluster.node_id must be lowercase letters, digits and hyphens, got {:?}",
+                    node_id
+                ));
+            }
+        }
+        if self.cluster.enabled {
+            if !self.server.database_url.starts_with("postgres") {
+                problems.push("cluster mode needs a shared Postgres server.database_url".to_string());
+            }
+            if self.cluster.node_id.is_none() {
+                problems.push("cluster.node_id must be set in cluster mode".to_string());
+            }
+            match &self.cluster.public_url {
+                Some(url) if url.starts_with("ws://") || url.starts_with("wss://") => {}
+                Some(url) => problems.push(format!("cluster.public_url must be a ws:// or wss:// URL, got {:?}", url)),
+                None => problems.push("cluster.public_url must be set in cluster mode".to_string()),
+            }
+            if self.cluster.heartbeat_interval_secs == 0
+                || self.cluster.node_timeout_secs <= self.cluster.heartbeat_interval_secs
+            {
+                problems.push(format!(
+                    "cluster.node_timeout_secs ({}) must be longer than a non-zero cluster.heartbeat_interval_secs ({})",
+                    self.cluster.node_timeout_secs, self.cluster.heartbeat_interval_secs
+                ));
+            }
+        }
+
+        if self.viewer_auth.enabled {
+            if self.viewer_auth.cookie_secret.trim().len() < 32 {
+                problems.push(
+                    "viewer_auth.cookie_secret must be at least 32 characters when viewer auth is enabled"
+                        .to_string(),
+                );
+            }
+            if !self.viewer_auth.email_from.contains('@') || self.viewer_auth.email_from.contains(['\r', '\n']) {
+                problems.push(format!(
+                    "viewer_auth.email_from is not an email address: {:?}",
+                    self.viewer_auth.email_from
+                ));
+            }
+            if self.viewer_auth.code_ttl_minutes == 0 || self.viewer_auth.session_ttl_hours == 0 {
+                problems.push("viewer_auth.code_ttl_minutes and viewer_auth.session_ttl_hours must be non-zero".to_string());
+            }
+        }
+
+        if self.bandwidth.collect_interval_secs == 0 {
+            problems.push("bandwidth.collect_interval_secs must be non-zero".to_string());
+        }
+        if !(1..=100).contains(&self.bandwidth.warn_percent) {
+            problems.push(format!(
+                "bandwidth.warn_percent must be between 1 and 100, got {}",
+                self.bandwidth.warn_percent
+            ));
+        }
+        if self.bandwidth.over_cap_action == OverCapAction::Throttle && self.bandwidth.throttle_rate_kbps == 0 {
+            problems.push("bandwidth.throttle_rate_kbps must be non-zero when over_cap_action is \"throttle\"".to_string());
+        }
+
+        if self.webhooks.max_attempts == 0 || self.webhooks.timeout_secs == 0 {
+            problems.push("webhooks.max_attempts and webhooks.timeout_secs must be non-zero".to_string());
+        }
+
+        // An app sends a few KB at most: an auth token, an SSH key or a list of emails
+        if self.limits.max_frame_bytes < 4096 {
+            problems.push(format!("limits.max_frame_bytes must be at least 4096, got {}", self.limits.max_frame_bytes));
+        }
+        if self.limits.messages_per_sec == 0 || self.limits.message_burst == 0 || self.limits.auth_timeout_secs == 0 {
+            problems.push("limits.messages_per_sec, limits.message_burst and limits.auth_timeout_secs must be non-zero".to_string());
+        }
+
+        if self.reconciler.interval_secs == 0 {
+            problems.push("reconciler.interval_secs must be non-zero".to_string());
+        }
+
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!("logging.level is not a valid filter ({}): {:?}", e, self.logging.level));
+        }
+
+        let paths = [
+            ("nginx.sites_available_dir", &self.nginx.sites_available_dir),
+            ("nginx.sites_enabled_dir", &self.nginx.sites_enabled_dir),
+            ("nginx.conf_dir", &self.nginx.conf_dir),
+            ("nginx.stream_conf_dir", &self.nginx.stream_conf_dir),
+            ("nginx.passwd_dir", &self.nginx.passwd_dir),
+            ("nginx.web_root", &self.nginx.web_root),
+            ("certbot.webroot", &self.certbot.webroot),
+            ("certbot.live_dir", &self.certbot.live_dir),
+            ("ssh.authorized_keys_path", &self.ssh.authorized_keys_path),
+            ("viewer_auth.sendmail_path", &self.viewer_auth.sendmail_path),
+            ("bandwidth.log_dir", &self.bandwidth.log_dir),
+        ];
+        let client_template = self.nginx.client_template.as_ref().map(|path| ("nginx.client_template", path));
+        for (name, path) in paths.into_iter().chain(client_template) {
+            if !path.is_absolute() {
+                problems.push(format!("{} must be an absolute path, got {}", name, path.display()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid configuration:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+}
+
+/// Lowercase DNS name made of letters, digits and hyphens, without a scheme,
+/// port or trailing dot
+pub fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// Node IDs are lowercase letters, digits and hyphens, like subdomain labels
+fn is_valid_node_id(node_id: &str) -> bool {
+    !node_id.is_empty()
+        && node_id.len() <= 63
+        && node_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// First of `names` that is set, with its value
+fn first_env(names: &[&str], env: &dyn Fn(&str) -> Option<String>) -> Option<(String, String)> {
+    names
+        .iter()
+        .find_map(|name| env(name).map(|value| (name.to_string(), value)))
+}
+
+fn override_from_env<T>(target: &mut T, names: &[&str], env: &dyn Fn(&str) -> Option<String>) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some((name, value)) = first_env(names, env) {
+        *target = value
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Invalid value for {}: {:?} ({})", name, value, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_parse_partial_file_keeps_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [server]
+            database_url = "sqlite:/var/lib/tnnl/tnnl.db"
+            jwt_secret = "secret"
+
+            [tunnels]
+            port_base = 20000
+
+            [nginx]
+            web_root = "/srv/tnnl/html"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.tunnels.port_base, 20000);
+        assert_eq!(config.tunnels.tcp_ports(), 30000..=30999);
+        assert_eq!(config.nginx.web_root, PathBuf::from("/srv/tnnl/html"));
+        assert_eq!(config.nginx.passwd_dir, PathBuf::from("/etc/nginx/passwd"));
+        assert_eq!(config.server.bind_address.port(), 8080);
+        assert_eq!(config.server.base_domain, "tnnl.to");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(Config::from_toml("[nginx]\nwebroot = \"/srv\"").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut config = Config::from_toml("[tunnels]\nport_base = 20000").unwrap();
+        let env = env_from(&[
+            ("DATABASE_URL", "memory:"),
+            ("JWT_SECRET", "legacy"),
+            ("TNNL_SERVER_JWT_SECRET", "preferred"),
+            ("TNNL_TUNNELS_PORT_BASE", "30000"),
+            ("TNNL_TUNNELS_RESERVED_SUBDOMAINS", "status, billing,"),
+            ("ADMIN_TOKEN", ""),
+            ("RUST_LOG", "info,tnnl_coordination_server::nginx=debug"),
+            ("TNNL_LOGGING_FORMAT", "json"),
+        ]);
+        config.apply_env(&env).unwrap();
+
+        assert_eq!(config.server.database_url, "memory:");
+        assert_eq!(config.server.jwt_secret, "preferred");
+        assert_eq!(config.tunnels.port_base, 30000);
+        assert_eq!(config.tunnels.reserved_subdomains, vec!["status", "billing"]);
+        assert!(config.admin.token.is_none());
+        assert_eq!(config.logging.level, "info,tnnl_coordination_server::nginx=debug");
+        assert_eq!(config.logging.format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_invalid_env_value_names_the_variable() {
+        let mut config = Config::default();
+        let err = config
+            .apply_env(&env_from(&[("TNNL_TUNNELS_PORT_BASE", "lots")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("TNNL_TUNNELS_PORT_BASE"));
+    }
+
+    #[test]
+    fn test_base_domain_and_ssh_host() {
+        let mut config = Config::from_toml("[server]\nbase_domain = \"tunnels.example.com\"").unwrap();
+        assert_eq!(config.server.ssh_host(), "tunnels.example.com");
+        assert_eq!(config.server.tunnel_host("happy-fox-1234"), "happy-fox-1234.tunnels.example.com");
+
+        config
+            .apply_env(&env_from(&[("TNNL_SERVER_SSH_HOST", "ssh.example.com")]))
+            .unwrap();
+        assert_eq!(config.server.ssh_host(), "ssh.example.com");
+
+        assert!(is_valid_hostname("example.com"));
+        assert!(!is_valid_hostname("https://example.com"));
+        assert!(!is_valid_hostname("example.com."));
+        assert!(!is_valid_hostname("Example.com"));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut config = Config::default();
+        config.tunnels.port_base = 80;
+        config.tunnels.tcp_port_base = 65000;
+        config.tunnels.tcp_port_count = 1000;
+        config.tunnels.max_per_client = 0;
+        config.tunnels.subdomain_digits = 0;
+        config.tunnels.max_ttl_secs = 0;
+        config.webhooks.max_attempts = 0;
+        config.logging.level = "info,nginx=loud".to_string();
+        config.limits.max_frame_bytes = 100;
+        config.reconciler.interval_secs = 0;
+        config.certbot.renew_before_days = 90;
+        config.nginx.web_root = PathBuf::from("html");
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("server.database_url"));
+        assert!(err.contains("server.jwt_secret"));
+        assert!(err.contains("tunnels.port_base"));
+        assert!(err.contains("tunnels.tcp_port_base"));
+        assert!(err.contains("tunnels.max_per_client"));
+        assert!(err.contains("bits of entropy"));
+        assert!(err.contains("tunnels.max_ttl_secs"));
+        assert!(err.contains("webhooks.max_attempts"));
+        assert!(err.contains("logging.level"));
+        assert!(err.contains("limits.max_frame_bytes"));
+        assert!(err.contains("reconciler.interval_secs"));
+        assert!(err.contains("certbot.renew_before_days"));
+        assert!(err.contains("nginx.web_root"));
+        assert!(!err.contains("overlaps"));
+
+        // Forward ports running into the public TCP ports
+        let mut config = Config::default();
+        config.server.database_url = "sqlite://tnnl.db".to_string();
+        config.server.jwt_secret = "secret".to_string();
+        config.tunnels.port_count = 25000;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("tunnels.port_base/port_count (10000-34999) overlaps"));
+        config.tunnels.port_count = 20000;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_viewer_auth_validation() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            database_url = "memory:"
+            jwt_secret = "secret"
+
+            [viewer_auth]
+            enabled = true
+            cookie_secret = "short"
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("viewer_auth.cookie_secret"));
+
+        config
+            .apply_env(&env_from(&[(
+                "TNNL_VIEWER_AUTH_COOKIE_SECRET",
+                "0123456789abcdef0123456789abcdef",
+            )]))
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.viewer_auth.bind_address.port(), 8082);
+    }
+
+    #[test]
+    fn test_bandwidth_caps() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            database_url = "memory:"
+            jwt_secret = "secret"
+
+            [bandwidth]
+            monthly_cap_mb = 2048
+            warn_percent = 0
+            over_cap_action = "close"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bandwidth.monthly_cap_bytes(), Some(2048 * 1024 * 1024));
+        assert_eq!(config.bandwidth.over_cap_action, OverCapAction::Close);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("bandwidth.warn_percent"));
+
+        config
+            .apply_env(&env_from(&[
+                ("TNNL_BANDWIDTH_WARN_PERCENT", "90"),
+                ("TNNL_BANDWIDTH_MONTHLY_CAP_MB", "0"),
+                ("TNNL_BANDWIDTH_OVER_CAP_ACTION", "throttle"),
+            ]))
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.bandwidth.monthly_cap_bytes(), None);
+        assert_eq!(config.bandwidth.over_cap_action, OverCapAction::Throttle);
+        assert!(config
+            .apply_env(&env_from(&[("TNNL_BANDWIDTH_OVER_CAP_ACTION", "slow")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_cluster_mode_validation() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            database_url = "sqlite:/var/lib/tnnl/tnnl.db"
+            jwt_secret = "secret"
+
+            [cluster]
+            enabled = true
+            node_timeout_secs = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cluster.node_id(), "local");
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Postgres"));
+        assert!(err.contains("cluster.node_id"));
+        assert!(err.contains("cluster.public_url"));
+        assert!(err.contains("cluster.node_timeout_secs"));
+
+        config
+            .apply_env(&env_from(&[
+                ("DATABASE_URL", "postgresql://tnnl@db/tnnl"),
+                ("TNNL_CLUSTER_NODE_ID", "eu-1"),
+                ("TNNL_CLUSTER_PUBLIC_URL", "wss://eu.tnnl.to"),
+                ("TNNL_CLUSTER_NODE_TIMEOUT_SECS", "30"),
+            ]))
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.cluster.node_id(), "eu-1");
+    }
+}
@@ -1,43 +1,148 @@
 use anyhow::Result;
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions, Row};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{PgPool, QueryBuilder, Row, SqlitePool};
+use std::str::FromStr;
 use uuid::Uuid;
-use crate::tunnel::Tunnel;
+use crate::audit::{AuditAction, AuditEvent, AuditFilter};
+use crate::bandwidth::{TrafficTotals, ViewerTraffic};
+use crate::cluster::ClusterNode;
+use crate::domains::{CustomDomain, VerificationMethod};
+use crate::tunnel::{Tunnel, TunnelHistoryEntry, TunnelKind};
+use crate::webhooks::{self, Webhook, WebhookDelivery, WebhookEvent};
 
-pub type DbPool = Pool<Postgres>;
+/// Database connection pool
+/// Postgres for hosted deployments, SQLite for single-binary self-hosting
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+/// Run the same query code against whichever backend the pool uses
+/// The body is expanded once per backend, so queries must be valid SQL for both
+/// (use `$N` placeholders and bind timestamps rather than using SQL time functions)
+macro_rules! with_pool {
+    ($pool:expr, $conn:ident => $body:expr) => {
+        match $pool {
+            DbPool::Postgres($conn) => $body,
+            DbPool::Sqlite($conn) => $body,
+        }
+    };
+}
 
-/// Initialize Postgres connection pool for Supabase
+/// Build a Tunnel from a row selecting its columns
+macro_rules! tunnel_from_row {
+    ($r:expr) => {{
+        let kind: String = $r.try_get("kind")?;
+        Tunnel {
+            id: $r.try_get("id")?,
+            subdomain: $r.try_get("subdomain")?,
+            user_id: $r.try_get("user_id")?,
+            is_custom: $r.try_get("is_custom")?,
+            port: $r.try_get::<i32, _>("port")? as u16,
+            password: $r.try_get("password")?,
+            created_at: $r.try_get("created_at")?,
+            node_id: $r.try_get("node_id")?,
+            kind: TunnelKind::parse(&kind).ok_or_else(|| anyhow::anyhow!("Unknown tunnel kind: {}", kind))?,
+            public_port: $r.try_get::<Option<i32>, _>("public_port")?.map(|p| p as u16),
+            label: $r.try_get("label")?,
+            expires_at: $r.try_get("expires_at")?,
+        }
+    }};
+}
+
+/// Build a CustomDomain from a row selecting its columns
+macro_rules! custom_domain_from_row {
+    ($r:expr) => {{
+        let method: String = $r.try_get("verification_method")?;
+        CustomDomain {
+            id: $r.try_get("id")?,
+            user_id: $r.try_get("user_id")?,
+            hostname: $r.try_get("hostname")?,
+            verification_method: VerificationMethod::parse(&method)
+                .ok_or_else(|| anyhow::anyhow!("Unknown verification method: {}", method))?,
+            verification_token: $r.try_get("verification_token")?,
+            verified_at: $r.try_get("verified_at")?,
+            target: $r.try_get("target")?,
+            created_at: $r.try_get("created_at")?,
+        }
+    }};
+}
+
+/// Build a Webhook from a row selecting its columns
+macro_rules! webhook_from_row {
+    ($r:expr) => {{
+        let events: String = $r.try_get("events")?;
+        Webhook {
+            id: $r.try_get("id")?,
+            user_id: $r.try_get("user_id")?,
+            url: $r.try_get("url")?,
+            secret: $r.try_get("secret")?,
+            events: webhooks::events_from_string(&events),
+            created_at: $r.try_get("created_at")?,
+        }
+    }};
+}
+
+/// Initialize the connection pool and apply embedded migrations
+/// `sqlite:` URLs use SQLite (the file is created if missing), anything else Postgres
 pub async fn init_pool(database_url: &str) -> Result<DbPool> {
-    let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(database_url)
-        .await?;
+    if database_url.starts_with("sqlite:") {
+        let options = SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
 
-    // Note: Schema is managed in Supabase SQL editor (see supabase-schema.sql)
-    // No automatic migrations - run the SQL file in Supabase dashboard
+        Ok(DbPool::Sqlite(pool))
+    } else {
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect(database_url)
+            .await?;
 
-    Ok(pool)
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+
+        Ok(DbPool::Postgres(pool))
+    }
 }
 
 /// Get or create user record
-/// For Supabase, users are managed in auth.users, so we don't need to create them here
-/// We just validate the user_id exists and return it
-/// If using tunnels.user_id FK to auth.users, this is just a passthrough
-pub async fn get_or_create_user(pool: &DbPool, user_id: Uuid, _email: &str) -> Result<Uuid> {
-    // With Supabase, users are created by Supabase Auth
-    // We just validate the user exists in auth.users
-    // The tunnels.user_id FK will enforce this
-
-    // For now, just return the user_id as-is
-    // The FK constraint will fail if the user doesn't exist in auth.users
+/// Users are identified by the `sub` claim of their auth token; the email is
+/// refreshed on every login
+pub async fn get_or_create_user(pool: &DbPool, user_id: Uuid, email: &str) -> Result<Uuid> {
+    let now = chrono::Utc::now();
+
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO users (id, email, created_at, last_seen_at)
+        VALUES ($1, $2, $3, $3)
+        ON CONFLICT (id) DO UPDATE SET
+            email = $2,
+            last_seen_at = $3
+        "#
+    )
+    .bind(user_id)
+    .bind(email)
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
     Ok(user_id)
 }
 
-/// Create tunnel record in Supabase
+/// Create tunnel record
 pub async fn create_tunnel_record(pool: &DbPool, tunnel: &Tunnel) -> Result<()> {
-    sqlx::query(
+    with_pool!(pool, p => sqlx::query(
         r#"
-        INSERT INTO tunnels (id, subdomain, user_id, is_custom, port, password, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        INSERT INTO tunnels (id, subdomain, user_id, is_custom, port, password, password_protected, created_at, updated_at, node_id, kind, public_port, label, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
         "#
     )
     .bind(tunnel.id)
@@ -46,113 +151,878 @@ pub async fn create_tunnel_record(pool: &DbPool, tunnel: &Tunnel) -> Result<()>
     .bind(tunnel.is_custom)
     .bind(tunnel.port as i32)
     .bind(&tunnel.password)
+    .bind(tunnel.password.is_some())
     .bind(tunnel.created_at)
     .bind(tunnel.created_at)
-    .execute(pool)
-    .await?;
+    .bind(&tunnel.node_id)
+    .bind(tunnel.kind.as_str())
+    .bind(tunnel.public_port.map(i32::from))
+    .bind(&tunnel.label)
+    .bind(tunnel.expires_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
 
     Ok(())
 }
 
-#[allow(dead_code)]
+/// Get the active tunnel holding a subdomain
 pub async fn get_tunnel_by_subdomain(pool: &DbPool, subdomain: &str) -> Result<Option<Tunnel>> {
-    let row = sqlx::query(
+    with_pool!(pool, p => {
+        let row = sqlx::query(
+            r#"
+            SELECT id, subdomain, user_id, is_custom, port, password, created_at, node_id, kind, public_port, label, expires_at
+            FROM tunnels
+            WHERE subdomain = $1 AND closed_at IS NULL
+            "#
+        )
+        .bind(subdomain)
+        .fetch_optional(p)
+        .await?;
+
+        match row {
+            Some(r) => Ok(Some(tunnel_from_row!(r))),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Mark a tunnel as closed, keeping the row as session history without its password
+pub async fn close_tunnel_record(pool: &DbPool, tunnel: &Tunnel, reason: &str) -> Result<()> {
+    let closed_at = chrono::Utc::now();
+    let duration_seconds = (closed_at - tunnel.created_at).num_seconds().max(0);
+
+    with_pool!(pool, p => sqlx::query(
         r#"
-        SELECT id, subdomain, user_id, is_custom, port, password, created_at
-        FROM tunnels
-        WHERE subdomain = $1
+        UPDATE tunnels
+        SET closed_at = $2, close_reason = $3, duration_seconds = $4, updated_at = $2, password = NULL
+        WHERE id = $1 AND closed_at IS NULL
         "#
     )
-    .bind(subdomain)
-    .fetch_optional(pool)
-    .await?;
+    .bind(tunnel.id)
+    .bind(closed_at)
+    .bind(reason)
+    .bind(duration_seconds)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
 
-    match row {
-        Some(r) => {
-            Ok(Some(Tunnel {
-                id: r.try_get("id")?,
-                subdomain: r.try_get("subdomain")?,
-                user_id: r.try_get("user_id")?,
-                is_custom: r.try_get("is_custom")?,
-                port: r.try_get::<i32, _>("port")? as u16,
-                password: r.try_get("password")?,
-                created_at: r.try_get("created_at")?,
-            }))
+    Ok(())
+}
+
+/// Close the active tunnels of a node, left by its previous process or by a
+/// node that died
+/// Returns the number of tunnels closed
+pub async fn close_stale_tunnel_records(pool: &DbPool, node_id: &str, reason: &str) -> Result<usize> {
+    let tunnels: Vec<Tunnel> = with_pool!(pool, p => {
+        let rows = sqlx::query(
+            "SELECT id, subdomain, user_id, is_custom, port, password, created_at, node_id, kind, public_port, label, expires_at FROM tunnels WHERE node_id = $1 AND closed_at IS NULL"
+        )
+        .bind(node_id)
+        .fetch_all(p)
+        .await?;
+
+        let mut tunnels = Vec::new();
+        for r in rows {
+            tunnels.push(tunnel_from_row!(r));
         }
-        None => Ok(None),
+        tunnels
+    });
+
+    for tunnel in &tunnels {
+        close_tunnel_record(pool, tunnel, reason).await?;
     }
+
+    Ok(tunnels.len())
 }
 
-pub async fn delete_tunnel_record(pool: &DbPool, subdomain: &str) -> Result<()> {
-    sqlx::query(
-        "DELETE FROM tunnels WHERE subdomain = $1"
+/// Active tunnels of a node that expire before `before`, soonest first
+pub async fn get_expiring_tunnels(
+    pool: &DbPool,
+    node_id: &str,
+    before: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<Tunnel>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, subdomain, user_id, is_custom, port, password, created_at, node_id, kind, public_port, label, expires_at
+            FROM tunnels
+            WHERE node_id = $1 AND closed_at IS NULL AND expires_at IS NOT NULL AND expires_at < $2
+            ORDER BY expires_at
+            "#
+        )
+        .bind(node_id)
+        .bind(before)
+        .fetch_all(p)
+        .await?;
+
+        let mut tunnels = Vec::new();
+        for r in rows {
+            tunnels.push(tunnel_from_row!(r));
+        }
+        Ok(tunnels)
+    })
+}
+
+/// Active tunnels of a node, oldest first
+pub async fn get_open_tunnels(pool: &DbPool, node_id: &str) -> Result<Vec<Tunnel>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, subdomain, user_id, is_custom, port, password, created_at, node_id, kind, public_port, label, expires_at
+            FROM tunnels
+            WHERE node_id = $1 AND closed_at IS NULL
+            ORDER BY created_at
+            "#
+        )
+        .bind(node_id)
+        .fetch_all(p)
+        .await?;
+
+        let mut tunnels = Vec::new();
+        for r in rows {
+            tunnels.push(tunnel_from_row!(r));
+        }
+        Ok(tunnels)
+    })
+}
+
+/// Record that the host of an active tunnel was seen
+pub async fn update_tunnel_last_connected(pool: &DbPool, tunnel_id: Uuid) -> Result<()> {
+    let now = chrono::Utc::now();
+
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE tunnels SET last_connected_at = $2 WHERE id = $1 AND closed_at IS NULL"
     )
-    .bind(subdomain)
-    .execute(pool)
-    .await?;
+    .bind(tunnel_id)
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
 
     Ok(())
 }
 
-#[allow(dead_code)]
-pub async fn update_tunnel_last_connected(pool: &DbPool, subdomain: &str) -> Result<()> {
-    sqlx::query(
-        "UPDATE tunnels SET last_connected_at = CURRENT_TIMESTAMP WHERE subdomain = $1"
+/// When the host of a tunnel (active or closed) was last seen, if ever
+pub async fn get_tunnel_last_connected(pool: &DbPool, tunnel_id: Uuid) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+    with_pool!(pool, p => {
+        let row = sqlx::query("SELECT last_connected_at FROM tunnels WHERE id = $1")
+            .bind(tunnel_id)
+            .fetch_optional(p)
+            .await?;
+
+        match row {
+            Some(r) => Ok(r.try_get("last_connected_at")?),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Count a viewer session against an active tunnel
+pub async fn increment_viewer_sessions(pool: &DbPool, tunnel_id: Uuid) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE tunnels SET viewer_sessions = viewer_sessions + 1 WHERE id = $1 AND closed_at IS NULL"
     )
-    .bind(subdomain)
-    .execute(pool)
-    .await?;
+    .bind(tunnel_id)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
 
     Ok(())
 }
 
-/// Get all tunnels for a user
-#[allow(dead_code)]
-pub async fn get_user_tunnels(pool: &DbPool, user_id: Uuid) -> Result<Vec<Tunnel>> {
-    let rows = sqlx::query(
+/// Get a user's tunnel sessions, newest first
+pub async fn get_user_tunnels(pool: &DbPool, user_id: Uuid, limit: i64) -> Result<Vec<TunnelHistoryEntry>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, subdomain, label, is_custom, kind, port, node_id, password_protected, created_at, expires_at, last_connected_at,
+                   closed_at, close_reason, duration_seconds, viewer_sessions, bytes_in, bytes_out
+            FROM tunnels
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(p)
+        .await?;
+
+        let mut tunnels = Vec::new();
+        for r in rows {
+            let kind: String = r.try_get("kind")?;
+            tunnels.push(TunnelHistoryEntry {
+                id: r.try_get("id")?,
+                subdomain: r.try_get("subdomain")?,
+                label: r.try_get("label")?,
+                is_custom: r.try_get("is_custom")?,
+                kind: TunnelKind::parse(&kind).ok_or_else(|| anyhow::anyhow!("Unknown tunnel kind: {}", kind))?,
+                port: r.try_get::<i32, _>("port")? as u16,
+                node_id: r.try_get("node_id")?,
+                password_protected: r.try_get("password_protected")?,
+                created_at: r.try_get("created_at")?,
+                expires_at: r.try_get("expires_at")?,
+                last_connected_at: r.try_get("last_connected_at")?,
+                closed_at: r.try_get("closed_at")?,
+                close_reason: r.try_get("close_reason")?,
+                duration_seconds: r.try_get("duration_seconds")?,
+                viewer_sessions: r.try_get("viewer_sessions")?,
+                bytes_in: r.try_get("bytes_in")?,
+                bytes_out: r.try_get("bytes_out")?,
+            });
+        }
+
+        Ok(tunnels)
+    })
+}
+
+/// Add proxied bytes to a tunnel (active or closed) and to its owner's total
+/// for `month`
+/// Returns the owner, or None if no tunnel has the ID
+pub async fn add_tunnel_traffic(
+    pool: &DbPool,
+    tunnel_id: Uuid,
+    month: &str,
+    traffic: TrafficTotals,
+) -> Result<Option<Uuid>> {
+    let user_id: Option<Uuid> = with_pool!(pool, p => {
+        let row = sqlx::query(
+            "UPDATE tunnels SET bytes_in = bytes_in + $2, bytes_out = bytes_out + $3 WHERE id = $1 RETURNING user_id"
+        )
+        .bind(tunnel_id)
+        .bind(traffic.bytes_in)
+        .bind(traffic.bytes_out)
+        .fetch_optional(p)
+        .await?;
+
+        match row {
+            Some(r) => Some(r.try_get("user_id")?),
+            None => None,
+        }
+    });
+
+    let user_id = match user_id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    with_pool!(pool, p => sqlx::query(
         r#"
-        SELECT id, subdomain, user_id, is_custom, port, password, created_at
-        FROM tunnels
-        WHERE user_id = $1
-        ORDER BY created_at DESC
+        INSERT INTO user_traffic (user_id, month, bytes_in, bytes_out)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, month) DO UPDATE SET
+            bytes_in = user_traffic.bytes_in + $3,
+            bytes_out = user_traffic.bytes_out + $4
         "#
     )
     .bind(user_id)
-    .fetch_all(pool)
-    .await?;
-
-    let mut tunnels = Vec::new();
-    for r in rows {
-        tunnels.push(Tunnel {
-            id: r.try_get("id")?,
-            subdomain: r.try_get("subdomain")?,
-            user_id: r.try_get("user_id")?,
-            is_custom: r.try_get("is_custom")?,
-            port: r.try_get::<i32, _>("port")? as u16,
-            password: r.try_get("password")?,
-            created_at: r.try_get("created_at")?,
-        });
-    }
+    .bind(month)
+    .bind(traffic.bytes_in)
+    .bind(traffic.bytes_out)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(Some(user_id))
+}
+
+/// Get a user's total traffic for a month
+pub async fn get_user_traffic(pool: &DbPool, user_id: Uuid, month: &str) -> Result<TrafficTotals> {
+    with_pool!(pool, p => {
+        let row = sqlx::query("SELECT bytes_in, bytes_out FROM user_traffic WHERE user_id = $1 AND month = $2")
+            .bind(user_id)
+            .bind(month)
+            .fetch_optional(p)
+            .await?;
+
+        match row {
+            Some(r) => Ok(TrafficTotals {
+                bytes_in: r.try_get("bytes_in")?,
+                bytes_out: r.try_get("bytes_out")?,
+            }),
+            None => Ok(TrafficTotals::default()),
+        }
+    })
+}
+
+/// Record a finished viewer connection
+pub async fn record_viewer_traffic(pool: &DbPool, viewer: &ViewerTraffic) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO viewer_traffic (id, tunnel_id, request_id, remote_ip, bytes_in, bytes_out, ended_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#
+    )
+    .bind(viewer.id)
+    .bind(viewer.tunnel_id)
+    .bind(&viewer.request_id)
+    .bind(&viewer.remote_ip)
+    .bind(viewer.bytes_in)
+    .bind(viewer.bytes_out)
+    .bind(viewer.ended_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
 
-    Ok(tunnels)
+/// Get the viewer connections of a tunnel owned by `user_id`, newest first
+pub async fn get_viewer_traffic(pool: &DbPool, user_id: Uuid, tunnel_id: Uuid, limit: i64) -> Result<Vec<ViewerTraffic>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT v.id, v.tunnel_id, v.request_id, v.remote_ip, v.bytes_in, v.bytes_out, v.ended_at
+            FROM viewer_traffic v
+            JOIN tunnels t ON t.id = v.tunnel_id
+            WHERE t.user_id = $1 AND v.tunnel_id = $2
+            ORDER BY v.ended_at DESC
+            LIMIT $3
+            "#
+        )
+        .bind(user_id)
+        .bind(tunnel_id)
+        .bind(limit)
+        .fetch_all(p)
+        .await?;
+
+        let mut viewers = Vec::new();
+        for r in rows {
+            viewers.push(ViewerTraffic {
+                id: r.try_get("id")?,
+                tunnel_id: r.try_get("tunnel_id")?,
+                request_id: r.try_get("request_id")?,
+                remote_ip: r.try_get("remote_ip")?,
+                bytes_in: r.try_get("bytes_in")?,
+                bytes_out: r.try_get("bytes_out")?,
+                ended_at: r.try_get("ended_at")?,
+            });
+        }
+
+        Ok(viewers)
+    })
+}
+
+/// Get the SSH public key registered for a user
+pub async fn get_ssh_public_key(pool: &DbPool, user_id: Uuid) -> Result<Option<String>> {
+    with_pool!(pool, p => {
+        let row = sqlx::query("SELECT ssh_public_key FROM user_profiles WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(p)
+            .await?;
+
+        match row {
+            Some(r) => Ok(r.try_get("ssh_public_key")?),
+            None => Ok(None),
+        }
+    })
 }
 
 /// Store SSH public key for a user
 /// Creates or updates user_profile with SSH key
 pub async fn store_ssh_public_key(pool: &DbPool, user_id: Uuid, ssh_public_key: &str) -> Result<()> {
-    sqlx::query(
+    let now = chrono::Utc::now();
+
+    with_pool!(pool, p => sqlx::query(
         r#"
-        INSERT INTO user_profiles (id, ssh_public_key)
-        VALUES ($1, $2)
+        INSERT INTO user_profiles (id, ssh_public_key, created_at, updated_at)
+        VALUES ($1, $2, $3, $3)
         ON CONFLICT (id) DO UPDATE SET
             ssh_public_key = $2,
-            updated_at = CURRENT_TIMESTAMP
+            updated_at = $3
         "#
     )
     .bind(user_id)
     .bind(ssh_public_key)
-    .execute(pool)
-    .await?;
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Remove the SSH public key from a user's profile
+/// Returns the key that was removed, if any
+pub async fn clear_ssh_public_key(pool: &DbPool, user_id: Uuid) -> Result<Option<String>> {
+    let now = chrono::Utc::now();
+    let existing = get_ssh_public_key(pool, user_id).await?;
+
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE user_profiles SET ssh_public_key = NULL, updated_at = $2 WHERE id = $1"
+    )
+    .bind(user_id)
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(existing)
+}
+
+/// Claim a custom domain
+/// Fails if the hostname is already claimed
+pub async fn create_custom_domain(pool: &DbPool, domain: &CustomDomain) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO custom_domains (id, user_id, hostname, verification_method, verification_token, verified_at, target, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(domain.id)
+    .bind(domain.user_id)
+    .bind(&domain.hostname)
+    .bind(domain.verification_method.as_str())
+    .bind(&domain.verification_token)
+    .bind(domain.verified_at)
+    .bind(&domain.target)
+    .bind(domain.created_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
 
     Ok(())
 }
+
+/// Get the claim on a hostname, whoever holds it
+pub async fn get_custom_domain(pool: &DbPool, hostname: &str) -> Result<Option<CustomDomain>> {
+    with_pool!(pool, p => {
+        let row = sqlx::query(
+            r#"
+            SELECT id, user_id, hostname, verification_method, verification_token, verified_at, target, created_at
+            FROM custom_domains
+            WHERE hostname = $1
+            "#
+        )
+        .bind(hostname)
+        .fetch_optional(p)
+        .await?;
+
+        match row {
+            Some(r) => Ok(Some(custom_domain_from_row!(r))),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Get a user's custom domains, oldest first
+pub async fn list_user_custom_domains(pool: &DbPool, user_id: Uuid) -> Result<Vec<CustomDomain>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, user_id, hostname, verification_method, verification_token, verified_at, target, created_at
+            FROM custom_domains
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(p)
+        .await?;
+
+        let mut domains = Vec::new();
+        for r in rows {
+            domains.push(custom_domain_from_row!(r));
+        }
+
+        Ok(domains)
+    })
+}
+
+/// Record that ownership of a custom domain was proven
+pub async fn mark_custom_domain_verified(pool: &DbPool, domain_id: Uuid) -> Result<()> {
+    let now = chrono::Utc::now();
+
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE custom_domains SET verified_at = $2 WHERE id = $1"
+    )
+    .bind(domain_id)
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Point a custom domain at the tunnels with this reserved subdomain or label
+pub async fn set_custom_domain_target(pool: &DbPool, domain_id: Uuid, target: Option<&str>) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE custom_domains SET target = $2 WHERE id = $1"
+    )
+    .bind(domain_id)
+    .bind(target)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Release a custom domain claim
+pub async fn delete_custom_domain(pool: &DbPool, domain_id: Uuid) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        "DELETE FROM custom_domains WHERE id = $1"
+    )
+    .bind(domain_id)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Register a webhook endpoint
+pub async fn create_webhook(pool: &DbPool, webhook: &Webhook) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO webhooks (id, user_id, url, secret, events, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#
+    )
+    .bind(webhook.id)
+    .bind(webhook.user_id)
+    .bind(&webhook.url)
+    .bind(&webhook.secret)
+    .bind(webhooks::events_to_string(&webhook.events))
+    .bind(webhook.created_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Get a user's webhooks, oldest first
+pub async fn list_user_webhooks(pool: &DbPool, user_id: Uuid) -> Result<Vec<Webhook>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, user_id, url, secret, events, created_at
+            FROM webhooks
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#
+        )
+        .bind(user_id)
+        .fetch_all(p)
+        .await?;
+
+        let mut hooks = Vec::new();
+        for r in rows {
+            hooks.push(webhook_from_row!(r));
+        }
+
+        Ok(hooks)
+    })
+}
+
+/// Remove one of a user's webhooks; its delivery log goes with it
+pub async fn delete_webhook(pool: &DbPool, user_id: Uuid, webhook_id: Uuid) -> Result<bool> {
+    let deleted = with_pool!(pool, p => sqlx::query(
+        "DELETE FROM webhooks WHERE id = $1 AND user_id = $2"
+    )
+    .bind(webhook_id)
+    .bind(user_id)
+    .execute(p)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    Ok(deleted > 0)
+}
+
+/// Log a webhook delivery attempt
+pub async fn record_webhook_delivery(pool: &DbPool, delivery: &WebhookDelivery) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO webhook_deliveries (id, webhook_id, event_id, event, attempt, status_code, error, delivered, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#
+    )
+    .bind(delivery.id)
+    .bind(delivery.webhook_id)
+    .bind(delivery.event_id)
+    .bind(delivery.event.as_str())
+    .bind(delivery.attempt)
+    .bind(delivery.status_code)
+    .bind(&delivery.error)
+    .bind(delivery.delivered)
+    .bind(delivery.created_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Get the delivery log of a webhook owned by `user_id`, newest first
+pub async fn get_webhook_deliveries(pool: &DbPool, user_id: Uuid, webhook_id: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            r#"
+            SELECT d.id, d.webhook_id, d.event_id, d.event, d.attempt, d.status_code, d.error, d.delivered, d.created_at
+            FROM webhook_deliveries d
+            JOIN webhooks w ON w.id = d.webhook_id
+            WHERE w.user_id = $1 AND d.webhook_id = $2
+            ORDER BY d.created_at DESC
+            LIMIT $3
+            "#
+        )
+        .bind(user_id)
+        .bind(webhook_id)
+        .bind(limit)
+        .fetch_all(p)
+        .await?;
+
+        let mut deliveries = Vec::new();
+        for r in rows {
+            let event: String = r.try_get("event")?;
+            deliveries.push(WebhookDelivery {
+                id: r.try_get("id")?,
+                webhook_id: r.try_get("webhook_id")?,
+                event_id: r.try_get("event_id")?,
+                event: WebhookEvent::parse(&event).ok_or_else(|| anyhow::anyhow!("Unknown webhook event: {}", event))?,
+                attempt: r.try_get("attempt")?,
+                status_code: r.try_get("status_code")?,
+                error: r.try_get("error")?,
+                delivered: r.try_get("delivered")?,
+                created_at: r.try_get("created_at")?,
+            });
+        }
+
+        Ok(deliveries)
+    })
+}
+
+/// Register a node, or refresh it when it restarts under the same ID
+pub async fn register_node(pool: &DbPool, node: &ClusterNode) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO cluster_nodes (id, region, public_url, base_domain, started_at, last_heartbeat_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (id) DO UPDATE SET
+            region = $2,
+            public_url = $3,
+            base_domain = $4,
+            started_at = $5,
+            last_heartbeat_at = $6
+        "#
+    )
+    .bind(&node.id)
+    .bind(&node.region)
+    .bind(&node.public_url)
+    .bind(&node.base_domain)
+    .bind(node.started_at)
+    .bind(node.last_heartbeat_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Record that a node is alive
+pub async fn heartbeat_node(pool: &DbPool, node_id: &str) -> Result<()> {
+    let now = chrono::Utc::now();
+
+    with_pool!(pool, p => sqlx::query(
+        "UPDATE cluster_nodes SET last_heartbeat_at = $2 WHERE id = $1"
+    )
+    .bind(node_id)
+    .bind(now)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Get every registered node, live or not
+pub async fn list_cluster_nodes(pool: &DbPool) -> Result<Vec<ClusterNode>> {
+    with_pool!(pool, p => {
+        let rows = sqlx::query(
+            "SELECT id, region, public_url, base_domain, started_at, last_heartbeat_at FROM cluster_nodes ORDER BY id"
+        )
+        .fetch_all(p)
+        .await?;
+
+        let mut nodes = Vec::new();
+        for r in rows {
+            nodes.push(ClusterNode {
+                id: r.try_get("id")?,
+                region: r.try_get("region")?,
+                public_url: r.try_get("public_url")?,
+                base_domain: r.try_get("base_domain")?,
+                started_at: r.try_get("started_at")?,
+                last_heartbeat_at: r.try_get("last_heartbeat_at")?,
+            });
+        }
+
+        Ok(nodes)
+    })
+}
+
+/// Remove a node from the registry
+pub async fn remove_node(pool: &DbPool, node_id: &str) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        "DELETE FROM cluster_nodes WHERE id = $1"
+    )
+    .bind(node_id)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Append an event to the audit log
+pub async fn insert_audit_event(pool: &DbPool, event: &AuditEvent) -> Result<()> {
+    with_pool!(pool, p => sqlx::query(
+        r#"
+        INSERT INTO audit_events (id, action, user_id, subdomain, client_ip, details, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#
+    )
+    .bind(event.id)
+    .bind(event.action.as_str())
+    .bind(event.user_id)
+    .bind(&event.subdomain)
+    .bind(&event.client_ip)
+    .bind(event.details.to_string())
+    .bind(event.created_at)
+    .execute(p)
+    .await
+    .map(|_| ()))?;
+
+    Ok(())
+}
+
+/// Query the audit log, newest first
+pub async fn query_audit_events(pool: &DbPool, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+    with_pool!(pool, p => {
+        let mut query = QueryBuilder::new(
+            "SELECT id, action, user_id, subdomain, client_ip, details, created_at FROM audit_events WHERE 1 = 1"
+        );
+
+        if let Some(user_id) = filter.user_id {
+            query.push(" AND user_id = ").push_bind(user_id);
+        }
+        if let Some(subdomain) = &filter.subdomain {
+            query.push(" AND subdomain = ").push_bind(subdomain.clone());
+        }
+        if let Some(since) = filter.since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = filter.until {
+            query.push(" AND created_at < ").push_bind(until);
+        }
+        query.push(" ORDER BY created_at DESC LIMIT ").push_bind(filter.effective_limit());
+
+        let rows = query.build().fetch_all(p).await?;
+
+        let mut events = Vec::new();
+        for r in rows {
+            let action: String = r.try_get("action")?;
+            let details: Option<String> = r.try_get("details")?;
+            events.push(AuditEvent {
+                id: r.try_get("id")?,
+                action: AuditAction::parse(&action)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown audit action: {}", action))?,
+                user_id: r.try_get("user_id")?,
+                subdomain: r.try_get("subdomain")?,
+                client_ip: r.try_get("client_ip")?,
+                details: details
+                    .and_then(|d| serde_json::from_str(&d).ok())
+                    .unwrap_or(serde_json::Value::Null),
+                created_at: r.try_get("created_at")?,
+            });
+        }
+
+        Ok(events)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_pool() -> DbPool {
+        init_pool("sqlite::memory:").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_migrations_and_tunnel_history() {
+        let pool = test_pool().await;
+        let user_id = Uuid::new_v4();
+        get_or_create_user(&pool, user_id, "dev@example.com").await.unwrap();
+
+        let tunnel = Tunnel {
+            id: Uuid::new_v4(),
+            subdomain: "happy-fox-1234".to_string(),
+            user_id,
+            is_custom: false,
+            created_at: chrono::Utc::now(),
+            port: 10000,
+            password: Some("secret".to_string()),
+            node_id: "local".to_string(),
+            kind: TunnelKind::Screen,
+            public_port: None,
+            label: Some("demo".to_string()),
+            expires_at: None,
+        };
+        create_tunnel_record(&pool, &tunnel).await.unwrap();
+        increment_viewer_sessions(&pool, tunnel.id).await.unwrap();
+
+        // The subdomain is held while active
+        let mut duplicate = tunnel.clone();
+        duplicate.id = Uuid::new_v4();
+        assert!(create_tunnel_record(&pool, &duplicate).await.is_err());
+
+        close_tunnel_record(&pool, &tunnel, "client_disconnected").await.unwrap();
+        assert!(get_tunnel_by_subdomain(&pool, "happy-fox-1234").await.unwrap().is_none());
+
+        // History does not keep the Basic Auth password
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        let password: Option<String> = sqlx::query_scalar("SELECT password FROM tunnels WHERE id = $1")
+            .bind(tunnel.id)
+            .fetch_one(p)
+            .await
+            .unwrap();
+        assert!(password.is_none());
+
+        // ...and free again once closed
+        create_tunnel_record(&pool, &duplicate).await.unwrap();
+
+        let history = get_user_tunnels(&pool, user_id, 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        let closed = history.iter().find(|t| t.id == tunnel.id).unwrap();
+        assert_eq!(closed.close_reason.as_deref(), Some("client_disconnected"));
+        assert_eq!(closed.viewer_sessions, 1);
+        assert!(closed.password_protected);
+        assert_eq!(closed.label.as_deref(), Some("demo"));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_audit_log_is_append_only() {
+        let pool = test_pool().await;
+        let user_id = Uuid::new_v4();
+
+        let event = AuditEvent::new(AuditAction::TunnelCreated)
+            .user(Some(user_id))
+            .subdomain("happy-fox-1234");
+        insert_audit_event(&pool, &event).await.unwrap();
+        insert_audit_event(&pool, &AuditEvent::new(AuditAction::AuthFailure)).await.unwrap();
+
+        let filter = AuditFilter {
+            user_id: Some(user_id),
+            ..Default::default()
+        };
+        let events = query_audit_events(&pool, &filter).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::TunnelCreated);
+
+        let DbPool::Sqlite(p) = &pool else { unreachable!() };
+        assert!(sqlx::query("DELETE FROM audit_events").execute(p).await.is_err());
+    }
+}
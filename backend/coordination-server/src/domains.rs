@@ -0,0 +1,252 @@
+// Custom domains: user-owned hostnames routed to their tunnels
+// A hostname is claimed with add_custom_domain, proven with a DNS TXT record or
+// an HTTP challenge, and only then gets a certificate and a proxy route to the
+// tunnel it targets
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::config::is_valid_hostname;
+use crate::tunnel::{Tunnel, TunnelKind};
+
+/// Maximum number of custom domains (verified or not) a user may hold
+pub const MAX_CUSTOM_DOMAINS_PER_USER: usize = 10;
+
+/// Unverified claims older than this may be taken over by another user,
+/// so an abandoned claim cannot squat a hostname forever
+pub const UNVERIFIED_CLAIM_TTL_HOURS: i64 = 48;
+
+/// How ownership of a custom domain is proven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationMethod {
+    /// TXT record at `_tnnl-challenge.<hostname>`
+    Txt,
+    /// Token served at `http://<hostname>/.well-known/tnnl-challenge/<token>`
+    Http,
+}
+
+impl VerificationMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerificationMethod::Txt => "txt",
+            VerificationMethod::Http => "http",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "txt" => Some(VerificationMethod::Txt),
+            "http" => Some(VerificationMethod::Http),
+            _ => None,
+        }
+    }
+}
+
+/// A hostname claimed by a user
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomDomain {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub hostname: String,
+    pub verification_method: VerificationMethod,
+    #[serde(skip)]
+    pub verification_token: String,
+    pub verified_at: Option<DateTime<Utc>>,
+    /// Reserved subdomain or label of the tunnels the domain is served from
+    /// None keeps it on the offline page
+    pub target: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl CustomDomain {
+    /// A fresh, unverified claim with a random challenge token
+    pub fn new(user_id: Uuid, hostname: String, method: VerificationMethod) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            hostname,
+            verification_method: method,
+            verification_token: Uuid::new_v4().simple().to_string(),
+            verified_at: None,
+            target: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    /// Whether the domain should be served from a tunnel
+    /// TCP tunnels never match, since custom domains are served over HTTPS
+    pub fn routes_to(&self, tunnel: &Tunnel) -> bool {
+        match &self.target {
+            Some(target) => {
+                tunnel.kind != TunnelKind::Tcp
+                    && (tunnel.subdomain == *target || tunnel.label.as_deref() == Some(target.as_str()))
+            }
+            None => false,
+        }
+    }
+
+    /// Whether an unverified claim is old enough to be taken over
+    pub fn is_stale_claim(&self) -> bool {
+        !self.is_verified()
+            && Utc::now() - self.created_at > chrono::Duration::hours(UNVERIFIED_CLAIM_TTL_HOURS)
+    }
+
+    /// What the user must publish to prove ownership, as sent to the client
+    /// `target` is the host the domain's DNS should point at
+    pub fn verification_instructions(&self, target: &str) -> serde_json::Value {
+        match self.verification_method {
+            VerificationMethod::Txt => serde_json::json!({
+                "method": "txt",
+                "record_name": txt_record_name(&self.hostname),
+                "record_value": txt_record_value(&self.verification_token),
+                "cname_target": target
+            }),
+            VerificationMethod::Http => serde_json::json!({
+                "method": "http",
+                "url": format!("http://{}{}", self.hostname, http_challenge_path(&self.verification_token)),
+                "body": self.verification_token,
+                "cname_target": target
+            }),
+        }
+    }
+}
+
+/// Lowercase and validate a hostname supplied by a user
+/// Hosts under the server's base domain are reserved for generated tunnels
+pub fn normalize_hostname(input: &str, base_domain: &str) -> Result<String> {
+    let hostname = input.trim().trim_end_matches('.').to_ascii_lowercase();
+
+    if !is_valid_hostname(&hostname) || !hostname.contains('.') {
+        return Err(anyhow!("Invalid hostname: {}", input));
+    }
+    if hostname == base_domain || hostname.ends_with(&format!(".{}", base_domain)) {
+        return Err(anyhow!("Hostnames under {} cannot be used as custom domains", base_domain));
+    }
+
+    Ok(hostname)
+}
+
+/// DNS name of the TXT record proving ownership of `hostname`
+pub fn txt_record_name(hostname: &str) -> String {
+    format!("_tnnl-challenge.{}", hostname)
+}
+
+/// Expected TXT record value for a challenge token
+pub fn txt_record_value(token: &str) -> String {
+    format!("tnnl-verify={}", token)
+}
+
+/// URL path the HTTP challenge token is served at
+pub fn http_challenge_path(token: &str) -> String {
+    format!("/.well-known/tnnl-challenge/{}", token)
+}
+
+/// Checks whether a domain's ownership challenge has been published
+/// SystemVerifier is the production implementation; tests substitute a stub
+#[async_trait]
+pub trait DomainVerifier: Send + Sync {
+    async fn check(&self, domain: &CustomDomain) -> Result<bool>;
+}
+
+/// Verifies challenges with `dig` (TXT) and `curl` (HTTP)
+pub struct SystemVerifier;
+
+#[async_trait]
+impl DomainVerifier for SystemVerifier {
+    async fn check(&self, domain: &CustomDomain) -> Result<bool> {
+        match domain.verification_method {
+            VerificationMethod::Txt => {
+                let output = Command::new("dig")
+                    .args(["+short", "TXT", &txt_record_name(&domain.hostname)])
+                    .output()
+                    .await?;
+
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "TXT lookup failed: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
+                }
+
+                let expected = txt_record_value(&domain.verification_token);
+                Ok(txt_records_contain(&String::from_utf8_lossy(&output.stdout), &expected))
+            }
+            VerificationMethod::Http => {
+                let url = format!(
+                    "http://{}{}",
+                    domain.hostname,
+                    http_challenge_path(&domain.verification_token)
+                );
+                let output = Command::new("curl")
+                    .args(["-fsS", "--max-time", "10", &url])
+                    .output()
+                    .await?;
+
+                // A failed fetch means the challenge is not reachable yet, not a server error
+                Ok(output.status.success()
+                    && String::from_utf8_lossy(&output.stdout).trim() == domain.verification_token)
+            }
+        }
+    }
+}
+
+/// Whether `dig +short` output contains the expected TXT value
+/// dig prints one quoted record per line and splits long values into several
+/// quoted strings, which are joined here
+fn txt_records_contain(dig_output: &str, expected: &str) -> bool {
+    dig_output.lines().any(|line| {
+        let value: String = line
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        value == expected
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_hostname() {
+        assert_eq!(
+            normalize_hostname(" Demo.Example.COM. ", "tnnl.to").unwrap(),
+            "demo.example.com"
+        );
+        assert!(normalize_hostname("localhost", "tnnl.to").is_err());
+        assert!(normalize_hostname("https://example.com", "tnnl.to").is_err());
+        assert!(normalize_hostname("tnnl.to", "tnnl.to").is_err());
+        assert!(normalize_hostname("happy-fox-1234.tnnl.to", "tnnl.to").is_err());
+        assert!(normalize_hostname("nottnnl.to", "tnnl.to").is_ok());
+    }
+
+    #[test]
+    fn test_txt_records_contain() {
+        let expected = txt_record_value("abc123");
+        assert!(txt_records_contain("\"v=spf1 -all\"\n\"tnnl-verify=abc123\"\n", &expected));
+        assert!(txt_records_contain("\"tnnl-verify=\" \"abc123\"\n", &expected));
+        assert!(!txt_records_contain("\"tnnl-verify=other\"\n", &expected));
+        assert!(!txt_records_contain("", &expected));
+    }
+
+    #[test]
+    fn test_verification_instructions() {
+        let domain = CustomDomain::new(Uuid::new_v4(), "demo.example.com".to_string(), VerificationMethod::Http);
+        let instructions = domain.verification_instructions("tnnl.to");
+        assert_eq!(
+            instructions["url"],
+            format!("http://demo.example.com/.well-known/tnnl-challenge/{}", domain.verification_token)
+        );
+        assert_eq!(instructions["cname_target"], "tnnl.to");
+        assert!(!domain.is_stale_claim());
+    }
+}
@@ -0,0 +1,491 @@
+// End-to-end tests: the real accept loop on an ephemeral port, driven by a real
+// WebSocket client, with an in-memory store and a recording proxy backend
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use crate::audit::{AuditAction, AuditFilter};
+use crate::test_support::*;
+use crate::{serve, AppState};
+
+/// How long to wait for a reply or a side effect before failing
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A coordination server listening on 127.0.0.1 with test backends
+struct TestServer {
+    url: String,
+    state: Arc<AppState>,
+    proxy: Arc<RecordingProxy>,
+    accept_task: JoinHandle<()>,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        Self::start_with_config(test_config()).await
+    }
+
+    async fn start_with_config(config: crate::config::Config) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let (state, proxy) = test_state_with_config(config);
+        let accept_task = tokio::spawn(serve(listener, state.clone()));
+
+        Self {
+            url,
+            state,
+            proxy,
+            accept_task,
+        }
+    }
+
+    async fn connect(&self) -> TestClient {
+        self.connect_with_headers(&[]).await
+    }
+
+    /// Connect sending extra request headers, e.g. ones a fronting proxy adds
+    async fn connect_with_headers(&self, headers: &[(&'static str, &str)]) -> TestClient {
+        let mut request = self.url.as_str().into_client_request().unwrap();
+        for &(name, value) in headers {
+            request.headers_mut().insert(name, value.parse().unwrap());
+        }
+        let (ws, _) = connect_async(request).await.unwrap();
+        TestClient { ws }
+    }
+
+    /// Wait until the server tracks exactly `count` connected clients
+    async fn wait_for_clients(&self, count: usize) {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while self.state.clients.read().await.len() != count {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "server still has {} client(s), expected {}",
+                self.state.clients.read().await.len(),
+                count
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn audit_actions(&self, user_id: Uuid) -> Vec<AuditAction> {
+        let filter = AuditFilter {
+            user_id: Some(user_id),
+            ..Default::default()
+        };
+        let events = self.state.store.query_audit_events(&filter).await.unwrap();
+        events.iter().rev().map(|e| e.action).collect()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// A desktop-app stand-in speaking JSON over a real WebSocket
+struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    async fn send(&mut self, msg: serde_json::Value) {
+        self.ws.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    async fn send_raw(&mut self, text: &str) {
+        self.ws.send(Message::Text(text.to_string())).await.unwrap();
+    }
+
+    /// Next JSON message from the server
+    async fn recv(&mut self) -> serde_json::Value {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the server")
+                .expect("connection closed")
+                .unwrap();
+            match msg {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    /// Close code the server ends the connection with, skipping other messages
+    async fn recv_close(&mut self) -> u16 {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the server")
+                .expect("connection closed without a close frame")
+                .unwrap();
+            if let Message::Close(frame) = msg {
+                return frame.expect("close frame without a code").code.into();
+            }
+        }
+    }
+
+    async fn request(&mut self, msg: serde_json::Value) -> serde_json::Value {
+        self.send(msg).await;
+        self.recv().await
+    }
+
+    async fn authenticate(&mut self, user_id: Uuid, email: &str) -> serde_json::Value {
+        let token = test_token(user_id, email);
+        self.request(serde_json::json!({ "type": "auth", "token": token })).await
+    }
+
+    async fn close(mut self) {
+        self.ws.close(None).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_session_lifecycle_over_websocket() {
+    let server = TestServer::start().await;
+    let user_id = Uuid::new_v4();
+
+    let mut host = server
+        .connect_with_headers(&[("x-forwarded-for", "203.0.113.7")])
+        .await;
+
+    // Nothing is allowed before auth, and a bad token does not authenticate
+    let reply = host.request(serde_json::json!({ "type": "request_tunnel" })).await;
+    assert_eq!(reply["message"], "Not authenticated");
+    let reply = host
+        .request(serde_json::json!({ "type": "register_ssh_key", "ssh_public_key": TEST_SSH_KEY }))
+        .await;
+    assert_eq!(reply["message"], "Not authenticated");
+    let reply = host.request(serde_json::json!({ "type": "auth", "token": "not-a-jwt" })).await;
+    assert_eq!(reply["message"], "Invalid token");
+    assert!(server.proxy.created.lock().unwrap().is_empty());
+
+    let reply = host.authenticate(user_id, "host@example.com").await;
+    assert_eq!(reply["type"], "auth_success");
+    assert_eq!(reply["user_id"], user_id.to_string());
+    assert_eq!(reply["base_domain"], "tunnels.example.com");
+
+    let reply = host
+        .request(serde_json::json!({ "type": "register_ssh_key", "ssh_public_key": "not a key" }))
+        .await;
+    assert_eq!(reply["type"], "error");
+    let reply = host
+        .request(serde_json::json!({ "type": "register_ssh_key", "ssh_public_key": TEST_SSH_KEY }))
+        .await;
+    assert_eq!(reply["type"], "ssh_key_registered");
+    assert_eq!(
+        server.state.store.get_ssh_public_key(user_id).await.unwrap().as_deref(),
+        Some(TEST_SSH_KEY)
+    );
+
+    let reply = host
+        .request(serde_json::json!({ "type": "request_tunnel", "custom_subdomain": "demo-app" }))
+        .await;
+    assert_eq!(reply["type"], "tunnel_assigned");
+    assert_eq!(reply["tunnel"]["subdomain"], "demo-app");
+    assert_eq!(reply["tunnel"]["url"], "https://demo-app.tunnels.example.com");
+    assert_eq!(*server.proxy.created.lock().unwrap(), vec!["demo-app".to_string()]);
+    assert!(server.state.tunnel_manager.get_tunnel("demo-app").await.is_some());
+    assert!(server.state.store.get_tunnel_by_subdomain("demo-app").await.unwrap().is_some());
+
+    // A second user cannot take the subdomain while it is active
+    let other_id = Uuid::new_v4();
+    let mut other = server.connect().await;
+    other.authenticate(other_id, "other@example.com").await;
+    let reply = other
+        .request(serde_json::json!({ "type": "request_tunnel", "custom_subdomain": "demo-app" }))
+        .await;
+    assert_eq!(reply["type"], "error");
+    assert!(reply["message"].as_str().unwrap().contains("already in use"));
+    assert_eq!(server.proxy.created.lock().unwrap().len(), 1);
+
+    // Dropping the connection tears the tunnel down everywhere
+    host.close().await;
+    server.wait_for_clients(1).await;
+
+    assert_eq!(*server.proxy.removed.lock().unwrap(), vec!["demo-app".to_string()]);
+    assert!(server.state.tunnel_manager.get_tunnel("demo-app").await.is_none());
+    assert!(server.state.store.get_tunnel_by_subdomain("demo-app").await.unwrap().is_none());
+    let history = server.state.store.get_user_tunnels(user_id, 10).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].close_reason.as_deref(), Some("client_disconnected"));
+
+    assert_eq!(
+        server.audit_actions(user_id).await,
+        vec![
+            AuditAction::AuthSuccess,
+            AuditAction::SshKeyRegistered,
+            AuditAction::TunnelCreated,
+            AuditAction::TunnelClosed,
+        ]
+    );
+    let failures = server
+        .state
+        .store
+        .query_audit_events(&AuditFilter::default())
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.action == AuditAction::AuthFailure)
+        .collect::<Vec<_>>();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].client_ip.as_deref(), Some("203.0.113.7"));
+
+    // ...so the subdomain is free again
+    let reply = other
+        .request(serde_json::json!({ "type": "request_tunnel", "custom_subdomain": "demo-app" }))
+        .await;
+    assert_eq!(reply["type"], "tunnel_assigned");
+}
+
+#[tokio::test]
+async fn test_malformed_messages_keep_the_connection_open() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send_raw("{not json").await;
+    assert_eq!(client.recv().await["message"], "Invalid JSON");
+
+    let reply = client.request(serde_json::json!({ "type": "teleport" })).await;
+    assert_eq!(reply["message"], "Unknown message type");
+
+    let reply = client.request(serde_json::json!({ "type": "heartbeat" })).await;
+    assert_eq!(reply["type"], "heartbeat_ack");
+
+    client.close().await;
+    server.wait_for_clients(0).await;
+}
+
+#[tokio::test]
+async fn test_connections_breaking_limits_are_closed_and_counted() {
+    let mut config = test_config();
+    config.limits.max_connections_per_ip = 2;
+    config.limits.max_frame_bytes = 4096;
+    config.limits.messages_per_sec = 1;
+    config.limits.message_burst = 3;
+    config.limits.auth_timeout_secs = 1;
+    let server = TestServer::start_with_config(config).await;
+
+    let mut flooder = server.connect().await;
+    let mut idler = server.connect().await;
+    server.wait_for_clients(2).await;
+    let mut extra = server.connect().await;
+    assert_eq!(extra.recv_close().await, 4003);
+
+    // Auth and two heartbeats use up the burst, the next message is one too many
+    flooder.authenticate(Uuid::new_v4(), "flood@example.com").await;
+    for _ in 0..3 {
+        flooder.send(serde_json::json!({ "type": "heartbeat" })).await;
+    }
+    assert_eq!(flooder.recv_close().await, 4029);
+
+    // Never authenticating gets the connection closed after the deadline
+    assert_eq!(idler.recv_close().await, 4008);
+    server.wait_for_clients(0).await;
+
+    let mut sender = server.connect().await;
+    sender.send_raw(&"x".repeat(5000)).await;
+    assert_eq!(sender.recv_close().await, 1009);
+    server.wait_for_clients(0).await;
+
+    assert_eq!(
+        server.state.limits.metrics(),
+        crate::limits::LimitMetrics {
+            rejected_connections: 1,
+            oversized_frames: 1,
+            rate_limited: 1,
+            auth_timeouts: 1,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_spoofed_forwarded_for_does_not_escape_the_connection_cap() {
+    let mut config = test_config();
+    config.limits.max_connections_per_ip = 1;
+    let server = TestServer::start_with_config(config).await;
+
+    // nginx sets X-Real-IP and appends the real address to whatever
+    // X-Forwarded-For the client sent
+    let _first = server
+        .connect_with_headers(&[("x-real-ip", "203.0.113.7"), ("x-forwarded-for", "10.0.0.1, 203.0.113.7")])
+        .await;
+    server.wait_for_clients(1).await;
+    let mut second = server
+        .connect_with_headers(&[("x-real-ip", "203.0.113.7"), ("x-forwarded-for", "10.0.0.2, 203.0.113.7")])
+        .await;
+    assert_eq!(second.recv_close().await, 4003);
+    let mut third = server
+        .connect_with_headers(&[("x-forwarded-for", "10.0.0.3, 203.0.113.7")])
+        .await;
+    assert_eq!(third.recv_close().await, 4003);
+    assert_eq!(server.state.limits.metrics().rejected_connections, 2);
+}
+
+#[tokio::test]
+async fn test_unfinished_handshakes_are_dropped() {
+    use tokio::io::AsyncReadExt;
+    let mut config = test_config();
+    config.limits.auth_timeout_secs = 1;
+    let server = TestServer::start_with_config(config).await;
+
+    // Connect and never send the upgrade request
+    let mut stream = TcpStream::connect(server.url.trim_start_matches("ws://")).await.unwrap();
+    let mut buf = [0u8; 16];
+    let read = tokio::time::timeout(TIMEOUT, stream.read(&mut buf)).await.expect("handshake was not dropped");
+    assert_eq!(read.unwrap_or(0), 0);
+}
+
+#[tokio::test]
+async fn test_tunnel_ready_only_once_the_forward_listens() {
+    // Stand in for sshd: the only port the server may assign is the one this
+    // listener holds
+    let forward = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = test_config();
+    config.tunnels.port_base = forward.local_addr().unwrap().port();
+    config.tunnels.port_count = 1;
+    config.tunnels.ready_timeout_secs = 1;
+    let server = TestServer::start_with_config(config).await;
+
+    let mut host = server.connect().await;
+    host.authenticate(Uuid::new_v4(), "host@example.com").await;
+    let assigned = host.request(serde_json::json!({ "type": "request_tunnel" })).await;
+    assert_eq!(assigned["type"], "tunnel_assigned");
+    assert_eq!(assigned["tunnel"]["ready"], false);
+    let ready = host.recv().await;
+    assert_eq!(ready["type"], "tunnel_ready");
+    assert_eq!(ready["id"], assigned["tunnel"]["id"]);
+    assert_eq!(ready["url"], assigned["tunnel"]["url"]);
+
+    // A port that was just released has nothing listening on it, so that
+    // tunnel fails and is closed
+    let dead_port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+    let mut config = test_config();
+    config.tunnels.port_base = dead_port;
+    config.tunnels.port_count = 1;
+    config.tunnels.ready_timeout_secs = 1;
+    let server = TestServer::start_with_config(config).await;
+
+    let mut host = server.connect().await;
+    host.authenticate(Uuid::new_v4(), "host@example.com").await;
+    let assigned = host.request(serde_json::json!({ "type": "request_tunnel" })).await;
+    assert_eq!(assigned["tunnel"]["port"], dead_port);
+    let subdomain = assigned["tunnel"]["subdomain"].as_str().unwrap().to_string();
+    let failed = host.recv().await;
+    assert_eq!(failed["type"], "tunnel_failed");
+    assert_eq!(failed["subdomain"], subdomain);
+    assert!(failed["reason"].as_str().unwrap().contains("SSH forward"));
+    let closed = host.recv().await;
+    assert_eq!(closed["type"], "tunnel_closed");
+    assert_eq!(closed["reason"], "forward_failed");
+    assert!(server.state.tunnel_manager.get_tunnel(&subdomain).await.is_none());
+    assert!(server.proxy.removed.lock().unwrap().contains(&subdomain));
+}
+
+/// Plain HTTP/1.1 GET, returning the status code, headers and body
+async fn http_get(addr: std::net::SocketAddr, host: &str, path: &str, extra: &str) -> (u16, String, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n", path, host, extra);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, head.to_ascii_lowercase(), body.to_string())
+}
+
+#[tokio::test]
+async fn test_viewer_client_is_shared_and_configured_per_tunnel() {
+    let server = TestServer::start().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = crate::viewer_client::ViewerClient::load(None).unwrap();
+    let version = client.version().to_string();
+    tokio::spawn(crate::viewer_client::serve(listener, server.state.clone(), client));
+
+    let mut host = server.connect().await;
+    host.authenticate(Uuid::new_v4(), "host@example.com").await;
+    let assigned = host
+        .request(serde_json::json!({ "type": "request_tunnel", "custom_subdomain": "demo-screen", "label": "demo" }))
+        .await;
+    assert_eq!(assigned["type"], "tunnel_assigned");
+
+    let (status, _, body) = http_get(addr, "demo-screen.tunnels.example.com", "/config", "").await;
+    assert_eq!(status, 200);
+    let config: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(config["subdomain"], "demo-screen");
+    assert_eq!(config["label"], "demo");
+    assert_eq!(config["ws_url"], "wss://demo-screen.tunnels.example.com");
+    assert_eq!(config["client_version"], version.as_str());
+
+    let (status, _, _) = http_get(addr, "gone.tunnels.example.com", "/config", "").await;
+    assert_eq!(status, 404);
+
+    // The same page for every tunnel, revalidated by its version
+    let (status, head, body) = http_get(addr, "demo-screen.tunnels.example.com", "/client", "").await;
+    assert_eq!(status, 200);
+    assert!(head.contains(&format!("x-tnnl-client-version: {}", version)));
+    assert!(body.contains("/_tnnl/config"));
+    let if_none_match = format!("If-None-Match: \"{}\"\r\n", version);
+    let (status, _, body) = http_get(addr, "other.tunnels.example.com", "/client", &if_none_match).await;
+    assert_eq!(status, 304);
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn test_offline_page_and_status_follow_the_forward() {
+    // Stand in for sshd, holding the port the tunnel gets
+    let forward = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = test_config();
+    config.tunnels.port_base = forward.local_addr().unwrap().port();
+    let server = TestServer::start_with_config(config).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = crate::viewer_client::ViewerClient::load(None).unwrap();
+    tokio::spawn(crate::viewer_client::serve(listener, server.state.clone(), client));
+
+    let mut host = server.connect().await;
+    host.authenticate(Uuid::new_v4(), "host@example.com").await;
+    let assigned = host
+        .request(serde_json::json!({ "type": "request_tunnel", "custom_subdomain": "demo-screen" }))
+        .await;
+    assert_eq!(assigned["type"], "tunnel_assigned");
+    assert_eq!(host.recv().await["type"], "tunnel_ready");
+
+    // Readiness counts as the host being seen
+    let (status, head, body) = http_get(addr, "demo-screen.tunnels.example.com", "/status", "").await;
+    assert_eq!(status, 200);
+    assert!(head.contains("cache-control: no-store"));
+    let online: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(online["subdomain"], "demo-screen");
+    assert_eq!(online["online"], true);
+    assert!(online["last_seen"].is_string());
+
+    // The forward drops: offline, last seen where it was
+    drop(forward);
+    let (_, _, body) = http_get(addr, "demo-screen.tunnels.example.com", "/status", "").await;
+    let offline: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(offline["online"], false);
+    assert_eq!(offline["last_seen"], online["last_seen"]);
+
+    let (status, head, body) = http_get(addr, "demo-screen.tunnels.example.com", "/offline", "").await;
+    assert_eq!(status, 503);
+    assert!(head.contains("retry-after: 5"));
+    assert!(body.contains("demo-screen.tunnels.example.com"));
+    assert!(body.contains("Last seen"));
+
+    let (status, _, _) = http_get(addr, "gone.tunnels.example.com", "/status", "").await;
+    assert_eq!(status, 404);
+    let (status, _, body) = http_get(addr, "gone.tunnels.example.com", "/offline", "").await;
+    assert_eq!(status, 503);
+    assert!(body.contains("not been seen"));
+}
@@ -0,0 +1,182 @@
+// Tunnel time-to-live
+// Tunnels requested with a TTL or a deadline carry expires_at, which is stored
+// with the tunnel record. The scheduler reads this node's tunnels that are
+// about to expire from the store, warns their host once, and closes them
+// through the normal cleanup path when the time is up
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::tunnel::Tunnel;
+use crate::AppState;
+
+/// Close reason of tunnels closed when their time ran out
+pub const EXPIRED_CLOSE_REASON: &str = "expired";
+
+/// Tunnels whose host has been warned of the coming expiry
+#[derive(Default)]
+pub struct ExpiryTracker {
+    warned: Mutex<HashSet<Uuid>>,
+}
+
+/// Deadline of a tunnel requested with `ttl_seconds` and/or `expires_at`: the
+/// earlier of the two, which must lie in the future and within `max_ttl_secs`
+pub fn resolve_expiry(
+    now: DateTime<Utc>,
+    ttl_seconds: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    max_ttl_secs: u64,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let from_ttl = match ttl_seconds {
+        Some(0) => return Err(anyhow::anyhow!("ttl_seconds must be positive")),
+        Some(secs) if secs > max_ttl_secs => {
+            return Err(anyhow::anyhow!("ttl_seconds may be at most {}", max_ttl_secs))
+        }
+        Some(secs) => Some(now + chrono::Duration::seconds(secs as i64)),
+        None => None,
+    };
+
+    let deadline = match (from_ttl, expires_at) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    if let Some(at) = deadline {
+        if at <= now {
+            return Err(anyhow::anyhow!("expires_at must be in the future"));
+        }
+        if at > now + chrono::Duration::seconds(max_ttl_secs as i64) {
+            return Err(anyhow::anyhow!("Tunnels may live at most {} seconds", max_ttl_secs));
+        }
+    }
+    Ok(deadline)
+}
+
+/// Check for expiring tunnels until the server stops
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.tunnels.expiry_check_interval_secs));
+    loop {
+        interval.tick().await;
+        check(&state, Utc::now()).await;
+    }
+}
+
+/// Warn the hosts of tunnels expiring within the warning period and close
+/// the tunnels that have expired as of `now`
+pub async fn check(state: &Arc<AppState>, now: DateTime<Utc>) {
+    let warning = chrono::Duration::seconds(state.config.tunnels.expiry_warning_secs as i64);
+    let due = match state.store.get_expiring_tunnels(state.config.cluster.node_id(), now + warning).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to load expiring tunnels: {}", e);
+            return;
+        }
+    };
+
+    // Forget tunnels that closed some other way
+    let due_ids: HashSet<Uuid> = due.iter().map(|t| t.id).collect();
+    state.expiry.warned.lock().await.retain(|id| due_ids.contains(id));
+
+    for tunnel in due {
+        let Some(expires_at) = tunnel.expires_at else {
+            continue;
+        };
+
+        if expires_at <= now {
+            info!("Tunnel {} expired at {}", tunnel.subdomain, expires_at);
+            state.expiry.warned.lock().await.remove(&tunnel.id);
+            if !crate::force_close_tunnel(state, &tunnel.subdomain, EXPIRED_CLOSE_REASON).await {
+                // No client here holds it any more; just close the record
+                if let Err(e) = state.store.close_tunnel_record(&tunnel, EXPIRED_CLOSE_REASON).await {
+                    error!("Failed to close expired tunnel record {}: {}", tunnel.subdomain, e);
+                }
+            }
+        } else if state.expiry.warned.lock().await.insert(tunnel.id) {
+            warn_host(state, &tunnel, expires_at, now).await;
+        }
+    }
+}
+
+/// Tell the client holding a tunnel that it is about to expire
+async fn warn_host(state: &AppState, tunnel: &Tunnel, expires_at: DateTime<Utc>, now: DateTime<Utc>) {
+    let notice = serde_json::json!({
+        "type": "tunnel_expiring",
+        "id": tunnel.id,
+        "subdomain": tunnel.subdomain,
+        "expires_at": expires_at.to_rfc3339(),
+        "seconds_left": (expires_at - now).num_seconds()
+    });
+
+    let clients = state.clients.read().await;
+    if let Some(client) = clients.values().find(|c| c.tunnels.iter().any(|t| t.id == tunnel.id)) {
+        let _ = client.sender.send(Message::Text(notice.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_resolve_expiry() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+
+        assert_eq!(resolve_expiry(now, None, None, 86400).unwrap(), None);
+        assert_eq!(resolve_expiry(now, Some(3600), None, 86400).unwrap(), Some(now + hour));
+        assert_eq!(resolve_expiry(now, None, Some(now + hour), 86400).unwrap(), Some(now + hour));
+        // Both given: whichever comes first
+        assert_eq!(
+            resolve_expiry(now, Some(7200), Some(now + hour), 86400).unwrap(),
+            Some(now + hour)
+        );
+
+        assert!(resolve_expiry(now, Some(0), None, 86400).is_err());
+        assert!(resolve_expiry(now, Some(86401), None, 86400).is_err());
+        assert!(resolve_expiry(now, None, Some(now - hour), 86400).is_err());
+        assert!(resolve_expiry(now, None, Some(now + hour * 25), 86400).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_expires_after_ttl() {
+        let (state, proxy) = test_state();
+        let (client_id, mut rx, _) = authed_client(&state).await;
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "ttl_seconds": 0 }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "ttl_seconds must be positive");
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "expires_at": "tomorrow" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "expires_at must be an RFC 3339 timestamp");
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "ttl_seconds": 3600 }), &state).await;
+        let tunnel = next_message(&mut rx)["tunnel"].clone();
+        let expires_at: chrono::DateTime<chrono::Utc> = tunnel["expires_at"].as_str().unwrap().parse().unwrap();
+        let subdomain = tunnel["subdomain"].as_str().unwrap().to_string();
+
+        // Nothing happens well before the deadline
+        check(&state, expires_at - chrono::Duration::hours(1)).await;
+        assert!(rx.try_recv().is_err());
+
+        // The host is warned once within the warning period
+        let soon = expires_at - chrono::Duration::seconds(60);
+        check(&state, soon).await;
+        let warning = next_message(&mut rx);
+        assert_eq!(warning["type"], "tunnel_expiring");
+        assert_eq!(warning["subdomain"], subdomain.as_str());
+        assert_eq!(warning["seconds_left"], 60);
+        check(&state, soon).await;
+        assert!(rx.try_recv().is_err());
+
+        // Past the deadline the tunnel is torn down
+        check(&state, expires_at).await;
+        let closed = next_message(&mut rx);
+        assert_eq!(closed["type"], "tunnel_closed");
+        assert_eq!(closed["reason"], EXPIRED_CLOSE_REASON);
+        assert_eq!(*proxy.removed.lock().unwrap(), vec![subdomain.clone()]);
+        assert!(state.store.get_tunnel_by_subdomain(&subdomain).await.unwrap().is_none());
+    }
+}
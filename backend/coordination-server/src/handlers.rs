@@ -0,0 +1,85 @@
+// Client messages
+// handle_message parses each WebSocket message from a desktop client and hands
+// it to the handler for its type; handlers reply on the client's channel
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+
+mod account;
+mod domains;
+mod tunnels;
+mod usage;
+mod viewers;
+mod webhooks;
+
+/// Default and maximum number of sessions returned by get_tunnel_history
+const DEFAULT_HISTORY_LIMIT: i64 = 20;
+const MAX_HISTORY_LIMIT: i64 = 100;
+
+pub async fn handle_message(client_id: Uuid, text: String, state: &Arc<AppState>) {
+    // Parse message as JSON
+    let msg: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to parse message: {}", e);
+            send_error(client_id, "Invalid JSON", state).await;
+            return;
+        }
+    };
+
+    let msg_type = msg.get("type").and_then(|v| v.as_str());
+
+    match msg_type {
+        Some("auth") => account::authenticate(client_id, &msg, state).await,
+        Some("request_tunnel") => tunnels::request_tunnel(client_id, &msg, state).await,
+        Some("register_ssh_key") => account::register_ssh_key(client_id, &msg, state).await,
+        Some("revoke_ssh_key") => account::revoke_ssh_key(client_id, state).await,
+        Some("heartbeat") => account::heartbeat(client_id, state).await,
+        Some("get_tunnel_history") => tunnels::get_tunnel_history(client_id, &msg, state).await,
+        Some("get_bandwidth_usage") => usage::get_bandwidth_usage(client_id, state).await,
+        Some("get_viewer_traffic") => usage::get_viewer_traffic(client_id, &msg, state).await,
+        Some("viewer_joined") => viewers::viewer_joined(client_id, &msg, state).await,
+        Some("viewer_left") => viewers::viewer_left(client_id, &msg, state).await,
+        Some("list_viewers") => viewers::list_viewers(client_id, state).await,
+        Some("list_tunnels") => tunnels::list_tunnels(client_id, state).await,
+        Some("close_tunnel") => tunnels::close_tunnel(client_id, &msg, state).await,
+        Some("set_viewer_allowlist") => viewers::set_viewer_allowlist(client_id, &msg, state).await,
+        Some("add_custom_domain") => domains::add_custom_domain(client_id, &msg, state).await,
+        Some("verify_custom_domain") => domains::verify_custom_domain(client_id, &msg, state).await,
+        Some("set_custom_domain_target") => domains::set_custom_domain_target(client_id, &msg, state).await,
+        Some("list_custom_domains") => domains::list_custom_domains(client_id, state).await,
+        Some("remove_custom_domain") => domains::remove_custom_domain(client_id, &msg, state).await,
+        Some("add_webhook") => webhooks::add_webhook(client_id, &msg, state).await,
+        Some("list_webhooks") => webhooks::list_webhooks(client_id, state).await,
+        Some("remove_webhook") => webhooks::remove_webhook(client_id, &msg, state).await,
+        Some("get_webhook_deliveries") => webhooks::get_webhook_deliveries(client_id, &msg, state).await,
+        _ => {
+            warn!("Unknown message type: {:?}", msg_type);
+            send_error(client_id, "Unknown message type", state).await;
+        }
+    }
+}
+
+/// Get the authenticated user ID and IP of a client
+async fn client_identity(client_id: Uuid, state: &Arc<AppState>) -> (Option<Uuid>, Option<String>) {
+    let clients = state.clients.read().await;
+    match clients.get(&client_id) {
+        Some(client) => (client.user_id, client.client_ip.clone()),
+        None => (None, None),
+    }
+}
+
+/// Helper function to send error message to client
+async fn send_error(client_id: Uuid, message: &str, state: &Arc<AppState>) {
+    let error_msg = serde_json::json!({
+        "type": "error",
+        "message": message
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(error_msg.to_string()));
+    }
+}
@@ -0,0 +1,299 @@
+// Client sign-in and account messages: auth, heartbeats and SSH keys
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{client_identity, send_error};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{cluster, ssh_keys, AppState};
+
+/// Handle authentication
+pub async fn authenticate(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    info!("Authentication request from {}", client_id);
+
+    let token = match msg.get("token").and_then(|v| v.as_str()) {
+        Some(t) => t,
+        None => {
+            error!("Missing token in auth message");
+            send_error(client_id, "Missing token", state).await;
+            return;
+        }
+    };
+
+    // Verify JWT token
+    // Use insecure mode if server.dev_mode (DEV_MODE) is enabled
+    let verified = if state.config.server.dev_mode {
+        info!("Using DEV_MODE authentication (insecure)");
+        state.auth_service.verify_token_insecure(token)
+    } else {
+        state.auth_service.verify_supabase_token(token)
+    };
+
+    let (user_id, email) = match verified {
+        Ok((uid, em)) => (uid, em),
+        Err(e) => {
+            error!("Token verification failed: {}", e);
+            let (_, client_ip) = client_identity(client_id, state).await;
+            audit::record(
+                state.store.as_ref(),
+                AuditEvent::new(AuditAction::AuthFailure)
+                    .client_ip(client_ip.as_deref())
+                    .details(serde_json::json!({ "error": e.to_string() })),
+            )
+            .await;
+            send_error(client_id, "Invalid token", state).await;
+            return;
+        }
+    };
+
+    // Store or update user in database and get actual user_id
+    let actual_user_id = match state.store.get_or_create_user(user_id, &email).await {
+        Ok(uid) => uid,
+        Err(e) => {
+            error!("Failed to store user: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    // Update client with ACTUAL user_id from database
+    {
+        let mut clients = state.clients.write().await;
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.user_id = Some(actual_user_id);
+        }
+    }
+    tracing::Span::current().record("user_id", tracing::field::display(actual_user_id));
+
+    // Send success response
+    // Tell the client where its tunnels will live so it never assumes tnnl.to,
+    // and which nodes it can fail over to, closest first
+    let client_region = msg.get("region").and_then(|v| v.as_str());
+    let mut response = serde_json::json!({
+        "type": "auth_success",
+        "user_id": actual_user_id,
+        "email": email,
+        "base_domain": state.config.server.base_domain,
+        "ssh_host": state.config.server.ssh_host()
+    });
+    if state.config.cluster.enabled {
+        response["node"] = serde_json::json!({
+            "id": state.config.cluster.node_id(),
+            "region": state.config.cluster.region
+        });
+        response["nodes"] = cluster::nodes_for_client(state, client_region)
+            .await
+            .iter()
+            .map(|n| {
+                serde_json::json!({
+                    "id": n.id,
+                    "region": n.region,
+                    "url": n.public_url,
+                    "base_domain": n.base_domain
+                })
+            })
+            .collect();
+    }
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    let (_, client_ip) = client_identity(client_id, state).await;
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::AuthSuccess)
+            .user(Some(actual_user_id))
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "email": email })),
+    )
+    .await;
+
+    info!("Client {} authenticated as user {}", client_id, actual_user_id);
+}
+
+/// Handle SSH key registration
+pub async fn register_ssh_key(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    info!("SSH key registration from {}", client_id);
+
+    // Get user_id from client
+    let user_id = {
+        let clients = state.clients.read().await;
+        match clients.get(&client_id) {
+            Some(client) => match client.user_id {
+                Some(uid) => uid,
+                None => {
+                    error!("Client {} not authenticated", client_id);
+                    send_error(client_id, "Not authenticated", state).await;
+                    return;
+                }
+            },
+            None => {
+                error!("Client {} not found", client_id);
+                return;
+            }
+        }
+    };
+
+    // Get SSH public key from message
+    let ssh_public_key = match msg.get("ssh_public_key").and_then(|v| v.as_str()) {
+        Some(key) => key,
+        None => {
+            error!("Missing ssh_public_key in message");
+            send_error(client_id, "Missing ssh_public_key", state).await;
+            return;
+        }
+    };
+
+    // Validate SSH key
+    if let Err(e) = ssh_keys::validate_ssh_public_key(ssh_public_key) {
+        error!("Invalid SSH key: {}", e);
+        send_error(client_id, &format!("Invalid SSH key: {}", e), state).await;
+        return;
+    }
+
+    // Store SSH key in database
+    if let Err(e) = state.store.store_ssh_public_key(user_id, ssh_public_key).await {
+        error!("Failed to store SSH key: {}", e);
+        send_error(client_id, "Failed to store SSH key", state).await;
+        return;
+    }
+
+    // Add to authorized_keys file
+    if let Err(e) = ssh_keys::add_ssh_key_to_authorized_keys(&state.config.ssh.authorized_keys_path, ssh_public_key).await {
+        error!("Failed to add SSH key to authorized_keys: {}", e);
+        send_error(client_id, "Failed to register SSH key", state).await;
+        return;
+    }
+
+    // Send success response
+    let response = serde_json::json!({
+        "type": "ssh_key_registered",
+        "success": true
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    let (_, client_ip) = client_identity(client_id, state).await;
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::SshKeyRegistered)
+            .user(Some(user_id))
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "key_type": ssh_keys::key_type(ssh_public_key) })),
+    )
+    .await;
+
+    webhooks::emit(
+        state,
+        user_id,
+        WebhookEvent::SshKeyAdded,
+        serde_json::json!({ "key_type": ssh_keys::key_type(ssh_public_key) }),
+    )
+    .await;
+
+    info!("SSH key registered for user {}", user_id);
+}
+
+/// Handle SSH key revocation
+pub async fn revoke_ssh_key(client_id: Uuid, state: &Arc<AppState>) {
+    info!("SSH key revocation from {}", client_id);
+
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    // Remove SSH key from database
+    let revoked_key = match state.store.clear_ssh_public_key(user_id).await {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Failed to revoke SSH key: {}", e);
+            send_error(client_id, "Failed to revoke SSH key", state).await;
+            return;
+        }
+    };
+
+    // Remove from authorized_keys file
+    if let Some(key) = &revoked_key {
+        if let Err(e) = ssh_keys::remove_ssh_key_from_authorized_keys(&state.config.ssh.authorized_keys_path, key).await {
+            error!("Failed to remove SSH key from authorized_keys: {}", e);
+            send_error(client_id, "Failed to revoke SSH key", state).await;
+            return;
+        }
+
+        audit::record(
+            state.store.as_ref(),
+            AuditEvent::new(AuditAction::SshKeyRevoked)
+                .user(Some(user_id))
+                .client_ip(client_ip.as_deref())
+                .details(serde_json::json!({ "key_type": ssh_keys::key_type(key) })),
+        )
+        .await;
+    }
+
+    let response = serde_json::json!({
+        "type": "ssh_key_revoked",
+        "success": revoked_key.is_some()
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    info!("SSH key revoked for user {}", user_id);
+}
+
+/// Respond to heartbeat
+pub async fn heartbeat(client_id: Uuid, state: &Arc<AppState>) {
+    let tunnel_ids: Vec<Uuid> = match state.clients.read().await.get(&client_id) {
+        Some(client) => {
+            let response = serde_json::json!({
+                "type": "heartbeat_ack",
+                "timestamp": chrono::Utc::now().to_rfc3339()
+            });
+            let _ = client.sender.send(Message::Text(response.to_string()));
+            client.tunnels.iter().map(|t| t.id).collect()
+        }
+        None => Vec::new(),
+    };
+
+    // The host is alive, so its tunnels were last seen now
+    for tunnel_id in tunnel_ids {
+        if let Err(e) = state.store.update_tunnel_last_connected(tunnel_id).await {
+            error!("Failed to update last_connected_at for tunnel {}: {}", tunnel_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn test_invalid_token_is_rejected_and_audited() {
+        let (state, _proxy) = test_state();
+        let (client_id, mut rx) = connect_client(&state).await;
+
+        send(client_id, serde_json::json!({ "type": "auth", "token": "not-a-jwt" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "Invalid token");
+
+        let events = state
+            .store
+            .query_audit_events(&audit::AuditFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::AuthFailure);
+        assert_eq!(events[0].client_ip.as_deref(), Some("127.0.0.1"));
+    }
+}
@@ -0,0 +1,566 @@
+// Custom domain messages: claiming, verifying, targeting and releasing
+// user-owned hostnames
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{client_identity, send_error};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::domains::{self, CustomDomain, VerificationMethod};
+use crate::{route_custom_domain, tunnel, tunnel_for_domain, AppState};
+
+/// Claim a hostname and hand back the ownership challenge
+pub async fn add_custom_domain(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let hostname = match msg.get("hostname").and_then(|v| v.as_str()) {
+        Some(h) => match domains::normalize_hostname(h, &state.config.server.base_domain) {
+            Ok(h) => h,
+            Err(e) => {
+                send_error(client_id, &e.to_string(), state).await;
+                return;
+            }
+        },
+        None => {
+            send_error(client_id, "Missing hostname", state).await;
+            return;
+        }
+    };
+
+    let method = match msg.get("method").and_then(|v| v.as_str()) {
+        None => VerificationMethod::Txt,
+        Some(m) => match VerificationMethod::parse(m) {
+            Some(method) => method,
+            None => {
+                send_error(client_id, "Invalid verification method", state).await;
+                return;
+            }
+        },
+    };
+
+    let target = match domain_target(client_id, msg, state).await {
+        Some(t) => t,
+        None => return,
+    };
+
+    let existing = match state.store.get_custom_domain(&hostname).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to load custom domain {}: {}", hostname, e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    let domain = match existing {
+        // Adding a domain twice re-sends its challenge, and retargets it
+        // when a target is given
+        Some(mut domain) if domain.user_id == user_id => {
+            if target.is_some() && target != domain.target {
+                if let Err(e) = state.store.set_custom_domain_target(domain.id, target.as_deref()).await {
+                    error!("Failed to retarget custom domain {}: {}", domain.hostname, e);
+                    send_error(client_id, "Database error", state).await;
+                    return;
+                }
+                domain.target = target;
+                if domain.is_verified() {
+                    route_custom_domain(state, &domain, None).await;
+                }
+            }
+            domain
+        }
+        Some(domain) if !domain.is_stale_claim() => {
+            send_error(client_id, "Hostname is already claimed", state).await;
+            return;
+        }
+        stale => {
+            if let Some(domain) = stale {
+                info!("Releasing stale claim on {} held by user {}", domain.hostname, domain.user_id);
+                if let Err(e) = state.store.delete_custom_domain(domain.id).await {
+                    error!("Failed to release stale claim on {}: {}", domain.hostname, e);
+                    send_error(client_id, "Database error", state).await;
+                    return;
+                }
+            }
+
+            match state.store.list_user_custom_domains(user_id).await {
+                Ok(owned) if owned.len() >= domains::MAX_CUSTOM_DOMAINS_PER_USER => {
+                    send_error(client_id, "Custom domain limit reached", state).await;
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to load custom domains: {}", e);
+                    send_error(client_id, "Database error", state).await;
+                    return;
+                }
+            }
+
+            let domain = CustomDomain {
+                target,
+                ..CustomDomain::new(user_id, hostname.clone(), method)
+            };
+            if let Err(e) = state.store.create_custom_domain(&domain).await {
+                error!("Failed to store custom domain {}: {}", hostname, e);
+                send_error(client_id, "Database error", state).await;
+                return;
+            }
+
+            audit::record(
+                state.store.as_ref(),
+                AuditEvent::new(AuditAction::CustomDomainAdded)
+                    .user(Some(user_id))
+                    .client_ip(client_ip.as_deref())
+                    .details(serde_json::json!({
+                        "hostname": hostname,
+                        "method": method.as_str(),
+                        "target": domain.target
+                    })),
+            )
+            .await;
+
+            domain
+        }
+    };
+
+    // The HTTP challenge is answered by the proxy until the domain is verified
+    if domain.verification_method == VerificationMethod::Http && !domain.is_verified() {
+        if let Err(e) = state
+            .proxy
+            .publish_domain_challenge(&domain.hostname, &domain.verification_token)
+            .await
+        {
+            error!("Failed to publish challenge for {}: {}", domain.hostname, e);
+            send_error(client_id, &format!("Failed to publish challenge: {}", e), state).await;
+            return;
+        }
+    }
+
+    let response = serde_json::json!({
+        "type": "custom_domain_added",
+        "domain": domain,
+        "verification": domain.verification_instructions(&state.config.server.base_domain)
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Check the ownership challenge, then issue a certificate and route the domain
+pub async fn verify_custom_domain(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let domain = match find_owned_domain(client_id, user_id, msg, state).await {
+        Some(d) => d,
+        None => return,
+    };
+
+    if !domain.is_verified() {
+        match state.verifier.check(&domain).await {
+            Ok(true) => {}
+            Ok(false) => {
+                send_error(
+                    client_id,
+                    &format!("Ownership challenge not found for {}", domain.hostname),
+                    state,
+                )
+                .await;
+                return;
+            }
+            Err(e) => {
+                error!("Failed to verify {}: {}", domain.hostname, e);
+                send_error(client_id, &format!("Verification failed: {}", e), state).await;
+                return;
+            }
+        }
+
+        if let Err(e) = state.proxy.provision_custom_domain(&domain.hostname).await {
+            error!("Failed to provision {}: {}", domain.hostname, e);
+            send_error(client_id, &format!("Certificate provisioning failed: {}", e), state).await;
+            return;
+        }
+
+        if let Err(e) = state.store.mark_custom_domain_verified(domain.id).await {
+            error!("Failed to mark {} verified: {}", domain.hostname, e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+
+        audit::record(
+            state.store.as_ref(),
+            AuditEvent::new(AuditAction::CustomDomainVerified)
+                .user(Some(user_id))
+                .client_ip(client_ip.as_deref())
+                .details(serde_json::json!({
+                    "hostname": domain.hostname,
+                    "method": domain.verification_method.as_str()
+                })),
+        )
+        .await;
+
+        // Serve it from the tunnel it targets straight away
+        if tunnel_for_domain(state, &domain, None).await.is_some() {
+            route_custom_domain(state, &domain, None).await;
+        }
+    }
+
+    let response = serde_json::json!({
+        "type": "custom_domain_verified",
+        "hostname": domain.hostname,
+        "url": format!("https://{}", domain.hostname)
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    info!("Custom domain {} verified for user {}", domain.hostname, user_id);
+}
+
+/// Choose which tunnels a domain is served from
+pub async fn set_custom_domain_target(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let mut domain = match find_owned_domain(client_id, user_id, msg, state).await {
+        Some(d) => d,
+        None => return,
+    };
+    let target = match domain_target(client_id, msg, state).await {
+        Some(t) => t,
+        None => return,
+    };
+
+    if let Err(e) = state.store.set_custom_domain_target(domain.id, target.as_deref()).await {
+        error!("Failed to retarget custom domain {}: {}", domain.hostname, e);
+        send_error(client_id, "Database error", state).await;
+        return;
+    }
+    domain.target = target;
+
+    // Verified domains move to their new target, or go offline
+    let routed_to = if domain.is_verified() {
+        route_custom_domain(state, &domain, None).await
+    } else {
+        None
+    };
+
+    let response = serde_json::json!({
+        "type": "custom_domain_updated",
+        "domain": domain,
+        "tunnel_id": routed_to.map(|t| t.id)
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    info!(
+        "Custom domain {} now targets {}",
+        domain.hostname,
+        domain.target.as_deref().unwrap_or("nothing")
+    );
+}
+
+/// The user's custom domains, with the challenge of pending ones
+pub async fn list_custom_domains(client_id: Uuid, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let owned = match state.store.list_user_custom_domains(user_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to load custom domains: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    // Pending domains carry their challenge so the client can show it again
+    let domains: Vec<serde_json::Value> = owned
+        .iter()
+        .map(|domain| {
+            let mut entry = serde_json::json!(domain);
+            if !domain.is_verified() {
+                entry["verification"] =
+                    domain.verification_instructions(&state.config.server.base_domain);
+            }
+            entry
+        })
+        .collect();
+
+    let response = serde_json::json!({
+        "type": "custom_domains",
+        "domains": domains
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Release one of the user's custom domains
+pub async fn remove_custom_domain(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let domain = match find_owned_domain(client_id, user_id, msg, state).await {
+        Some(d) => d,
+        None => return,
+    };
+
+    if let Err(e) = state.proxy.remove_custom_domain(&domain.hostname).await {
+        error!("Failed to remove proxy config for {}: {}", domain.hostname, e);
+    }
+
+    if let Err(e) = state.store.delete_custom_domain(domain.id).await {
+        error!("Failed to delete custom domain {}: {}", domain.hostname, e);
+        send_error(client_id, "Database error", state).await;
+        return;
+    }
+
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::CustomDomainRemoved)
+            .user(Some(user_id))
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "hostname": domain.hostname })),
+    )
+    .await;
+
+    let response = serde_json::json!({
+        "type": "custom_domain_removed",
+        "hostname": domain.hostname
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    info!("Custom domain {} removed for user {}", domain.hostname, user_id);
+}
+
+/// Look up the custom domain named by a message's `hostname` field
+/// Sends an error and returns None unless the user owns it
+async fn find_owned_domain(
+    client_id: Uuid,
+    user_id: Uuid,
+    msg: &serde_json::Value,
+    state: &Arc<AppState>,
+) -> Option<CustomDomain> {
+    let hostname = match msg.get("hostname").and_then(|v| v.as_str()) {
+        Some(h) => h.trim().trim_end_matches('.').to_ascii_lowercase(),
+        None => {
+            send_error(client_id, "Missing hostname", state).await;
+            return None;
+        }
+    };
+
+    match state.store.get_custom_domain(&hostname).await {
+        Ok(Some(domain)) if domain.user_id == user_id => Some(domain),
+        Ok(_) => {
+            send_error(client_id, "Custom domain not found", state).await;
+            None
+        }
+        Err(e) => {
+            error!("Failed to load custom domain {}: {}", hostname, e);
+            send_error(client_id, "Database error", state).await;
+            None
+        }
+    }
+}
+
+/// Read the optional `target` of a custom domain message: the reserved
+/// subdomain or label of the tunnels the domain should be served from
+/// Sends an error and returns None if it is malformed
+async fn domain_target(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) -> Option<Option<String>> {
+    match msg.get("target") {
+        None | Some(serde_json::Value::Null) => Some(None),
+        Some(value) => match value.as_str().map(tunnel::parse_label) {
+            Some(Ok(target)) => Some(Some(target)),
+            _ => {
+                send_error(client_id, "target must be a tunnel label or reserved subdomain", state).await;
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::force_close_tunnel;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn test_custom_domain_verify_route_remove_flow() {
+        let (state, proxy, verifier) = test_state_with_verifier();
+        let (client_id, mut rx, _) = authed_client(&state).await;
+
+        // Hosts under the base domain belong to generated tunnels
+        send(
+            client_id,
+            serde_json::json!({ "type": "add_custom_domain", "hostname": "x.tunnels.example.com" }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["type"], "error");
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "add_custom_domain", "hostname": "Demo.Example.com", "method": "http", "target": 7 }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["message"], "target must be a tunnel label or reserved subdomain");
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "add_custom_domain", "hostname": "Demo.Example.com", "method": "http", "target": "web" }),
+            &state,
+        )
+        .await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "custom_domain_added");
+        assert_eq!(response["domain"]["hostname"], "demo.example.com");
+        assert_eq!(response["domain"]["target"], "web");
+        assert_eq!(response["verification"]["method"], "http");
+        assert_eq!(response["verification"]["cname_target"], "tunnels.example.com");
+
+        // Not verified until the challenge is published
+        send(
+            client_id,
+            serde_json::json!({ "type": "verify_custom_domain", "hostname": "demo.example.com" }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["type"], "error");
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "label": "web" }), &state).await;
+        let response = next_message(&mut rx);
+        let subdomain = response["tunnel"]["subdomain"].as_str().unwrap().to_string();
+        assert_eq!(response["tunnel"]["custom_urls"], serde_json::json!([]));
+
+        verifier.published.store(true, Ordering::SeqCst);
+        send(
+            client_id,
+            serde_json::json!({ "type": "verify_custom_domain", "hostname": "demo.example.com" }),
+            &state,
+        )
+        .await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "custom_domain_verified");
+        assert_eq!(response["url"], "https://demo.example.com");
+
+        // A newer tunnel with another label does not take the domain over
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "label": "api" }), &state).await;
+        let response = next_message(&mut rx);
+        let api_subdomain = response["tunnel"]["subdomain"].as_str().unwrap().to_string();
+        assert_eq!(response["tunnel"]["custom_urls"], serde_json::json!([]));
+        let domain = state.store.get_custom_domain("demo.example.com").await.unwrap().unwrap();
+        assert_eq!(tunnel_for_domain(&state, &domain, None).await.unwrap().subdomain, subdomain);
+
+        // Until the domain is retargeted, by label or subdomain
+        send(
+            client_id,
+            serde_json::json!({ "type": "set_custom_domain_target", "hostname": "demo.example.com", "target": api_subdomain }),
+            &state,
+        )
+        .await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "custom_domain_updated");
+        assert_eq!(response["domain"]["target"], api_subdomain.as_str());
+        send(
+            client_id,
+            serde_json::json!({ "type": "set_custom_domain_target", "hostname": "demo.example.com", "target": "web" }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["domain"]["target"], "web");
+        assert!(force_close_tunnel(&state, &api_subdomain, "admin").await);
+        next_message(&mut rx);
+
+        // Another user cannot claim a verified domain
+        let (other_id, mut other_rx) = connect_client(&state).await;
+        let other_token = test_token(Uuid::new_v4(), "other@example.com");
+        send(other_id, serde_json::json!({ "type": "auth", "token": other_token }), &state).await;
+        next_message(&mut other_rx);
+        send(
+            other_id,
+            serde_json::json!({ "type": "add_custom_domain", "hostname": "demo.example.com" }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut other_rx)["message"], "Hostname is already claimed");
+
+        send(client_id, serde_json::json!({ "type": "list_custom_domains" }), &state).await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "custom_domains");
+        assert_eq!(response["domains"][0]["hostname"], "demo.example.com");
+        assert!(response["domains"][0]["verified_at"].is_string());
+        assert!(response["domains"][0].get("verification_token").is_none());
+
+        // Closing the tunnel parks the domain on the offline page
+        assert!(force_close_tunnel(&state, &subdomain, "admin").await);
+        next_message(&mut rx);
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "remove_custom_domain", "hostname": "demo.example.com" }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["type"], "custom_domain_removed");
+        assert!(state.store.get_custom_domain("demo.example.com").await.unwrap().is_none());
+
+        assert_eq!(
+            *proxy.domains.lock().unwrap(),
+            vec![
+                "challenge demo.example.com".to_string(),
+                "provision demo.example.com".to_string(),
+                format!("route demo.example.com {}", subdomain),
+                format!("route demo.example.com {}", subdomain),
+                format!("route demo.example.com {}", api_subdomain),
+                format!("route demo.example.com {}", subdomain),
+                format!("route demo.example.com {}", subdomain),
+                "route demo.example.com offline".to_string(),
+                "remove demo.example.com".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,583 @@
+// Tunnel messages: requesting, listing and closing tunnels, and their history
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{client_identity, send_error, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::bandwidth::{self, UsageLevel};
+use crate::config::OverCapAction;
+use crate::tunnel::{self, Tunnel, TunnelKind, TunnelOptions};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{
+    create_random_tunnel, expiry, force_close_tunnel, readiness, route_custom_domains, tunnel_url, viewer_auth,
+    AppState, Client,
+};
+
+/// Handle tunnel request
+pub async fn request_tunnel(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    info!("Tunnel request from {}", client_id);
+
+    // Get user_id from client
+    let user_id = {
+        let clients = state.clients.read().await;
+        match clients.get(&client_id) {
+            Some(client) => match client.user_id {
+                Some(uid) => uid,
+                None => {
+                    error!("Client {} not authenticated", client_id);
+                    send_error(client_id, "Not authenticated", state).await;
+                    return;
+                }
+            },
+            None => {
+                error!("Client {} not found", client_id);
+                return;
+            }
+        }
+    };
+
+    // What the tunnel forwards to: the screen-sharing server by default,
+    // or a local HTTP or TCP service on local_port
+    let kind = match msg.get("kind").and_then(|v| v.as_str()) {
+        None => TunnelKind::Screen,
+        Some(name) => match TunnelKind::parse(name) {
+            Some(kind) => kind,
+            None => {
+                send_error(client_id, &format!("Unknown tunnel kind: {}", name), state).await;
+                return;
+            }
+        },
+    };
+    let local_port = match msg.get("local_port") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => match value.as_u64().and_then(|p| u16::try_from(p).ok()).filter(|p| *p != 0) {
+            Some(port) => Some(port),
+            None => {
+                send_error(client_id, "local_port must be a port number", state).await;
+                return;
+            }
+        },
+    };
+    if kind != TunnelKind::Screen && local_port.is_none() {
+        send_error(client_id, &format!("local_port is required for {} tunnels", kind.as_str()), state).await;
+        return;
+    }
+
+    let label = match msg.get("label") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => match value.as_str().map(tunnel::parse_label) {
+            Some(Ok(label)) => Some(label),
+            Some(Err(e)) => {
+                send_error(client_id, &e.to_string(), state).await;
+                return;
+            }
+            None => {
+                send_error(client_id, "label must be a string", state).await;
+                return;
+            }
+        },
+    };
+
+    // Optional time to live and/or hard deadline; the earlier one wins
+    let ttl_seconds = match msg.get("ttl_seconds") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => match value.as_u64() {
+            Some(secs) => Some(secs),
+            None => {
+                send_error(client_id, "ttl_seconds must be a positive integer", state).await;
+                return;
+            }
+        },
+    };
+    let deadline = match msg.get("expires_at") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => match value.as_str().and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok()) {
+            Some(at) => Some(at.with_timezone(&chrono::Utc)),
+            None => {
+                send_error(client_id, "expires_at must be an RFC 3339 timestamp", state).await;
+                return;
+            }
+        },
+    };
+    let expires_at = match expiry::resolve_expiry(
+        chrono::Utc::now(),
+        ttl_seconds,
+        deadline,
+        state.config.tunnels.max_ttl_secs,
+    ) {
+        Ok(at) => at,
+        Err(e) => {
+            send_error(client_id, &e.to_string(), state).await;
+            return;
+        }
+    };
+
+    // Get optional password and custom subdomain from request
+    let password = msg.get("password").and_then(|v| v.as_str()).map(String::from);
+    let custom_subdomain = msg.get("custom_subdomain").and_then(|v| v.as_str()).map(String::from);
+    let allowed_viewers = match msg.get("allowed_viewers") {
+        None | Some(serde_json::Value::Null) => Vec::new(),
+        Some(value) => match viewer_auth::parse_allowlist(value, state.config.viewer_auth.enabled) {
+            Ok(list) => list,
+            Err(e) => {
+                send_error(client_id, &e.to_string(), state).await;
+                return;
+            }
+        },
+    };
+
+    // TCP connections carry no HTTP, so there is nothing to check a
+    // password or viewer sign-in against
+    if kind == TunnelKind::Tcp && (password.is_some() || !allowed_viewers.is_empty()) {
+        send_error(client_id, "Passwords and viewer allowlists are not supported for tcp tunnels", state).await;
+        return;
+    }
+
+    // Each connection may hold a few tunnels at once, e.g. its screen
+    // and a dev server
+    let open_tunnels = state
+        .clients
+        .read()
+        .await
+        .get(&client_id)
+        .map_or(0, |c| c.tunnels.len());
+    if open_tunnels >= state.config.tunnels.max_per_client {
+        send_error(
+            client_id,
+            &format!(
+                "Tunnel limit reached ({} per connection); close one first",
+                state.config.tunnels.max_per_client
+            ),
+            state,
+        )
+        .await;
+        return;
+    }
+
+    // Users past their monthly transfer cap get no new tunnels, or only
+    // throttled ones
+    let usage = match bandwidth::monthly_usage(state, user_id).await {
+        Ok(u) => bandwidth::usage_level(&state.config.bandwidth, u.total()),
+        Err(e) => {
+            error!("Failed to load traffic for user {}: {}", user_id, e);
+            UsageLevel::Normal
+        }
+    };
+    let throttled = usage == UsageLevel::OverCap;
+    if throttled && state.config.bandwidth.over_cap_action == OverCapAction::Close {
+        send_error(client_id, "Monthly transfer cap reached", state).await;
+        return;
+    }
+
+    // Create tunnel
+    let options = TunnelOptions { kind, label, password, expires_at };
+    let created = match custom_subdomain {
+        Some(subdomain) => state.tunnel_manager.create_custom_tunnel(user_id, subdomain, options).await,
+        None => create_random_tunnel(state, user_id, options).await,
+    };
+    let tunnel = match created {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to create tunnel: {}", e);
+            send_error(client_id, &format!("Tunnel creation failed: {}", e), state).await;
+            return;
+        }
+    };
+
+    // Store tunnel in database
+    // This also catches a subdomain held by a tunnel on another node
+    if let Err(e) = state.store.create_tunnel_record(&tunnel).await {
+        error!("Failed to store tunnel in database: {}", e);
+        let _ = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await;
+        send_error(client_id, "Database error", state).await;
+        return;
+    }
+
+    // Create Nginx configuration
+    if let Err(e) = state.proxy.create_tunnel_config(&tunnel).await {
+        error!("Failed to create Nginx config: {}", e);
+        send_error(client_id, &format!("Nginx configuration failed: {}", e), state).await;
+
+        // Clean up tunnel
+        let _ = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await;
+        let _ = state.store.close_tunnel_record(&tunnel, "provisioning_failed").await;
+
+        let (_, client_ip) = client_identity(client_id, state).await;
+        audit::record(
+            state.store.as_ref(),
+            AuditEvent::new(AuditAction::TunnelClosed)
+                .user(Some(user_id))
+                .subdomain(&tunnel.subdomain)
+                .client_ip(client_ip.as_deref())
+                .details(serde_json::json!({
+                    "reason": "provisioning_failed",
+                    "tunnel_id": tunnel.id,
+                    "error": e.to_string()
+                })),
+        )
+        .await;
+        return;
+    }
+
+    if throttled {
+        let rate = state.config.bandwidth.throttle_rate_bytes();
+        if let Err(e) = state.proxy.set_tunnel_rate_limit(&tunnel, Some(rate)).await {
+            error!("Failed to throttle tunnel {}: {}", tunnel.subdomain, e);
+        }
+    }
+
+    // Restrict viewers before the tunnel is announced
+    state.viewer_auth.set_allowlist(&tunnel.subdomain, allowed_viewers.clone()).await;
+
+    // Add tunnel to client's tunnel list
+    {
+        let mut clients = state.clients.write().await;
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.tunnels.push(tunnel.clone());
+        }
+    }
+
+    // Serve the user's verified custom domains targeting the new tunnel from it
+    let custom_urls: Vec<String> = if kind == TunnelKind::Tcp {
+        Vec::new()
+    } else {
+        route_custom_domains(state, user_id, None)
+            .await
+            .iter()
+            .filter(|(_, tunnel_id)| *tunnel_id == tunnel.id)
+            .map(|(hostname, _)| format!("https://{}", hostname))
+            .collect()
+    };
+
+    // Send tunnel info to client; the URL only works once tunnel_ready follows
+    let url = tunnel_url(&state.config, &tunnel);
+    let response = serde_json::json!({
+        "type": "tunnel_assigned",
+        "tunnel": {
+            "id": tunnel.id,
+            "subdomain": tunnel.subdomain,
+            "label": tunnel.label,
+            "kind": tunnel.kind.as_str(),
+            "url": url,
+            "ready": false,
+            "custom_urls": custom_urls,
+            "port": tunnel.port,
+            "local_port": local_port,
+            "public_port": tunnel.public_port,
+            "password": tunnel.password,
+            "allowed_viewers": allowed_viewers,
+            "throttled": throttled,
+            "created_at": tunnel.created_at.to_rfc3339(),
+            "expires_at": tunnel.expires_at.map(|at| at.to_rfc3339())
+        }
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    // Confirm once the app's SSH forward is listening
+    tokio::spawn(readiness::watch(state.clone(), client_id, tunnel.clone(), url));
+
+    let (_, client_ip) = client_identity(client_id, state).await;
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::TunnelCreated)
+            .user(Some(user_id))
+            .subdomain(&tunnel.subdomain)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({
+                "tunnel_id": tunnel.id,
+                "label": tunnel.label,
+                "kind": tunnel.kind.as_str(),
+                "port": tunnel.port,
+                "public_port": tunnel.public_port,
+                "password_protected": tunnel.password.is_some(),
+                "allowed_viewers": allowed_viewers.len(),
+                "expires_at": tunnel.expires_at.map(|at| at.to_rfc3339())
+            })),
+    )
+    .await;
+
+    webhooks::emit(
+        state,
+        user_id,
+        WebhookEvent::TunnelCreated,
+        serde_json::json!({ "tunnel": webhooks::tunnel_summary(&tunnel, &tunnel_url(&state.config, &tunnel)) }),
+    )
+    .await;
+
+    info!("Tunnel {} assigned to client {}", tunnel.subdomain, client_id);
+}
+
+/// The user's live tunnels on this node, across all their clients
+pub async fn list_tunnels(client_id: Uuid, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let tunnels = {
+        let clients = state.clients.read().await;
+        let mut tunnels: Vec<(&Tunnel, &Client)> = clients
+            .values()
+            .filter(|c| c.user_id == Some(user_id))
+            .flat_map(|c| c.tunnels.iter().map(move |t| (t, c)))
+            .collect();
+        tunnels.sort_by_key(|(t, _)| t.created_at);
+
+        tunnels
+            .into_iter()
+            .map(|(t, c)| {
+                serde_json::json!({
+                    "id": t.id,
+                    "subdomain": t.subdomain,
+                    "label": t.label,
+                    "kind": t.kind.as_str(),
+                    "url": tunnel_url(&state.config, t),
+                    "public_port": t.public_port,
+                    "password_protected": t.password.is_some(),
+                    "viewer_count": c.viewers.iter().filter(|v| v.subdomain == t.subdomain).count(),
+                    "this_client": c.id == client_id,
+                    "created_at": t.created_at.to_rfc3339(),
+                    "expires_at": t.expires_at.map(|at| at.to_rfc3339())
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let response = serde_json::json!({
+        "type": "tunnels",
+        "tunnels": tunnels
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Close one of the user's tunnels, leaving the connection and any
+/// other tunnels up
+pub async fn close_tunnel(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+    let tunnel_id = match msg.get("id").and_then(|v| v.as_str()).and_then(|id| Uuid::parse_str(id).ok()) {
+        Some(id) => id,
+        None => {
+            send_error(client_id, "Missing or invalid tunnel id", state).await;
+            return;
+        }
+    };
+
+    let owner = {
+        let clients = state.clients.read().await;
+        clients
+            .values()
+            .filter(|c| c.user_id == Some(user_id))
+            .find_map(|c| c.tunnels.iter().find(|t| t.id == tunnel_id).map(|t| (c.id, t.subdomain.clone())))
+    };
+    let (owner_id, subdomain) = match owner {
+        Some(o) => o,
+        None => {
+            send_error(client_id, "Tunnel not found", state).await;
+            return;
+        }
+    };
+
+    info!("Client {} closing tunnel {}", client_id, subdomain);
+    force_close_tunnel(state, &subdomain, "closed_by_client").await;
+
+    // The owning client was notified; tell the requester too if it is
+    // another of the user's apps
+    if owner_id != client_id {
+        let notice = serde_json::json!({
+            "type": "tunnel_closed",
+            "id": tunnel_id,
+            "subdomain": subdomain,
+            "reason": "closed_by_client"
+        });
+        if let Some(client) = state.clients.read().await.get(&client_id) {
+            let _ = client.sender.send(Message::Text(notice.to_string()));
+        }
+    }
+}
+
+/// Handle tunnel history request
+pub async fn get_tunnel_history(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let limit = msg
+        .get("limit")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let tunnels = match state.store.get_user_tunnels(user_id, limit).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to load tunnel history: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    let response = serde_json::json!({
+        "type": "tunnel_history",
+        "tunnels": tunnels
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::domains::{CustomDomain, VerificationMethod};
+    use crate::tunnel_for_domain;
+
+    #[tokio::test]
+    async fn test_http_and_tcp_tunnel_kinds() {
+        let (state, proxy) = test_state();
+        let (client_id, mut rx, user_id) = authed_client(&state).await;
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "kind": "udp" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "Unknown tunnel kind: udp");
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "kind": "http" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "local_port is required for http tunnels");
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "kind": "http", "local_port": 70000 }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["message"], "local_port must be a port number");
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "kind": "tcp", "local_port": 5432, "password": "secret" }),
+            &state,
+        )
+        .await;
+        assert!(next_message(&mut rx)["message"].as_str().unwrap().contains("not supported for tcp"));
+        assert!(proxy.created.lock().unwrap().is_empty());
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "kind": "http", "local_port": 3000 }),
+            &state,
+        )
+        .await;
+        let http = next_message(&mut rx)["tunnel"].clone();
+        assert_eq!(http["kind"], "http");
+        assert_eq!(http["local_port"], 3000);
+        assert!(http["public_port"].is_null());
+        assert_eq!(
+            http["url"],
+            format!("https://{}.tunnels.example.com", http["subdomain"].as_str().unwrap())
+        );
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "kind": "tcp", "local_port": 5432 }),
+            &state,
+        )
+        .await;
+        let tcp = next_message(&mut rx)["tunnel"].clone();
+        let subdomain = tcp["subdomain"].as_str().unwrap();
+        assert_eq!(tcp["kind"], "tcp");
+        assert_eq!(tcp["public_port"], 30000);
+        assert_eq!(tcp["url"], format!("tcp://{}.tunnels.example.com:30000", subdomain));
+
+        let stored = state.store.get_tunnel_by_subdomain(subdomain).await.unwrap().unwrap();
+        assert_eq!((stored.kind, stored.public_port), (TunnelKind::Tcp, Some(30000)));
+
+        // Custom domains are only served from HTTP tunnels
+        let mut domain = CustomDomain::new(user_id, "db.example.com".to_string(), VerificationMethod::Txt);
+        domain.target = Some(subdomain.to_string());
+        assert!(tunnel_for_domain(&state, &domain, None).await.is_none());
+        domain.target = http["subdomain"].as_str().map(str::to_string);
+        assert_eq!(tunnel_for_domain(&state, &domain, None).await.unwrap().kind, TunnelKind::Http);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_labeled_tunnels_per_client() {
+        let mut config = test_config();
+        config.tunnels.max_per_client = 2;
+        let (state, proxy) = test_state_with_config(config);
+        let (client_id, mut rx, user_id) = authed_client(&state).await;
+        let (other_id, mut other_rx, _) = authed_client(&state).await;
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "label": " " }), &state).await;
+        assert!(next_message(&mut rx)["message"].as_str().unwrap().contains("Tunnel labels"));
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel", "label": "screen" }), &state).await;
+        let screen = next_message(&mut rx)["tunnel"].clone();
+        assert_eq!(screen["label"], "screen");
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "kind": "http", "local_port": 5173, "label": "dev server" }),
+            &state,
+        )
+        .await;
+        let dev = next_message(&mut rx)["tunnel"].clone();
+        assert_eq!(dev["label"], "dev server");
+
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &state).await;
+        assert!(next_message(&mut rx)["message"].as_str().unwrap().starts_with("Tunnel limit reached"));
+
+        send(client_id, serde_json::json!({ "type": "list_tunnels" }), &state).await;
+        let list = next_message(&mut rx);
+        assert_eq!(list["type"], "tunnels");
+        let labels: Vec<&str> = list["tunnels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, vec!["screen", "dev server"]);
+        assert_eq!(list["tunnels"][1]["kind"], "http");
+        assert_eq!(list["tunnels"][1]["this_client"], true);
+
+        // Other users can neither see nor close them
+        send(other_id, serde_json::json!({ "type": "list_tunnels" }), &state).await;
+        assert!(next_message(&mut other_rx)["tunnels"].as_array().unwrap().is_empty());
+        send(other_id, serde_json::json!({ "type": "close_tunnel", "id": dev["id"] }), &state).await;
+        assert_eq!(next_message(&mut other_rx)["message"], "Tunnel not found");
+
+        send(client_id, serde_json::json!({ "type": "close_tunnel", "id": dev["id"] }), &state).await;
+        let closed = next_message(&mut rx);
+        assert_eq!(closed["type"], "tunnel_closed");
+        assert_eq!(closed["id"], dev["id"]);
+        assert_eq!(closed["reason"], "closed_by_client");
+        assert_eq!(*proxy.removed.lock().unwrap(), vec![dev["subdomain"].as_str().unwrap().to_string()]);
+
+        // The screen tunnel is untouched and there is room for another
+        let clients = state.clients.read().await;
+        let tunnels = &clients.get(&client_id).unwrap().tunnels;
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].label.as_deref(), Some("screen"));
+        drop(clients);
+        let history = state.store.get_user_tunnels(user_id, 10).await.unwrap();
+        let dev_entry = history.iter().find(|t| t.label.as_deref() == Some("dev server")).unwrap();
+        assert_eq!(dev_entry.close_reason.as_deref(), Some("closed_by_client"));
+    }
+}
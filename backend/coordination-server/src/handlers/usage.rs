@@ -0,0 +1,82 @@
+// Usage messages: the user's transfer this month and per-viewer traffic
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+use uuid::Uuid;
+
+use super::{client_identity, send_error, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT};
+use crate::{bandwidth, AppState};
+
+/// This month's transfer against the user's cap
+pub async fn get_bandwidth_usage(client_id: Uuid, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let response = match bandwidth::usage_report(state, user_id).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to load traffic for user {}: {}", user_id, e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Finished viewer connections of one of the user's tunnels
+pub async fn get_viewer_traffic(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let tunnel_id = match msg
+        .get("tunnel_id")
+        .and_then(|v| v.as_str())
+        .and_then(|v| Uuid::parse_str(v).ok())
+    {
+        Some(id) => id,
+        None => {
+            send_error(client_id, "Missing or invalid tunnel_id", state).await;
+            return;
+        }
+    };
+
+    let limit = msg
+        .get("limit")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let sessions = match state.store.get_viewer_traffic(user_id, tunnel_id, limit).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to load viewer traffic: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    let response = serde_json::json!({
+        "type": "viewer_traffic",
+        "tunnel_id": tunnel_id,
+        "sessions": sessions
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
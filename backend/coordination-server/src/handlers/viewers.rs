@@ -0,0 +1,275 @@
+// Viewer messages: presence reported by the host and viewer allowlists
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::{client_identity, send_error};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::tunnel::Tunnel;
+use crate::viewers::{self, ViewerSession};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{tunnel_url, viewer_auth, AppState};
+
+/// Host reports a viewer connecting through one of its tunnels
+pub async fn viewer_joined(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let subdomain = match msg.get("subdomain").and_then(|v| v.as_str()) {
+        Some(s) => s,
+        None => {
+            send_error(client_id, "Missing subdomain", state).await;
+            return;
+        }
+    };
+
+    let viewer = match msg.get("viewer").map(|v| ViewerSession::from_message(subdomain, v)) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            send_error(client_id, &e.to_string(), state).await;
+            return;
+        }
+        None => {
+            send_error(client_id, "Missing viewer", state).await;
+            return;
+        }
+    };
+
+    // (tunnel, whether this is a viewer we have not seen yet)
+    let joined = {
+        let mut clients = state.clients.write().await;
+        clients.get_mut(&client_id).and_then(|client| {
+            let tunnel = client.tunnels.iter().find(|t| t.subdomain == subdomain)?.clone();
+            if client.viewers.iter().any(|v| v.id == viewer.id) {
+                return Some((tunnel, false));
+            }
+
+            let tracked = client.viewers.iter().filter(|v| v.subdomain == subdomain).count();
+            if tracked < viewers::MAX_VIEWERS_PER_TUNNEL {
+                info!("Viewer {} joined {} from {}", viewer.id, subdomain, viewer.remote_ip);
+                client.viewers.push(viewer.clone());
+            } else {
+                warn!("Tunnel {} has too many viewers, not tracking {}", subdomain, viewer.id);
+            }
+            Some((tunnel, true))
+        })
+    };
+
+    match joined {
+        Some((tunnel, true)) => {
+            if let Err(e) = state.store.increment_viewer_sessions(tunnel.id).await {
+                error!("Failed to count viewer session for {}: {}", subdomain, e);
+            }
+            webhooks::emit(
+                state,
+                tunnel.user_id,
+                WebhookEvent::ViewerJoined,
+                serde_json::json!({
+                    "tunnel": webhooks::tunnel_summary(&tunnel, &tunnel_url(&state.config, &tunnel)),
+                    "viewer": viewer
+                }),
+            )
+            .await;
+        }
+        Some((_, false)) => {}
+        None => send_error(client_id, "Tunnel not found", state).await,
+    }
+}
+
+/// Host reports a viewer disconnecting; unknown viewers are ignored since
+/// their tunnel may already be closed
+pub async fn viewer_left(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let viewer_id = match msg
+        .get("viewer_id")
+        .and_then(|v| v.as_str())
+        .and_then(|v| Uuid::parse_str(v).ok())
+    {
+        Some(id) => id,
+        None => {
+            send_error(client_id, "Missing or invalid viewer_id", state).await;
+            return;
+        }
+    };
+
+    if let Some(client) = state.clients.write().await.get_mut(&client_id) {
+        if let Some(pos) = client.viewers.iter().position(|v| v.id == viewer_id) {
+            let viewer = client.viewers.remove(pos);
+            info!("Viewer {} left {}", viewer.id, viewer.subdomain);
+        }
+    }
+}
+
+/// Viewers on each of the user's live tunnels, across all their clients
+pub async fn list_viewers(client_id: Uuid, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let tunnels = {
+        let clients = state.clients.read().await;
+        let mut tunnels: Vec<(&Tunnel, Vec<&ViewerSession>)> = clients
+            .values()
+            .filter(|c| c.user_id == Some(user_id))
+            .flat_map(|c| {
+                c.tunnels.iter().map(move |t| {
+                    (t, c.viewers.iter().filter(|v| v.subdomain == t.subdomain).collect())
+                })
+            })
+            .collect();
+        tunnels.sort_by_key(|(t, _)| t.created_at);
+
+        tunnels
+            .into_iter()
+            .map(|(t, viewers)| {
+                serde_json::json!({
+                    "subdomain": t.subdomain,
+                    "viewer_count": viewers.len(),
+                    "viewers": viewers
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let response = serde_json::json!({
+        "type": "viewers",
+        "tunnels": tunnels
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Replace the emails and domains allowed to view a live tunnel
+pub async fn set_viewer_allowlist(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            error!("Client {} not authenticated", client_id);
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let subdomain = match msg.get("subdomain").and_then(|v| v.as_str()) {
+        Some(s) => s,
+        None => {
+            send_error(client_id, "Missing subdomain", state).await;
+            return;
+        }
+    };
+
+    let allowed_viewers = match msg.get("allowed_viewers") {
+        Some(value) => match viewer_auth::parse_allowlist(value, state.config.viewer_auth.enabled) {
+            Ok(list) => list,
+            Err(e) => {
+                send_error(client_id, &e.to_string(), state).await;
+                return;
+            }
+        },
+        None => {
+            send_error(client_id, "Missing allowed_viewers", state).await;
+            return;
+        }
+    };
+
+    let owns_tunnel = state
+        .clients
+        .read()
+        .await
+        .get(&client_id)
+        .is_some_and(|client| client.tunnels.iter().any(|t| t.subdomain == subdomain));
+    if !owns_tunnel {
+        send_error(client_id, "Tunnel not found", state).await;
+        return;
+    }
+
+    state.viewer_auth.set_allowlist(subdomain, allowed_viewers.clone()).await;
+    info!("Viewer allowlist of {} set to {} entr(ies)", subdomain, allowed_viewers.len());
+
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::ViewerAllowlistUpdated)
+            .user(Some(user_id))
+            .subdomain(subdomain)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "allowed_viewers": allowed_viewers })),
+    )
+    .await;
+
+    let response = serde_json::json!({
+        "type": "viewer_allowlist",
+        "subdomain": subdomain,
+        "allowed_viewers": allowed_viewers
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn test_viewer_presence_is_tracked_per_tunnel() {
+        let (state, _proxy) = test_state();
+        let (client_id, mut rx, tunnel) = authed_tunnel(&state, serde_json::json!({})).await;
+        let subdomain = tunnel.subdomain;
+
+        let viewer_id = Uuid::new_v4();
+        let joined = serde_json::json!({
+            "type": "viewer_joined",
+            "subdomain": subdomain,
+            "viewer": {
+                "id": viewer_id,
+                "remote_ip": "203.0.113.9",
+                "user_agent": "Mozilla/5.0",
+                "identity": "tnnl"
+            }
+        });
+        send(client_id, joined.clone(), &state).await;
+        // A repeated report is neither listed nor counted twice
+        send(client_id, joined, &state).await;
+        assert!(rx.try_recv().is_err());
+
+        send(
+            client_id,
+            serde_json::json!({
+                "type": "viewer_joined",
+                "subdomain": "someone-else-1234",
+                "viewer": { "id": Uuid::new_v4(), "remote_ip": "203.0.113.10" }
+            }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["message"], "Tunnel not found");
+
+        send(client_id, serde_json::json!({ "type": "list_viewers" }), &state).await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "viewers");
+        assert_eq!(response["tunnels"][0]["subdomain"], subdomain);
+        assert_eq!(response["tunnels"][0]["viewer_count"], 1);
+        let viewer = &response["tunnels"][0]["viewers"][0];
+        assert_eq!(viewer["id"], viewer_id.to_string());
+        assert_eq!(viewer["remote_ip"], "203.0.113.9");
+        assert_eq!(viewer["identity"], "tnnl");
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "viewer_left", "subdomain": subdomain, "viewer_id": viewer_id }),
+            &state,
+        )
+        .await;
+        send(client_id, serde_json::json!({ "type": "list_viewers" }), &state).await;
+        assert_eq!(next_message(&mut rx)["tunnels"][0]["viewer_count"], 0);
+
+        let history = state.store.get_user_tunnels(tunnel.user_id, 10).await.unwrap();
+        assert_eq!(history[0].viewer_sessions, 1);
+    }
+}
@@ -0,0 +1,219 @@
+// Webhook messages: registering endpoints and reading their delivery log
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{client_identity, send_error, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::webhooks::{self, Webhook};
+use crate::AppState;
+
+/// Register an endpoint for event notifications; the signing
+/// secret is only ever sent in this reply
+pub async fn add_webhook(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    if !state.config.webhooks.enabled {
+        send_error(client_id, "Webhooks are disabled on this server", state).await;
+        return;
+    }
+
+    let url = match msg.get("url").and_then(|v| v.as_str()) {
+        Some(u) => match webhooks::normalize_url(u, state.config.webhooks.allow_http) {
+            Ok(u) => u,
+            Err(e) => {
+                send_error(client_id, &e.to_string(), state).await;
+                return;
+            }
+        },
+        None => {
+            send_error(client_id, "Missing url", state).await;
+            return;
+        }
+    };
+    if let Err(e) = webhooks::resolve_endpoint(&url, state.config.webhooks.allow_private_addresses).await {
+        send_error(client_id, &e.to_string(), state).await;
+        return;
+    }
+    let events = match webhooks::parse_events(msg.get("events")) {
+        Ok(e) => e,
+        Err(e) => {
+            send_error(client_id, &e.to_string(), state).await;
+            return;
+        }
+    };
+
+    match state.store.list_user_webhooks(user_id).await {
+        Ok(hooks) if hooks.len() >= state.config.webhooks.max_per_user => {
+            send_error(client_id, "Webhook limit reached", state).await;
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to load webhooks: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    }
+
+    let webhook = Webhook::new(user_id, url, events);
+    if let Err(e) = state.store.create_webhook(&webhook).await {
+        error!("Failed to store webhook: {}", e);
+        send_error(client_id, "Database error", state).await;
+        return;
+    }
+
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::WebhookAdded)
+            .user(Some(user_id))
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({
+                "webhook_id": webhook.id,
+                "url": webhook.url,
+                "events": webhook.events
+            })),
+    )
+    .await;
+
+    let response = serde_json::json!({
+        "type": "webhook_added",
+        "webhook": webhook,
+        "secret": webhook.secret
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+
+    info!("Webhook {} added for user {}", webhook.id, user_id);
+}
+
+/// The user's webhooks
+pub async fn list_webhooks(client_id: Uuid, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let hooks = match state.store.list_user_webhooks(user_id).await {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to load webhooks: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    let response = serde_json::json!({
+        "type": "webhooks",
+        "webhooks": hooks
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Remove one of the user's webhooks and its delivery log
+pub async fn remove_webhook(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let (user_id, client_ip) = match client_identity(client_id, state).await {
+        (Some(uid), ip) => (uid, ip),
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let webhook_id = match msg.get("id").and_then(|v| v.as_str()).and_then(|v| Uuid::parse_str(v).ok()) {
+        Some(id) => id,
+        None => {
+            send_error(client_id, "Missing or invalid id", state).await;
+            return;
+        }
+    };
+
+    match state.store.delete_webhook(user_id, webhook_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            send_error(client_id, "Webhook not found", state).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to delete webhook {}: {}", webhook_id, e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    }
+
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::WebhookRemoved)
+            .user(Some(user_id))
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "webhook_id": webhook_id })),
+    )
+    .await;
+
+    let response = serde_json::json!({
+        "type": "webhook_removed",
+        "id": webhook_id
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
+
+/// Delivery attempts of one of the user's webhooks, newest first
+pub async fn get_webhook_deliveries(client_id: Uuid, msg: &serde_json::Value, state: &Arc<AppState>) {
+    let user_id = match client_identity(client_id, state).await {
+        (Some(uid), _) => uid,
+        (None, _) => {
+            send_error(client_id, "Not authenticated", state).await;
+            return;
+        }
+    };
+
+    let webhook_id = match msg.get("id").and_then(|v| v.as_str()).and_then(|v| Uuid::parse_str(v).ok()) {
+        Some(id) => id,
+        None => {
+            send_error(client_id, "Missing or invalid id", state).await;
+            return;
+        }
+    };
+    let limit = msg
+        .get("limit")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let deliveries = match state.store.get_webhook_deliveries(user_id, webhook_id, limit).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to load webhook deliveries: {}", e);
+            send_error(client_id, "Database error", state).await;
+            return;
+        }
+    };
+
+    let response = serde_json::json!({
+        "type": "webhook_deliveries",
+        "id": webhook_id,
+        "deliveries": deliveries
+    });
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(response.to_string()));
+    }
+}
@@ -0,0 +1,236 @@
+// Connection and message limits
+// Every app connection counts against a per-IP cap, must authenticate within a
+// deadline and spends a token per message from a bucket that refills at a
+// steady rate. Frames are capped in size by tungstenite itself. Connections that
+// break a limit are closed with a close code naming the limit, and counted
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::config::LimitsConfig;
+
+/// A limit an app connection broke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    TooManyConnections,
+    FrameTooLarge,
+    RateLimited,
+    AuthTimeout,
+}
+
+impl Violation {
+    /// Close code sent to the app; 1009 is the standard "message too big"
+    pub fn close_code(self) -> u16 {
+        match self {
+            Violation::TooManyConnections => 4003,
+            Violation::FrameTooLarge => 1009,
+            Violation::RateLimited => 4029,
+            Violation::AuthTimeout => 4008,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Violation::TooManyConnections => "too many connections from this address",
+            Violation::FrameTooLarge => "message too large",
+            Violation::RateLimited => "too many messages",
+            Violation::AuthTimeout => "authentication timed out",
+        }
+    }
+
+    /// Close frame telling the app which limit it hit
+    pub fn close_message(self) -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::from(self.close_code()),
+            reason: self.reason().into(),
+        }))
+    }
+}
+
+/// Counts of limit violations since startup
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct LimitMetrics {
+    pub rejected_connections: u64,
+    pub oversized_frames: u64,
+    pub rate_limited: u64,
+    pub auth_timeouts: u64,
+}
+
+/// Open connections per client IP and violation counters
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    open: Mutex<HashMap<String, usize>>,
+    rejected_connections: AtomicU64,
+    oversized_frames: AtomicU64,
+    rate_limited: AtomicU64,
+    auth_timeouts: AtomicU64,
+}
+
+/// A connection slot for one IP, given back when dropped
+pub struct ConnectionSlot<'a> {
+    limiter: &'a ConnectionLimiter,
+    ip: String,
+}
+
+impl ConnectionLimiter {
+    /// Take a connection slot for `ip`, or None when it already holds `max`
+    /// (0 is unlimited)
+    pub fn acquire(&self, ip: &str, max: usize) -> Option<ConnectionSlot<'_>> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip.to_string()).or_insert(0);
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            limiter: self,
+            ip: ip.to_string(),
+        })
+    }
+
+    pub fn record(&self, violation: Violation) {
+        let counter = match violation {
+            Violation::TooManyConnections => &self.rejected_connections,
+            Violation::FrameTooLarge => &self.oversized_frames,
+            Violation::RateLimited => &self.rate_limited,
+            Violation::AuthTimeout => &self.auth_timeouts,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> LimitMetrics {
+        LimitMetrics {
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            oversized_frames: self.oversized_frames.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            auth_timeouts: self.auth_timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Per-connection message budget: holds up to `burst` tokens and gains
+/// `per_sec` tokens a second
+pub struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            capacity: burst as f64,
+            per_sec: per_sec as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    /// Spend a token for a message arriving at `now`; false when none is left
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Client IP as reported by the local proxy
+/// nginx sets X-Real-IP to the address it was connected from and appends that
+/// address to X-Forwarded-For, so only the last entry of the latter can be
+/// trusted; anything before it was sent by the client
+pub fn proxied_client_ip(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+        })
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// WebSocket settings enforcing the frame size limit during the handshake
+pub fn websocket_config(config: &LimitsConfig) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(config.max_frame_bytes),
+        max_frame_size: Some(config.max_frame_bytes),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket_allows_bursts_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 3, start);
+
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // Half a second at 2/s buys one more message, and never more than the burst
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(later));
+        }
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn test_proxied_client_ip_ignores_client_sent_entries() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(proxied_client_ip(&headers), None);
+        headers.insert("x-forwarded-for", "10.9.8.7, 198.51.100.4".parse().unwrap());
+        assert_eq!(proxied_client_ip(&headers).as_deref(), Some("198.51.100.4"));
+        headers.insert("x-real-ip", "203.0.113.7".parse().unwrap());
+        assert_eq!(proxied_client_ip(&headers).as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_connection_slots_are_returned_on_drop() {
+        let limiter = ConnectionLimiter::default();
+        let first = limiter.acquire("203.0.113.7", 2).unwrap();
+        let _second = limiter.acquire("203.0.113.7", 2).unwrap();
+        assert!(limiter.acquire("203.0.113.7", 2).is_none());
+        assert!(limiter.acquire("198.51.100.1", 2).is_some());
+
+        drop(first);
+        assert!(limiter.acquire("203.0.113.7", 2).is_some());
+        assert!(limiter.acquire("203.0.113.7", 0).is_some());
+    }
+}
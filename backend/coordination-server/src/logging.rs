@@ -0,0 +1,78 @@
+// Log output: human-readable or JSON lines, filtered per module
+// Every WebSocket connection runs in a `connection` span carrying its client
+// ID, peer IP and, once authenticated, user ID. Protocol frames only reach the
+// log through redact(), so tokens, passwords and keys stay out of the journal
+use anyhow::{anyhow, Result};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+/// Message fields whose values are never logged
+pub const REDACTED_FIELDS: &[&str] = &["token", "access_token", "password", "ssh_public_key", "secret"];
+
+const REDACTED: &str = "[redacted]";
+
+/// Install the global subscriber
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.level)?);
+    let installed = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    installed.map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
+
+/// A protocol frame as it may be logged: JSON with the values of
+/// REDACTED_FIELDS replaced at any depth; anything else only by size
+pub fn redact(text: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", text.len()),
+    }
+}
+
+fn redact_value(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) && !field.is_null() {
+                    *field = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_hides_secrets_at_any_depth() {
+        let frame = r#"{"type":"auth","token":"eyJhbGciOi.payload.sig"}"#;
+        assert_eq!(redact(frame), r#"{"token":"[redacted]","type":"auth"}"#);
+
+        let frame = serde_json::json!({
+            "type": "request_tunnel",
+            "password": "hunter2",
+            "label": "demo",
+            "nested": [{ "ssh_public_key": "ssh-ed25519 AAAA", "secret": null }]
+        })
+        .to_string();
+        let logged = redact(&frame);
+        assert!(!logged.contains("hunter2"));
+        assert!(!logged.contains("AAAA"));
+        assert!(logged.contains(r#""label":"demo""#));
+        assert!(logged.contains(r#""secret":null"#));
+
+        // Not JSON: nothing of the content is kept
+        assert_eq!(redact("token=abc"), "<9 bytes, not JSON>");
+    }
+}
@@ -1,99 +1,311 @@
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-use tracing::{error, info, warn};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::Message};
+use tracing::{debug, error, info, warn, Instrument};
 use uuid::Uuid;
 
+mod config;
 mod tunnel;
 mod auth;
 mod nginx;
 mod db;
 mod ssh_keys;
-
-use tunnel::{Tunnel, TunnelManager};
-use db::DbPool;
+mod audit;
+mod admin;
+mod store;
+mod domains;
+mod cluster;
+mod viewers;
+mod viewer_auth;
+mod bandwidth;
+mod subdomains;
+mod expiry;
+mod webhooks;
+mod logging;
+mod limits;
+mod readiness;
+mod viewer_client;
+mod reconcile;
+mod certs;
+mod handlers;
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod e2e_tests;
+
+use config::Config;
+use tunnel::{Tunnel, TunnelManager, TunnelOptions};
+use nginx::ProxyBackend;
+use store::TunnelStore;
+use audit::{AuditAction, AuditEvent};
+use domains::{CustomDomain, DomainVerifier};
+use cluster::{ClusterBus, ClusterEvent};
+use viewers::ViewerSession;
+use viewer_auth::{CodeMailer, ViewerAuth};
+use subdomains::SubdomainGenerator;
+use webhooks::WebhookEvent;
+use limits::{TokenBucket, Violation};
 
 /// Represents a connected desktop app client
 struct Client {
     #[allow(dead_code)]
     id: Uuid,
     user_id: Option<Uuid>,
+    client_ip: Option<String>,
     sender: tokio::sync::mpsc::UnboundedSender<Message>,
     tunnels: Vec<Tunnel>,
+    /// Viewers the host reports on its tunnels
+    viewers: Vec<ViewerSession>,
+}
+
+/// Random names tried against tunnels held on other nodes before giving up
+const CLUSTER_SUBDOMAIN_ATTEMPTS: usize = 5;
+
+/// Create a tunnel with a random subdomain that no node holds
+/// The tunnel manager only knows this node's tunnels, so names taken elsewhere
+/// in the cluster are caught in the store and redrawn
+async fn create_random_tunnel(state: &Arc<AppState>, user_id: Uuid, options: TunnelOptions) -> Result<Tunnel> {
+    for _ in 0..CLUSTER_SUBDOMAIN_ATTEMPTS {
+        let tunnel = state.tunnel_manager.create_random_tunnel(user_id, options.clone()).await?;
+        match state.store.get_tunnel_by_subdomain(&tunnel.subdomain).await {
+            Ok(Some(held)) => {
+                warn!("Subdomain {} is held on node {}, drawing another", tunnel.subdomain, held.node_id);
+                let _ = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await;
+            }
+            // A store error surfaces when the tunnel record is written
+            Ok(None) | Err(_) => return Ok(tunnel),
+        }
+    }
+    Err(anyhow::anyhow!("No free subdomain found; try again"))
 }
 
 /// Global state shared across all connections
 struct AppState {
+    config: Config,
     clients: RwLock<HashMap<Uuid, Client>>,
     tunnel_manager: TunnelManager,
-    db_pool: DbPool,
-    nginx_manager: nginx::NginxManager,
+    store: Box<dyn TunnelStore>,
+    proxy: Box<dyn ProxyBackend>,
+    verifier: Box<dyn DomainVerifier>,
+    bus: Box<dyn ClusterBus>,
     auth_service: auth::AuthService,
+    viewer_auth: ViewerAuth,
+    bandwidth: bandwidth::UsageTracker,
+    expiry: expiry::ExpiryTracker,
+    limits: limits::ConnectionLimiter,
+    reconciler: reconcile::Reconciler,
+    certificates: certs::CertificateMonitor,
 }
 
 impl AppState {
-    fn new(db_pool: DbPool, jwt_secret: String) -> Arc<Self> {
+    fn new(
+        config: Config,
+        store: Box<dyn TunnelStore>,
+        proxy: Box<dyn ProxyBackend>,
+        verifier: Box<dyn DomainVerifier>,
+        bus: Box<dyn ClusterBus>,
+        mailer: Box<dyn CodeMailer>,
+    ) -> Arc<Self> {
         Arc::new(Self {
             clients: RwLock::new(HashMap::new()),
-            tunnel_manager: TunnelManager::new(),
-            db_pool,
-            nginx_manager: nginx::NginxManager::new(),
-            auth_service: auth::AuthService::new(jwt_secret),
+            tunnel_manager: TunnelManager::new(config.tunnels.ports(), config.tunnels.tcp_ports(), config.cluster.node_id())
+                .with_subdomain_generator(SubdomainGenerator::new(config.tunnels.subdomain_words, config.tunnels.subdomain_digits))
+                .with_reserved_subdomains(config.tunnels.reserved_subdomains.clone()),
+            store,
+            proxy,
+            verifier,
+            bus,
+            auth_service: auth::AuthService::new(config.server.jwt_secret.clone()),
+            viewer_auth: ViewerAuth::new(mailer),
+            bandwidth: bandwidth::UsageTracker::default(),
+            expiry: expiry::ExpiryTracker::default(),
+            limits: limits::ConnectionLimiter::default(),
+            reconciler: reconcile::Reconciler::default(),
+            certificates: certs::CertificateMonitor::default(),
+            config,
         })
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
-
     // Load environment variables
     dotenv::dotenv().ok();
 
-    let addr = std::env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let database_url = std::env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set in .env");
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .expect("JWT_SECRET must be set in .env (use Supabase JWT secret)");
+    // Load configuration file and environment overrides
+    let config = Config::load()?;
+    let addr = config.server.bind_address;
+
+    // Initialize tracing
+    logging::init(&config.logging)?;
 
     info!("Starting tnnl coordination server on {}", addr);
 
-    // Initialize database connection pool
+    // Initialize storage
     info!("Connecting to database...");
-    let db_pool = db::init_pool(&database_url).await?;
+    let store = store::open(&config.server.database_url).await?;
     info!("Database connected and migrations applied");
 
+    // Tunnels from this node's previous run have no live SSH forward or client any more
+    match store.close_stale_tunnel_records(config.cluster.node_id(), "server_restart").await {
+        Ok(0) => {}
+        Ok(n) => info!("Closed {} stale tunnel record(s) from previous run", n),
+        Err(e) => error!("Failed to close stale tunnel records: {}", e),
+    }
+
     // Initialize shared state
-    let state = AppState::new(db_pool, jwt_secret);
+    let proxy = nginx::NginxManager::new(&config);
+    let bus = cluster::open_bus(&config).await?;
+    let mailer = viewer_auth::SendmailMailer::new(&config.viewer_auth);
+    let state = AppState::new(
+        config,
+        store,
+        Box::new(proxy),
+        Box::new(domains::SystemVerifier),
+        bus,
+        Box::new(mailer),
+    );
+
+    // Join the cluster: heartbeat, fail over dead nodes, take events from other nodes
+    if state.config.cluster.enabled {
+        info!("Cluster mode enabled, node ID {}", state.config.cluster.node_id());
+        tokio::spawn(cluster::run(state.clone()));
+    }
+
+    // Account proxied traffic and enforce monthly transfer caps
+    tokio::spawn(bandwidth::run(state.clone()));
+
+    // Warn about and close tunnels whose time to live has run out
+    tokio::spawn(expiry::run(state.clone()));
+
+    // Clean up proxy config, certificates and records no live tunnel accounts for
+    if state.config.reconciler.enabled {
+        tokio::spawn(reconcile::run(state.clone()));
+    }
+
+    // Keep an inventory of issued certificates and renew them ahead of expiry
+    tokio::spawn(certs::run(state.clone()));
+
+    // Start admin HTTP API if an admin token is configured
+    match state.config.admin.token.clone() {
+        Some(admin_token) => {
+            let admin_addr = state.config.admin.bind_address;
+            let admin_listener = TcpListener::bind(admin_addr).await?;
+            info!("Admin API listening on: {}", admin_addr);
+            tokio::spawn(admin::serve(admin_listener, state.clone(), admin_token));
+        }
+        None => info!("Admin token not set, admin API disabled"),
+    }
+
+    // Start the forward-auth endpoint nginx consults for tunnels with viewer allowlists
+    if state.config.viewer_auth.enabled {
+        let auth_addr = state.config.viewer_auth.bind_address;
+        let auth_listener = TcpListener::bind(auth_addr).await?;
+        info!("Viewer auth listening on: {}", auth_addr);
+        tokio::spawn(viewer_auth::serve(auth_listener, state.clone()));
+    }
+
+    // Serve the viewer client nginx hands to browsers opening a screen tunnel
+    let client = viewer_client::ViewerClient::load(state.config.nginx.client_template.as_deref())?;
+    let client_addr = state.config.nginx.client_bind_address;
+    let client_listener = TcpListener::bind(client_addr).await?;
+    info!("Viewer client {} listening on: {}", client.version(), client_addr);
+    tokio::spawn(viewer_client::serve(client_listener, state.clone(), client));
 
     // Start WebSocket listener
-    let listener = TcpListener::bind(&addr).await?;
+    let listener = TcpListener::bind(addr).await?;
     info!("WebSocket server listening on: {}", addr);
 
+    serve(listener, state).await;
+
+    Ok(())
+}
+
+/// Accept WebSocket clients on an already-bound listener
+async fn serve(listener: TcpListener, state: Arc<AppState>) {
     while let Ok((stream, peer)) = listener.accept().await {
         info!("New connection from: {}", peer);
-        tokio::spawn(handle_connection(stream, state.clone()));
+        tokio::spawn(handle_connection(stream, peer, state.clone()));
     }
-
-    Ok(())
 }
 
-async fn handle_connection(stream: TcpStream, state: Arc<AppState>) {
-    let ws_stream = match accept_async(stream).await {
-        Ok(ws) => ws,
-        Err(e) => {
+// The handshake callback's error type is fixed by tungstenite
+#[allow(clippy::result_large_err)]
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, state: Arc<AppState>) {
+    let max_connections = state.config.limits.max_connections_per_ip;
+
+    // Clients connecting directly hold a slot from before the handshake, so
+    // unfinished handshakes count too. Behind the local proxy the client is
+    // only known from the request headers
+    let proxied = peer.ip().is_loopback();
+    let mut slot = None;
+    if !proxied {
+        match state.limits.acquire(&peer.ip().to_string(), max_connections) {
+            Some(peer_slot) => slot = Some(peer_slot),
+            None => {
+                warn!("Refusing connection from {}: too many open connections", peer.ip());
+                state.limits.record(Violation::TooManyConnections);
+                return;
+            }
+        }
+    }
+
+    let mut forwarded_ip = None;
+    let ws_config = limits::websocket_config(&state.config.limits);
+    let callback = |request: &Request, response: Response| {
+        forwarded_ip = limits::proxied_client_ip(request.headers());
+        Ok(response)
+    };
+    // The handshake counts against the time an app has to authenticate
+    let handshake_timeout = std::time::Duration::from_secs(state.config.limits.auth_timeout_secs);
+    let handshake = accept_hdr_async_with_config(stream, callback, Some(ws_config));
+    let mut ws_stream = match tokio::time::timeout(handshake_timeout, handshake).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => {
             error!("Error during WebSocket handshake: {}", e);
             return;
         }
+        Err(_) => {
+            warn!("WebSocket handshake from {} timed out", peer);
+            state.limits.record(Violation::AuthTimeout);
+            return;
+        }
+    };
+
+    // Only trust forwarding headers when the connection comes from the local proxy
+    let client_ip = match forwarded_ip {
+        Some(ip) if proxied => ip,
+        _ => peer.ip().to_string(),
+    };
+
+    // Held until the connection ends
+    let _slot = match slot {
+        Some(slot) => slot,
+        None => match state.limits.acquire(&client_ip, max_connections) {
+            Some(slot) => slot,
+            None => {
+                warn!("Refusing connection from {}: too many open connections", client_ip);
+                state.limits.record(Violation::TooManyConnections);
+                let _ = ws_stream.send(Violation::TooManyConnections.close_message()).await;
+                return;
+            }
+        },
     };
 
     let client_id = Uuid::new_v4();
-    info!("Client connected: {}", client_id);
+    let span = tracing::info_span!(
+        "connection",
+        %client_id,
+        peer = %client_ip,
+        user_id = tracing::field::Empty
+    );
+    info!(parent: &span, "Client connected: {} ({})", client_id, client_ip);
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
@@ -106,52 +318,121 @@ async fn handle_connection(stream: TcpStream, state: Arc<AppState>) {
             Client {
                 id: client_id,
                 user_id: None,
+                client_ip: Some(client_ip),
                 sender: tx.clone(),
                 tunnels: Vec::new(),
+                viewers: Vec::new(),
             },
         );
     }
 
     // Spawn task to send messages to client
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Err(e) = ws_sender.send(msg).await {
-                error!("Error sending message: {}", e);
-                break;
+    let mut send_task = tokio::spawn(
+        async move {
+            while let Some(msg) = rx.recv().await {
+                if let Err(e) = ws_sender.send(msg).await {
+                    error!("Error sending message: {}", e);
+                    break;
+                }
             }
         }
-    });
+        .instrument(span.clone()),
+    );
 
     // Handle incoming messages
-    while let Some(msg) = ws_receiver.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                info!("Received message from {}: {}", client_id, text);
-                handle_message(client_id, text, &state).await;
-            }
-            Ok(Message::Binary(_)) => {
-                warn!("Received binary message from {}, ignoring", client_id);
-            }
-            Ok(Message::Close(_)) => {
-                info!("Client {} disconnected", client_id);
+    let limits = &state.config.limits;
+    let mut bucket = TokenBucket::new(limits.messages_per_sec, limits.message_burst, std::time::Instant::now());
+    let auth_deadline = tokio::time::sleep(std::time::Duration::from_secs(limits.auth_timeout_secs));
+    tokio::pin!(auth_deadline);
+    let mut authenticated = false;
+
+    async {
+        loop {
+            let msg = tokio::select! {
+                msg = ws_receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = &mut auth_deadline, if !authenticated => {
+                    close_for_violation(client_id, &tx, Violation::AuthTimeout, &state);
+                    break;
+                }
+            };
+
+            if matches!(msg, Ok(Message::Text(_)) | Ok(Message::Binary(_)))
+                && !bucket.try_take(std::time::Instant::now())
+            {
+                close_for_violation(client_id, &tx, Violation::RateLimited, &state);
                 break;
             }
-            Ok(Message::Ping(data)) => {
-                info!("Received ping from {}", client_id);
-                if let Some(client) = state.clients.read().await.get(&client_id) {
-                    let _ = client.sender.send(Message::Pong(data));
+
+            match msg {
+                Ok(Message::Text(text)) => {
+                    // Frames carry tokens, passwords and keys, so never log them as is
+                    debug!(frame = %logging::redact(&text), "Received message");
+                    handlers::handle_message(client_id, text, &state).await;
+                    if !authenticated {
+                        authenticated = state
+                            .clients
+                            .read()
+                            .await
+                            .get(&client_id)
+                            .is_some_and(|c| c.user_id.is_some());
+                    }
                 }
+                Ok(Message::Binary(_)) => {
+                    warn!("Received binary message from {}, ignoring", client_id);
+                }
+                Ok(Message::Close(_)) => {
+                    info!("Client {} disconnected", client_id);
+                    break;
+                }
+                Ok(Message::Ping(data)) => {
+                    debug!("Received ping from {}", client_id);
+                    if let Some(client) = state.clients.read().await.get(&client_id) {
+                        let _ = client.sender.send(Message::Pong(data));
+                    }
+                }
+                Ok(Message::Pong(_)) => {}
+                Err(tokio_tungstenite::tungstenite::Error::Capacity(e)) => {
+                    debug!("Frame from client {} over the limit: {}", client_id, e);
+                    close_for_violation(client_id, &tx, Violation::FrameTooLarge, &state);
+                    break;
+                }
+                Err(e) => {
+                    error!("WebSocket error for client {}: {}", client_id, e);
+                    break;
+                }
+                _ => {}
             }
-            Ok(Message::Pong(_)) => {}
-            Err(e) => {
-                error!("WebSocket error for client {}: {}", client_id, e);
-                break;
-            }
-            _ => {}
         }
+
+        disconnect_client(client_id, &state).await;
+    }
+    .instrument(span)
+    .await;
+
+    // Let a queued close frame go out before the connection is dropped
+    drop(tx);
+    if tokio::time::timeout(std::time::Duration::from_secs(1), &mut send_task).await.is_err() {
+        send_task.abort();
     }
+}
+
+/// Count a broken limit and queue the close frame naming it
+fn close_for_violation(
+    client_id: Uuid,
+    sender: &tokio::sync::mpsc::UnboundedSender<Message>,
+    violation: Violation,
+    state: &AppState,
+) {
+    warn!("Closing client {}: {}", client_id, violation.reason());
+    state.limits.record(violation);
+    let _ = sender.send(violation.close_message());
+}
 
-    // Cleanup on disconnect
+/// Tear down a disconnected client's tunnels and forget the client
+async fn disconnect_client(client_id: Uuid, state: &Arc<AppState>) {
     info!("Cleaning up client {}", client_id);
 
     // Get client's tunnels before removing
@@ -164,24 +445,7 @@ async fn handle_connection(stream: TcpStream, state: Arc<AppState>) {
 
     // Clean up each tunnel
     for tunnel in tunnels_to_cleanup {
-        info!("Cleaning up tunnel: {}", tunnel.subdomain);
-
-        // Remove nginx configuration
-        if let Err(e) = state.nginx_manager.remove_tunnel_config(&tunnel.subdomain).await {
-            error!("Failed to remove nginx config for {}: {}", tunnel.subdomain, e);
-        }
-
-        // Remove from tunnel manager
-        if let Err(e) = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await {
-            error!("Failed to remove tunnel {}: {}", tunnel.subdomain, e);
-        }
-
-        // Mark tunnel as inactive in database (don't delete, for history)
-        if let Err(e) = db::delete_tunnel_record(&state.db_pool, &tunnel.subdomain).await {
-            error!("Failed to delete tunnel record {}: {}", tunnel.subdomain, e);
-        }
-
-        info!("Tunnel {} cleaned up", tunnel.subdomain);
+        teardown_tunnel(state, &tunnel, "client_disconnected").await;
     }
 
     // Remove client from state
@@ -190,267 +454,271 @@ async fn handle_connection(stream: TcpStream, state: Arc<AppState>) {
         clients.remove(&client_id);
     }
 
-    send_task.abort();
     info!("Client {} removed and cleaned up", client_id);
 }
 
-async fn handle_message(client_id: Uuid, text: String, state: &Arc<AppState>) {
-    // Parse message as JSON
-    let msg: serde_json::Value = match serde_json::from_str(&text) {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Failed to parse message: {}", e);
-            send_error(client_id, "Invalid JSON", state).await;
-            return;
-        }
-    };
-
-    let msg_type = msg.get("type").and_then(|v| v.as_str());
-
-    match msg_type {
-        Some("auth") => {
-            // Handle authentication
-            info!("Authentication request from {}", client_id);
+/// Tear down a tunnel's proxy config, allocation and database record
+/// and record the closure in the audit log
+async fn teardown_tunnel(state: &Arc<AppState>, tunnel: &Tunnel, reason: &str) {
+    info!("Cleaning up tunnel: {} ({})", tunnel.subdomain, reason);
 
-            let token = match msg.get("token").and_then(|v| v.as_str()) {
-                Some(t) => t,
-                None => {
-                    error!("Missing token in auth message");
-                    send_error(client_id, "Missing token", state).await;
-                    return;
-                }
-            };
-
-            // Verify JWT token
-            // Use insecure mode if DEV_MODE env var is set to "true"
-            let use_dev_mode = std::env::var("DEV_MODE")
-                .unwrap_or_else(|_| "false".to_string())
-                .to_lowercase() == "true";
-
-            let (user_id, email) = if use_dev_mode {
-                info!("Using DEV_MODE authentication (insecure)");
-                match state.auth_service.verify_token_insecure(token) {
-                    Ok((uid, em)) => (uid, em),
-                    Err(e) => {
-                        error!("Token verification failed: {}", e);
-                        send_error(client_id, "Invalid token", state).await;
-                        return;
-                    }
-                }
-            } else {
-                match state.auth_service.verify_supabase_token(token) {
-                    Ok((uid, em)) => (uid, em),
-                    Err(e) => {
-                        error!("Token verification failed: {}", e);
-                        send_error(client_id, "Invalid token", state).await;
-                        return;
-                    }
-                }
-            };
-
-            // Store or update user in database and get actual user_id
-            let actual_user_id = match db::get_or_create_user(&state.db_pool, user_id, &email).await {
-                Ok(uid) => uid,
-                Err(e) => {
-                    error!("Failed to store user: {}", e);
-                    send_error(client_id, "Database error", state).await;
-                    return;
-                }
-            };
-
-            // Update client with ACTUAL user_id from database
-            {
-                let mut clients = state.clients.write().await;
-                if let Some(client) = clients.get_mut(&client_id) {
-                    client.user_id = Some(actual_user_id);
-                }
-            }
-
-            // Send success response
-            let response = serde_json::json!({
-                "type": "auth_success",
-                "user_id": actual_user_id,
-                "email": email
-            });
-
-            if let Some(client) = state.clients.read().await.get(&client_id) {
-                let _ = client.sender.send(Message::Text(response.to_string()));
-            }
+    // Remove nginx configuration
+    if let Err(e) = state.proxy.remove_tunnel_config(&tunnel.subdomain).await {
+        error!("Failed to remove nginx config for {}: {}", tunnel.subdomain, e);
+    }
 
-            info!("Client {} authenticated as user {}", client_id, actual_user_id);
-        }
-        Some("request_tunnel") => {
-            // Handle tunnel request
-            info!("Tunnel request from {}", client_id);
-
-            // Get user_id from client
-            let user_id = {
-                let clients = state.clients.read().await;
-                match clients.get(&client_id) {
-                    Some(client) => match client.user_id {
-                        Some(uid) => uid,
-                        None => {
-                            error!("Client {} not authenticated", client_id);
-                            send_error(client_id, "Not authenticated", state).await;
-                            return;
-                        }
-                    },
-                    None => {
-                        error!("Client {} not found", client_id);
-                        return;
-                    }
-                }
-            };
+    // Move custom domains targeting this tunnel to another live match, if any
+    route_custom_domains(state, tunnel.user_id, Some(tunnel.id)).await;
 
-            // Get optional password from request
-            let password = msg.get("password").and_then(|v| v.as_str()).map(String::from);
+    state.viewer_auth.remove_tunnel(&tunnel.subdomain).await;
 
-            // Create tunnel
-            let tunnel = match state.tunnel_manager.create_random_tunnel(user_id, password).await {
-                Ok(t) => t,
-                Err(e) => {
-                    error!("Failed to create tunnel: {}", e);
-                    send_error(client_id, &format!("Tunnel creation failed: {}", e), state).await;
-                    return;
-                }
-            };
+    // Remove from tunnel manager
+    if let Err(e) = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await {
+        error!("Failed to remove tunnel {}: {}", tunnel.subdomain, e);
+    }
 
-            // Store tunnel in database
-            if let Err(e) = db::create_tunnel_record(&state.db_pool, &tunnel).await {
-                error!("Failed to store tunnel in database: {}", e);
-                send_error(client_id, "Database error", state).await;
-                return;
-            }
+    // Mark tunnel as inactive in database (don't delete, for history)
+    if let Err(e) = state.store.close_tunnel_record(tunnel, reason).await {
+        error!("Failed to close tunnel record {}: {}", tunnel.subdomain, e);
+    }
 
-            // Create Nginx configuration
-            if let Err(e) = state.nginx_manager.create_tunnel_config(&tunnel).await {
-                error!("Failed to create Nginx config: {}", e);
-                send_error(client_id, &format!("Nginx configuration failed: {}", e), state).await;
+    let client_ip = client_ip_for_tunnel(state, &tunnel.subdomain).await;
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::TunnelClosed)
+            .user(Some(tunnel.user_id))
+            .subdomain(&tunnel.subdomain)
+            .client_ip(client_ip.as_deref())
+            .details(serde_json::json!({ "reason": reason, "tunnel_id": tunnel.id })),
+    )
+    .await;
+
+    webhooks::emit(
+        state,
+        tunnel.user_id,
+        WebhookEvent::TunnelClosed,
+        serde_json::json!({
+            "tunnel": webhooks::tunnel_summary(tunnel, &tunnel_url(&state.config, tunnel)),
+            "reason": reason
+        }),
+    )
+    .await;
+
+    info!("Tunnel {} cleaned up", tunnel.subdomain);
+}
 
-                // Clean up tunnel
-                let _ = state.tunnel_manager.remove_tunnel(&tunnel.subdomain).await;
-                let _ = db::delete_tunnel_record(&state.db_pool, &tunnel.subdomain).await;
-                return;
-            }
+/// Public URL of a tunnel: https for screen and HTTP tunnels, tcp://host:port
+/// for TCP tunnels
+fn tunnel_url(config: &Config, tunnel: &Tunnel) -> String {
+    let host = config.server.tunnel_host(&tunnel.subdomain);
+    match tunnel.public_port {
+        Some(public_port) => format!("tcp://{}:{}", host, public_port),
+        None => format!("https://{}", host),
+    }
+}
 
-            // Add tunnel to client's tunnel list
-            {
-                let mut clients = state.clients.write().await;
-                if let Some(client) = clients.get_mut(&client_id) {
-                    client.tunnels.push(tunnel.clone());
-                }
-            }
+/// Most recently created live tunnel of the domain's owner, across all their
+/// clients, that the domain targets, skipping `except`
+async fn tunnel_for_domain(state: &Arc<AppState>, domain: &CustomDomain, except: Option<Uuid>) -> Option<Tunnel> {
+    let clients = state.clients.read().await;
+    clients
+        .values()
+        .filter(|c| c.user_id == Some(domain.user_id))
+        .flat_map(|c| c.tunnels.iter())
+        .filter(|t| Some(t.id) != except && domain.routes_to(t))
+        .max_by_key(|t| t.created_at)
+        .cloned()
+}
 
-            // Send tunnel info to client
-            let response = serde_json::json!({
-                "type": "tunnel_assigned",
-                "tunnel": {
-                    "id": tunnel.id,
-                    "subdomain": tunnel.subdomain,
-                    "url": format!("https://{}.tnnl.to", tunnel.subdomain),
-                    "port": tunnel.port,
-                    "password": tunnel.password,
-                    "created_at": tunnel.created_at.to_rfc3339()
-                }
-            });
+/// Point a verified custom domain at the tunnel it targets, or at the offline
+/// page when none is live
+/// Returns the tunnel it was routed to
+async fn route_custom_domain(state: &Arc<AppState>, domain: &CustomDomain, except: Option<Uuid>) -> Option<Tunnel> {
+    let tunnel = tunnel_for_domain(state, domain, except).await;
+    if let Err(e) = state.proxy.route_custom_domain(&domain.hostname, tunnel.as_ref()).await {
+        error!("Failed to route custom domain {}: {}", domain.hostname, e);
+        return None;
+    }
+    tunnel
+}
 
-            if let Some(client) = state.clients.read().await.get(&client_id) {
-                let _ = client.sender.send(Message::Text(response.to_string()));
-            }
+/// Re-route all of a user's verified custom domains, skipping tunnel `except`
+/// Returns the hostnames routed to a live tunnel, with that tunnel's id
+async fn route_custom_domains(state: &Arc<AppState>, user_id: Uuid, except: Option<Uuid>) -> Vec<(String, Uuid)> {
+    let domains = match state.store.list_user_custom_domains(user_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to load custom domains for user {}: {}", user_id, e);
+            return Vec::new();
+        }
+    };
 
-            info!("Tunnel {} assigned to client {}", tunnel.subdomain, client_id);
+    let mut routed = Vec::new();
+    for domain in domains.iter().filter(|d| d.is_verified()) {
+        if let Some(tunnel) = route_custom_domain(state, domain, except).await {
+            routed.push((domain.hostname.clone(), tunnel.id));
         }
-        Some("register_ssh_key") => {
-            // Handle SSH key registration
-            info!("SSH key registration from {}", client_id);
-
-            // Get user_id from client
-            let user_id = {
-                let clients = state.clients.read().await;
-                match clients.get(&client_id) {
-                    Some(client) => match client.user_id {
-                        Some(uid) => uid,
-                        None => {
-                            error!("Client {} not authenticated", client_id);
-                            send_error(client_id, "Not authenticated", state).await;
-                            return;
-                        }
-                    },
-                    None => {
-                        error!("Client {} not found", client_id);
-                        return;
-                    }
-                }
-            };
+    }
+    routed
+}
 
-            // Get SSH public key from message
-            let ssh_public_key = match msg.get("ssh_public_key").and_then(|v| v.as_str()) {
-                Some(key) => key,
-                None => {
-                    error!("Missing ssh_public_key in message");
-                    send_error(client_id, "Missing ssh_public_key", state).await;
-                    return;
-                }
-            };
+/// Look up the IP of the client that owns a tunnel
+async fn client_ip_for_tunnel(state: &Arc<AppState>, subdomain: &str) -> Option<String> {
+    let clients = state.clients.read().await;
+    clients
+        .values()
+        .find(|c| c.tunnels.iter().any(|t| t.subdomain == subdomain))
+        .and_then(|c| c.client_ip.clone())
+}
 
-            // Validate SSH key
-            if let Err(e) = ssh_keys::validate_ssh_public_key(ssh_public_key) {
-                error!("Invalid SSH key: {}", e);
-                send_error(client_id, &format!("Invalid SSH key: {}", e), state).await;
-                return;
-            }
+/// Close a live tunnel on behalf of the server (e.g. from the admin API)
+/// Tunnels owned by another cluster node are handed to that node over the bus
+/// Returns false if no active tunnel holds the subdomain
+async fn close_tunnel_anywhere(state: &Arc<AppState>, subdomain: &str, reason: &str) -> bool {
+    if force_close_tunnel(state, subdomain, reason).await {
+        return true;
+    }
 
-            // Store SSH key in database
-            if let Err(e) = db::store_ssh_public_key(&state.db_pool, user_id, ssh_public_key).await {
-                error!("Failed to store SSH key: {}", e);
-                send_error(client_id, "Failed to store SSH key", state).await;
-                return;
-            }
+    let tunnel = match state.store.get_tunnel_by_subdomain(subdomain).await {
+        Ok(Some(t)) if t.node_id != state.config.cluster.node_id() => t,
+        Ok(_) => return false,
+        Err(e) => {
+            error!("Failed to look up tunnel {}: {}", subdomain, e);
+            return false;
+        }
+    };
 
-            // Add to authorized_keys file
-            if let Err(e) = ssh_keys::add_ssh_key_to_authorized_keys(ssh_public_key).await {
-                error!("Failed to add SSH key to authorized_keys: {}", e);
-                send_error(client_id, "Failed to register SSH key", state).await;
-                return;
-            }
+    let event = ClusterEvent::ForceCloseTunnel {
+        node_id: tunnel.node_id.clone(),
+        subdomain: subdomain.to_string(),
+        reason: reason.to_string(),
+    };
+    match state.bus.publish(&event).await {
+        Ok(()) => {
+            info!("Forwarded close of {} to node {}", subdomain, tunnel.node_id);
+            true
+        }
+        Err(e) => {
+            error!("Failed to forward close of {} to node {}: {}", subdomain, tunnel.node_id, e);
+            false
+        }
+    }
+}
 
-            // Send success response
-            let response = serde_json::json!({
-                "type": "ssh_key_registered",
-                "success": true
-            });
+/// Close a tunnel owned by a client connected to this node
+/// Detaches it from its owning client, tears it down and notifies the client
+/// Returns false if no connected client owns the subdomain
+async fn force_close_tunnel(state: &Arc<AppState>, subdomain: &str, reason: &str) -> bool {
+    let owner = {
+        let clients = state.clients.read().await;
+        clients.iter().find_map(|(id, c)| {
+            c.tunnels
+                .iter()
+                .find(|t| t.subdomain == subdomain)
+                .map(|t| (*id, t.clone()))
+        })
+    };
 
-            if let Some(client) = state.clients.read().await.get(&client_id) {
-                let _ = client.sender.send(Message::Text(response.to_string()));
-            }
+    let (client_id, tunnel) = match owner {
+        Some(o) => o,
+        None => return false,
+    };
 
-            info!("SSH key registered for user {}", user_id);
-        }
-        Some("heartbeat") => {
-            // Respond to heartbeat
-            if let Some(client) = state.clients.read().await.get(&client_id) {
-                let response = serde_json::json!({
-                    "type": "heartbeat_ack",
-                    "timestamp": chrono::Utc::now().to_rfc3339()
-                });
-                let _ = client.sender.send(Message::Text(response.to_string()));
-            }
-        }
-        _ => {
-            warn!("Unknown message type: {:?}", msg_type);
-            send_error(client_id, "Unknown message type", state).await;
-        }
+    // Tear down while the client still lists the tunnel so the audit entry gets its IP
+    teardown_tunnel(state, &tunnel, reason).await;
+
+    let mut clients = state.clients.write().await;
+    if let Some(client) = clients.get_mut(&client_id) {
+        client.tunnels.retain(|t| t.subdomain != subdomain);
+        client.viewers.retain(|v| v.subdomain != subdomain);
+        let notice = serde_json::json!({
+            "type": "tunnel_closed",
+            "id": tunnel.id,
+            "subdomain": subdomain,
+            "reason": reason
+        });
+        let _ = client.sender.send(Message::Text(notice.to_string()));
     }
+
+    true
 }
 
-/// Helper function to send error message to client
-async fn send_error(client_id: Uuid, message: &str, state: &Arc<AppState>) {
-    let error_msg = serde_json::json!({
-        "type": "error",
-        "message": message
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn test_auth_register_key_tunnel_disconnect_flow() {
+        let (state, proxy) = test_state();
+        let (client_id, mut rx) = connect_client(&state).await;
+        let user_id = Uuid::new_v4();
+
+        // Requests before auth are rejected
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &state).await;
+        assert_eq!(next_message(&mut rx)["message"], "Not authenticated");
+
+        let token = test_token(user_id, "dev@example.com");
+        send(client_id, serde_json::json!({ "type": "auth", "token": token }), &state).await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "auth_success");
+        assert_eq!(response["user_id"], user_id.to_string());
+        assert_eq!(response["base_domain"], "tunnels.example.com");
+        assert_eq!(response["ssh_host"], "tunnels.example.com");
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "register_ssh_key", "ssh_public_key": TEST_SSH_KEY }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["type"], "ssh_key_registered");
+        assert_eq!(
+            state.store.get_ssh_public_key(user_id).await.unwrap().as_deref(),
+            Some(TEST_SSH_KEY)
+        );
 
-    if let Some(client) = state.clients.read().await.get(&client_id) {
-        let _ = client.sender.send(Message::Text(error_msg.to_string()));
+        send(client_id, serde_json::json!({ "type": "request_tunnel" }), &state).await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "tunnel_assigned");
+        let subdomain = response["tunnel"]["subdomain"].as_str().unwrap().to_string();
+        assert_eq!(
+            response["tunnel"]["url"],
+            format!("https://{}.tunnels.example.com", subdomain)
+        );
+        assert_eq!(*proxy.created.lock().unwrap(), vec![subdomain.clone()]);
+        assert!(state.store.get_tunnel_by_subdomain(&subdomain).await.unwrap().is_some());
+
+        disconnect_client(client_id, &state).await;
+
+        assert_eq!(*proxy.removed.lock().unwrap(), vec![subdomain.clone()]);
+        assert!(state.clients.read().await.is_empty());
+        assert!(state.tunnel_manager.get_tunnel(&subdomain).await.is_none());
+        assert!(state.store.get_tunnel_by_subdomain(&subdomain).await.unwrap().is_none());
+
+        let history = state.store.get_user_tunnels(user_id, 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].close_reason.as_deref(), Some("client_disconnected"));
+
+        let events = state
+            .store
+            .query_audit_events(&audit::AuditFilter {
+                user_id: Some(user_id),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut actions: Vec<AuditAction> = events.iter().map(|e| e.action).collect();
+        actions.reverse();
+        assert_eq!(
+            actions,
+            vec![
+                AuditAction::AuthSuccess,
+                AuditAction::SshKeyRegistered,
+                AuditAction::TunnelCreated,
+                AuditAction::TunnelClosed,
+            ]
+        );
     }
+
 }
@@ -1,209 +1,525 @@
 // Nginx configuration management
-use std::path::Path;
+// Configs are rendered by the pure functions in `render`, written atomically
+// and applied as one transaction: if any step fails, from writing a file to
+// `nginx -t` or certbot, every file is put back and nginx reloaded with the
+// previous config
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
-use crate::tunnel::Tunnel;
+use crate::bandwidth::{self, TrafficSample};
+use crate::certs::Certificate;
+use crate::config::{CertbotConfig, Config, NginxConfig};
+use crate::tunnel::{Tunnel, TunnelKind};
 
-const NGINX_CONF_DIR: &str = "/etc/nginx/tunnels";
-const NGINX_PASSWD_DIR: &str = "/etc/nginx/passwd";
+pub mod render;
+
+use render::SiteRenderer;
+
+/// Publishes tunnels and custom domains on the public proxy
+/// NginxManager is the production backend; tests substitute one that only
+/// records what it was asked to do
+#[async_trait]
+pub trait ProxyBackend: Send + Sync {
+    /// Make a tunnel reachable at its public URL
+    async fn create_tunnel_config(&self, tunnel: &Tunnel) -> anyhow::Result<()>;
+
+    /// Stop serving a tunnel
+    async fn remove_tunnel_config(&self, subdomain: &str) -> anyhow::Result<()>;
+
+    /// Serve the HTTP ownership challenge for a custom domain
+    async fn publish_domain_challenge(&self, hostname: &str, token: &str) -> anyhow::Result<()>;
+
+    /// Issue a certificate for a verified custom domain
+    async fn provision_custom_domain(&self, hostname: &str) -> anyhow::Result<()>;
+
+    /// Point a provisioned custom domain at a tunnel, or at an offline page when None
+    async fn route_custom_domain(&self, hostname: &str, tunnel: Option<&Tunnel>) -> anyhow::Result<()>;
+
+    /// Stop serving a custom domain and delete its certificate
+    async fn remove_custom_domain(&self, hostname: &str) -> anyhow::Result<()>;
+
+    /// Limit the rate a tunnel sends to viewers at, or lift the limit with None
+    async fn set_tunnel_rate_limit(&self, tunnel: &Tunnel, bytes_per_sec: Option<u64>) -> anyhow::Result<()>;
+
+    /// Traffic proxied since the last call
+    async fn collect_traffic(&self) -> anyhow::Result<Vec<TrafficSample>>;
+
+    /// Tunnel config and certificates the proxy holds, to be checked against
+    /// the tunnels that should exist
+    async fn inventory(&self) -> anyhow::Result<ProxyInventory>;
+
+    /// Certificates the proxy serves tunnels and custom domains with
+    async fn certificates(&self) -> anyhow::Result<Vec<Certificate>>;
+
+    /// Issue a certificate again now, whether or not it is due
+    async fn renew_certificate(&self, domain: &str) -> anyhow::Result<()>;
+}
+
+/// A piece of a tunnel's proxy setup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyArtifact {
+    Site,
+    Stream,
+    Htpasswd,
+    /// Per-tunnel copy of the viewer client written by older versions
+    ClientPage,
+    Certificate,
+}
+
+/// Tunnel config and certificates found on the proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyInventory {
+    /// What is there for each tunnel subdomain
+    pub tunnels: BTreeMap<String, BTreeSet<ProxyArtifact>>,
+    /// Custom domains with a site the server wrote
+    pub custom_domains: BTreeSet<String>,
+}
+
+impl ProxyInventory {
+    pub fn add(&mut self, subdomain: &str, artifact: ProxyArtifact) {
+        self.tunnels.entry(subdomain.to_string()).or_default().insert(artifact);
+    }
+}
+
+/// State of the per-tunnel traffic logs
+#[derive(Default)]
+struct TrafficLogs {
+    /// Tunnels with a site config, by subdomain
+    tunnels: HashMap<String, Uuid>,
+    /// Tunnels whose site was removed; their log is deleted once drained
+    closed: HashSet<Uuid>,
+    /// Bytes of each log already collected
+    offsets: HashMap<Uuid, u64>,
+    /// Throttled tunnels and their rate in bytes per second
+    rate_limits: HashMap<Uuid, u64>,
+}
 
 pub struct NginxManager {
-    // Configuration paths
+    base_domain: String,
+    nginx: NginxConfig,
+    certbot: CertbotConfig,
+    render: SiteRenderer,
+    traffic: Mutex<TrafficLogs>,
+}
+
+/// What a file held before a transaction changed it
+#[derive(Debug)]
+enum Previous {
+    Missing,
+    File(Vec<u8>),
+    Link(PathBuf),
+}
+
+/// Files changed while applying a config change, with what they held before
+#[derive(Default)]
+struct Transaction {
+    changes: Vec<(PathBuf, Previous)>,
+}
+
+impl Transaction {
+    /// Note what `path` holds now, unless this transaction already changed it
+    async fn remember(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.changes.iter().any(|(p, _)| p == path) {
+            return Ok(());
+        }
+        let previous = match tokio::fs::symlink_metadata(path).await {
+            Ok(meta) if meta.file_type().is_symlink() => Previous::Link(tokio::fs::read_link(path).await?),
+            Ok(_) => Previous::File(tokio::fs::read(path).await?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Previous::Missing,
+            Err(e) => return Err(e.into()),
+        };
+        self.changes.push((path.to_path_buf(), previous));
+        Ok(())
+    }
+
+    /// Replace the contents of `path`, leaving it alone if they are unchanged
+    async fn write(&mut self, path: &Path, contents: &str) -> anyhow::Result<()> {
+        if tokio::fs::read(path).await.ok().as_deref() == Some(contents.as_bytes()) {
+            return Ok(());
+        }
+        self.remember(path).await?;
+        write_atomic(path, contents.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Point the symlink `link` at `target`
+    async fn link(&mut self, target: &Path, link: &Path) -> anyhow::Result<()> {
+        if tokio::fs::read_link(link).await.ok().as_deref() == Some(target) {
+            return Ok(());
+        }
+        self.remember(link).await?;
+        let tmp = temp_path(link);
+        tokio::fs::remove_file(&tmp).await.ok();
+        tokio::fs::symlink(target, &tmp).await?;
+        tokio::fs::rename(&tmp, link).await?;
+        Ok(())
+    }
+
+    /// Put every changed file back as it was, newest change first
+    async fn rollback(self) {
+        for (path, previous) in self.changes.into_iter().rev() {
+            let restored = match &previous {
+                Previous::Missing => match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    other => other,
+                },
+                Previous::File(contents) => write_atomic(&path, contents).await,
+                Previous::Link(target) => {
+                    let tmp = temp_path(&path);
+                    tokio::fs::remove_file(&tmp).await.ok();
+                    match tokio::fs::symlink(target, &tmp).await {
+                        Ok(()) => tokio::fs::rename(&tmp, &path).await,
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            if let Err(e) = restored {
+                warn!(path = %path.display(), error = %e, "Failed to restore nginx config during rollback");
+            }
+        }
+    }
+}
+
+/// Sibling of `path` that a new version is written to before it is renamed
+/// into place; the leading dot keeps it out of nginx's include globs
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.tnnl-tmp", name))
+}
+
+/// Write `contents` to `path` so that readers see either the old or the new file
+async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = temp_path(path);
+    tokio::fs::write(&tmp, contents).await?;
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+        tokio::fs::remove_file(&tmp).await.ok();
+        return Err(e);
+    }
+    Ok(())
 }
 
 impl NginxManager {
-    pub fn new() -> Self {
-        Self {}
-    }
-
-    /// Generate Nginx server block for a tunnel
-    pub async fn create_tunnel_config(&self, tunnel: &Tunnel) -> anyhow::Result<()> {
-        let subdomain = &tunnel.subdomain;
-        let port = tunnel.port;
-
-        println!("[Nginx] Creating configuration for tunnel: {}", subdomain);
-
-        // Build optional auth_basic directives
-        let auth_config = if let Some(_password) = &tunnel.password {
-            format!(
-                r#"
-    auth_basic "Tunnel Access";
-    auth_basic_user_file {passwd_dir}/{subdomain}.htpasswd;
-"#,
-                passwd_dir = NGINX_PASSWD_DIR,
-                subdomain = subdomain
-            )
-        } else {
-            String::new()
+    pub fn new(config: &Config) -> Self {
+        Self {
+            base_domain: config.server.base_domain.clone(),
+            nginx: config.nginx.clone(),
+            certbot: config.certbot.clone(),
+            render: SiteRenderer::new(config),
+            traffic: Mutex::new(TrafficLogs::default()),
+        }
+    }
+
+    /// Public hostname of a tunnel, also used as its site and certificate name
+    fn domain(&self, subdomain: &str) -> String {
+        format!("{}.{}", subdomain, self.base_domain)
+    }
+
+    fn site_available_path(&self, domain: &str) -> PathBuf {
+        self.nginx.sites_available_dir.join(domain)
+    }
+
+    fn site_enabled_path(&self, domain: &str) -> PathBuf {
+        self.nginx.sites_enabled_dir.join(domain)
+    }
+
+    /// Per-tunnel copy of the viewer client written by older versions
+    fn client_html_path(&self, subdomain: &str) -> PathBuf {
+        self.nginx.web_root.join(format!("{}.html", subdomain))
+    }
+
+    /// Stream snippets are included in name order, so the prefix keeps them
+    /// after the log format they use
+    fn stream_config_path(&self, subdomain: &str) -> PathBuf {
+        self.nginx.stream_conf_dir.join(format!("tunnel-{}.conf", subdomain))
+    }
+
+    fn rate_limit(&self, tunnel: &Tunnel) -> Option<u64> {
+        self.traffic.lock().unwrap().rate_limits.get(&tunnel.id).copied()
+    }
+
+    fn has_certificate(&self, domain: &str) -> bool {
+        self.certbot.live_dir.join(domain).join("fullchain.pem").exists()
+    }
+
+    /// Keep the changes of a successful transaction, or undo a failed one and
+    /// reload nginx with the config it replaced
+    async fn finish(&self, tx: Transaction, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
         };
+        if !tx.changes.is_empty() {
+            warn!(%error, "Rolling back nginx changes");
+            tx.rollback().await;
+            if let Err(e) = self.reload_nginx().await {
+                error!(error = %e, "Nginx reload after rollback failed");
+            }
+        }
+        Err(error)
+    }
 
-        // Generate server block config with HTTP + HTTPS
-        // Serves HTML for browser, proxies WebSocket for WS connections
-        // Note: map $http_upgrade $connection_upgrade must be in main nginx.conf http block
-        let config = format!(
-            r#"server {{
-    listen 80;
-    listen [::]:80;
-    server_name {subdomain}.tnnl.to;
-
-    # ACME challenge location for certbot
-    location /.well-known/acme-challenge/ {{
-        root /var/www/certbot;
-    }}
-
-    # Redirect all other traffic to HTTPS
-    location / {{
-        return 301 https://$server_name$request_uri;
-    }}
-}}
-
-server {{
-    listen 443 ssl http2;
-    listen [::]:443 ssl http2;
-    server_name {subdomain}.tnnl.to;
-
-    # SSL certificates (will be created by certbot)
-    ssl_certificate /etc/letsencrypt/live/{subdomain}.tnnl.to/fullchain.pem;
-    ssl_certificate_key /etc/letsencrypt/live/{subdomain}.tnnl.to/privkey.pem;
-
-    # SSL configuration
-    ssl_protocols TLSv1.2 TLSv1.3;
-    ssl_ciphers HIGH:!aNULL:!MD5;
-    ssl_prefer_server_ciphers on;
-
-    root /var/www/html;
-{auth_config}
-    # Serve HTML for browser requests (no Upgrade header)
-    location = / {{
-        if ($http_upgrade = '') {{
-            rewrite ^ /{subdomain}.html last;
-        }}
-        # WebSocket upgrade requests go to proxy
-        proxy_pass http://127.0.0.1:{port};
-        proxy_http_version 1.1;
-        proxy_set_header Upgrade $http_upgrade;
-        proxy_set_header Connection $connection_upgrade;
-        proxy_set_header Host $host;
-        proxy_set_header X-Real-IP $remote_addr;
-        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
-        proxy_set_header X-Forwarded-Proto $scheme;
-        proxy_read_timeout 86400;
-    }}
-}}
-"#,
-            subdomain = subdomain,
-            port = port,
-            auth_config = auth_config
-        );
-
-        // Ensure nginx directory exists
-        tokio::fs::create_dir_all(NGINX_CONF_DIR).await.ok();
-
-        // First, create HTTP-only config for certificate provisioning
-        let http_only_config = format!(
-            r#"server {{
-    listen 80;
-    listen [::]:80;
-    server_name {subdomain}.tnnl.to;
-
-    # ACME challenge location for certbot
-    location /.well-known/acme-challenge/ {{
-        root /var/www/certbot;
-    }}
-
-    # Temporary: serve content over HTTP
-    root /var/www/html;
-    location / {{
-        return 200 'Certificate provisioning in progress...';
-        add_header Content-Type text/plain;
-    }}
-}}
-"#,
-            subdomain = subdomain
-        );
-
-        // Write HTTP-only config
-        let config_path = format!("/etc/nginx/sites-available/{}.tnnl.to", subdomain);
-        let mut child = Command::new("sudo")
-            .args(&["tee", &config_path])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .spawn()?;
-
-        if let Some(mut stdin) = child.stdin.take() {
-            use std::io::Write;
-            stdin.write_all(http_only_config.as_bytes())?;
-        }
-        child.wait()?;
-
-        // Enable site by creating symlink in sites-enabled using sudo
-        let enabled_path = format!("/etc/nginx/sites-enabled/{}.tnnl.to", subdomain);
-        Command::new("sudo")
-            .args(&["ln", "-sf", &config_path, &enabled_path])
-            .output()?;
+    /// Write a site config for `domain` and enable it
+    async fn write_site(&self, tx: &mut Transaction, domain: &str, contents: &str) -> anyhow::Result<()> {
+        let config_path = self.site_available_path(domain);
+        tx.write(&config_path, contents).await?;
+        tx.link(&config_path, &self.site_enabled_path(domain)).await
+    }
 
-        // Reload Nginx with HTTP-only config
-        self.reload_nginx().await?;
+    /// Disable and delete the site config for `domain`
+    async fn delete_site(&self, domain: &str) -> anyhow::Result<()> {
+        // Remove symlink from sites-enabled
+        let enabled_path = self.site_enabled_path(domain);
+        if tokio::fs::symlink_metadata(&enabled_path).await.is_ok() {
+            tokio::fs::remove_file(&enabled_path).await?;
+        }
 
-        // Request SSL certificate for this subdomain
-        self.request_ssl_certificate(subdomain).await?;
+        // Remove config file from sites-available
+        let config_path = self.site_available_path(domain);
+        if Path::new(&config_path).exists() {
+            tokio::fs::remove_file(&config_path).await?;
+        }
 
-        // Now write the full config with HTTPS
-        let mut child = Command::new("sudo")
-            .args(&["tee", &config_path])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .spawn()?;
+        Ok(())
+    }
 
-        if let Some(mut stdin) = child.stdin.take() {
-            use std::io::Write;
-            stdin.write_all(config.as_bytes())?;
+    /// Publish a site for `domain` that needs a certificate: a new domain is
+    /// served over plain HTTP until certbot has issued one through it
+    async fn stage_certified_site(&self, tx: &mut Transaction, domain: &str, contents: &str) -> anyhow::Result<()> {
+        if !self.has_certificate(domain) {
+            self.write_site(tx, domain, &self.render.http_only_site(domain, None)).await?;
+            self.reload_nginx().await?;
+            self.request_ssl_certificate(domain).await?;
         }
-        child.wait()?;
+        self.write_site(tx, domain, contents).await?;
+        self.reload_nginx().await
+    }
+
+    /// Serve an HTTP or screen tunnel at its subdomain
+    async fn stage_tunnel_site(&self, tx: &mut Transaction, tunnel: &Tunnel) -> anyhow::Result<()> {
+        let domain = self.domain(&tunnel.subdomain);
 
-        // Create client HTML file with pre-configured WebSocket URL
-        self.create_client_html(subdomain).await?;
+        // Tunnel sites log their traffic in the tnnl_traffic format
+        tokio::fs::create_dir_all(&self.nginx.conf_dir).await?;
+        tokio::fs::create_dir_all(&self.render.traffic_log_dir).await?;
+        tx.write(&self.nginx.conf_dir.join("tnnl-traffic.conf"), &render::http_log_format())
+            .await?;
 
-        // Create htpasswd file if password is set
         if let Some(password) = &tunnel.password {
-            self.create_htpasswd(subdomain, password).await?;
+            tx.write(&self.render.htpasswd_path(&tunnel.subdomain), &htpasswd_entry(password)?)
+                .await?;
         }
 
-        // Reload Nginx with full HTTPS config
-        self.reload_nginx().await?;
+        let contents = self.render.tunnel_site(&domain, tunnel, self.rate_limit(tunnel));
+        self.stage_certified_site(tx, &domain, &contents).await
+    }
+
+    /// Expose a TCP tunnel on its public port
+    /// No certificate is involved; TLS, if any, is up to the client's service
+    async fn stage_tcp_stream(&self, tx: &mut Transaction, tunnel: &Tunnel) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.nginx.stream_conf_dir).await?;
+        tokio::fs::create_dir_all(&self.render.traffic_log_dir).await?;
+        tx.write(&self.nginx.stream_conf_dir.join("log-format.conf"), &render::stream_log_format())
+            .await?;
+
+        let contents = self.render.tcp_stream(tunnel, self.rate_limit(tunnel))?;
+        tx.write(&self.stream_config_path(&tunnel.subdomain), &contents).await?;
+        self.reload_nginx().await
+    }
+
+    /// Publish a tunnel, leaving nothing behind if any step fails
+    async fn write_tunnel_config(&self, tunnel: &Tunnel) -> anyhow::Result<()> {
+        info!(subdomain = %tunnel.subdomain, kind = ?tunnel.kind, "Creating nginx configuration");
 
-        println!("[Nginx] Configuration created for {}.tnnl.to", subdomain);
+        let mut tx = Transaction::default();
+        let result = match tunnel.kind {
+            TunnelKind::Tcp => self.stage_tcp_stream(&mut tx, tunnel).await,
+            TunnelKind::Screen | TunnelKind::Http => self.stage_tunnel_site(&mut tx, tunnel).await,
+        };
+        self.finish(tx, result).await?;
+
+        self.traffic
+            .lock()
+            .unwrap()
+            .tunnels
+            .insert(tunnel.subdomain.clone(), tunnel.id);
+        info!(subdomain = %tunnel.subdomain, "Nginx configuration created");
         Ok(())
     }
 
-    /// Request SSL certificate for a subdomain using certbot
-    async fn request_ssl_certificate(&self, subdomain: &str) -> anyhow::Result<()> {
-        let domain = format!("{}.tnnl.to", subdomain);
+    /// Read new lines from every tunnel's traffic log
+    /// Only complete lines are consumed, since nginx may be mid-write. Logs of
+    /// removed tunnels are deleted once a pass finds nothing new in them, and
+    /// logs of unknown tunnels (left by a previous run) are deleted unread
+    async fn read_traffic_logs(&self) -> anyhow::Result<Vec<TrafficSample>> {
+        let mut entries = match tokio::fs::read_dir(&self.render.traffic_log_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
 
-        println!("[Nginx] Requesting SSL certificate for {}...", domain);
+        let mut samples = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("log") {
+                continue;
+            }
+            let tunnel_id = match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| Uuid::parse_str(s).ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let (known, offset) = {
+                let traffic = self.traffic.lock().unwrap();
+                (
+                    traffic.closed.contains(&tunnel_id) || traffic.tunnels.values().any(|id| *id == tunnel_id),
+                    traffic.offsets.get(&tunnel_id).copied().unwrap_or(0),
+                )
+            };
+            if !known {
+                tokio::fs::remove_file(&path).await.ok();
+                continue;
+            }
+
+            let mut file = tokio::fs::File::open(&path).await?;
+            // A shorter file than what was read means it was truncated
+            let start = if file.metadata().await?.len() < offset { 0 } else { offset };
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).await?;
+
+            let complete = buf.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+            for line in String::from_utf8_lossy(&buf[..complete]).lines() {
+                match bandwidth::parse_log_line(tunnel_id, line) {
+                    Some(sample) => samples.push(sample),
+                    None => warn!(%tunnel_id, line, "Skipping malformed traffic log line"),
+                }
+            }
+
+            let drained = {
+                let mut traffic = self.traffic.lock().unwrap();
+                if complete == 0 && traffic.closed.remove(&tunnel_id) {
+                    traffic.offsets.remove(&tunnel_id);
+                    true
+                } else {
+                    traffic.offsets.insert(tunnel_id, start + complete as u64);
+                    false
+                }
+            };
+            if drained {
+                tokio::fs::remove_file(&path).await.ok();
+            }
+        }
 
-        // Check if certificate already exists
-        let cert_path = format!("/etc/letsencrypt/live/{}/fullchain.pem", domain);
-        if Path::new(&cert_path).exists() {
-            println!("[Nginx] SSL certificate already exists for {}", domain);
-            return Ok(());
+        Ok(samples)
+    }
+
+    /// Subdomain of a tunnel hostname under the base domain, if `name` is one
+    fn tunnel_subdomain<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let subdomain = name.strip_suffix(&self.base_domain)?.strip_suffix('.')?;
+        let valid = !subdomain.is_empty()
+            && subdomain.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+        valid.then_some(subdomain)
+    }
+
+    /// Walk the directories the server writes to, attributing every file to
+    /// the tunnel or custom domain it serves
+    /// Files that cannot be attributed, like the operator's own sites and
+    /// certificates, are left out: sites must carry the marker, and a
+    /// certificate only counts next to a site or htpasswd file the server wrote
+    async fn scan_inventory(&self) -> anyhow::Result<ProxyInventory> {
+        let mut inventory = ProxyInventory::default();
+
+        for dir in [&self.nginx.sites_enabled_dir, &self.nginx.sites_available_dir] {
+            for name in list_dir(dir).await? {
+                if !is_written_by_server(&dir.join(&name)).await {
+                    continue;
+                }
+                match self.tunnel_subdomain(&name) {
+                    Some(subdomain) => inventory.add(subdomain, ProxyArtifact::Site),
+                    None => {
+                        inventory.custom_domains.insert(name);
+                    }
+                }
+            }
+        }
+
+        for name in list_dir(&self.nginx.stream_conf_dir).await? {
+            let subdomain = name.strip_prefix("tunnel-").and_then(|n| n.strip_suffix(".conf"));
+            if let Some(subdomain) = subdomain.filter(|s| !s.is_empty()) {
+                inventory.add(subdomain, ProxyArtifact::Stream);
+            }
+        }
+
+        for name in list_dir(&self.render.passwd_dir).await? {
+            if let Some(subdomain) = name.strip_suffix(".htpasswd").filter(|s| !s.is_empty()) {
+                inventory.add(subdomain, ProxyArtifact::Htpasswd);
+            }
+        }
+
+        // Old client copies were the client page with the tunnel's URL filled in
+        for name in list_dir(&self.nginx.web_root).await? {
+            let Some(subdomain) = name.strip_suffix(".html").filter(|s| !s.is_empty()) else {
+                continue;
+            };
+            let url = format!("value=\"wss://{}\"", self.domain(subdomain));
+            let contents = tokio::fs::read_to_string(self.nginx.web_root.join(&name)).await.unwrap_or_default();
+            if contents.contains(&url) {
+                inventory.add(subdomain, ProxyArtifact::ClientPage);
+            }
         }
 
+        for name in list_dir(&self.certbot.live_dir).await? {
+            let Some(subdomain) = self.tunnel_subdomain(&name) else {
+                continue;
+            };
+            let ours = inventory.tunnels.get(subdomain).is_some_and(|artifacts| {
+                artifacts.contains(&ProxyArtifact::Site) || artifacts.contains(&ProxyArtifact::Htpasswd)
+            });
+            if ours {
+                inventory.add(subdomain, ProxyArtifact::Certificate);
+            }
+        }
+
+        Ok(inventory)
+    }
+
+    /// Request SSL certificate for a domain using certbot
+    async fn request_ssl_certificate(&self, domain: &str) -> anyhow::Result<()> {
+        info!(domain, "Requesting SSL certificate");
+        self.run_certbot(domain, "--keep-until-expiring").await?;
+        info!(domain, "SSL certificate obtained");
+        Ok(())
+    }
+
+    /// Run certbot with the webroot plugin for `domain`, with `mode` deciding
+    /// whether a certificate that is not yet due is issued again
+    async fn run_certbot(&self, domain: &str, mode: &str) -> anyhow::Result<()> {
         // Ensure certbot webroot directory exists
-        tokio::fs::create_dir_all("/var/www/certbot").await.ok();
+        tokio::fs::create_dir_all(&self.certbot.webroot).await.ok();
 
-        // Request certificate using certbot with webroot plugin
         let output = Command::new("sudo")
-            .args(&[
+            .args([
                 "certbot",
                 "certonly",
                 "--webroot",
-                "--webroot-path", "/var/www/certbot",
-                "-d", &domain,
+                "--webroot-path", &self.certbot.webroot.to_string_lossy(),
+                "-d", domain,
                 "--non-interactive",
                 "--agree-tos",
-                "--email", "admin@tnnl.to",
-                "--keep-until-expiring"
+                "--email", &self.certbot.email,
+                mode
             ])
             .output()?;
 
@@ -214,116 +530,116 @@ server {{
                 String::from_utf8_lossy(&output.stderr)
             ));
         }
-
-        println!("[Nginx] SSL certificate obtained for {}", domain);
         Ok(())
     }
 
-    /// Create client HTML file with pre-configured WebSocket URL
-    async fn create_client_html(&self, subdomain: &str) -> anyhow::Result<()> {
-        // Read template client.html
-        let template_path = "/opt/tnnl/client.html";
-        if !Path::new(template_path).exists() {
-            println!("[Nginx] Warning: client.html template not found at {}", template_path);
-            return Ok(()); // Don't fail if template missing
+    /// Read the certificates of tunnel hostnames and of the custom domains the
+    /// server wrote a site for
+    /// A certificate that cannot be read is logged and left out
+    async fn read_certificates(&self) -> anyhow::Result<Vec<Certificate>> {
+        let inventory = self.scan_inventory().await?;
+        let mut domains: Vec<String> = inventory
+            .tunnels
+            .iter()
+            .filter(|(_, artifacts)| artifacts.contains(&ProxyArtifact::Certificate))
+            .map(|(subdomain, _)| self.domain(subdomain))
+            .collect();
+        domains.extend(inventory.custom_domains.into_iter().filter(|d| self.has_certificate(d)));
+
+        let mut certificates = Vec::new();
+        for domain in domains {
+            let path = self.certbot.live_dir.join(&domain).join("fullchain.pem");
+            let output = Command::new("openssl")
+                .args(["x509", "-noout", "-enddate", "-issuer", "-in"])
+                .arg(&path)
+                .output()?;
+            if !output.status.success() {
+                warn!(
+                    domain,
+                    path = %path.display(),
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "Cannot read certificate"
+                );
+                continue;
+            }
+            match Certificate::from_openssl(&domain, &String::from_utf8_lossy(&output.stdout)) {
+                Ok(certificate) => certificates.push(certificate),
+                Err(e) => warn!(domain, path = %path.display(), error = %e, "Cannot read certificate"),
+            }
         }
-
-        let template = tokio::fs::read_to_string(template_path).await?;
-
-        // Replace placeholder WebSocket URL with this tunnel's HTTPS URL
-        // Set the value attribute to pre-fill the input field
-        let customized = template
-            .replace("placeholder=\"ws://192.168.1.100:9001\"",
-                    &format!("value=\"wss://{}.tnnl.to\" placeholder=\"wss://{}.tnnl.to\"", subdomain, subdomain));
-
-        // Write to /var/www/html
-        let html_path = format!("/var/www/html/{}.html", subdomain);
-        tokio::fs::write(&html_path, customized).await?;
-
-        println!("[Nginx] Created client HTML at {}", html_path);
-        Ok(())
+        Ok(certificates)
     }
 
     /// Remove tunnel configuration
-    pub async fn remove_tunnel_config(&self, subdomain: &str) -> anyhow::Result<()> {
-        println!("[Nginx] Removing configuration for tunnel: {}", subdomain);
-
-        // Remove symlink from sites-enabled
-        let enabled_path = format!("/etc/nginx/sites-enabled/{}.tnnl.to", subdomain);
-        if Path::new(&enabled_path).exists() {
-            tokio::fs::remove_file(&enabled_path).await?;
+    async fn delete_tunnel_config(&self, subdomain: &str) -> anyhow::Result<()> {
+        info!(subdomain, "Removing nginx configuration");
+        let domain = self.domain(subdomain);
+
+        // TCP tunnels only have a stream config
+        let stream_path = self.stream_config_path(subdomain);
+        let is_tcp = stream_path.exists();
+        if is_tcp {
+            tokio::fs::remove_file(&stream_path).await?;
         }
 
-        // Remove config file from sites-available
-        let config_path = format!("/etc/nginx/sites-available/{}.tnnl.to", subdomain);
-        if Path::new(&config_path).exists() {
-            tokio::fs::remove_file(&config_path).await?;
+        // Remove site config
+        self.delete_site(&domain).await?;
+
+        // Its traffic log is collected until drained, then deleted
+        {
+            let mut traffic = self.traffic.lock().unwrap();
+            if let Some(tunnel_id) = traffic.tunnels.remove(subdomain) {
+                traffic.rate_limits.remove(&tunnel_id);
+                traffic.closed.insert(tunnel_id);
+            }
         }
 
-        // Remove client HTML
-        let html_path = format!("/var/www/html/{}.html", subdomain);
+        // Remove a client HTML copy left by an older version
+        let html_path = self.client_html_path(subdomain);
         if Path::new(&html_path).exists() {
             tokio::fs::remove_file(&html_path).await?;
         }
 
         // Remove htpasswd file
-        let passwd_path = format!("{}/{}.htpasswd", NGINX_PASSWD_DIR, subdomain);
+        let passwd_path = self.render.htpasswd_path(subdomain);
         if Path::new(&passwd_path).exists() {
             tokio::fs::remove_file(&passwd_path).await?;
         }
 
         // Delete SSL certificate
-        self.delete_ssl_certificate(subdomain).await.ok();
+        if !is_tcp {
+            self.delete_ssl_certificate(&domain).await.ok();
+        }
 
         // Reload Nginx
         self.reload_nginx().await?;
 
-        println!("[Nginx] Configuration removed for {}.tnnl.to", subdomain);
+        info!(subdomain, "Nginx configuration removed");
         Ok(())
     }
 
-    /// Delete SSL certificate for a subdomain
-    async fn delete_ssl_certificate(&self, subdomain: &str) -> anyhow::Result<()> {
-        let domain = format!("{}.tnnl.to", subdomain);
-
-        println!("[Nginx] Deleting SSL certificate for {}...", domain);
+    /// Delete SSL certificate for a domain
+    async fn delete_ssl_certificate(&self, domain: &str) -> anyhow::Result<()> {
+        info!(domain, "Deleting SSL certificate");
 
         // Use certbot to delete the certificate
         let output = Command::new("sudo")
-            .args(&[
+            .args([
                 "certbot",
                 "delete",
-                "--cert-name", &domain,
+                "--cert-name", domain,
                 "--non-interactive"
             ])
             .output()?;
 
         if !output.status.success() {
-            eprintln!("[Nginx] Warning: Failed to delete certificate for {}: {}",
-                domain, String::from_utf8_lossy(&output.stderr));
+            warn!(
+                domain,
+                stderr = %String::from_utf8_lossy(&output.stderr),
+                "Failed to delete SSL certificate"
+            );
         } else {
-            println!("[Nginx] SSL certificate deleted for {}", domain);
-        }
-
-        Ok(())
-    }
-
-    /// Create htpasswd file for HTTP Basic Auth
-    /// Always uses "tnnl" as the username for simplicity
-    async fn create_htpasswd(&self, subdomain: &str, password: &str) -> anyhow::Result<()> {
-        let passwd_path = format!("{}/{}.htpasswd", NGINX_PASSWD_DIR, subdomain);
-
-        // Use htpasswd command to create the file
-        // htpasswd -bc /path/to/file username password
-        let output = Command::new("sudo")
-            .args(&["htpasswd", "-bc", &passwd_path, "tnnl", password])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(anyhow::anyhow!(
-                "Failed to create htpasswd file: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
+            info!(domain, "SSL certificate deleted");
         }
 
         Ok(())
@@ -333,7 +649,7 @@ server {{
     async fn reload_nginx(&self) -> anyhow::Result<()> {
         // First validate the configuration
         let test_output = Command::new("sudo")
-            .args(&["nginx", "-t"])
+            .args(["nginx", "-t"])
             .output()?;
 
         if !test_output.status.success() {
@@ -345,7 +661,7 @@ server {{
 
         // Then reload using systemctl
         let output = Command::new("sudo")
-            .args(&["systemctl", "reload", "nginx"])
+            .args(["systemctl", "reload", "nginx"])
             .output()?;
 
         if !output.status.success() {
@@ -358,3 +674,268 @@ server {{
         Ok(())
     }
 }
+
+/// Names of the entries in `dir`; a missing directory is empty
+async fn list_dir(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow::anyhow!("Cannot list {}: {}", dir.display(), e)),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Temporaries of an interrupted write
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Whether the file at `path` starts with the marker the server writes
+async fn is_written_by_server(path: &Path) -> bool {
+    tokio::fs::read_to_string(path)
+        .await
+        .is_ok_and(|contents| contents.starts_with(render::MARKER))
+}
+
+/// htpasswd line for HTTP Basic Auth
+/// Always uses "tnnl" as the username for simplicity; the password is passed on
+/// stdin so it never shows up in the process list
+fn htpasswd_entry(password: &str) -> anyhow::Result<String> {
+    use std::io::Write;
+
+    let mut child = Command::new("htpasswd")
+        .args(["-niB", "tnnl"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(password.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to hash tunnel password: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(format!("{}\n", String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+#[async_trait]
+impl ProxyBackend for NginxManager {
+    async fn create_tunnel_config(&self, tunnel: &Tunnel) -> anyhow::Result<()> {
+        self.write_tunnel_config(tunnel).await
+    }
+
+    async fn remove_tunnel_config(&self, subdomain: &str) -> anyhow::Result<()> {
+        self.delete_tunnel_config(subdomain).await
+    }
+
+    async fn publish_domain_challenge(&self, hostname: &str, token: &str) -> anyhow::Result<()> {
+        info!(hostname, "Publishing ownership challenge");
+        let mut tx = Transaction::default();
+        let result = async {
+            self.write_site(&mut tx, hostname, &self.render.http_only_site(hostname, Some(token)))
+                .await?;
+            self.reload_nginx().await
+        }
+        .await;
+        self.finish(tx, result).await
+    }
+
+    async fn provision_custom_domain(&self, hostname: &str) -> anyhow::Result<()> {
+        info!(hostname, "Provisioning custom domain");
+        let mut tx = Transaction::default();
+        let result = self
+            .stage_certified_site(&mut tx, hostname, &self.render.offline_site(hostname))
+            .await;
+        self.finish(tx, result).await
+    }
+
+    async fn route_custom_domain(&self, hostname: &str, tunnel: Option<&Tunnel>) -> anyhow::Result<()> {
+        let contents = match tunnel {
+            Some(tunnel) if tunnel.kind == TunnelKind::Tcp => {
+                return Err(anyhow::anyhow!("TCP tunnels cannot be served on a custom domain"));
+            }
+            Some(tunnel) => {
+                info!(hostname, subdomain = %tunnel.subdomain, "Routing custom domain to tunnel");
+                self.render.tunnel_site(hostname, tunnel, self.rate_limit(tunnel))
+            }
+            None => {
+                info!(hostname, "Routing custom domain to offline page");
+                self.render.offline_site(hostname)
+            }
+        };
+        let mut tx = Transaction::default();
+        let result = async {
+            self.write_site(&mut tx, hostname, &contents).await?;
+            self.reload_nginx().await
+        }
+        .await;
+        self.finish(tx, result).await
+    }
+
+    async fn remove_custom_domain(&self, hostname: &str) -> anyhow::Result<()> {
+        info!(hostname, "Removing custom domain");
+        self.delete_site(hostname).await?;
+        self.delete_ssl_certificate(hostname).await.ok();
+        self.reload_nginx().await
+    }
+
+    async fn set_tunnel_rate_limit(&self, tunnel: &Tunnel, bytes_per_sec: Option<u64>) -> anyhow::Result<()> {
+        let previous = {
+            let mut traffic = self.traffic.lock().unwrap();
+            let previous = match bytes_per_sec {
+                Some(rate) => traffic.rate_limits.insert(tunnel.id, rate),
+                None => traffic.rate_limits.remove(&tunnel.id),
+            };
+            if previous == bytes_per_sec {
+                return Ok(());
+            }
+            previous
+        };
+
+        match bytes_per_sec {
+            Some(rate) => info!(subdomain = %tunnel.subdomain, bytes_per_sec = rate, "Limiting tunnel rate"),
+            None => info!(subdomain = %tunnel.subdomain, "Removing tunnel rate limit"),
+        }
+        let mut tx = Transaction::default();
+        let result = async {
+            if tunnel.kind == TunnelKind::Tcp {
+                let contents = self.render.tcp_stream(tunnel, bytes_per_sec)?;
+                tx.write(&self.stream_config_path(&tunnel.subdomain), &contents).await?;
+            } else {
+                let domain = self.domain(&tunnel.subdomain);
+                self.write_site(&mut tx, &domain, &self.render.tunnel_site(&domain, tunnel, bytes_per_sec))
+                    .await?;
+            }
+            self.reload_nginx().await
+        }
+        .await;
+
+        if let Err(e) = self.finish(tx, result).await {
+            let mut traffic = self.traffic.lock().unwrap();
+            match previous {
+                Some(rate) => traffic.rate_limits.insert(tunnel.id, rate),
+                None => traffic.rate_limits.remove(&tunnel.id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn collect_traffic(&self) -> anyhow::Result<Vec<TrafficSample>> {
+        self.read_traffic_logs().await
+    }
+
+    async fn inventory(&self) -> anyhow::Result<ProxyInventory> {
+        self.scan_inventory().await
+    }
+
+    async fn certificates(&self) -> anyhow::Result<Vec<Certificate>> {
+        self.read_certificates().await
+    }
+
+    async fn renew_certificate(&self, domain: &str) -> anyhow::Result<()> {
+        info!(domain, "Renewing SSL certificate");
+        self.run_certbot(domain, "--force-renewal").await?;
+        self.reload_nginx().await?;
+        info!(domain, "SSL certificate renewed");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rollback_restores_every_file() {
+        let dir = std::env::temp_dir().join(format!("tnnl-nginx-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let existing = dir.join("existing.conf");
+        let created = dir.join("created.conf");
+        let enabled = dir.join("enabled.conf");
+        tokio::fs::write(&existing, "old").await.unwrap();
+
+        let mut tx = Transaction::default();
+        tx.write(&existing, "new").await.unwrap();
+        tx.write(&existing, "newer").await.unwrap();
+        tx.write(&created, "created").await.unwrap();
+        tx.link(&created, &enabled).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&existing).await.unwrap(), "newer");
+        assert_eq!(tokio::fs::read_to_string(&enabled).await.unwrap(), "created");
+
+        tx.rollback().await;
+        assert_eq!(tokio::fs::read_to_string(&existing).await.unwrap(), "old");
+        assert!(tokio::fs::symlink_metadata(&created).await.is_err());
+        assert!(tokio::fs::symlink_metadata(&enabled).await.is_err());
+
+        // Only the original file is left, no temporaries
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        assert_eq!(names, ["existing.conf"]);
+
+        tokio::fs::remove_dir_all(&dir).await.ok();
+    }
+
+    #[tokio::test]
+    async fn test_inventory_leaves_out_the_operators_files() {
+        let dir = std::env::temp_dir().join(format!("tnnl-nginx-{}", Uuid::new_v4()));
+        let mut config = Config::default();
+        config.nginx.sites_available_dir = dir.join("sites-available");
+        config.nginx.sites_enabled_dir = dir.join("sites-enabled");
+        config.nginx.stream_conf_dir = dir.join("streams");
+        config.nginx.passwd_dir = dir.join("passwd");
+        config.nginx.web_root = dir.join("html");
+        config.certbot.live_dir = dir.join("live");
+        let manager = NginxManager::new(&config);
+        let offline_site = manager.render.offline_site("app.example.com");
+        let tunnel_site = manager.render.offline_site("calm-elk-1234.tnnl.to");
+
+        let files = [
+            ("sites-enabled/calm-elk-1234.tnnl.to", tunnel_site.as_str()),
+            ("sites-enabled/grafana.tnnl.to", "server {}"),
+            ("sites-enabled/app.example.com", offline_site.as_str()),
+            ("sites-enabled/tnnl", "server {}"),
+            ("sites-enabled/.calm-elk-1234.tnnl.to.tnnl-tmp", "server {}"),
+            ("streams/tunnel-quiet-bee-1234.conf", "server {}"),
+            ("streams/log-format.conf", "log_format"),
+            ("passwd/lost-fox-1234.htpasswd", "tnnl:hash"),
+            ("html/lost-fox-1234.html", r#"<input value="wss://lost-fox-1234.tnnl.to">"#),
+            ("html/index.html", "<html>"),
+            ("live/lost-fox-1234.tnnl.to/fullchain.pem", ""),
+            ("live/calm-elk-1234.tnnl.to/fullchain.pem", ""),
+            ("live/grafana.tnnl.to/fullchain.pem", ""),
+            ("live/idle-owl-1234.tnnl.to/fullchain.pem", ""),
+            ("live/tnnl.to/fullchain.pem", ""),
+            ("live/other.example.org/fullchain.pem", ""),
+        ];
+        for (path, contents) in files {
+            let path = dir.join(path);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            tokio::fs::write(&path, contents).await.unwrap();
+        }
+
+        let inventory = manager.inventory().await.unwrap();
+        let mut expected = ProxyInventory::default();
+        expected.add("calm-elk-1234", ProxyArtifact::Site);
+        expected.add("calm-elk-1234", ProxyArtifact::Certificate);
+        expected.add("quiet-bee-1234", ProxyArtifact::Stream);
+        expected.add("lost-fox-1234", ProxyArtifact::Htpasswd);
+        expected.add("lost-fox-1234", ProxyArtifact::ClientPage);
+        expected.add("lost-fox-1234", ProxyArtifact::Certificate);
+        expected.custom_domains.insert("app.example.com".to_string());
+        assert_eq!(inventory, expected);
+
+        tokio::fs::remove_dir_all(&dir).await.ok();
+    }
+}
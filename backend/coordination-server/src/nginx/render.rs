@@ -0,0 +1,521 @@
+// Nginx config rendering
+// Pure functions from a tunnel or domain to the config text nginx gets, kept
+// apart from the code that writes and reloads it so every template has a
+// snapshot test
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::bandwidth;
+use crate::config::Config;
+use crate::domains::http_challenge_path;
+use crate::tunnel::{Tunnel, TunnelKind};
+
+/// First line of the sites and snippets the server writes, telling them apart
+/// from the operator's own
+pub const MARKER: &str = "# Written by the tnnl coordination server";
+
+/// Paths and endpoints the templates refer to
+pub struct SiteRenderer {
+    pub certbot_webroot: PathBuf,
+    pub live_dir: PathBuf,
+    pub web_root: PathBuf,
+    pub passwd_dir: PathBuf,
+    pub traffic_log_dir: PathBuf,
+    /// Forward-auth endpoint for viewer allowlists, when enabled
+    pub viewer_auth: Option<SocketAddr>,
+    /// Where the coordination server serves the viewer client
+    pub client_addr: SocketAddr,
+}
+
+impl SiteRenderer {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            certbot_webroot: config.certbot.webroot.clone(),
+            live_dir: config.certbot.live_dir.clone(),
+            web_root: config.nginx.web_root.clone(),
+            passwd_dir: config.nginx.passwd_dir.clone(),
+            traffic_log_dir: config.bandwidth.log_dir.clone(),
+            viewer_auth: config
+                .viewer_auth
+                .enabled
+                .then_some(config.viewer_auth.bind_address),
+            client_addr: config.nginx.client_bind_address,
+        }
+    }
+
+    pub fn htpasswd_path(&self, subdomain: &str) -> PathBuf {
+        self.passwd_dir.join(format!("{}.htpasswd", subdomain))
+    }
+
+    pub fn traffic_log_path(&self, tunnel_id: Uuid) -> PathBuf {
+        self.traffic_log_dir.join(format!("{}.log", tunnel_id))
+    }
+
+    /// Full HTTP + HTTPS server block serving a tunnel at `domain`
+    /// `domain` is the tunnel's own hostname or a custom domain routed to it
+    pub fn tunnel_site(&self, domain: &str, tunnel: &Tunnel, rate: Option<u64>) -> String {
+        let subdomain = &tunnel.subdomain;
+
+        // Build optional auth_basic directives
+        let auth_config = if let Some(_password) = &tunnel.password {
+            format!(
+                r#"
+    auth_basic "Tunnel Access";
+    auth_basic_user_file {passwd_path};
+"#,
+                passwd_path = self.htpasswd_path(subdomain).display()
+            )
+        } else {
+            String::new()
+        };
+
+        // Ask the coordination server about every request so allowlists can be
+        // changed while the tunnel is live; it admits everyone when there is none.
+        // The signed-in email is passed on to the host app, and any copy of the
+        // header sent by the viewer is dropped
+        let (viewer_auth_config, viewer_header) = match self.viewer_auth {
+            Some(addr) => (
+                format!(
+                    r#"
+    # Viewer allowlist, checked by the coordination server
+    auth_request /_tnnl/auth;
+    auth_request_set $tnnl_viewer $upstream_http_x_tnnl_viewer;
+    error_page 401 = @tnnl_login;
+
+    location = /_tnnl/auth {{
+        internal;
+        proxy_pass http://{addr}/auth/check;
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length "";
+        proxy_set_header Host $host;
+        proxy_set_header Cookie $http_cookie;
+    }}
+
+    # Viewer sign-in pages
+    location ^~ /_tnnl/ {{
+        auth_request off;
+        proxy_pass http://{addr};
+        proxy_set_header Host $host;
+        proxy_set_header X-Real-IP $remote_addr;
+    }}
+
+    location @tnnl_login {{
+        return 302 /_tnnl/login;
+    }}
+"#,
+                    addr = addr
+                ),
+                "$tnnl_viewer",
+            ),
+            None => (String::new(), "\"\""),
+        };
+
+        let rate_config = match rate {
+            Some(rate) => format!(
+                r#"
+    # Over the monthly transfer cap
+    limit_rate {rate};
+"#,
+                rate = rate
+            ),
+            None => String::new(),
+        };
+
+        // nginx's own 502 and 504, when the forward is down, become the
+        // coordination server's offline page; errors from the host app pass as is
+        let offline_config = format!(
+            r#"
+    # Offline page and host status, served by the coordination server
+    error_page 502 504 = @tnnl_offline;
+
+    location = /_tnnl/status {{
+        proxy_pass http://{client_addr}/status;
+        proxy_set_header Host $host;
+    }}
+
+    location @tnnl_offline {{
+        rewrite ^ /offline break;
+        proxy_pass http://{client_addr};
+        proxy_method GET;
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length "";
+        proxy_set_header Host $host;
+    }}
+"#,
+            client_addr = self.client_addr
+        );
+
+        // Screen tunnels serve the viewer page to browsers and proxy only its
+        // WebSocket; HTTP tunnels proxy everything to the local server
+        let location_config = match tunnel.kind {
+            TunnelKind::Screen => format!(
+                r#"
+    # The viewer client and its settings, served by the coordination server
+    location = /_tnnl/client {{
+        internal;
+        proxy_pass http://{client_addr}/client;
+        proxy_set_header Host $host;
+    }}
+
+    location = /_tnnl/config {{
+        proxy_pass http://{client_addr}/config;
+        proxy_set_header Host $host;
+    }}
+
+    # Serve the viewer client for browser requests (no Upgrade header)
+    location = / {{
+        if ($http_upgrade = '') {{
+            rewrite ^ /_tnnl/client last;
+        }}
+        # WebSocket upgrade requests go to proxy
+        proxy_pass http://127.0.0.1:{port};
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $connection_upgrade;
+        proxy_set_header Host $host;
+        proxy_set_header X-Real-IP $remote_addr;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+        proxy_set_header X-Tnnl-Viewer {viewer_header};
+        proxy_read_timeout 86400;
+    }}
+"#,
+                client_addr = self.client_addr,
+                port = tunnel.port,
+                viewer_header = viewer_header
+            ),
+            // TCP tunnels have no site; they are served by tcp_stream_config
+            TunnelKind::Http | TunnelKind::Tcp => format!(
+                r#"
+    # Everything goes to the local HTTP server, upgrades included
+    location / {{
+        proxy_pass http://127.0.0.1:{port};
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $connection_upgrade;
+        proxy_set_header Host $host;
+        proxy_set_header X-Real-IP $remote_addr;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+        proxy_set_header X-Tnnl-Viewer {viewer_header};
+        proxy_read_timeout 86400;
+        client_max_body_size 0;
+    }}
+"#,
+                port = tunnel.port,
+                viewer_header = viewer_header
+            ),
+        };
+
+        // Generate server block config with HTTP + HTTPS
+        // Note: map $http_upgrade $connection_upgrade must be in main nginx.conf http block
+        format!(
+            r#"{MARKER}
+server {{
+    listen 80;
+    listen [::]:80;
+    server_name {domain};
+
+    # ACME challenge location for certbot
+    location /.well-known/acme-challenge/ {{
+        root {certbot_webroot};
+    }}
+
+    # Redirect all other traffic to HTTPS
+    location / {{
+        return 301 https://$server_name$request_uri;
+    }}
+}}
+
+server {{
+    listen 443 ssl http2;
+    listen [::]:443 ssl http2;
+    server_name {domain};
+
+    # SSL certificates (will be created by certbot)
+    ssl_certificate {live_dir}/{domain}/fullchain.pem;
+    ssl_certificate_key {live_dir}/{domain}/privkey.pem;
+
+    # SSL configuration
+    ssl_protocols TLSv1.2 TLSv1.3;
+    ssl_ciphers HIGH:!aNULL:!MD5;
+    ssl_prefer_server_ciphers on;
+
+    root {web_root};
+
+    # Bytes per request, collected by the coordination server for bandwidth accounting
+    access_log {traffic_log} tnnl_traffic;
+{rate_config}{auth_config}{viewer_auth_config}{offline_config}{location_config}}}
+"#,
+            domain = domain,
+            offline_config = offline_config,
+            auth_config = auth_config,
+            viewer_auth_config = viewer_auth_config,
+            location_config = location_config,
+            traffic_log = self.traffic_log_path(tunnel.id).display(),
+            rate_config = rate_config,
+            certbot_webroot = self.certbot_webroot.display(),
+            web_root = self.web_root.display(),
+            live_dir = self.live_dir.display()
+        )
+    }
+
+    /// Stream server block exposing a TCP tunnel on its public port
+    pub fn tcp_stream(&self, tunnel: &Tunnel, rate: Option<u64>) -> anyhow::Result<String> {
+        let public_port = tunnel
+            .public_port
+            .ok_or_else(|| anyhow::anyhow!("TCP tunnel {} has no public port", tunnel.subdomain))?;
+
+        let rate_config = match rate {
+            Some(rate) => format!(
+                r#"
+    # Over the monthly transfer cap
+    proxy_download_rate {rate};
+"#,
+                rate = rate
+            ),
+            None => String::new(),
+        };
+
+        Ok(format!(
+            r#"# TCP tunnel {subdomain}
+server {{
+    listen {public_port};
+    listen [::]:{public_port};
+    proxy_pass 127.0.0.1:{port};
+    proxy_timeout 24h;
+
+    # Bytes per connection, collected by the coordination server for bandwidth accounting
+    access_log {traffic_log} tnnl_traffic;
+{rate_config}}}
+"#,
+            subdomain = tunnel.subdomain,
+            public_port = public_port,
+            port = tunnel.port,
+            traffic_log = self.traffic_log_path(tunnel.id).display(),
+            rate_config = rate_config
+        ))
+    }
+
+    /// HTTP-only server block used while a certificate is provisioned
+    /// Optionally answers a custom domain ownership challenge
+    pub fn http_only_site(&self, domain: &str, challenge_token: Option<&str>) -> String {
+        let challenge_config = match challenge_token {
+            Some(token) => format!(
+                r#"
+    # Ownership challenge for custom domain verification
+    location = {path} {{
+        return 200 '{token}';
+        add_header Content-Type text/plain;
+    }}
+"#,
+                path = http_challenge_path(token),
+                token = token
+            ),
+            None => String::new(),
+        };
+
+        format!(
+            r#"{MARKER}
+server {{
+    listen 80;
+    listen [::]:80;
+    server_name {domain};
+
+    # ACME challenge location for certbot
+    location /.well-known/acme-challenge/ {{
+        root {certbot_webroot};
+    }}
+{challenge_config}
+    # Temporary: serve content over HTTP
+    root {web_root};
+    location / {{
+        return 200 'Certificate provisioning in progress...';
+        add_header Content-Type text/plain;
+    }}
+}}
+"#,
+            domain = domain,
+            challenge_config = challenge_config,
+            certbot_webroot = self.certbot_webroot.display(),
+            web_root = self.web_root.display()
+        )
+    }
+
+    /// HTTPS server block for a custom domain with no connected tunnel
+    pub fn offline_site(&self, domain: &str) -> String {
+        format!(
+            r#"{MARKER}
+server {{
+    listen 80;
+    listen [::]:80;
+    server_name {domain};
+
+    # ACME challenge location for certbot
+    location /.well-known/acme-challenge/ {{
+        root {certbot_webroot};
+    }}
+
+    location / {{
+        return 301 https://$server_name$request_uri;
+    }}
+}}
+
+server {{
+    listen 443 ssl http2;
+    listen [::]:443 ssl http2;
+    server_name {domain};
+
+    ssl_certificate {live_dir}/{domain}/fullchain.pem;
+    ssl_certificate_key {live_dir}/{domain}/privkey.pem;
+
+    ssl_protocols TLSv1.2 TLSv1.3;
+    ssl_ciphers HIGH:!aNULL:!MD5;
+    ssl_prefer_server_ciphers on;
+
+    # Offline page and host status, served by the coordination server
+    location = /_tnnl/status {{
+        proxy_pass http://{client_addr}/status;
+        proxy_set_header Host $host;
+    }}
+
+    location / {{
+        rewrite ^ /offline break;
+        proxy_pass http://{client_addr};
+        proxy_method GET;
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length "";
+        proxy_set_header Host $host;
+    }}
+}}
+"#,
+            domain = domain,
+            client_addr = self.client_addr,
+            certbot_webroot = self.certbot_webroot.display(),
+            live_dir = self.live_dir.display()
+        )
+    }
+}
+
+/// Snippet defining the tnnl_traffic log format
+fn log_format(format: &str) -> String {
+    format!(
+        "{}\nlog_format tnnl_traffic '{}';\n",
+        MARKER, format
+    )
+}
+
+/// Snippet defining the tnnl_traffic log format in the http context
+pub fn http_log_format() -> String {
+    log_format(bandwidth::LOG_FORMAT)
+}
+
+/// Snippet defining the tnnl_traffic log format in the stream context
+pub fn stream_log_format() -> String {
+    log_format(bandwidth::STREAM_LOG_FORMAT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::path::Path;
+
+    fn renderer(viewer_auth: bool) -> SiteRenderer {
+        SiteRenderer {
+            certbot_webroot: PathBuf::from("/var/www/certbot"),
+            live_dir: PathBuf::from("/etc/letsencrypt/live"),
+            web_root: PathBuf::from("/var/www/html"),
+            passwd_dir: PathBuf::from("/etc/nginx/tnnl-passwd"),
+            traffic_log_dir: PathBuf::from("/var/log/nginx/tnnl"),
+            viewer_auth: viewer_auth.then(|| "127.0.0.1:8082".parse().unwrap()),
+            client_addr: "127.0.0.1:8083".parse().unwrap(),
+        }
+    }
+
+    fn tunnel(kind: TunnelKind) -> Tunnel {
+        Tunnel {
+            id: Uuid::from_u128(0x5eed),
+            subdomain: "quiet-river".to_string(),
+            user_id: Uuid::from_u128(0x0123),
+            is_custom: false,
+            created_at: chrono::Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap(),
+            port: 10042,
+            password: None,
+            node_id: "node-a".to_string(),
+            kind,
+            public_port: None,
+            label: None,
+            expires_at: None,
+        }
+    }
+
+    /// Compare `rendered` with src/nginx/snapshots/<name>.conf
+    /// Run with UPDATE_SNAPSHOTS=1 to write the snapshots after an intended change
+    fn assert_snapshot(name: &str, rendered: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/nginx/snapshots")
+            .join(format!("{}.conf", name));
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, rendered).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Cannot read snapshot {}: {}", path.display(), e));
+        assert_eq!(rendered, expected, "{} differs from its snapshot", name);
+    }
+
+    #[test]
+    fn test_screen_site_with_password_viewer_auth_and_rate() {
+        let mut tunnel = tunnel(TunnelKind::Screen);
+        tunnel.password = Some("hunter2".to_string());
+        let rendered = renderer(true).tunnel_site("quiet-river.tnnl.to", &tunnel, Some(65536));
+        assert!(!rendered.contains("hunter2"));
+        assert_snapshot("screen_site", &rendered);
+    }
+
+    #[test]
+    fn test_http_site() {
+        let rendered = renderer(false).tunnel_site("quiet-river.tnnl.to", &tunnel(TunnelKind::Http), None);
+        assert_snapshot("http_site", &rendered);
+    }
+
+    #[test]
+    fn test_http_site_on_custom_domain() {
+        let rendered = renderer(false).tunnel_site("app.example.com", &tunnel(TunnelKind::Http), None);
+        assert_snapshot("http_site_custom_domain", &rendered);
+    }
+
+    #[test]
+    fn test_tcp_stream_with_rate() {
+        let mut tunnel = tunnel(TunnelKind::Tcp);
+        tunnel.public_port = Some(20042);
+        let rendered = renderer(false).tcp_stream(&tunnel, Some(65536)).unwrap();
+        assert_snapshot("tcp_stream", &rendered);
+
+        tunnel.public_port = None;
+        assert!(renderer(false).tcp_stream(&tunnel, None).is_err());
+    }
+
+    #[test]
+    fn test_http_only_site() {
+        let renderer = renderer(false);
+        assert_snapshot("http_only_site", &renderer.http_only_site("quiet-river.tnnl.to", None));
+        assert_snapshot(
+            "http_only_site_challenge",
+            &renderer.http_only_site("app.example.com", Some("challenge-token")),
+        );
+    }
+
+    #[test]
+    fn test_offline_site() {
+        assert_snapshot("offline_site", &renderer(false).offline_site("app.example.com"));
+    }
+
+    #[test]
+    fn test_log_formats() {
+        assert_snapshot("http_log_format", &http_log_format());
+        assert_snapshot("stream_log_format", &stream_log_format());
+    }
+}
@@ -0,0 +1,102 @@
+// Tunnel readiness
+// tunnel_assigned goes out as soon as the proxy is configured, before the app
+// has started `ssh -R`. The server then probes the tunnel's loopback port until
+// sshd listens on it, and tells the app the tunnel is ready, or that the forward
+// never came up, in which case the tunnel is closed again
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::tunnel::Tunnel;
+use crate::AppState;
+
+/// Close reason of tunnels whose SSH forward never came up
+pub const FORWARD_FAILED_CLOSE_REASON: &str = "forward_failed";
+
+/// Pause between probes of a port nothing listens on yet
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How waiting for a tunnel's forward ended
+#[derive(Debug, PartialEq, Eq)]
+pub enum Readiness {
+    Ready,
+    TimedOut,
+    /// The tunnel was closed while waiting
+    Closed,
+}
+
+/// Whether something accepts connections on the loopback port
+pub async fn is_listening(port: u16) -> bool {
+    matches!(
+        tokio::time::timeout(Duration::from_secs(1), TcpStream::connect(("127.0.0.1", port))).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Probe the tunnel's port until the forward is up, the timeout passes or the
+/// tunnel goes away
+pub async fn wait_for_forward(state: &AppState, tunnel: &Tunnel, timeout: Duration) -> Readiness {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = state
+            .tunnel_manager
+            .get_tunnel(&tunnel.subdomain)
+            .await
+            .is_some_and(|t| t.id == tunnel.id);
+        if !open {
+            return Readiness::Closed;
+        }
+        if is_listening(tunnel.port).await {
+            return Readiness::Ready;
+        }
+        if Instant::now() >= deadline {
+            return Readiness::TimedOut;
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+/// Wait for a newly assigned tunnel's forward and report the outcome to its app
+pub async fn watch(state: Arc<AppState>, client_id: Uuid, tunnel: Tunnel, url: String) {
+    let timeout = Duration::from_secs(state.config.tunnels.ready_timeout_secs);
+    let outcome = wait_for_forward(&state, &tunnel, timeout).await;
+    let notice = match outcome {
+        Readiness::Ready => {
+            info!("Tunnel {} is ready", tunnel.subdomain);
+            if let Err(e) = state.store.update_tunnel_last_connected(tunnel.id).await {
+                error!("Failed to update last_connected_at for tunnel {}: {}", tunnel.id, e);
+            }
+            serde_json::json!({
+                "type": "tunnel_ready",
+                "id": tunnel.id,
+                "subdomain": tunnel.subdomain,
+                "url": url
+            })
+        }
+        Readiness::TimedOut => {
+            warn!("SSH forward for tunnel {} did not come up, closing it", tunnel.subdomain);
+            serde_json::json!({
+                "type": "tunnel_failed",
+                "id": tunnel.id,
+                "subdomain": tunnel.subdomain,
+                "reason": format!(
+                    "The SSH forward to port {} was not up within {} seconds",
+                    tunnel.port,
+                    timeout.as_secs()
+                )
+            })
+        }
+        Readiness::Closed => return,
+    };
+
+    if let Some(client) = state.clients.read().await.get(&client_id) {
+        let _ = client.sender.send(Message::Text(notice.to_string()));
+    }
+    if outcome == Readiness::TimedOut {
+        crate::force_close_tunnel(&state, &tunnel.subdomain, FORWARD_FAILED_CLOSE_REASON).await;
+    }
+}
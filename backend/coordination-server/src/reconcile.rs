@@ -0,0 +1,422 @@
+// Drift reconciliation
+// Cleanup after a tunnel closes only logs its errors, so a failed reload or
+// certbot call can leave sites, htpasswd files and certificates behind, and a
+// failed database write an open tunnel record. The reconciler periodically
+// compares what should exist (this node's live tunnels, the reserved names and
+// the custom domains in the store) with what the proxy holds and the database
+// has open, fixes the difference and keeps a report for the admin API
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::nginx::{ProxyArtifact, ProxyInventory};
+use crate::tunnel::{Tunnel, TunnelKind};
+use crate::AppState;
+
+/// Close reason of tunnel records closed because no live tunnel backs them
+pub const RECONCILED_CLOSE_REASON: &str = "reconciled";
+
+/// A difference between what should exist and what does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// Proxy config or a certificate for a subdomain no live tunnel holds
+    OrphanTunnel { subdomain: String, artifacts: Vec<ProxyArtifact> },
+    /// A site for a custom domain that has been deleted
+    OrphanDomain { hostname: String },
+    /// A live tunnel missing part of its proxy config
+    MissingConfig { subdomain: String, artifacts: Vec<ProxyArtifact> },
+    /// An open tunnel record with no live tunnel behind it
+    StaleRecord { subdomain: String, tunnel_id: Uuid },
+}
+
+/// A drift and what became of it
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub drift: Drift,
+    /// Always false in dry-run mode
+    pub fixed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcome of one reconciliation pass
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub dry_run: bool,
+    pub findings: Vec<Finding>,
+    /// Why the pass could not compare anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Counts since startup
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ReconcileMetrics {
+    pub runs: u64,
+    pub drift_found: u64,
+    pub drift_fixed: u64,
+    pub fix_failures: u64,
+}
+
+/// Last report and counters, shared by scheduled and on-demand passes
+#[derive(Default)]
+pub struct Reconciler {
+    /// Held for a whole pass so two never run at once
+    running: tokio::sync::Mutex<()>,
+    last: std::sync::Mutex<Option<Report>>,
+    runs: AtomicU64,
+    drift_found: AtomicU64,
+    drift_fixed: AtomicU64,
+    fix_failures: AtomicU64,
+}
+
+impl Reconciler {
+    pub fn last_report(&self) -> Option<Report> {
+        self.last.lock().unwrap().clone()
+    }
+
+    pub fn metrics(&self) -> ReconcileMetrics {
+        ReconcileMetrics {
+            runs: self.runs.load(Ordering::Relaxed),
+            drift_found: self.drift_found.load(Ordering::Relaxed),
+            drift_fixed: self.drift_fixed.load(Ordering::Relaxed),
+            fix_failures: self.fix_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the proxy holds for a live tunnel
+fn expected_artifacts(tunnel: &Tunnel) -> Vec<ProxyArtifact> {
+    match tunnel.kind {
+        TunnelKind::Tcp => vec![ProxyArtifact::Stream],
+        TunnelKind::Screen | TunnelKind::Http => {
+            let mut expected = vec![ProxyArtifact::Site, ProxyArtifact::Certificate];
+            if tunnel.password.is_some() {
+                expected.push(ProxyArtifact::Htpasswd);
+            }
+            expected
+        }
+    }
+}
+
+/// Drift between the live tunnels and what the proxy and database hold
+/// `known_domains` are the inventory's custom domains the store still has;
+/// tunnels created after `settled_before` may still be being set up, so they
+/// are not checked for missing config
+pub fn plan(
+    live: &[Tunnel],
+    inventory: &ProxyInventory,
+    open_records: &[Tunnel],
+    known_domains: &HashSet<String>,
+    is_reserved: impl Fn(&str) -> bool,
+    settled_before: DateTime<Utc>,
+) -> Vec<Drift> {
+    let live_by_name: HashMap<&str, &Tunnel> = live.iter().map(|t| (t.subdomain.as_str(), t)).collect();
+    let mut drift = Vec::new();
+
+    for (subdomain, artifacts) in &inventory.tunnels {
+        if !live_by_name.contains_key(subdomain.as_str()) && !is_reserved(subdomain) {
+            drift.push(Drift::OrphanTunnel {
+                subdomain: subdomain.clone(),
+                artifacts: artifacts.iter().copied().collect(),
+            });
+        }
+    }
+
+    for hostname in inventory.custom_domains.iter().filter(|h| !known_domains.contains(*h)) {
+        drift.push(Drift::OrphanDomain { hostname: hostname.clone() });
+    }
+
+    for tunnel in live.iter().filter(|t| t.created_at <= settled_before) {
+        let found = inventory.tunnels.get(&tunnel.subdomain);
+        let missing: Vec<ProxyArtifact> = expected_artifacts(tunnel)
+            .into_iter()
+            .filter(|a| !found.is_some_and(|found| found.contains(a)))
+            .collect();
+        if !missing.is_empty() {
+            drift.push(Drift::MissingConfig {
+                subdomain: tunnel.subdomain.clone(),
+                artifacts: missing,
+            });
+        }
+    }
+
+    for record in open_records {
+        if live_by_name.get(record.subdomain.as_str()).is_none_or(|t| t.id != record.id) {
+            drift.push(Drift::StaleRecord {
+                subdomain: record.subdomain.clone(),
+                tunnel_id: record.id,
+            });
+        }
+    }
+
+    drift
+}
+
+/// Reconcile until the server stops
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.reconciler.interval_secs));
+    loop {
+        interval.tick().await;
+        reconcile(&state, state.config.reconciler.dry_run).await;
+    }
+}
+
+/// Compare what should exist with what does and, unless `dry_run`, fix the
+/// difference; the report is kept for the admin API
+pub async fn reconcile(state: &Arc<AppState>, dry_run: bool) -> Report {
+    let _running = state.reconciler.running.lock().await;
+    let started_at = Utc::now();
+
+    let (findings, error) = match find_drift(state, started_at).await {
+        Ok(drift) => {
+            let mut findings = Vec::new();
+            for drift in drift {
+                findings.push(settle(state, drift, dry_run).await);
+            }
+            (findings, None)
+        }
+        Err(e) => {
+            error!("Reconciliation failed: {}", e);
+            (Vec::new(), Some(e.to_string()))
+        }
+    };
+
+    let reconciler = &state.reconciler;
+    reconciler.runs.fetch_add(1, Ordering::Relaxed);
+    reconciler.drift_found.fetch_add(findings.len() as u64, Ordering::Relaxed);
+    let fixed = findings.iter().filter(|f| f.fixed).count() as u64;
+    let failed = findings.iter().filter(|f| f.error.is_some()).count() as u64;
+    reconciler.drift_fixed.fetch_add(fixed, Ordering::Relaxed);
+    reconciler.fix_failures.fetch_add(failed, Ordering::Relaxed);
+    if !findings.is_empty() {
+        info!(
+            "Reconciliation found {} drift(s): {} fixed, {} failed{}",
+            findings.len(),
+            fixed,
+            failed,
+            if dry_run { " (dry run)" } else { "" }
+        );
+    }
+
+    let report = Report {
+        started_at,
+        finished_at: Utc::now(),
+        dry_run,
+        findings,
+        error,
+    };
+    *reconciler.last.lock().unwrap() = Some(report.clone());
+    report
+}
+
+/// Gather both sides and compare them
+async fn find_drift(state: &Arc<AppState>, now: DateTime<Utc>) -> anyhow::Result<Vec<Drift>> {
+    // What exists is read before what should: a tunnel created in between is
+    // then already live when its config is looked at, never taken for an orphan
+    let inventory = state.proxy.inventory().await?;
+    let open_records = state.store.get_open_tunnels(state.config.cluster.node_id()).await?;
+    let mut known_domains = HashSet::new();
+    for hostname in &inventory.custom_domains {
+        if state.store.get_custom_domain(hostname).await?.is_some() {
+            known_domains.insert(hostname.clone());
+        }
+    }
+    let live = state.tunnel_manager.list_tunnels().await;
+
+    let settled_before = now - chrono::Duration::seconds(state.config.reconciler.grace_secs as i64);
+    Ok(plan(
+        &live,
+        &inventory,
+        &open_records,
+        &known_domains,
+        |subdomain| state.tunnel_manager.is_reserved(subdomain),
+        settled_before,
+    ))
+}
+
+/// Fix a drift unless in dry-run mode
+async fn settle(state: &Arc<AppState>, drift: Drift, dry_run: bool) -> Finding {
+    warn!("Reconciliation found drift: {:?}", drift);
+    if dry_run {
+        return Finding {
+            drift,
+            fixed: false,
+            error: None,
+        };
+    }
+
+    match fix(state, &drift).await {
+        Ok(fixed) => Finding {
+            drift,
+            fixed,
+            error: None,
+        },
+        Err(e) => {
+            error!("Failed to fix {:?}: {}", drift, e);
+            Finding {
+                drift,
+                fixed: false,
+                error: Some(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Fix a drift, checking first that it is still there; false when it went away
+async fn fix(state: &Arc<AppState>, drift: &Drift) -> anyhow::Result<bool> {
+    match drift {
+        Drift::OrphanTunnel { subdomain, .. } => {
+            if state.tunnel_manager.get_tunnel(subdomain).await.is_some() {
+                return Ok(false);
+            }
+            state.proxy.remove_tunnel_config(subdomain).await?;
+        }
+        Drift::OrphanDomain { hostname } => {
+            if state.store.get_custom_domain(hostname).await?.is_some() {
+                return Ok(false);
+            }
+            state.proxy.remove_custom_domain(hostname).await?;
+        }
+        Drift::MissingConfig { subdomain, .. } => {
+            let Some(tunnel) = state.tunnel_manager.get_tunnel(subdomain).await else {
+                return Ok(false);
+            };
+            state.proxy.create_tunnel_config(&tunnel).await?;
+        }
+        Drift::StaleRecord { subdomain, tunnel_id } => {
+            if state.tunnel_manager.get_tunnel(subdomain).await.is_some_and(|t| t.id == *tunnel_id) {
+                return Ok(false);
+            }
+            let Some(record) = state.store.get_tunnel_by_subdomain(subdomain).await? else {
+                return Ok(false);
+            };
+            if record.id != *tunnel_id {
+                return Ok(false);
+            }
+            state.store.close_tunnel_record(&record, RECONCILED_CLOSE_REASON).await?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::nginx;
+
+    fn tunnel(subdomain: &str, kind: TunnelKind, created_at: DateTime<Utc>) -> Tunnel {
+        Tunnel {
+            id: Uuid::new_v4(),
+            subdomain: subdomain.to_string(),
+            user_id: Uuid::new_v4(),
+            is_custom: false,
+            created_at,
+            port: 10000,
+            password: None,
+            node_id: "local".to_string(),
+            kind,
+            public_port: None,
+            label: None,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_finds_every_kind_of_drift() {
+        let now = Utc::now();
+        let old = now - chrono::Duration::hours(1);
+        let settled = tunnel("calm-elk-1234", TunnelKind::Screen, old);
+        let fresh = tunnel("brisk-owl-1234", TunnelKind::Http, now);
+        let tcp = tunnel("quiet-bee-1234", TunnelKind::Tcp, old);
+        let gone = tunnel("lost-fox-1234", TunnelKind::Http, old);
+
+        let mut inventory = ProxyInventory::default();
+        inventory.add("calm-elk-1234", ProxyArtifact::Site);
+        inventory.add("quiet-bee-1234", ProxyArtifact::Stream);
+        inventory.add("lost-fox-1234", ProxyArtifact::Htpasswd);
+        inventory.add("lost-fox-1234", ProxyArtifact::Certificate);
+        inventory.add("status", ProxyArtifact::Site);
+        inventory.custom_domains.insert("app.example.com".to_string());
+        inventory.custom_domains.insert("old.example.com".to_string());
+        let known = HashSet::from(["app.example.com".to_string()]);
+
+        let live = [settled.clone(), fresh, tcp.clone()];
+        let records = [settled, gone.clone(), tcp];
+        let drift = plan(&live, &inventory, &records, &known, |s| s == "status", now - chrono::Duration::minutes(2));
+
+        assert_eq!(
+            drift,
+            vec![
+                Drift::OrphanTunnel {
+                    subdomain: "lost-fox-1234".to_string(),
+                    artifacts: vec![ProxyArtifact::Htpasswd, ProxyArtifact::Certificate],
+                },
+                Drift::OrphanDomain { hostname: "old.example.com".to_string() },
+                Drift::MissingConfig {
+                    subdomain: "calm-elk-1234".to_string(),
+                    artifacts: vec![ProxyArtifact::Certificate],
+                },
+                Drift::StaleRecord {
+                    subdomain: "lost-fox-1234".to_string(),
+                    tunnel_id: gone.id,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconciler_removes_what_a_failed_teardown_left() {
+        let (state, proxy) = test_state();
+        let (_, _live_rx, live) = authed_tunnel(&state, serde_json::json!({})).await;
+        let (_, _lost_rx, lost) = authed_tunnel(&state, serde_json::json!({})).await;
+        let (live, lost) = (&live.subdomain, &lost.subdomain);
+
+        // The second tunnel is gone, but removing its config and closing its record failed
+        state.tunnel_manager.remove_tunnel(lost).await.unwrap();
+        {
+            let mut inventory = proxy.inventory.lock().unwrap();
+            inventory.add(live, nginx::ProxyArtifact::Site);
+            inventory.add(lost, nginx::ProxyArtifact::Site);
+            inventory.add(lost, nginx::ProxyArtifact::Htpasswd);
+        }
+
+        // A dry run only reports
+        let report = reconcile(&state, true).await;
+        let kinds: Vec<_> = report
+            .findings
+            .iter()
+            .map(|f| serde_json::to_value(f).unwrap())
+            .map(|f| (f["kind"].as_str().unwrap().to_string(), f["subdomain"].as_str().unwrap().to_string(), f["fixed"].clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("orphan_tunnel".to_string(), lost.clone(), serde_json::json!(false)),
+                ("stale_record".to_string(), lost.clone(), serde_json::json!(false)),
+            ]
+        );
+        assert!(proxy.removed.lock().unwrap().is_empty());
+        assert!(state.store.get_tunnel_by_subdomain(lost).await.unwrap().is_some());
+
+        let report = reconcile(&state, false).await;
+        assert!(report.findings.iter().all(|f| f.fixed));
+        assert_eq!(*proxy.removed.lock().unwrap(), vec![lost.clone()]);
+        assert!(state.store.get_tunnel_by_subdomain(lost).await.unwrap().is_none());
+        assert!(state.store.get_tunnel_by_subdomain(live).await.unwrap().is_some());
+
+        let metrics = state.reconciler.metrics();
+        assert_eq!((metrics.runs, metrics.drift_found, metrics.drift_fixed), (2, 4, 2));
+        assert!(!state.reconciler.last_report().unwrap().dry_run);
+    }
+}
@@ -7,9 +7,6 @@ use tokio::fs;
 #[allow(unused_imports)]
 use tokio::io::AsyncWriteExt;
 
-#[allow(dead_code)]
-const AUTHORIZED_KEYS_PATH: &str = "/home/tnnl/.ssh/authorized_keys";
-
 /// Validate SSH public key format
 /// Returns true if the key appears to be a valid SSH public key
 pub fn validate_ssh_public_key(key: &str) -> Result<()> {
@@ -42,23 +39,30 @@ pub fn validate_ssh_public_key(key: &str) -> Result<()> {
     Ok(())
 }
 
+/// Get the key type (e.g. "ssh-ed25519") of an SSH public key
+/// Used where the key itself should not be recorded
+pub fn key_type(key: &str) -> &str {
+    key.split_whitespace().next().unwrap_or("")
+}
+
 /// Add SSH public key to authorized_keys file
 /// This allows the user to establish SSH tunnels
-pub async fn add_ssh_key_to_authorized_keys(public_key: &str) -> Result<()> {
+#[allow(clippy::needless_return)]
+pub async fn add_ssh_key_to_authorized_keys(authorized_keys_path: &Path, public_key: &str) -> Result<()> {
     // Validate key first
     validate_ssh_public_key(public_key)?;
 
     // In development mode, skip actual file operations
     #[cfg(debug_assertions)]
     {
-        println!("[Dev Mode] Would add SSH key to authorized_keys: {}", public_key);
+        println!("[Dev Mode] Would add SSH key to {}: {}", authorized_keys_path.display(), public_key);
         return Ok(());
     }
 
     #[cfg(not(debug_assertions))]
     {
         // Ensure the .ssh directory exists
-        let ssh_dir = Path::new(AUTHORIZED_KEYS_PATH).parent()
+        let ssh_dir = authorized_keys_path.parent()
             .ok_or_else(|| anyhow!("Invalid authorized_keys path"))?;
 
         if !ssh_dir.exists() {
@@ -72,8 +76,8 @@ pub async fn add_ssh_key_to_authorized_keys(public_key: &str) -> Result<()> {
         }
 
         // Read existing authorized_keys if it exists
-        let existing_keys = if Path::new(AUTHORIZED_KEYS_PATH).exists() {
-            fs::read_to_string(AUTHORIZED_KEYS_PATH).await?
+        let existing_keys = if authorized_keys_path.exists() {
+            fs::read_to_string(authorized_keys_path).await?
         } else {
             String::new()
         };
@@ -88,7 +92,7 @@ pub async fn add_ssh_key_to_authorized_keys(public_key: &str) -> Result<()> {
         let mut file = fs::OpenOptions::new()
             .create(true)
             .append(true)
-            .open(AUTHORIZED_KEYS_PATH)
+            .open(authorized_keys_path)
             .await?;
 
         // Ensure there's a newline before the key if file isn't empty
@@ -105,7 +109,7 @@ pub async fn add_ssh_key_to_authorized_keys(public_key: &str) -> Result<()> {
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(AUTHORIZED_KEYS_PATH, std::fs::Permissions::from_mode(0o600)).await?;
+            fs::set_permissions(authorized_keys_path, std::fs::Permissions::from_mode(0o600)).await?;
         }
 
         Ok(())
@@ -113,25 +117,25 @@ pub async fn add_ssh_key_to_authorized_keys(public_key: &str) -> Result<()> {
 }
 
 /// Remove SSH public key from authorized_keys file
-/// Used for cleanup when a user is deleted
-#[allow(dead_code)]
-pub async fn remove_ssh_key_from_authorized_keys(public_key: &str) -> Result<()> {
+/// Used when a user revokes their key
+#[allow(clippy::needless_return)]
+pub async fn remove_ssh_key_from_authorized_keys(authorized_keys_path: &Path, public_key: &str) -> Result<()> {
     // In development mode, skip actual file operations
     #[cfg(debug_assertions)]
     {
-        println!("[Dev Mode] Would remove SSH key from authorized_keys: {}", public_key);
+        println!("[Dev Mode] Would remove SSH key from {}: {}", authorized_keys_path.display(), public_key);
         return Ok(());
     }
 
     #[cfg(not(debug_assertions))]
     {
-        if !Path::new(AUTHORIZED_KEYS_PATH).exists() {
+        if !authorized_keys_path.exists() {
             // File doesn't exist, nothing to remove
             return Ok(());
         }
 
         // Read all keys
-        let contents = fs::read_to_string(AUTHORIZED_KEYS_PATH).await?;
+        let contents = fs::read_to_string(authorized_keys_path).await?;
 
         // Filter out the key to remove
         let new_contents: String = contents
@@ -141,7 +145,7 @@ pub async fn remove_ssh_key_from_authorized_keys(public_key: &str) -> Result<()>
             .join("\n");
 
         // Write back the filtered keys
-        fs::write(AUTHORIZED_KEYS_PATH, new_contents.as_bytes()).await?;
+        fs::write(authorized_keys_path, new_contents.as_bytes()).await?;
 
         Ok(())
     }
@@ -154,8 +158,8 @@ mod tests {
     #[test]
     fn test_validate_ssh_public_key() {
         // Valid keys
-        assert!(validate_ssh_public_key("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC... user@host").is_ok());
-        assert!(validate_ssh_public_key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMv... user@host").is_ok());
+        assert!(validate_ssh_public_key("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC7vbqajDhA0sP2Yx4kq9r3nFz8wLmT1eXcVbN5uJhG2aKdRsWpQ user@host").is_ok());
+        assert!(validate_ssh_public_key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMvFqP9nC2xT8rJbKdL4wYz0aHs6eUgVtRm3oNpQiXyB user@host").is_ok());
 
         // Invalid keys
         assert!(validate_ssh_public_key("").is_err());
@@ -163,4 +167,11 @@ mod tests {
         assert!(validate_ssh_public_key("ssh-rsa").is_err()); // Too short
         assert!(validate_ssh_public_key("invalid-prefix AAAAB3NzaC1yc2E...").is_err());
     }
+
+    #[test]
+    fn test_key_type() {
+        assert_eq!(key_type("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMv... user@host"), "ssh-ed25519");
+        assert_eq!(key_type("  ssh-rsa AAAAB3NzaC1yc2E"), "ssh-rsa");
+        assert_eq!(key_type(""), "");
+    }
 }
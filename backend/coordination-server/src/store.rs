@@ -0,0 +1,987 @@
+// Storage abstraction over users, tunnels, SSH keys, custom domains, cluster
+// nodes, traffic counters and the audit log
+// The SQL backends live in db.rs; MemoryStore keeps everything in process
+// for tests and throwaway dev servers (DATABASE_URL=memory:)
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::bandwidth::{TrafficTotals, ViewerTraffic};
+use crate::cluster::ClusterNode;
+use crate::db::{self, DbPool};
+use crate::domains::CustomDomain;
+use crate::tunnel::{Tunnel, TunnelHistoryEntry};
+use crate::webhooks::{Webhook, WebhookDelivery};
+
+#[async_trait]
+pub trait TunnelStore: Send + Sync {
+    /// Get or create a user, refreshing their email
+    async fn get_or_create_user(&self, user_id: Uuid, email: &str) -> Result<Uuid>;
+
+    /// Create an active tunnel record
+    /// Fails if another active tunnel holds the subdomain
+    async fn create_tunnel_record(&self, tunnel: &Tunnel) -> Result<()>;
+
+    /// Get the active tunnel holding a subdomain, on any node
+    async fn get_tunnel_by_subdomain(&self, subdomain: &str) -> Result<Option<Tunnel>>;
+
+    /// Mark a tunnel as closed, keeping it as session history
+    async fn close_tunnel_record(&self, tunnel: &Tunnel, reason: &str) -> Result<()>;
+
+    /// Close the active tunnels of a node, left by its previous process or by
+    /// a node that died
+    async fn close_stale_tunnel_records(&self, node_id: &str, reason: &str) -> Result<usize>;
+
+    /// Active tunnels of a node that expire before `before`, soonest first
+    async fn get_expiring_tunnels(&self, node_id: &str, before: chrono::DateTime<chrono::Utc>) -> Result<Vec<Tunnel>>;
+
+    /// Active tunnels of a node, oldest first
+    async fn get_open_tunnels(&self, node_id: &str) -> Result<Vec<Tunnel>>;
+
+    /// Record that the host of an active tunnel was seen
+    async fn update_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<()>;
+
+    /// When the host of a tunnel (active or closed) was last seen, if ever
+    async fn get_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<Option<chrono::DateTime<chrono::Utc>>>;
+
+    /// Count a viewer session against an active tunnel
+    async fn increment_viewer_sessions(&self, tunnel_id: Uuid) -> Result<()>;
+
+    /// Get a user's tunnel sessions, newest first
+    async fn get_user_tunnels(&self, user_id: Uuid, limit: i64) -> Result<Vec<TunnelHistoryEntry>>;
+
+    /// Add proxied bytes to a tunnel (active or closed) and to its owner's
+    /// total for `month`; returns the owner, or None for an unknown tunnel
+    async fn add_tunnel_traffic(&self, tunnel_id: Uuid, month: &str, traffic: TrafficTotals) -> Result<Option<Uuid>>;
+
+    /// Get a user's total traffic for `month` ("YYYY-MM")
+    async fn get_user_traffic(&self, user_id: Uuid, month: &str) -> Result<TrafficTotals>;
+
+    /// Record a finished viewer connection
+    async fn record_viewer_traffic(&self, viewer: &ViewerTraffic) -> Result<()>;
+
+    /// Get the viewer connections of one of a user's tunnels, newest first
+    async fn get_viewer_traffic(&self, user_id: Uuid, tunnel_id: Uuid, limit: i64) -> Result<Vec<ViewerTraffic>>;
+
+    /// Get a user's registered SSH public key
+    #[allow(dead_code)]
+    async fn get_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>>;
+
+    /// Store (or replace) a user's SSH public key
+    async fn store_ssh_public_key(&self, user_id: Uuid, ssh_public_key: &str) -> Result<()>;
+
+    /// Remove a user's SSH public key, returning the removed key
+    async fn clear_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>>;
+
+    /// Claim a custom domain
+    /// Fails if the hostname is already claimed
+    async fn create_custom_domain(&self, domain: &CustomDomain) -> Result<()>;
+
+    /// Get the claim on a hostname, whoever holds it
+    async fn get_custom_domain(&self, hostname: &str) -> Result<Option<CustomDomain>>;
+
+    /// Get a user's custom domains, oldest first
+    async fn list_user_custom_domains(&self, user_id: Uuid) -> Result<Vec<CustomDomain>>;
+
+    /// Record that ownership of a custom domain was proven
+    async fn mark_custom_domain_verified(&self, domain_id: Uuid) -> Result<()>;
+
+    /// Point a custom domain at the tunnels with this reserved subdomain or label
+    async fn set_custom_domain_target(&self, domain_id: Uuid, target: Option<&str>) -> Result<()>;
+
+    /// Release a custom domain claim
+    async fn delete_custom_domain(&self, domain_id: Uuid) -> Result<()>;
+
+    /// Register a webhook endpoint
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()>;
+
+    /// Get a user's webhooks, oldest first
+    async fn list_user_webhooks(&self, user_id: Uuid) -> Result<Vec<Webhook>>;
+
+    /// Remove one of a user's webhooks and its delivery log
+    /// Returns false if the user has no such webhook
+    async fn delete_webhook(&self, user_id: Uuid, webhook_id: Uuid) -> Result<bool>;
+
+    /// Log a delivery attempt
+    async fn record_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()>;
+
+    /// Get the delivery log of one of a user's webhooks, newest first
+    async fn get_webhook_deliveries(&self, user_id: Uuid, webhook_id: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>>;
+
+    /// Register a node, or refresh it when it restarts under the same ID
+    async fn register_node(&self, node: &ClusterNode) -> Result<()>;
+
+    /// Record that a node is alive
+    async fn heartbeat_node(&self, node_id: &str) -> Result<()>;
+
+    /// Get every registered node, live or not
+    async fn list_cluster_nodes(&self) -> Result<Vec<ClusterNode>>;
+
+    /// Remove a node from the registry
+    async fn remove_node(&self, node_id: &str) -> Result<()>;
+
+    /// Append an event to the audit log
+    async fn insert_audit_event(&self, event: &AuditEvent) -> Result<()>;
+
+    /// Query the audit log, newest first
+    async fn query_audit_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>>;
+}
+
+/// Open the store for a database URL
+/// `memory:` keeps all state in process; anything else goes to db::init_pool
+pub async fn open(database_url: &str) -> Result<Box<dyn TunnelStore>> {
+    if database_url == "memory:" {
+        Ok(Box::new(MemoryStore::new()))
+    } else {
+        Ok(Box::new(db::init_pool(database_url).await?))
+    }
+}
+
+#[async_trait]
+impl TunnelStore for DbPool {
+    async fn get_or_create_user(&self, user_id: Uuid, email: &str) -> Result<Uuid> {
+        db::get_or_create_user(self, user_id, email).await
+    }
+
+    async fn create_tunnel_record(&self, tunnel: &Tunnel) -> Result<()> {
+        db::create_tunnel_record(self, tunnel).await
+    }
+
+    async fn get_tunnel_by_subdomain(&self, subdomain: &str) -> Result<Option<Tunnel>> {
+        db::get_tunnel_by_subdomain(self, subdomain).await
+    }
+
+    async fn close_tunnel_record(&self, tunnel: &Tunnel, reason: &str) -> Result<()> {
+        db::close_tunnel_record(self, tunnel, reason).await
+    }
+
+    async fn close_stale_tunnel_records(&self, node_id: &str, reason: &str) -> Result<usize> {
+        db::close_stale_tunnel_records(self, node_id, reason).await
+    }
+
+    async fn get_expiring_tunnels(&self, node_id: &str, before: chrono::DateTime<chrono::Utc>) -> Result<Vec<Tunnel>> {
+        db::get_expiring_tunnels(self, node_id, before).await
+    }
+
+    async fn get_open_tunnels(&self, node_id: &str) -> Result<Vec<Tunnel>> {
+        db::get_open_tunnels(self, node_id).await
+    }
+
+    async fn update_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<()> {
+        db::update_tunnel_last_connected(self, tunnel_id).await
+    }
+
+    async fn get_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        db::get_tunnel_last_connected(self, tunnel_id).await
+    }
+
+    async fn increment_viewer_sessions(&self, tunnel_id: Uuid) -> Result<()> {
+        db::increment_viewer_sessions(self, tunnel_id).await
+    }
+
+    async fn get_user_tunnels(&self, user_id: Uuid, limit: i64) -> Result<Vec<TunnelHistoryEntry>> {
+        db::get_user_tunnels(self, user_id, limit).await
+    }
+
+    async fn add_tunnel_traffic(&self, tunnel_id: Uuid, month: &str, traffic: TrafficTotals) -> Result<Option<Uuid>> {
+        db::add_tunnel_traffic(self, tunnel_id, month, traffic).await
+    }
+
+    async fn get_user_traffic(&self, user_id: Uuid, month: &str) -> Result<TrafficTotals> {
+        db::get_user_traffic(self, user_id, month).await
+    }
+
+    async fn record_viewer_traffic(&self, viewer: &ViewerTraffic) -> Result<()> {
+        db::record_viewer_traffic(self, viewer).await
+    }
+
+    async fn get_viewer_traffic(&self, user_id: Uuid, tunnel_id: Uuid, limit: i64) -> Result<Vec<ViewerTraffic>> {
+        db::get_viewer_traffic(self, user_id, tunnel_id, limit).await
+    }
+
+    async fn get_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>> {
+        db::get_ssh_public_key(self, user_id).await
+    }
+
+    async fn store_ssh_public_key(&self, user_id: Uuid, ssh_public_key: &str) -> Result<()> {
+        db::store_ssh_public_key(self, user_id, ssh_public_key).await
+    }
+
+    async fn clear_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>> {
+        db::clear_ssh_public_key(self, user_id).await
+    }
+
+    async fn create_custom_domain(&self, domain: &CustomDomain) -> Result<()> {
+        db::create_custom_domain(self, domain).await
+    }
+
+    async fn get_custom_domain(&self, hostname: &str) -> Result<Option<CustomDomain>> {
+        db::get_custom_domain(self, hostname).await
+    }
+
+    async fn list_user_custom_domains(&self, user_id: Uuid) -> Result<Vec<CustomDomain>> {
+        db::list_user_custom_domains(self, user_id).await
+    }
+
+    async fn mark_custom_domain_verified(&self, domain_id: Uuid) -> Result<()> {
+        db::mark_custom_domain_verified(self, domain_id).await
+    }
+
+    async fn set_custom_domain_target(&self, domain_id: Uuid, target: Option<&str>) -> Result<()> {
+        db::set_custom_domain_target(self, domain_id, target).await
+    }
+
+    async fn delete_custom_domain(&self, domain_id: Uuid) -> Result<()> {
+        db::delete_custom_domain(self, domain_id).await
+    }
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        db::create_webhook(self, webhook).await
+    }
+
+    async fn list_user_webhooks(&self, user_id: Uuid) -> Result<Vec<Webhook>> {
+        db::list_user_webhooks(self, user_id).await
+    }
+
+    async fn delete_webhook(&self, user_id: Uuid, webhook_id: Uuid) -> Result<bool> {
+        db::delete_webhook(self, user_id, webhook_id).await
+    }
+
+    async fn record_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        db::record_webhook_delivery(self, delivery).await
+    }
+
+    async fn get_webhook_deliveries(&self, user_id: Uuid, webhook_id: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>> {
+        db::get_webhook_deliveries(self, user_id, webhook_id, limit).await
+    }
+
+    async fn register_node(&self, node: &ClusterNode) -> Result<()> {
+        db::register_node(self, node).await
+    }
+
+    async fn heartbeat_node(&self, node_id: &str) -> Result<()> {
+        db::heartbeat_node(self, node_id).await
+    }
+
+    async fn list_cluster_nodes(&self) -> Result<Vec<ClusterNode>> {
+        db::list_cluster_nodes(self).await
+    }
+
+    async fn remove_node(&self, node_id: &str) -> Result<()> {
+        db::remove_node(self, node_id).await
+    }
+
+    async fn insert_audit_event(&self, event: &AuditEvent) -> Result<()> {
+        db::insert_audit_event(self, event).await
+    }
+
+    async fn query_audit_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+        db::query_audit_events(self, filter).await
+    }
+}
+
+/// A tunnel row as kept by MemoryStore
+#[derive(Debug, Clone)]
+struct TunnelRecord {
+    tunnel: Tunnel,
+    password_protected: bool,
+    last_connected_at: Option<chrono::DateTime<chrono::Utc>>,
+    closed_at: Option<chrono::DateTime<chrono::Utc>>,
+    close_reason: Option<String>,
+    duration_seconds: Option<i64>,
+    viewer_sessions: i32,
+    traffic: TrafficTotals,
+}
+
+impl TunnelRecord {
+    fn is_active(&self) -> bool {
+        self.closed_at.is_none()
+    }
+
+    fn history_entry(&self) -> TunnelHistoryEntry {
+        TunnelHistoryEntry {
+            id: self.tunnel.id,
+            subdomain: self.tunnel.subdomain.clone(),
+            label: self.tunnel.label.clone(),
+            is_custom: self.tunnel.is_custom,
+            kind: self.tunnel.kind,
+            port: self.tunnel.port,
+            node_id: self.tunnel.node_id.clone(),
+            password_protected: self.password_protected,
+            created_at: self.tunnel.created_at,
+            expires_at: self.tunnel.expires_at,
+            last_connected_at: self.last_connected_at,
+            closed_at: self.closed_at,
+            close_reason: self.close_reason.clone(),
+            duration_seconds: self.duration_seconds,
+            viewer_sessions: self.viewer_sessions,
+            bytes_in: self.traffic.bytes_in,
+            bytes_out: self.traffic.bytes_out,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemoryData {
+    users: HashMap<Uuid, String>,             // user_id -> email
+    ssh_keys: HashMap<Uuid, String>,          // user_id -> public key
+    tunnels: Vec<TunnelRecord>,
+    custom_domains: Vec<CustomDomain>,
+    nodes: Vec<ClusterNode>,
+    audit_events: Vec<AuditEvent>,
+    user_traffic: HashMap<(Uuid, String), TrafficTotals>, // (user_id, month) -> totals
+    viewer_traffic: Vec<ViewerTraffic>,
+    webhooks: Vec<Webhook>,
+    webhook_deliveries: Vec<WebhookDelivery>,
+}
+
+/// In-process store with the same semantics as the SQL backends
+/// Nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<MemoryData>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TunnelStore for MemoryStore {
+    async fn get_or_create_user(&self, user_id: Uuid, email: &str) -> Result<Uuid> {
+        let mut data = self.data.write().await;
+        data.users.insert(user_id, email.to_string());
+        Ok(user_id)
+    }
+
+    async fn create_tunnel_record(&self, tunnel: &Tunnel) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.users.contains_key(&tunnel.user_id) {
+            return Err(anyhow!("Unknown user {}", tunnel.user_id));
+        }
+        if data
+            .tunnels
+            .iter()
+            .any(|r| r.is_active() && r.tunnel.subdomain == tunnel.subdomain)
+        {
+            return Err(anyhow!("Subdomain {} is already in use", tunnel.subdomain));
+        }
+        if tunnel.public_port.is_some()
+            && data.tunnels.iter().any(|r| {
+                r.is_active() && r.tunnel.node_id == tunnel.node_id && r.tunnel.public_port == tunnel.public_port
+            })
+        {
+            return Err(anyhow!("Public port {:?} is already in use", tunnel.public_port));
+        }
+
+        data.tunnels.push(TunnelRecord {
+            tunnel: tunnel.clone(),
+            password_protected: tunnel.password.is_some(),
+            last_connected_at: None,
+            closed_at: None,
+            close_reason: None,
+            duration_seconds: None,
+            viewer_sessions: 0,
+            traffic: TrafficTotals::default(),
+        });
+        Ok(())
+    }
+
+    async fn get_tunnel_by_subdomain(&self, subdomain: &str) -> Result<Option<Tunnel>> {
+        let data = self.data.read().await;
+        Ok(data
+            .tunnels
+            .iter()
+            .find(|r| r.is_active() && r.tunnel.subdomain == subdomain)
+            .map(|r| r.tunnel.clone()))
+    }
+
+    async fn close_tunnel_record(&self, tunnel: &Tunnel, reason: &str) -> Result<()> {
+        let closed_at = chrono::Utc::now();
+        let mut data = self.data.write().await;
+        if let Some(record) = data
+            .tunnels
+            .iter_mut()
+            .find(|r| r.is_active() && r.tunnel.id == tunnel.id)
+        {
+            record.closed_at = Some(closed_at);
+            record.close_reason = Some(reason.to_string());
+            record.duration_seconds = Some((closed_at - record.tunnel.created_at).num_seconds().max(0));
+            record.tunnel.password = None;
+        }
+        Ok(())
+    }
+
+    async fn close_stale_tunnel_records(&self, node_id: &str, reason: &str) -> Result<usize> {
+        let active: Vec<Tunnel> = {
+            let data = self.data.read().await;
+            data.tunnels
+                .iter()
+                .filter(|r| r.is_active() && r.tunnel.node_id == node_id)
+                .map(|r| r.tunnel.clone())
+                .collect()
+        };
+
+        for tunnel in &active {
+            self.close_tunnel_record(tunnel, reason).await?;
+        }
+
+        Ok(active.len())
+    }
+
+    async fn get_expiring_tunnels(&self, node_id: &str, before: chrono::DateTime<chrono::Utc>) -> Result<Vec<Tunnel>> {
+        let data = self.data.read().await;
+        let mut tunnels: Vec<Tunnel> = data
+            .tunnels
+            .iter()
+            .filter(|r| r.is_active() && r.tunnel.node_id == node_id)
+            .filter(|r| r.tunnel.expires_at.is_some_and(|at| at < before))
+            .map(|r| r.tunnel.clone())
+            .collect();
+        tunnels.sort_by_key(|t| t.expires_at);
+        Ok(tunnels)
+    }
+
+    async fn get_open_tunnels(&self, node_id: &str) -> Result<Vec<Tunnel>> {
+        let data = self.data.read().await;
+        let mut tunnels: Vec<Tunnel> = data
+            .tunnels
+            .iter()
+            .filter(|r| r.is_active() && r.tunnel.node_id == node_id)
+            .map(|r| r.tunnel.clone())
+            .collect();
+        tunnels.sort_by_key(|t| t.created_at);
+        Ok(tunnels)
+    }
+
+    async fn update_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<()> {
+        let mut data = self.data.write().await;
+        if let Some(record) = data
+            .tunnels
+            .iter_mut()
+            .find(|r| r.is_active() && r.tunnel.id == tunnel_id)
+        {
+            record.last_connected_at = Some(chrono::Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn get_tunnel_last_connected(&self, tunnel_id: Uuid) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let data = self.data.read().await;
+        Ok(data
+            .tunnels
+            .iter()
+            .find(|r| r.tunnel.id == tunnel_id)
+            .and_then(|r| r.last_connected_at))
+    }
+
+    async fn increment_viewer_sessions(&self, tunnel_id: Uuid) -> Result<()> {
+        let mut data = self.data.write().await;
+        if let Some(record) = data
+            .tunnels
+            .iter_mut()
+            .find(|r| r.is_active() && r.tunnel.id == tunnel_id)
+        {
+            record.viewer_sessions += 1;
+        }
+        Ok(())
+    }
+
+    async fn get_user_tunnels(&self, user_id: Uuid, limit: i64) -> Result<Vec<TunnelHistoryEntry>> {
+        let data = self.data.read().await;
+        let mut tunnels: Vec<TunnelHistoryEntry> = data
+            .tunnels
+            .iter()
+            .filter(|r| r.tunnel.user_id == user_id)
+            .map(TunnelRecord::history_entry)
+            .collect();
+        tunnels.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+        tunnels.truncate(limit.max(0) as usize);
+        Ok(tunnels)
+    }
+
+    async fn add_tunnel_traffic(&self, tunnel_id: Uuid, month: &str, traffic: TrafficTotals) -> Result<Option<Uuid>> {
+        let mut data = self.data.write().await;
+        let user_id = match data.tunnels.iter_mut().find(|r| r.tunnel.id == tunnel_id) {
+            Some(record) => {
+                record.traffic.bytes_in += traffic.bytes_in;
+                record.traffic.bytes_out += traffic.bytes_out;
+                record.tunnel.user_id
+            }
+            None => return Ok(None),
+        };
+
+        let totals = data.user_traffic.entry((user_id, month.to_string())).or_default();
+        totals.bytes_in += traffic.bytes_in;
+        totals.bytes_out += traffic.bytes_out;
+        Ok(Some(user_id))
+    }
+
+    async fn get_user_traffic(&self, user_id: Uuid, month: &str) -> Result<TrafficTotals> {
+        let data = self.data.read().await;
+        Ok(data
+            .user_traffic
+            .get(&(user_id, month.to_string()))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn record_viewer_traffic(&self, viewer: &ViewerTraffic) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.tunnels.iter().any(|r| r.tunnel.id == viewer.tunnel_id) {
+            return Err(anyhow!("Unknown tunnel {}", viewer.tunnel_id));
+        }
+        data.viewer_traffic.push(viewer.clone());
+        Ok(())
+    }
+
+    async fn get_viewer_traffic(&self, user_id: Uuid, tunnel_id: Uuid, limit: i64) -> Result<Vec<ViewerTraffic>> {
+        let data = self.data.read().await;
+        if !data
+            .tunnels
+            .iter()
+            .any(|r| r.tunnel.id == tunnel_id && r.tunnel.user_id == user_id)
+        {
+            return Ok(Vec::new());
+        }
+
+        let mut viewers: Vec<ViewerTraffic> = data
+            .viewer_traffic
+            .iter()
+            .filter(|v| v.tunnel_id == tunnel_id)
+            .cloned()
+            .collect();
+        viewers.sort_by_key(|v| std::cmp::Reverse(v.ended_at));
+        viewers.truncate(limit.max(0) as usize);
+        Ok(viewers)
+    }
+
+    async fn get_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>> {
+        let data = self.data.read().await;
+        Ok(data.ssh_keys.get(&user_id).cloned())
+    }
+
+    async fn store_ssh_public_key(&self, user_id: Uuid, ssh_public_key: &str) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.users.contains_key(&user_id) {
+            return Err(anyhow!("Unknown user {}", user_id));
+        }
+        data.ssh_keys.insert(user_id, ssh_public_key.to_string());
+        Ok(())
+    }
+
+    async fn clear_ssh_public_key(&self, user_id: Uuid) -> Result<Option<String>> {
+        let mut data = self.data.write().await;
+        Ok(data.ssh_keys.remove(&user_id))
+    }
+
+    async fn create_custom_domain(&self, domain: &CustomDomain) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.users.contains_key(&domain.user_id) {
+            return Err(anyhow!("Unknown user {}", domain.user_id));
+        }
+        if data.custom_domains.iter().any(|d| d.hostname == domain.hostname) {
+            return Err(anyhow!("Hostname {} is already claimed", domain.hostname));
+        }
+        data.custom_domains.push(domain.clone());
+        Ok(())
+    }
+
+    async fn get_custom_domain(&self, hostname: &str) -> Result<Option<CustomDomain>> {
+        let data = self.data.read().await;
+        Ok(data.custom_domains.iter().find(|d| d.hostname == hostname).cloned())
+    }
+
+    async fn list_user_custom_domains(&self, user_id: Uuid) -> Result<Vec<CustomDomain>> {
+        let data = self.data.read().await;
+        let mut domains: Vec<CustomDomain> = data
+            .custom_domains
+            .iter()
+            .filter(|d| d.user_id == user_id)
+            .cloned()
+            .collect();
+        domains.sort_by_key(|d| d.created_at);
+        Ok(domains)
+    }
+
+    async fn mark_custom_domain_verified(&self, domain_id: Uuid) -> Result<()> {
+        let mut data = self.data.write().await;
+        if let Some(domain) = data.custom_domains.iter_mut().find(|d| d.id == domain_id) {
+            domain.verified_at = Some(chrono::Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn set_custom_domain_target(&self, domain_id: Uuid, target: Option<&str>) -> Result<()> {
+        let mut data = self.data.write().await;
+        if let Some(domain) = data.custom_domains.iter_mut().find(|d| d.id == domain_id) {
+            domain.target = target.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn delete_custom_domain(&self, domain_id: Uuid) -> Result<()> {
+        let mut data = self.data.write().await;
+        data.custom_domains.retain(|d| d.id != domain_id);
+        Ok(())
+    }
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.users.contains_key(&webhook.user_id) {
+            return Err(anyhow!("Unknown user {}", webhook.user_id));
+        }
+        data.webhooks.push(webhook.clone());
+        Ok(())
+    }
+
+    async fn list_user_webhooks(&self, user_id: Uuid) -> Result<Vec<Webhook>> {
+        let data = self.data.read().await;
+        let mut hooks: Vec<Webhook> = data.webhooks.iter().filter(|w| w.user_id == user_id).cloned().collect();
+        hooks.sort_by_key(|w| w.created_at);
+        Ok(hooks)
+    }
+
+    async fn delete_webhook(&self, user_id: Uuid, webhook_id: Uuid) -> Result<bool> {
+        let mut data = self.data.write().await;
+        let before = data.webhooks.len();
+        data.webhooks.retain(|w| !(w.id == webhook_id && w.user_id == user_id));
+        if data.webhooks.len() == before {
+            return Ok(false);
+        }
+        data.webhook_deliveries.retain(|d| d.webhook_id != webhook_id);
+        Ok(true)
+    }
+
+    async fn record_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let mut data = self.data.write().await;
+        if !data.webhooks.iter().any(|w| w.id == delivery.webhook_id) {
+            return Err(anyhow!("Unknown webhook {}", delivery.webhook_id));
+        }
+        data.webhook_deliveries.push(delivery.clone());
+        Ok(())
+    }
+
+    async fn get_webhook_deliveries(&self, user_id: Uuid, webhook_id: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>> {
+        let data = self.data.read().await;
+        if !data.webhooks.iter().any(|w| w.id == webhook_id && w.user_id == user_id) {
+            return Ok(Vec::new());
+        }
+
+        let mut deliveries: Vec<WebhookDelivery> = data
+            .webhook_deliveries
+            .iter()
+            .filter(|d| d.webhook_id == webhook_id)
+            .cloned()
+            .collect();
+        deliveries.sort_by_key(|d| std::cmp::Reverse(d.created_at));
+        deliveries.truncate(limit.max(0) as usize);
+        Ok(deliveries)
+    }
+
+    async fn register_node(&self, node: &ClusterNode) -> Result<()> {
+        let mut data = self.data.write().await;
+        data.nodes.retain(|n| n.id != node.id);
+        data.nodes.push(node.clone());
+        data.nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(())
+    }
+
+    async fn heartbeat_node(&self, node_id: &str) -> Result<()> {
+        let mut data = self.data.write().await;
+        if let Some(node) = data.nodes.iter_mut().find(|n| n.id == node_id) {
+            node.last_heartbeat_at = chrono::Utc::now();
+        }
+        Ok(())
+    }
+
+    async fn list_cluster_nodes(&self) -> Result<Vec<ClusterNode>> {
+        let data = self.data.read().await;
+        Ok(data.nodes.clone())
+    }
+
+    async fn remove_node(&self, node_id: &str) -> Result<()> {
+        let mut data = self.data.write().await;
+        data.nodes.retain(|n| n.id != node_id);
+        Ok(())
+    }
+
+    async fn insert_audit_event(&self, event: &AuditEvent) -> Result<()> {
+        let mut data = self.data.write().await;
+        data.audit_events.push(event.clone());
+        Ok(())
+    }
+
+    async fn query_audit_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+        let data = self.data.read().await;
+        let mut events: Vec<AuditEvent> = data
+            .audit_events
+            .iter()
+            .filter(|e| filter.user_id.is_none_or(|id| e.user_id == Some(id)))
+            .filter(|e| filter.subdomain.is_none() || e.subdomain == filter.subdomain)
+            .filter(|e| filter.since.is_none_or(|since| e.created_at >= since))
+            .filter(|e| filter.until.is_none_or(|until| e.created_at < until))
+            .cloned()
+            .collect();
+        events.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        events.truncate(filter.effective_limit() as usize);
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditAction;
+    use crate::domains::VerificationMethod;
+    use crate::tunnel::TunnelKind;
+    use crate::webhooks::WebhookEvent;
+
+    fn test_tunnel(user_id: Uuid, subdomain: &str) -> Tunnel {
+        Tunnel {
+            id: Uuid::new_v4(),
+            subdomain: subdomain.to_string(),
+            user_id,
+            is_custom: false,
+            created_at: chrono::Utc::now(),
+            port: 10000,
+            password: None,
+            node_id: "local".to_string(),
+            kind: TunnelKind::Screen,
+            public_port: None,
+            label: None,
+            expires_at: None,
+        }
+    }
+
+    /// The same checks run against every backend so they stay interchangeable
+    async fn exercise_store(store: &dyn TunnelStore) {
+        let user_id = Uuid::new_v4();
+        store.get_or_create_user(user_id, "dev@example.com").await.unwrap();
+
+        let mut tunnel = test_tunnel(user_id, "happy-fox-1234");
+        tunnel.password = Some("secret".to_string());
+        store.create_tunnel_record(&tunnel).await.unwrap();
+        assert!(store
+            .create_tunnel_record(&test_tunnel(user_id, "happy-fox-1234"))
+            .await
+            .is_err());
+        assert_eq!(
+            store.get_tunnel_by_subdomain("happy-fox-1234").await.unwrap().map(|t| t.id),
+            Some(tunnel.id)
+        );
+
+        // Only active tunnels of the node with an expiry before the cutoff
+        let now = chrono::Utc::now();
+        let other_user = Uuid::new_v4();
+        store.get_or_create_user(other_user, "ops@example.com").await.unwrap();
+        let mut expiring = test_tunnel(other_user, "brisk-owl-1234");
+        expiring.expires_at = Some(now + chrono::Duration::minutes(10));
+        expiring.port = 10101;
+        store.create_tunnel_record(&expiring).await.unwrap();
+        let mut later = test_tunnel(other_user, "calm-elk-1234");
+        later.expires_at = Some(now + chrono::Duration::hours(2));
+        later.port = 10102;
+        store.create_tunnel_record(&later).await.unwrap();
+        let due = store.get_expiring_tunnels("local", now + chrono::Duration::hours(1)).await.unwrap();
+        assert_eq!(due.iter().map(|t| t.id).collect::<Vec<_>>(), vec![expiring.id]);
+        let mut open: Vec<Uuid> = store.get_open_tunnels("local").await.unwrap().iter().map(|t| t.id).collect();
+        let mut expected = vec![tunnel.id, expiring.id, later.id];
+        open.sort();
+        expected.sort();
+        assert_eq!(open, expected);
+        assert!(store.get_open_tunnels("eu-1").await.unwrap().is_empty());
+        assert_eq!(
+            due[0].expires_at.map(|at| at.timestamp_millis()),
+            expiring.expires_at.map(|at| at.timestamp_millis())
+        );
+        assert!(store.get_expiring_tunnels("eu-1", now + chrono::Duration::hours(3)).await.unwrap().is_empty());
+        store.close_tunnel_record(&expiring, "expired").await.unwrap();
+        store.close_tunnel_record(&later, "client_disconnected").await.unwrap();
+        assert!(store.get_expiring_tunnels("local", now + chrono::Duration::hours(3)).await.unwrap().is_empty());
+
+        store.increment_viewer_sessions(tunnel.id).await.unwrap();
+        assert!(store.get_tunnel_last_connected(tunnel.id).await.unwrap().is_none());
+        store.update_tunnel_last_connected(tunnel.id).await.unwrap();
+        let seen = store.get_tunnel_last_connected(tunnel.id).await.unwrap().unwrap();
+        assert!(chrono::Utc::now() - seen < chrono::Duration::minutes(1));
+        assert!(store.get_tunnel_last_connected(Uuid::new_v4()).await.unwrap().is_none());
+        assert_eq!(store.close_stale_tunnel_records("eu-1", "node_failed").await.unwrap(), 0);
+        assert_eq!(store.close_stale_tunnel_records("local", "server_restart").await.unwrap(), 1);
+        assert!(store.get_tunnel_by_subdomain("happy-fox-1234").await.unwrap().is_none());
+
+        // Traffic logged after a tunnel closed still counts
+        let traffic = TrafficTotals { bytes_in: 100, bytes_out: 5000 };
+        for _ in 0..2 {
+            assert_eq!(
+                store.add_tunnel_traffic(tunnel.id, "2026-01", traffic).await.unwrap(),
+                Some(user_id)
+            );
+        }
+        assert_eq!(store.add_tunnel_traffic(Uuid::new_v4(), "2026-01", traffic).await.unwrap(), None);
+        assert_eq!(
+            store.get_user_traffic(user_id, "2026-01").await.unwrap(),
+            TrafficTotals { bytes_in: 200, bytes_out: 10000 }
+        );
+        assert_eq!(store.get_user_traffic(user_id, "2026-02").await.unwrap(), TrafficTotals::default());
+
+        let viewer = ViewerTraffic {
+            id: Uuid::new_v4(),
+            tunnel_id: tunnel.id,
+            request_id: "4f3c2a1b".to_string(),
+            remote_ip: "203.0.113.9".to_string(),
+            bytes_in: 100,
+            bytes_out: 5000,
+            ended_at: chrono::Utc::now(),
+        };
+        store.record_viewer_traffic(&viewer).await.unwrap();
+        let viewers = store.get_viewer_traffic(user_id, tunnel.id, 10).await.unwrap();
+        assert_eq!(viewers.len(), 1);
+        assert_eq!(viewers[0].bytes_out, 5000);
+        assert!(store.get_viewer_traffic(Uuid::new_v4(), tunnel.id, 10).await.unwrap().is_empty());
+
+        let history = store.get_user_tunnels(user_id, 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].close_reason.as_deref(), Some("server_restart"));
+        assert_eq!(history[0].viewer_sessions, 1);
+        assert!(history[0].password_protected);
+        assert_eq!((history[0].bytes_in, history[0].bytes_out), (200, 10000));
+
+        // A public port is held by one active TCP tunnel per node
+        let mut tcp = test_tunnel(user_id, "db-one");
+        tcp.kind = TunnelKind::Tcp;
+        tcp.public_port = Some(30000);
+        store.create_tunnel_record(&tcp).await.unwrap();
+        let mut clash = test_tunnel(user_id, "db-two");
+        clash.kind = TunnelKind::Tcp;
+        clash.public_port = Some(30000);
+        assert!(store.create_tunnel_record(&clash).await.is_err());
+        clash.node_id = "eu-1".to_string();
+        store.create_tunnel_record(&clash).await.unwrap();
+        let stored = store.get_tunnel_by_subdomain("db-one").await.unwrap().unwrap();
+        assert_eq!((stored.kind, stored.public_port), (TunnelKind::Tcp, Some(30000)));
+        store.close_tunnel_record(&tcp, "client_disconnected").await.unwrap();
+        store.close_tunnel_record(&clash, "client_disconnected").await.unwrap();
+
+        store.store_ssh_public_key(user_id, "ssh-ed25519 AAAA").await.unwrap();
+        assert_eq!(
+            store.get_ssh_public_key(user_id).await.unwrap().as_deref(),
+            Some("ssh-ed25519 AAAA")
+        );
+        assert_eq!(
+            store.clear_ssh_public_key(user_id).await.unwrap().as_deref(),
+            Some("ssh-ed25519 AAAA")
+        );
+        assert!(store.get_ssh_public_key(user_id).await.unwrap().is_none());
+
+        let now = chrono::Utc::now();
+        let node = ClusterNode {
+            id: "eu-1".to_string(),
+            region: Some("eu-west".to_string()),
+            public_url: Some("wss://eu.tnnl.to".to_string()),
+            base_domain: "eu.tnnl.to".to_string(),
+            started_at: now,
+            last_heartbeat_at: now - chrono::Duration::minutes(5),
+        };
+        store.register_node(&node).await.unwrap();
+        store.register_node(&node).await.unwrap();
+        assert!(!store.list_cluster_nodes().await.unwrap()[0].is_live(30));
+        store.heartbeat_node("eu-1").await.unwrap();
+        let nodes = store.list_cluster_nodes().await.unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert!(nodes[0].is_live(30));
+        assert_eq!(nodes[0].region.as_deref(), Some("eu-west"));
+        store.remove_node("eu-1").await.unwrap();
+        assert!(store.list_cluster_nodes().await.unwrap().is_empty());
+
+        let domain = CustomDomain {
+            target: Some("api".to_string()),
+            ..CustomDomain::new(user_id, "demo.example.com".to_string(), VerificationMethod::Txt)
+        };
+        store.create_custom_domain(&domain).await.unwrap();
+        assert!(store
+            .create_custom_domain(&CustomDomain::new(user_id, "demo.example.com".to_string(), VerificationMethod::Http))
+            .await
+            .is_err());
+        store.mark_custom_domain_verified(domain.id).await.unwrap();
+        let stored = store.get_custom_domain("demo.example.com").await.unwrap().unwrap();
+        assert!(stored.is_verified());
+        assert_eq!(stored.verification_method, VerificationMethod::Txt);
+        assert_eq!(stored.verification_token, domain.verification_token);
+        assert_eq!(stored.target.as_deref(), Some("api"));
+        store.set_custom_domain_target(domain.id, Some("status")).await.unwrap();
+        let listed = store.list_user_custom_domains(user_id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].target.as_deref(), Some("status"));
+        store.set_custom_domain_target(domain.id, None).await.unwrap();
+        assert!(store.get_custom_domain("demo.example.com").await.unwrap().unwrap().target.is_none());
+        store.delete_custom_domain(domain.id).await.unwrap();
+        assert!(store.get_custom_domain("demo.example.com").await.unwrap().is_none());
+
+        let webhook = Webhook::new(
+            user_id,
+            "https://hooks.example.com/tnnl".to_string(),
+            vec![WebhookEvent::TunnelCreated, WebhookEvent::TunnelClosed],
+        );
+        store.create_webhook(&webhook).await.unwrap();
+        let hooks = store.list_user_webhooks(user_id).await.unwrap();
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(hooks[0].secret, webhook.secret);
+        assert_eq!(hooks[0].events, webhook.events);
+        let event_id = Uuid::new_v4();
+        for (attempt, status_code) in [(1, Some(502)), (2, Some(200))] {
+            let delivery = WebhookDelivery {
+                id: Uuid::new_v4(),
+                webhook_id: webhook.id,
+                event_id,
+                event: WebhookEvent::TunnelCreated,
+                attempt,
+                status_code,
+                error: (attempt == 1).then(|| "HTTP 502".to_string()),
+                delivered: attempt == 2,
+                created_at: chrono::Utc::now() + chrono::Duration::seconds(attempt as i64),
+            };
+            store.record_webhook_delivery(&delivery).await.unwrap();
+        }
+        let deliveries = store.get_webhook_deliveries(user_id, webhook.id, 10).await.unwrap();
+        assert_eq!(deliveries.iter().map(|d| d.attempt).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(deliveries[0].delivered);
+        assert_eq!(deliveries[1].status_code, Some(502));
+        assert!(store.get_webhook_deliveries(Uuid::new_v4(), webhook.id, 10).await.unwrap().is_empty());
+        assert!(!store.delete_webhook(Uuid::new_v4(), webhook.id).await.unwrap());
+        assert!(store.delete_webhook(user_id, webhook.id).await.unwrap());
+        assert!(store.list_user_webhooks(user_id).await.unwrap().is_empty());
+
+        store
+            .insert_audit_event(&AuditEvent::new(AuditAction::AuthSuccess).user(Some(user_id)))
+            .await
+            .unwrap();
+        store
+            .insert_audit_event(&AuditEvent::new(AuditAction::AuthFailure))
+            .await
+            .unwrap();
+        let filter = AuditFilter {
+            user_id: Some(user_id),
+            ..Default::default()
+        };
+        let events = store.query_audit_events(&filter).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::AuthSuccess);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        exercise_store(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = open("sqlite::memory:").await.unwrap();
+        exercise_store(store.as_ref()).await;
+    }
+}
@@ -0,0 +1,240 @@
+// Random subdomain names for tunnels
+// Names are <adjective>-...-<noun>-<digits>, drawn from curated word lists and
+// checked against a blocklist so a generated URL is never offensive
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::sync::Mutex;
+
+/// Fewest bits of entropy the configured name shape may give
+pub const MIN_ENTROPY_BITS: f64 = 24.0;
+
+/// Words and suffix digits allowed in generated names
+pub const WORDS_RANGE: std::ops::RangeInclusive<usize> = 1..=4;
+pub const DIGITS_RANGE: std::ops::RangeInclusive<u32> = 0..=8;
+
+/// Names that can never be handed out, neither generated nor requested, since
+/// they are used (or likely to be used) by the service itself
+pub const RESERVED_SUBDOMAINS: &[&str] = &[
+    "admin", "api", "app", "assets", "auth", "blog", "cdn", "dashboard", "docs", "help",
+    "login", "mail", "ns1", "ns2", "smtp", "ssh", "static", "status", "support", "tnnl",
+    "www", "ws", "wss",
+];
+
+const ADJECTIVES: &[&str] = &[
+    "able", "agile", "airy", "alert", "amber", "ample", "azure", "balmy", "bold", "brave", "breezy",
+    "brief", "bright", "brisk", "bubbly", "busy", "calm", "candid", "cheery", "chill", "civic",
+    "clean", "clear", "clever", "cloudy", "cobalt", "comfy", "cosmic", "cozy", "crafty", "crisp",
+    "curly", "dainty", "dandy", "daring", "dapper", "dashing", "dazzling", "deft", "dewy", "eager",
+    "early", "easy", "elated", "elegant", "epic", "even", "exact", "fair", "fancy", "fast",
+    "fearless", "festive", "fiery", "fine", "firm", "fleet", "fluffy", "fond", "frank", "free",
+    "fresh", "frosty", "frugal", "funny", "fuzzy", "gentle", "giant", "giddy", "gifted", "glad",
+    "gleeful", "glossy", "golden", "grand", "great", "green", "happy", "hardy", "hazel", "hearty",
+    "helpful", "hidden", "honest", "humble", "icy", "ideal", "indigo", "inky", "ivory", "jade",
+    "jazzy", "jolly", "jovial", "joyful", "jumbo", "keen", "kind", "kindly", "lavish", "leafy",
+    "lemon", "level", "light", "lilac", "limber", "lively", "loyal", "lucid", "lucky", "lunar",
+    "lush", "magic", "majestic", "mellow", "merry", "mighty", "mild", "minty", "misty", "modest",
+    "mossy", "nimble", "noble", "nifty", "nordic", "novel", "oaken", "ocean", "olive", "open",
+    "orange", "orderly", "plucky", "plush", "polar", "polite", "proud", "quick", "quiet", "quirky",
+    "radiant", "rapid", "rare", "ready", "regal", "rosy", "royal", "ruby", "rustic", "sandy",
+    "savvy", "scarlet", "serene", "sharp", "shiny", "silent", "silky", "silver", "simple", "sleek",
+    "smart", "smooth", "snappy", "snowy", "snug", "social", "solar", "solid", "sonic", "sparkly",
+    "speedy", "spry", "steady", "stellar", "stoic", "stormy", "sturdy", "sunny", "super", "sweet",
+    "swift", "tidy", "tiny", "topaz", "tranquil", "trusty", "upbeat", "urban", "valiant", "vast",
+    "velvet", "vivid", "warm", "wavy", "wild", "windy", "wise", "witty", "woolly", "young", "zany",
+    "zen", "zesty", "amiable", "bouncy", "bronze", "buoyant", "cheerful", "coral", "crimson",
+    "dreamy", "dusky", "emerald", "fabled", "feisty", "fleecy", "floral", "fluent", "gallant",
+    "glowing", "hushed", "jaunty", "lofty", "mirthful", "peppy", "perky", "pastel", "placid",
+    "plum", "primal", "prime", "rugged", "sage", "scenic", "shady", "snazzy", "spotted", "starry",
+    "sunlit", "tawny", "teal", "tender", "thrifty", "timely", "toasty", "tropic", "tuneful",
+    "vernal", "vibrant", "violet", "wintry", "woody", "zippy", "autumn", "balanced", "blissful",
+    "brainy", "caring", "chipper", "classic", "cordial",
+];
+
+const NOUNS: &[&str] = &[
+    "acorn", "alpaca", "anchor", "antelope", "apple", "arrow", "aspen", "atlas", "badger", "bagel",
+    "bamboo", "banjo", "basil", "beacon", "bear", "beaver", "bee", "birch", "bison", "blossom",
+    "bobcat", "bongo", "breeze", "brook", "buffalo", "bunny", "cabin", "cactus", "camel", "canoe",
+    "canyon", "capybara", "cardinal", "caribou", "castle", "cedar", "cello", "cheetah", "cherry",
+    "chipmunk", "cicada", "cliff", "clover", "cobra", "comet", "condor", "coral", "cougar",
+    "coyote", "crane", "crater", "cricket", "crow", "cub", "cypress", "daisy", "dingo", "dolphin",
+    "donkey", "dove", "dragon", "dune", "eagle", "echo", "egret", "elk", "elm", "ember", "falcon",
+    "fern", "ferret", "fig", "finch", "fjord", "flamingo", "forest", "fox", "frog", "galaxy",
+    "gazelle", "gecko", "geyser", "ginger", "giraffe", "glacier", "goose", "gopher", "granite",
+    "grove", "gull", "harbor", "hare", "hawk", "hedgehog", "heron", "hippo", "horizon", "hornet",
+    "husky", "ibis", "iguana", "island", "jackal", "jaguar", "jasmine", "jay", "jellyfish", "kayak",
+    "kestrel", "kettle", "kiwi", "koala", "lagoon", "lake", "lantern", "lark", "lemur", "leopard",
+    "lily", "lion", "llama", "lobster", "lotus", "lynx", "magpie", "mallard", "mango", "maple",
+    "marmot", "meadow", "meerkat", "mesa", "meteor", "mink", "moose", "moth", "mountain", "mule",
+    "narwhal", "nebula", "newt", "nutmeg", "oak", "oasis", "ocelot", "octopus", "orca", "oriole",
+    "osprey", "otter", "owl", "panda", "panther", "parrot", "peach", "pebble", "pelican", "penguin",
+    "pepper", "pine", "planet", "plover", "pony", "poppy", "prairie", "puffin", "puma", "quail",
+    "quartz", "rabbit", "raven", "reef", "ridge", "river", "robin", "rocket", "salmon", "sequoia",
+    "shark", "sparrow", "spruce", "squid", "starling", "stork", "summit", "swan", "tapir",
+    "thistle", "thrush", "tiger", "toucan", "trout", "tulip", "tundra", "turtle", "valley",
+    "violin", "vole", "walrus", "wasp", "willow", "wombat", "wren", "yak", "zebra", "acacia",
+    "basin", "bay", "bluff", "boulder", "cascade", "cove", "creek", "delta", "fossil", "garnet",
+    "grotto", "harp", "heather", "ivy", "kelp", "larch", "marsh", "meadowlark", "minnow", "mist",
+    "nectar", "opal", "orchid", "pagoda", "papaya", "pasta", "piano", "pickle", "pumpkin", "quill",
+    "radish", "rover", "saffron", "scarab", "shrimp", "sloth", "sprout", "sundial", "teapot",
+    "thimble", "tortoise", "trellis", "tuba", "waffle", "walnut", "wigeon", "yarrow", "zephyr",
+];
+
+/// Matched anywhere in a name with hyphens and digits removed, so words that
+/// only appear across a word boundary are caught too
+const BLOCKED_WORDS: &[&str] = &[
+    "anal", "anus", "arse", "bitch", "boob", "cock", "coon", "crap", "cunt", "dick", "dildo",
+    "dyke", "fag", "fuck", "gook", "homo", "jizz", "kike", "kkk", "nazi", "nigg", "penis", "piss",
+    "porn", "pube", "pussy", "rape", "scum", "semen", "shit", "slut", "spic", "tits", "twat",
+    "wank", "whore", "retard",
+];
+
+/// Matched anywhere in the digit suffix
+const BLOCKED_NUMBERS: &[&str] = &["1488"];
+
+/// Draws subdomain names of a fixed shape
+/// Seed it with `seeded` to get a repeatable sequence in tests
+pub struct SubdomainGenerator {
+    words: usize,
+    digits: u32,
+    rng: Mutex<StdRng>,
+}
+
+impl SubdomainGenerator {
+    /// Names of `words` words (adjectives then a noun) and a `digits`-digit suffix
+    pub fn new(words: usize, digits: u32) -> Self {
+        Self::with_rng(words, digits, StdRng::from_entropy())
+    }
+
+    /// Same names for the same seed, for tests
+    #[cfg(test)]
+    pub fn seeded(words: usize, digits: u32, seed: u64) -> Self {
+        Self::with_rng(words, digits, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(words: usize, digits: u32, rng: StdRng) -> Self {
+        Self {
+            words: words.clamp(*WORDS_RANGE.start(), *WORDS_RANGE.end()),
+            digits: digits.min(*DIGITS_RANGE.end()),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// A random name that passes the blocklist
+    /// Callers still have to check it against live and reserved names
+    pub fn generate(&self) -> String {
+        let mut rng = self.rng.lock().unwrap();
+        loop {
+            let name = self.draw(&mut rng);
+            if !is_offensive(&name) {
+                return name;
+            }
+        }
+    }
+
+    fn draw(&self, rng: &mut StdRng) -> String {
+        let mut parts: Vec<String> = (1..self.words)
+            .map(|_| ADJECTIVES.choose(rng).unwrap().to_string())
+            .collect();
+        parts.push(NOUNS.choose(rng).unwrap().to_string());
+        if self.digits > 0 {
+            let low = 10u64.pow(self.digits - 1);
+            parts.push(rng.gen_range(low..low * 10).to_string());
+        }
+        parts.join("-")
+    }
+}
+
+/// Bits of entropy in names of `words` words and a `digits`-digit suffix
+pub fn entropy_bits(words: usize, digits: u32) -> f64 {
+    let mut bits = (NOUNS.len() as f64).log2();
+    bits += words.saturating_sub(1) as f64 * (ADJECTIVES.len() as f64).log2();
+    if digits > 0 {
+        bits += (9.0 * 10f64.powi(digits as i32 - 1)).log2();
+    }
+    bits
+}
+
+/// Whether a name contains a blocked word, ignoring hyphens and digits
+pub fn is_offensive(name: &str) -> bool {
+    let letters: String = name.chars().filter(|c| c.is_ascii_alphabetic()).collect();
+    let digits: String = name.chars().filter(|c| c.is_ascii_digit()).collect();
+    BLOCKED_WORDS.iter().any(|word| letters.contains(word))
+        || BLOCKED_NUMBERS.iter().any(|number| digits.contains(number))
+}
+
+/// Whether a name is one of the built-in reserved names
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED_SUBDOMAINS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_word_lists_are_clean() {
+        for list in [ADJECTIVES, NOUNS] {
+            assert!(list.len() >= 250);
+            let unique: HashSet<&&str> = list.iter().collect();
+            assert_eq!(unique.len(), list.len(), "word lists must not repeat words");
+            for word in list {
+                assert!(word.chars().all(|c| c.is_ascii_lowercase()), "{} is not lowercase ascii", word);
+                assert!(!is_offensive(word), "{} is blocked", word);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_generator_is_repeatable() {
+        let a = SubdomainGenerator::seeded(2, 4, 42);
+        let b = SubdomainGenerator::seeded(2, 4, 42);
+        let names: Vec<String> = (0..20).map(|_| a.generate()).collect();
+        assert_eq!(names, (0..20).map(|_| b.generate()).collect::<Vec<_>>());
+
+        let other = SubdomainGenerator::seeded(2, 4, 43);
+        assert_ne!(names, (0..20).map(|_| other.generate()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_name_shape() {
+        let generator = SubdomainGenerator::seeded(3, 6, 7);
+        for _ in 0..100 {
+            let name = generator.generate();
+            let parts: Vec<&str> = name.split('-').collect();
+            assert_eq!(parts.len(), 4);
+            assert!(ADJECTIVES.contains(&parts[0]) && ADJECTIVES.contains(&parts[1]));
+            assert!(NOUNS.contains(&parts[2]));
+            assert!((100_000..=999_999).contains(&parts[3].parse::<u32>().unwrap()));
+        }
+
+        let bare = SubdomainGenerator::seeded(1, 0, 7).generate();
+        assert!(NOUNS.contains(&bare.as_str()));
+    }
+
+    #[test]
+    fn test_entropy_bits() {
+        // 262 adjectives x 254 nouns x 9000 suffixes
+        let bits = entropy_bits(2, 4);
+        assert!(bits > 29.0 && bits < 29.5, "{}", bits);
+        assert!(entropy_bits(1, 0) < MIN_ENTROPY_BITS);
+        assert!(entropy_bits(3, 0) > entropy_bits(2, 0));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        assert!(is_offensive("brave-shit-1234"));
+        // Caught across word boundaries too
+        assert!(is_offensive("fresh-itch-1234"));
+        assert!(is_offensive("calm-otter-14880"));
+        assert!(!is_offensive("calm-otter-1234"));
+    }
+
+    #[test]
+    fn test_reserved() {
+        assert!(is_reserved("www"));
+        assert!(is_reserved("api"));
+        assert!(!is_reserved("happy-fox-1234"));
+    }
+}
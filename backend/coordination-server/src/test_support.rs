@@ -0,0 +1,370 @@
+// Fixtures shared by the unit tests and the end-to-end tests: backends that
+// record instead of touching Nginx, DNS or mail, a local HTTP endpoint for
+// webhooks, a JWT signer matching the test config, and app state driven
+// through handle_message without a socket
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::bandwidth::TrafficSample;
+use crate::certs::Certificate;
+use crate::cluster::{self, ClusterBus, ClusterEvent};
+use crate::config::Config;
+use crate::domains::{CustomDomain, DomainVerifier};
+use crate::nginx::{ProxyBackend, ProxyInventory};
+use crate::store::TunnelStore;
+use crate::tunnel::Tunnel;
+use crate::viewer_auth::CodeMailer;
+use crate::{db, handlers, store, AppState, Client};
+
+pub const TEST_JWT_SECRET: &str = "test-jwt-secret";
+pub const TEST_SSH_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMvFqP9nC2xT8rJbKdL4wYz0aHs6eUgVtRm3oNpQiXyB user@host";
+
+/// Proxy backend that records subdomains instead of touching Nginx
+/// Custom domain calls are recorded as "<action> <hostname> [<target>]" and
+/// rate limits as "<subdomain> <bytes per second|none>"; traffic samples
+/// queued by the test are handed out on the next collection
+#[derive(Default)]
+pub struct RecordingProxy {
+    pub created: Mutex<Vec<String>>,
+    pub removed: Mutex<Vec<String>>,
+    pub domains: Mutex<Vec<String>>,
+    pub rate_limits: Mutex<Vec<String>>,
+    pub traffic: Mutex<Vec<TrafficSample>>,
+    /// What inventory() reports
+    pub inventory: Mutex<ProxyInventory>,
+    /// What certificates() reports; a renewal extends one by 90 days
+    pub certificates: Mutex<Vec<Certificate>>,
+    pub renewed: Mutex<Vec<String>>,
+}
+
+#[async_trait::async_trait]
+impl ProxyBackend for Arc<RecordingProxy> {
+    async fn create_tunnel_config(&self, tunnel: &Tunnel) -> anyhow::Result<()> {
+        self.created.lock().unwrap().push(tunnel.subdomain.clone());
+        Ok(())
+    }
+
+    async fn remove_tunnel_config(&self, subdomain: &str) -> anyhow::Result<()> {
+        self.removed.lock().unwrap().push(subdomain.to_string());
+        Ok(())
+    }
+
+    async fn publish_domain_challenge(&self, hostname: &str, _token: &str) -> anyhow::Result<()> {
+        self.domains.lock().unwrap().push(format!("challenge {}", hostname));
+        Ok(())
+    }
+
+    async fn provision_custom_domain(&self, hostname: &str) -> anyhow::Result<()> {
+        self.domains.lock().unwrap().push(format!("provision {}", hostname));
+        Ok(())
+    }
+
+    async fn route_custom_domain(&self, hostname: &str, tunnel: Option<&Tunnel>) -> anyhow::Result<()> {
+        let target = tunnel.map_or("offline", |t| t.subdomain.as_str());
+        self.domains.lock().unwrap().push(format!("route {} {}", hostname, target));
+        Ok(())
+    }
+
+    async fn remove_custom_domain(&self, hostname: &str) -> anyhow::Result<()> {
+        self.domains.lock().unwrap().push(format!("remove {}", hostname));
+        Ok(())
+    }
+
+    async fn set_tunnel_rate_limit(&self, tunnel: &Tunnel, bytes_per_sec: Option<u64>) -> anyhow::Result<()> {
+        let rate = bytes_per_sec.map_or("none".to_string(), |r| r.to_string());
+        self.rate_limits.lock().unwrap().push(format!("{} {}", tunnel.subdomain, rate));
+        Ok(())
+    }
+
+    async fn collect_traffic(&self) -> anyhow::Result<Vec<TrafficSample>> {
+        Ok(std::mem::take(&mut *self.traffic.lock().unwrap()))
+    }
+
+    async fn inventory(&self) -> anyhow::Result<ProxyInventory> {
+        Ok(self.inventory.lock().unwrap().clone())
+    }
+
+    async fn certificates(&self) -> anyhow::Result<Vec<Certificate>> {
+        Ok(self.certificates.lock().unwrap().clone())
+    }
+
+    async fn renew_certificate(&self, domain: &str) -> anyhow::Result<()> {
+        self.renewed.lock().unwrap().push(domain.to_string());
+        for certificate in self.certificates.lock().unwrap().iter_mut() {
+            if certificate.domain == domain {
+                certificate.not_after = chrono::Utc::now() + chrono::Duration::days(90);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verifier whose answer is set by the test
+#[derive(Default)]
+pub struct StubVerifier {
+    pub published: AtomicBool,
+}
+
+#[async_trait::async_trait]
+impl DomainVerifier for Arc<StubVerifier> {
+    async fn check(&self, _domain: &CustomDomain) -> anyhow::Result<bool> {
+        Ok(self.published.load(Ordering::SeqCst))
+    }
+}
+
+/// Mailer that keeps sign-in codes as (email, host, code) for the test to read
+#[derive(Default)]
+pub struct RecordingMailer {
+    pub sent: Mutex<Vec<(String, String, String)>>,
+}
+
+#[async_trait::async_trait]
+impl CodeMailer for Arc<RecordingMailer> {
+    async fn send_code(&self, email: &str, host: &str, code: &str) -> anyhow::Result<()> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((email.to_string(), host.to_string(), code.to_string()));
+        Ok(())
+    }
+}
+
+/// HTTP server on a local port taking webhook deliveries
+/// Each POST is kept as (headers, body) and answered with the next queued
+/// status, or 200 once the queue is empty
+pub struct WebhookStub {
+    pub url: String,
+    pub received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+}
+
+impl WebhookStub {
+    pub async fn start(statuses: &[u16]) -> Self {
+        type Shared = (Arc<Mutex<Vec<(HeaderMap, String)>>>, Arc<Mutex<VecDeque<u16>>>);
+
+        async fn receive(State((received, statuses)): State<Shared>, headers: HeaderMap, body: String) -> StatusCode {
+            received.lock().unwrap().push((headers, body));
+            let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+            StatusCode::from_u16(status).unwrap()
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.iter().copied().collect::<VecDeque<_>>()));
+        let router = axum::Router::new()
+            .route("/hook", axum::routing::post(receive))
+            .with_state((received.clone(), statuses));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        Self { url, received }
+    }
+
+    /// Wait until `count` requests have arrived, returning them
+    pub async fn wait_for(&self, count: usize) -> Vec<(HeaderMap, String)> {
+        for _ in 0..200 {
+            if self.received.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(25)).await;
+        }
+        self.received.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl ClusterBus for Arc<cluster::LocalBus> {
+    async fn publish(&self, event: &ClusterEvent) -> anyhow::Result<()> {
+        self.as_ref().publish(event).await
+    }
+
+    async fn subscribe(&self) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<ClusterEvent>> {
+        self.as_ref().subscribe().await
+    }
+}
+
+pub fn test_token(user_id: Uuid, email: &str) -> String {
+    let now = chrono::Utc::now().timestamp();
+    let claims = serde_json::json!({
+        "sub": user_id.to_string(),
+        "email": email,
+        "aud": "authenticated",
+        "role": "authenticated",
+        "iat": now,
+        "exp": now + 3600
+    });
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(TEST_JWT_SECRET.as_bytes()),
+    )
+    .unwrap()
+}
+
+/// Config accepted by test_token, publishing tunnels under tunnels.example.com
+pub fn test_config() -> Config {
+    let mut config = Config::default();
+    config.server.jwt_secret = TEST_JWT_SECRET.to_string();
+    config.server.base_domain = "tunnels.example.com".to_string();
+    config
+}
+
+/// App state for tests: an in-memory store, a local bus and recording
+/// backends, each of which a test can swap out
+pub struct TestState {
+    config: Config,
+    store: Box<dyn TunnelStore>,
+    verifier: Arc<StubVerifier>,
+    bus: Box<dyn ClusterBus>,
+    mailer: Arc<RecordingMailer>,
+}
+
+impl TestState {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            store: Box::new(store::MemoryStore::new()),
+            verifier: Arc::new(StubVerifier::default()),
+            bus: Box::new(cluster::LocalBus::new()),
+            mailer: Arc::new(RecordingMailer::default()),
+        }
+    }
+
+    pub fn store(mut self, store: Box<dyn TunnelStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn verifier(mut self, verifier: Arc<StubVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn bus(mut self, bus: Box<dyn ClusterBus>) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    pub fn mailer(mut self, mailer: Arc<RecordingMailer>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    /// The state, and the proxy backend recording what it was asked to do
+    pub fn build(self) -> (Arc<AppState>, Arc<RecordingProxy>) {
+        let proxy = Arc::new(RecordingProxy::default());
+        let state = AppState::new(
+            self.config,
+            self.store,
+            Box::new(proxy.clone()),
+            Box::new(self.verifier),
+            self.bus,
+            Box::new(self.mailer),
+        );
+        (state, proxy)
+    }
+}
+
+/// App state on an in-memory store with recording backends
+pub fn test_state() -> (Arc<AppState>, Arc<RecordingProxy>) {
+    TestState::new(test_config()).build()
+}
+
+pub fn test_state_with_verifier() -> (Arc<AppState>, Arc<RecordingProxy>, Arc<StubVerifier>) {
+    let verifier = Arc::new(StubVerifier::default());
+    let (state, proxy) = TestState::new(test_config()).verifier(verifier.clone()).build();
+    (state, proxy, verifier)
+}
+
+pub fn test_state_with_config(config: Config) -> (Arc<AppState>, Arc<RecordingProxy>) {
+    TestState::new(config).build()
+}
+
+/// App state on `config` whose viewer sign-in codes are recorded by the returned mailer
+pub fn test_state_with_mailer(config: Config) -> (Arc<AppState>, Arc<RecordingMailer>) {
+    let mailer = Arc::new(RecordingMailer::default());
+    let (state, _proxy) = TestState::new(config).mailer(mailer.clone()).build();
+    (state, mailer)
+}
+
+/// A cluster node sharing `pool` and `bus` with the other test nodes
+pub fn test_node(node_id: &str, region: &str, pool: &db::DbPool, bus: &Arc<cluster::LocalBus>) -> Arc<AppState> {
+    let mut config = test_config();
+    config.server.base_domain = format!("{}.example.com", node_id);
+    config.cluster.enabled = true;
+    config.cluster.node_id = Some(node_id.to_string());
+    config.cluster.region = Some(region.to_string());
+    config.cluster.public_url = Some(format!("wss://{}.example.com", node_id));
+    let (state, _proxy) = TestState::new(config)
+        .store(Box::new(pool.clone()))
+        .bus(Box::new(bus.clone()))
+        .build();
+    state
+}
+
+/// Register a client the way handle_connection does, minus the socket
+pub async fn connect_client(state: &Arc<AppState>) -> (Uuid, UnboundedReceiver<Message>) {
+    let client_id = Uuid::new_v4();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    state.clients.write().await.insert(
+        client_id,
+        Client {
+            id: client_id,
+            user_id: None,
+            client_ip: Some("127.0.0.1".to_string()),
+            sender: tx,
+            tunnels: Vec::new(),
+            viewers: Vec::new(),
+        },
+    );
+    (client_id, rx)
+}
+
+pub async fn send(client_id: Uuid, msg: serde_json::Value, state: &Arc<AppState>) {
+    handlers::handle_message(client_id, msg.to_string(), state).await;
+}
+
+pub fn next_message(rx: &mut UnboundedReceiver<Message>) -> serde_json::Value {
+    match rx.try_recv().expect("expected a response") {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+/// Connect a client signed in as a new user, returning that user's id too
+pub async fn authed_client(state: &Arc<AppState>) -> (Uuid, UnboundedReceiver<Message>, Uuid) {
+    let (client_id, mut rx) = connect_client(state).await;
+    let user_id = Uuid::new_v4();
+    let token = test_token(user_id, "dev@example.com");
+    send(client_id, serde_json::json!({ "type": "auth", "token": token }), state).await;
+    assert_eq!(next_message(&mut rx)["type"], "auth_success");
+    (client_id, rx, user_id)
+}
+
+/// Connect a client signed in as a new user and give it a tunnel
+/// `options` are extra request_tunnel fields, e.g. `{ "label": "web" }`
+pub async fn authed_tunnel(state: &Arc<AppState>, options: serde_json::Value) -> (Uuid, UnboundedReceiver<Message>, Tunnel) {
+    let (client_id, mut rx, _user_id) = authed_client(state).await;
+    let mut request = serde_json::json!({ "type": "request_tunnel" });
+    if let (Some(request), Some(options)) = (request.as_object_mut(), options.as_object()) {
+        request.extend(options.clone());
+    }
+    send(client_id, request, state).await;
+    let assigned = next_message(&mut rx);
+    assert_eq!(assigned["type"], "tunnel_assigned", "{}", assigned);
+    let id = assigned["tunnel"]["id"].as_str().and_then(|id| Uuid::parse_str(id).ok());
+    let tunnel = state.clients.read().await[&client_id]
+        .tunnels
+        .iter()
+        .find(|t| Some(t.id) == id)
+        .cloned()
+        .unwrap();
+    (client_id, rx, tunnel)
+}
@@ -1,9 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+use crate::subdomains::{self, SubdomainGenerator};
+
+/// Random names drawn before giving up on finding a free one
+const SUBDOMAIN_ATTEMPTS: usize = 32;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(dead_code)]
 #[allow(unused)]
@@ -15,69 +21,211 @@ pub struct Tunnel {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub port: u16, // Local port for forwarding
     pub password: Option<String>, // Optional HTTP Basic Auth password
+    pub node_id: String, // Cluster node holding the client connection and SSH forward
+    #[serde(default)]
+    pub kind: TunnelKind,
+    #[serde(default)]
+    pub public_port: Option<u16>, // Port TCP tunnels are reachable on
+    #[serde(default)]
+    pub label: Option<String>, // Name the client gave the tunnel, e.g. "api"
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>, // Closed by the expiry scheduler after this
+}
+
+/// Longest label a client may give a tunnel
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// How a requested tunnel is set up
+#[derive(Debug, Clone, Default)]
+pub struct TunnelOptions {
+    pub kind: TunnelKind,
+    pub label: Option<String>,
+    pub password: Option<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// What a tunnel forwards to on the client's machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelKind {
+    /// The desktop app's screen-sharing server, opened with the viewer page
+    #[default]
+    Screen,
+    /// Any local HTTP server, proxied as is
+    Http,
+    /// Any local TCP service, exposed on a public port
+    Tcp,
+}
+
+impl TunnelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelKind::Screen => "screen",
+            TunnelKind::Http => "http",
+            TunnelKind::Tcp => "tcp",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "screen" => Some(TunnelKind::Screen),
+            "http" => Some(TunnelKind::Http),
+            "tcp" => Some(TunnelKind::Tcp),
+            _ => None,
+        }
+    }
+}
+
+/// A tunnel session as shown in the user's history
+/// Passwords are reduced to a flag so history never leaks them
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelHistoryEntry {
+    pub id: Uuid,
+    pub subdomain: String,
+    pub label: Option<String>,
+    pub is_custom: bool,
+    pub kind: TunnelKind,
+    pub port: u16,
+    pub node_id: String,
+    pub password_protected: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_connected_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub closed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub close_reason: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub viewer_sessions: i32,
+    /// Bytes received from and sent to viewers through the proxy
+    pub bytes_in: i64,
+    pub bytes_out: i64,
 }
 
 pub struct TunnelManager {
     tunnels: Arc<RwLock<HashMap<String, Tunnel>>>, // subdomain -> tunnel
     ports: Arc<RwLock<HashMap<u16, Uuid>>>,         // port -> tunnel_id
-    next_port: Arc<RwLock<u16>>,
+    local_ports: RangeInclusive<u16>,
+    next_port: Arc<RwLock<u16>>, // where the search for a free local port starts
+    tcp_ports: RangeInclusive<u16>,
+    node_id: String,
+    generator: SubdomainGenerator,
+    reserved: HashSet<String>, // on top of subdomains::RESERVED_SUBDOMAINS
 }
 
 impl TunnelManager {
-    pub fn new() -> Self {
+    /// SSH forwards get the next free port in `local_ports` after the last
+    /// one handed out, wrapping around
+    /// TCP tunnels get the lowest free public port in `tcp_ports`
+    /// Tunnels are created as owned by `node_id`
+    /// Random names are adjective-noun-NNNN until `with_subdomain_generator`
+    pub fn new(local_ports: RangeInclusive<u16>, tcp_ports: RangeInclusive<u16>, node_id: &str) -> Self {
         Self {
             tunnels: Arc::new(RwLock::new(HashMap::new())),
             ports: Arc::new(RwLock::new(HashMap::new())),
-            next_port: Arc::new(RwLock::new(10000)), // Start from port 10000
+            next_port: Arc::new(RwLock::new(*local_ports.start())),
+            local_ports,
+            tcp_ports,
+            node_id: node_id.to_string(),
+            generator: SubdomainGenerator::new(2, 4),
+            reserved: HashSet::new(),
         }
     }
 
+    pub fn with_subdomain_generator(mut self, generator: SubdomainGenerator) -> Self {
+        self.generator = generator;
+        self
+    }
+
+    /// Names never handed out in addition to the built-in reserved ones
+    pub fn with_reserved_subdomains<I: IntoIterator<Item = String>>(mut self, reserved: I) -> Self {
+        self.reserved = reserved.into_iter().collect();
+        self
+    }
+
+    /// Whether a name is reserved for the service itself
+    pub fn is_reserved(&self, subdomain: &str) -> bool {
+        subdomains::is_reserved(subdomain) || self.reserved.contains(subdomain)
+    }
+
     /// Create a new tunnel with a random subdomain
+    /// Names held by live tunnels on this node or reserved are skipped
     pub async fn create_random_tunnel(
         &self,
         user_id: Uuid,
-        password: Option<String>,
+        options: TunnelOptions,
     ) -> anyhow::Result<Tunnel> {
-        // Generate random subdomain (adjective-noun-number pattern)
-        let subdomain = generate_random_subdomain();
-        self.create_tunnel(user_id, subdomain, false, password).await
+        self.create_tunnel(user_id, false, options, |tunnels| {
+            (0..SUBDOMAIN_ATTEMPTS)
+                .map(|_| self.generator.generate())
+                .find(|name| !tunnels.contains_key(name) && !self.is_reserved(name))
+                .ok_or_else(|| anyhow::anyhow!("No free subdomain found; try again"))
+        })
+        .await
     }
 
     /// Create a new tunnel with a custom subdomain
-    #[allow(dead_code)]
     pub async fn create_custom_tunnel(
         &self,
         user_id: Uuid,
         subdomain: String,
-        password: Option<String>,
+        options: TunnelOptions,
     ) -> anyhow::Result<Tunnel> {
         // Validate subdomain
         if !is_valid_subdomain(&subdomain) {
             return Err(anyhow::anyhow!("Invalid subdomain format"));
         }
 
-        // Check if already exists
-        let tunnels = self.tunnels.read().await;
-        if tunnels.contains_key(&subdomain) {
-            return Err(anyhow::anyhow!("Subdomain already in use"));
+        if self.is_reserved(&subdomain) {
+            return Err(anyhow::anyhow!("Subdomain is reserved"));
         }
-        drop(tunnels);
 
-        self.create_tunnel(user_id, subdomain, true, password).await
+        self.create_tunnel(user_id, true, options, |tunnels| {
+            if tunnels.contains_key(&subdomain) {
+                return Err(anyhow::anyhow!("Subdomain already in use"));
+            }
+            Ok(subdomain)
+        })
+        .await
     }
 
+    /// Create a tunnel under the name `pick` chooses
+    /// `pick` sees the live tunnels under the write lock, so two requests
+    /// cannot take the same name
     async fn create_tunnel(
         &self,
         user_id: Uuid,
-        subdomain: String,
         is_custom: bool,
-        password: Option<String>,
+        options: TunnelOptions,
+        pick: impl FnOnce(&HashMap<String, Tunnel>) -> anyhow::Result<String>,
     ) -> anyhow::Result<Tunnel> {
-        // Allocate port
+        let mut tunnels = self.tunnels.write().await;
+        let subdomain = pick(&tunnels)?;
+
+        // Allocate a public port for TCP tunnels
+        let public_port = match options.kind {
+            TunnelKind::Tcp => {
+                let port = self
+                    .tcp_ports
+                    .clone()
+                    .find(|p| !tunnels.values().any(|t| t.public_port == Some(*p)))
+                    .ok_or_else(|| anyhow::anyhow!("No public TCP ports left"))?;
+                Some(port)
+            }
+            TunnelKind::Screen | TunnelKind::Http => None,
+        };
+
+        // Allocate a local port for the SSH forward. Searching on from the last
+        // one rather than from the start reuses a freed port as late as
+        // possible, in case the closed tunnel's forward is still up
         let port = {
+            let used: HashSet<u16> = tunnels.values().map(|t| t.port).collect();
+            let (first, last) = (*self.local_ports.start(), *self.local_ports.end());
             let mut next_port = self.next_port.write().await;
-            let port = *next_port;
-            *next_port += 1;
+            let from = (*next_port).clamp(first, last);
+            let port = (from..=last)
+                .chain(first..from)
+                .find(|p| !used.contains(p))
+                .ok_or_else(|| anyhow::anyhow!("No local ports left for SSH forwards"))?;
+            *next_port = if port == last { first } else { port + 1 };
             port
         };
 
@@ -88,14 +236,17 @@ impl TunnelManager {
             is_custom,
             created_at: chrono::Utc::now(),
             port,
-            password,
+            password: options.password,
+            node_id: self.node_id.clone(),
+            kind: options.kind,
+            public_port,
+            label: options.label,
+            expires_at: options.expires_at,
         };
 
         // Store tunnel
-        {
-            let mut tunnels = self.tunnels.write().await;
-            tunnels.insert(subdomain.clone(), tunnel.clone());
-        }
+        tunnels.insert(subdomain.clone(), tunnel.clone());
+        drop(tunnels);
 
         {
             let mut ports = self.ports.write().await;
@@ -112,6 +263,11 @@ impl TunnelManager {
         tunnels.get(subdomain).cloned()
     }
 
+    /// All live tunnels on this node
+    pub async fn list_tunnels(&self) -> Vec<Tunnel> {
+        self.tunnels.read().await.values().cloned().collect()
+    }
+
     /// Remove tunnel
     pub async fn remove_tunnel(&self, subdomain: &str) -> anyhow::Result<()> {
         let mut tunnels = self.tunnels.write().await;
@@ -125,26 +281,18 @@ impl TunnelManager {
     }
 }
 
-fn generate_random_subdomain() -> String {
-    use rand::seq::SliceRandom;
-    use rand::Rng;
-
-    let adjectives = vec![
-        "happy", "fuzzy", "clever", "swift", "bright", "calm", "brave", "wild", "cool", "warm",
-    ];
-    let nouns = vec![
-        "cat", "dog", "bird", "fish", "bear", "wolf", "fox", "deer", "owl", "lion",
-    ];
-
-    let mut rng = rand::thread_rng();
-    let adj = adjectives.choose(&mut rng).unwrap();
-    let noun = nouns.choose(&mut rng).unwrap();
-    let num: u16 = rng.gen_range(1000..9999);
-
-    format!("{}-{}-{}", adj, noun, num)
+/// Trim a client-supplied tunnel label and check it is printable and not too long
+pub fn parse_label(raw: &str) -> anyhow::Result<String> {
+    let label = raw.trim();
+    if label.is_empty() || label.chars().count() > MAX_LABEL_LEN || label.chars().any(char::is_control) {
+        return Err(anyhow::anyhow!(
+            "Tunnel labels must be 1-{} printable characters",
+            MAX_LABEL_LEN
+        ));
+    }
+    Ok(label.to_string())
 }
 
-#[allow(dead_code)]
 fn is_valid_subdomain(subdomain: &str) -> bool {
     // Subdomain must be 3-63 chars, lowercase alphanumeric and hyphens only
     if subdomain.len() < 3 || subdomain.len() > 63 {
@@ -194,48 +342,102 @@ mod tests {
     }
 
     #[test]
-    fn test_generate_random_subdomain_format() {
-        let subdomain = generate_random_subdomain();
-
-        // Should be valid subdomain format
-        assert!(is_valid_subdomain(&subdomain));
+    fn test_parse_label() {
+        assert_eq!(parse_label("  dev server ").unwrap(), "dev server");
+        assert_eq!(parse_label("ünïcode").unwrap(), "ünïcode");
+        assert!(parse_label("   ").is_err());
+        assert!(parse_label("line\nbreak").is_err());
+        assert!(parse_label(&"a".repeat(MAX_LABEL_LEN + 1)).is_err());
+    }
 
-        // Should match pattern: adjective-noun-number
-        let parts: Vec<&str> = subdomain.split('-').collect();
-        assert_eq!(parts.len(), 3, "Subdomain should have 3 parts separated by hyphens");
+    #[tokio::test]
+    async fn test_random_subdomains_skip_taken_names() {
+        // Two generators with the same seed draw the same names, so the second
+        // manager's first pick is already held and must be retried
+        let user_id = Uuid::new_v4();
+        let probe = TunnelManager::new(10000..=10099, 20000..=20001, "local")
+            .with_subdomain_generator(SubdomainGenerator::seeded(2, 4, 1));
+        let first = probe.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+        assert!(is_valid_subdomain(&first.subdomain));
+
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local")
+            .with_subdomain_generator(SubdomainGenerator::seeded(2, 4, 1))
+            .with_reserved_subdomains(["status-page".to_string()]);
+        manager
+            .create_custom_tunnel(user_id, first.subdomain.clone(), TunnelOptions::default())
+            .await
+            .unwrap();
+        let second = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+        assert_ne!(second.subdomain, first.subdomain);
+
+        // Reserved names cannot be requested either
+        for reserved in ["www", "status-page"] {
+            let result = manager
+                .create_custom_tunnel(user_id, reserved.to_string(), TunnelOptions::default())
+                .await;
+            assert!(result.unwrap_err().to_string().contains("reserved"));
+        }
+    }
 
-        // Third part should be a 4-digit number
-        let number = parts[2].parse::<u16>();
-        assert!(number.is_ok(), "Third part should be a number");
-        let num = number.unwrap();
-        assert!(num >= 1000 && num <= 9999, "Number should be between 1000-9999");
+    #[tokio::test]
+    async fn test_random_subdomains_unique_in_small_namespace() {
+        // One word and no digits leaves only the nouns, so repeats are drawn
+        // often and have to be retried
+        let user_id = Uuid::new_v4();
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local")
+            .with_subdomain_generator(SubdomainGenerator::seeded(1, 0, 9));
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..5 {
+            let tunnel = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+            assert!(seen.insert(tunnel.subdomain));
+        }
     }
 
     #[tokio::test]
     async fn test_tunnel_manager_port_allocation() {
-        let manager = TunnelManager::new();
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local");
         let user_id = Uuid::new_v4();
 
         // Create first tunnel
-        let tunnel1 = manager.create_random_tunnel(user_id, None).await.unwrap();
+        let tunnel1 = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
         assert_eq!(tunnel1.port, 10000);
 
         // Create second tunnel
-        let tunnel2 = manager.create_random_tunnel(user_id, None).await.unwrap();
+        let tunnel2 = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
         assert_eq!(tunnel2.port, 10001);
 
         // Ports should increment
         assert_ne!(tunnel1.port, tunnel2.port);
     }
 
+    #[tokio::test]
+    async fn test_local_ports_are_bounded_and_reused() {
+        let manager = TunnelManager::new(10000..=10002, 20000..=20001, "local");
+        let user_id = Uuid::new_v4();
+        let mut ports = Vec::new();
+        for _ in 0..3 {
+            ports.push(manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap());
+        }
+        assert_eq!(ports.iter().map(|t| t.port).collect::<Vec<_>>(), vec![10000, 10001, 10002]);
+        assert!(manager.create_random_tunnel(user_id, TunnelOptions::default()).await.is_err());
+
+        // Freed ports are handed out again, the search wrapping past the end
+        manager.remove_tunnel(&ports[1].subdomain).await.unwrap();
+        manager.remove_tunnel(&ports[0].subdomain).await.unwrap();
+        let reused = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+        assert_eq!(reused.port, 10000);
+        let reused = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+        assert_eq!(reused.port, 10001);
+    }
+
     #[tokio::test]
     async fn test_tunnel_manager_custom_subdomain() {
-        let manager = TunnelManager::new();
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local");
         let user_id = Uuid::new_v4();
 
         // Create tunnel with custom subdomain
         let tunnel = manager
-            .create_custom_tunnel(user_id, "my-custom-tunnel".to_string(), None)
+            .create_custom_tunnel(user_id, "my-custom-tunnel".to_string(), TunnelOptions::default())
             .await
             .unwrap();
 
@@ -244,7 +446,7 @@ mod tests {
 
         // Should fail to create duplicate subdomain
         let result = manager
-            .create_custom_tunnel(user_id, "my-custom-tunnel".to_string(), None)
+            .create_custom_tunnel(user_id, "my-custom-tunnel".to_string(), TunnelOptions::default())
             .await;
 
         assert!(result.is_err());
@@ -253,12 +455,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_tunnel_manager_invalid_subdomain() {
-        let manager = TunnelManager::new();
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local");
         let user_id = Uuid::new_v4();
 
         // Should reject invalid subdomain
         let result = manager
-            .create_custom_tunnel(user_id, "INVALID".to_string(), None)
+            .create_custom_tunnel(user_id, "INVALID".to_string(), TunnelOptions::default())
             .await;
 
         assert!(result.is_err());
@@ -267,12 +469,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_tunnel_manager_remove() {
-        let manager = TunnelManager::new();
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local");
         let user_id = Uuid::new_v4();
 
         // Create tunnel
         let _tunnel = manager
-            .create_custom_tunnel(user_id, "test-tunnel".to_string(), None)
+            .create_custom_tunnel(user_id, "test-tunnel".to_string(), TunnelOptions::default())
             .await
             .unwrap();
 
@@ -289,4 +491,48 @@ mod tests {
         let result = manager.remove_tunnel("test-tunnel").await;
         assert!(result.is_err());
     }
+
+    fn tcp() -> TunnelOptions {
+        TunnelOptions {
+            kind: TunnelKind::Tcp,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_manager_tcp_ports() {
+        let manager = TunnelManager::new(10000..=10099, 20000..=20001, "local");
+        let user_id = Uuid::new_v4();
+
+        let screen = manager.create_random_tunnel(user_id, TunnelOptions::default()).await.unwrap();
+        assert_eq!(screen.public_port, None);
+
+        let first = manager
+            .create_custom_tunnel(user_id, "db-one".to_string(), tcp())
+            .await
+            .unwrap();
+        let second = manager
+            .create_custom_tunnel(user_id, "db-two".to_string(), tcp())
+            .await
+            .unwrap();
+        assert_eq!(first.public_port, Some(20000));
+        assert_eq!(second.public_port, Some(20001));
+
+        // The range is used up until a TCP tunnel closes
+        let result = manager.create_random_tunnel(user_id, tcp()).await;
+        assert!(result.unwrap_err().to_string().contains("No public TCP ports"));
+
+        manager.remove_tunnel("db-one").await.unwrap();
+        let third = manager.create_random_tunnel(user_id, tcp()).await.unwrap();
+        assert_eq!(third.public_port, Some(20000));
+    }
+
+    #[test]
+    fn test_tunnel_kind_round_trip() {
+        for kind in [TunnelKind::Screen, TunnelKind::Http, TunnelKind::Tcp] {
+            assert_eq!(TunnelKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(TunnelKind::parse("udp"), None);
+        assert_eq!(TunnelKind::default(), TunnelKind::Screen);
+    }
 }
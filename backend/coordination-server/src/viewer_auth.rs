@@ -0,0 +1,704 @@
+// Viewer authentication: per-tunnel email allowlists enforced through nginx auth_request
+// A tunnel with an allowlist only admits viewers holding a session cookie for
+// its host. Viewers get one on the sign-in pages nginx forwards from
+// /_tnnl/ on the tunnel's own domain, by entering a code mailed to an address
+// on the list. Allowlists live with the tunnel and can be edited while it is up
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::process::Command;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info};
+
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::config::{is_valid_hostname, ViewerAuthConfig};
+use crate::AppState;
+
+/// Maximum number of entries in a tunnel's allowlist
+pub const MAX_ALLOWLIST_ENTRIES: usize = 100;
+
+/// Cookie holding a viewer's signed session
+pub const SESSION_COOKIE: &str = "tnnl_viewer";
+
+/// Response header nginx forwards to the host app with the signed-in email
+pub const VIEWER_HEADER: &str = "x-tnnl-viewer";
+
+/// Wrong guesses allowed before a sign-in code is discarded
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+/// A new code is not mailed while the last one is younger than this
+const CODE_RESEND_INTERVAL_SECS: i64 = 30;
+
+/// Lowercase and validate an email address
+pub fn normalize_email(input: &str) -> Option<String> {
+    let email = input.trim().to_ascii_lowercase();
+    let (local, domain) = email.split_once('@')?;
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.contains(|c: char| c.is_whitespace() || c.is_control() || "@<>\",;".contains(c));
+    if local_ok && is_valid_hostname(domain) && domain.contains('.') {
+        Some(email)
+    } else {
+        None
+    }
+}
+
+/// Validate allowlist entries: email addresses, or domains written as
+/// `@example.com` or `example.com` (stored as `@example.com`)
+pub fn normalize_allowlist(entries: &[String]) -> Result<Vec<String>> {
+    if entries.len() > MAX_ALLOWLIST_ENTRIES {
+        return Err(anyhow!("Allowlists are limited to {} entries", MAX_ALLOWLIST_ENTRIES));
+    }
+
+    let mut allowlist: Vec<String> = Vec::new();
+    for entry in entries {
+        let trimmed = entry.trim().to_ascii_lowercase();
+        let domain = trimmed.strip_prefix('@').unwrap_or(&trimmed);
+
+        let normalized = if !domain.contains('@') && is_valid_hostname(domain) && domain.contains('.') {
+            format!("@{}", domain)
+        } else {
+            normalize_email(&trimmed).ok_or_else(|| anyhow!("Invalid allowlist entry: {}", entry))?
+        };
+
+        if !allowlist.contains(&normalized) {
+            allowlist.push(normalized);
+        }
+    }
+    Ok(allowlist)
+}
+
+/// Parse an `allowed_viewers` array from a client message
+pub fn parse_allowlist(value: &serde_json::Value, enabled: bool) -> Result<Vec<String>> {
+    let entries: Vec<String> = value
+        .as_array()
+        .ok_or_else(|| anyhow!("allowed_viewers must be a list of emails or domains"))?
+        .iter()
+        .map(|v| v.as_str().map(String::from))
+        .collect::<Option<_>>()
+        .ok_or_else(|| anyhow!("allowed_viewers must be a list of emails or domains"))?;
+
+    if !enabled && !entries.is_empty() {
+        return Err(anyhow!("Viewer authentication is not enabled on this server"));
+    }
+    normalize_allowlist(&entries)
+}
+
+/// Whether a (normalized) email matches an allowlist
+pub fn is_allowed(allowlist: &[String], email: &str) -> bool {
+    let domain = email.rsplit_once('@').map(|(_, d)| d);
+    allowlist.iter().any(|entry| match entry.strip_prefix('@') {
+        Some(allowed_domain) => domain == Some(allowed_domain),
+        None => entry == email,
+    })
+}
+
+/// Delivers sign-in codes to viewers
+/// SendmailMailer is the production implementation; tests record the codes
+#[async_trait]
+pub trait CodeMailer: Send + Sync {
+    async fn send_code(&self, email: &str, host: &str, code: &str) -> Result<()>;
+}
+
+/// Mails codes through a local sendmail-compatible MTA
+pub struct SendmailMailer {
+    from: String,
+    sendmail_path: PathBuf,
+    code_ttl_minutes: u64,
+}
+
+impl SendmailMailer {
+    pub fn new(config: &ViewerAuthConfig) -> Self {
+        Self {
+            from: config.email_from.clone(),
+            sendmail_path: config.sendmail_path.clone(),
+            code_ttl_minutes: config.code_ttl_minutes,
+        }
+    }
+}
+
+#[async_trait]
+impl CodeMailer for SendmailMailer {
+    async fn send_code(&self, email: &str, host: &str, code: &str) -> Result<()> {
+        // email and host are validated, so they cannot inject headers
+        let message = format!(
+            "From: {from}\r\nTo: {email}\r\nSubject: Your sign-in code for {host}\r\n\r\n\
+             Your code to view https://{host} is {code}\r\n\r\n\
+             It expires in {ttl} minutes. If you did not ask for it, you can ignore this email.\r\n",
+            from = self.from,
+            ttl = self.code_ttl_minutes,
+        );
+
+        let mut child = Command::new(&self.sendmail_path)
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(message.as_bytes()).await?;
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(anyhow!("sendmail failed: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(())
+    }
+}
+
+/// A sign-in code waiting to be entered
+struct PendingCode {
+    code: String,
+    sent_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    attempts: u32,
+}
+
+/// Allowlists of live tunnels and pending sign-in codes
+pub struct ViewerAuth {
+    /// Keyed by subdomain; tunnels without an entry are open to anyone
+    allowlists: RwLock<HashMap<String, Vec<String>>>,
+    /// Keyed by (host, email), since session cookies are per host
+    codes: Mutex<HashMap<(String, String), PendingCode>>,
+    mailer: Box<dyn CodeMailer>,
+}
+
+impl ViewerAuth {
+    pub fn new(mailer: Box<dyn CodeMailer>) -> Self {
+        Self {
+            allowlists: RwLock::new(HashMap::new()),
+            codes: Mutex::new(HashMap::new()),
+            mailer,
+        }
+    }
+
+    /// A tunnel's allowlist; empty when the tunnel is open
+    pub async fn allowlist(&self, subdomain: &str) -> Vec<String> {
+        self.allowlists.read().await.get(subdomain).cloned().unwrap_or_default()
+    }
+
+    /// Replace a tunnel's allowlist; an empty list opens the tunnel
+    pub async fn set_allowlist(&self, subdomain: &str, allowlist: Vec<String>) {
+        let mut allowlists = self.allowlists.write().await;
+        if allowlist.is_empty() {
+            allowlists.remove(subdomain);
+        } else {
+            allowlists.insert(subdomain.to_string(), allowlist);
+        }
+    }
+
+    /// Forget a closed tunnel
+    pub async fn remove_tunnel(&self, subdomain: &str) {
+        self.allowlists.write().await.remove(subdomain);
+    }
+}
+
+/// Claims of a viewer session cookie, bound to one host
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    sub: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Sign a session for `email` on `host`
+pub fn issue_session(secret: &str, host: &str, email: &str, ttl_hours: u64) -> Result<String> {
+    let now = Utc::now().timestamp();
+    let claims = SessionClaims {
+        sub: email.to_string(),
+        aud: host.to_string(),
+        iat: now,
+        exp: now + ttl_hours as i64 * 3600,
+    };
+    Ok(encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes()))?)
+}
+
+/// Email of a valid session for `host`
+pub fn verify_session(secret: &str, host: &str, token: &str) -> Result<String> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[host]);
+    let data = decode::<SessionClaims>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation)
+        .map_err(|e| anyhow!("Invalid viewer session: {}", e))?;
+    Ok(data.claims.sub)
+}
+
+/// Value of the session cookie in a Cookie header
+fn session_cookie(cookie_header: &str) -> Option<&str> {
+    cookie_header
+        .split(';')
+        .find_map(|pair| pair.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('='))
+}
+
+/// Outcome of a forward-auth check
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    /// The tunnel has no allowlist
+    Open,
+    /// Signed in with an allowed email
+    Viewer(String),
+    /// Not signed in, or no longer on the allowlist
+    Denied,
+    /// No live tunnel is served at the host
+    UnknownHost,
+}
+
+/// Live tunnel served at a host: one of ours under the base domain, or the
+/// tunnel a verified custom domain targets
+pub async fn tunnel_for_host(state: &Arc<AppState>, host: &str) -> Option<String> {
+    let suffix = format!(".{}", state.config.server.base_domain);
+    if let Some(subdomain) = host.strip_suffix(&suffix) {
+        return state.tunnel_manager.get_tunnel(subdomain).await.map(|t| t.subdomain);
+    }
+
+    let domain = match state.store.get_custom_domain(host).await {
+        Ok(Some(domain)) if domain.is_verified() => domain,
+        Ok(_) => return None,
+        Err(e) => {
+            error!("Failed to look up custom domain {}: {}", host, e);
+            return None;
+        }
+    };
+    crate::tunnel_for_domain(state, &domain, None)
+        .await
+        .map(|t| t.subdomain)
+}
+
+/// Decide whether a request to `host` carrying `cookie_header` may pass
+pub async fn check_access(state: &Arc<AppState>, host: &str, cookie_header: Option<&str>) -> Access {
+    let subdomain = match tunnel_for_host(state, host).await {
+        Some(s) => s,
+        None => return Access::UnknownHost,
+    };
+
+    let allowlist = state.viewer_auth.allowlist(&subdomain).await;
+    if allowlist.is_empty() {
+        return Access::Open;
+    }
+
+    let email = cookie_header
+        .and_then(session_cookie)
+        .and_then(|token| verify_session(&state.config.viewer_auth.cookie_secret, host, token).ok());
+    match email {
+        Some(email) if is_allowed(&allowlist, &email) => Access::Viewer(email),
+        _ => Access::Denied,
+    }
+}
+
+/// Mail a sign-in code to `email` if it may view the tunnel at `host`
+/// Emails that are not allowed get nothing, without telling the requester
+pub async fn request_code(state: &Arc<AppState>, host: &str, email: &str) -> Result<()> {
+    let subdomain = tunnel_for_host(state, host)
+        .await
+        .ok_or_else(|| anyhow!("No tunnel is served at {}", host))?;
+    if !is_allowed(&state.viewer_auth.allowlist(&subdomain).await, email) {
+        info!("Sign-in code for {} not sent: {} is not on the allowlist", host, email);
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let key = (host.to_string(), email.to_string());
+    let code = {
+        let mut codes = state.viewer_auth.codes.lock().await;
+        codes.retain(|_, pending| pending.expires_at > now);
+        if codes
+            .get(&key)
+            .is_some_and(|pending| now - pending.sent_at < chrono::Duration::seconds(CODE_RESEND_INTERVAL_SECS))
+        {
+            return Ok(());
+        }
+
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        codes.insert(
+            key.clone(),
+            PendingCode {
+                code: code.clone(),
+                sent_at: now,
+                expires_at: now + chrono::Duration::minutes(state.config.viewer_auth.code_ttl_minutes as i64),
+                attempts: 0,
+            },
+        );
+        code
+    };
+
+    if let Err(e) = state.viewer_auth.mailer.send_code(email, host, &code).await {
+        state.viewer_auth.codes.lock().await.remove(&key);
+        return Err(e);
+    }
+    info!("Sent sign-in code for {} to {}", host, email);
+    Ok(())
+}
+
+/// Exchange a sign-in code for a session cookie value
+pub async fn verify_code(state: &Arc<AppState>, host: &str, email: &str, code: &str) -> Result<String> {
+    let key = (host.to_string(), email.to_string());
+    {
+        let mut codes = state.viewer_auth.codes.lock().await;
+        let pending = codes
+            .get_mut(&key)
+            .ok_or_else(|| anyhow!("No code was sent to this address, request a new one"))?;
+
+        if pending.expires_at <= Utc::now() {
+            codes.remove(&key);
+            return Err(anyhow!("The code has expired, request a new one"));
+        }
+        if pending.code != code.trim() {
+            pending.attempts += 1;
+            if pending.attempts >= MAX_CODE_ATTEMPTS {
+                codes.remove(&key);
+                return Err(anyhow!("Too many wrong codes, request a new one"));
+            }
+            return Err(anyhow!("Wrong code"));
+        }
+        codes.remove(&key);
+    }
+
+    // The allowlist may have changed since the code was sent
+    let subdomain = tunnel_for_host(state, host)
+        .await
+        .ok_or_else(|| anyhow!("No tunnel is served at {}", host))?;
+    if !is_allowed(&state.viewer_auth.allowlist(&subdomain).await, email) {
+        return Err(anyhow!("This address may not view this tunnel"));
+    }
+
+    let config = &state.config.viewer_auth;
+    issue_session(&config.cookie_secret, host, email, config.session_ttl_hours)
+}
+
+/// Serve the forward-auth endpoint and sign-in pages on an already-bound listener
+pub async fn serve(listener: TcpListener, app: Arc<AppState>) {
+    let router = Router::new()
+        .route("/auth/check", get(check))
+        .route("/_tnnl/login", get(login_page).post(send_code))
+        .route("/_tnnl/verify", post(verify))
+        .route("/_tnnl/logout", get(logout))
+        .with_state(app);
+
+    if let Err(e) = axum::serve(listener, router).await {
+        error!("Viewer auth server error: {}", e);
+    }
+}
+
+/// Host the viewer requested, as passed on by nginx
+pub fn request_host(headers: &HeaderMap) -> String {
+    headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(':').next().unwrap_or(v).trim().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// GET /auth/check, called by nginx auth_request for every tunnel request
+/// 2xx lets the request through, 401 sends the viewer to the sign-in page
+async fn check(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let host = request_host(&headers);
+    let cookies = headers.get(header::COOKIE).and_then(|v| v.to_str().ok());
+
+    match check_access(&state, &host, cookies).await {
+        Access::Open => StatusCode::NO_CONTENT.into_response(),
+        Access::Viewer(email) => (StatusCode::NO_CONTENT, [(VIEWER_HEADER, email)]).into_response(),
+        Access::Denied => StatusCode::UNAUTHORIZED.into_response(),
+        Access::UnknownHost => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyForm {
+    email: String,
+    code: String,
+}
+
+/// GET /_tnnl/login
+async fn login_page(headers: HeaderMap) -> Html<String> {
+    email_form(&request_host(&headers), None)
+}
+
+/// POST /_tnnl/login
+async fn send_code(State(state): State<Arc<AppState>>, headers: HeaderMap, Form(form): Form<LoginForm>) -> Html<String> {
+    let host = request_host(&headers);
+    let email = match normalize_email(&form.email) {
+        Some(email) => email,
+        None => return email_form(&host, Some("Enter a valid email address")),
+    };
+
+    match request_code(&state, &host, &email).await {
+        Ok(()) => code_form(&host, &email, None),
+        Err(e) => {
+            error!("Failed to send sign-in code for {}: {}", host, e);
+            email_form(&host, Some("The code could not be sent, try again later"))
+        }
+    }
+}
+
+/// POST /_tnnl/verify
+async fn verify(State(state): State<Arc<AppState>>, headers: HeaderMap, Form(form): Form<VerifyForm>) -> Response {
+    let host = request_host(&headers);
+    let email = match normalize_email(&form.email) {
+        Some(email) => email,
+        None => return email_form(&host, Some("Enter a valid email address")).into_response(),
+    };
+
+    let token = match verify_code(&state, &host, &email, &form.code).await {
+        Ok(token) => token,
+        Err(e) => return code_form(&host, &email, Some(&e.to_string())).into_response(),
+    };
+
+    let client_ip = headers.get("x-real-ip").and_then(|v| v.to_str().ok());
+    let subdomain = tunnel_for_host(&state, &host).await;
+    audit::record(
+        state.store.as_ref(),
+        AuditEvent::new(AuditAction::ViewerSignedIn)
+            .subdomain(subdomain.as_deref().unwrap_or(&host))
+            .client_ip(client_ip)
+            .details(serde_json::json!({ "email": email, "host": host })),
+    )
+    .await;
+    info!("Viewer {} signed in to {}", email, host);
+
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE,
+        token,
+        state.config.viewer_auth.session_ttl_hours * 3600
+    );
+    (StatusCode::SEE_OTHER, [(header::LOCATION, "/".to_string()), (header::SET_COOKIE, cookie)]).into_response()
+}
+
+/// GET /_tnnl/logout
+async fn logout() -> Response {
+    let cookie = format!("{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax", SESSION_COOKIE);
+    (
+        StatusCode::SEE_OTHER,
+        [(header::LOCATION, "/_tnnl/login".to_string()), (header::SET_COOKIE, cookie)],
+    )
+        .into_response()
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn email_form(host: &str, error: Option<&str>) -> Html<String> {
+    page(
+        host,
+        error,
+        r#"<p>This tunnel is only open to invited viewers. Enter your email to get a sign-in code.</p>
+    <form method="post" action="/_tnnl/login">
+      <input type="email" name="email" placeholder="you@example.com" required autofocus>
+      <button type="submit">Send code</button>
+    </form>"#
+            .to_string(),
+    )
+}
+
+fn code_form(host: &str, email: &str, error: Option<&str>) -> Html<String> {
+    page(
+        host,
+        error,
+        format!(
+            r#"<p>If {email} may view this tunnel, a sign-in code is on its way.</p>
+    <form method="post" action="/_tnnl/verify">
+      <input type="hidden" name="email" value="{email}">
+      <input type="text" name="code" inputmode="numeric" maxlength="6" placeholder="6-digit code" required autofocus>
+      <button type="submit">Sign in</button>
+    </form>
+    <p><a href="/_tnnl/login">Use a different email</a></p>"#,
+            email = escape_html(email)
+        ),
+    )
+}
+
+fn page(host: &str, error: Option<&str>, body: String) -> Html<String> {
+    let error = error
+        .map(|e| format!(r#"<p class="error">{}</p>"#, escape_html(e)))
+        .unwrap_or_default();
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>Sign in to {host}</title>
+  <style>
+    body {{ font-family: -apple-system, BlinkMacSystemFont, sans-serif; background: #0f172a; color: #e2e8f0; display: flex; justify-content: center; padding-top: 15vh; }}
+    main {{ max-width: 360px; width: 100%; padding: 0 16px; }}
+    input, button {{ width: 100%; box-sizing: border-box; padding: 10px; margin-top: 8px; border-radius: 6px; border: 1px solid #334155; font-size: 15px; }}
+    button {{ background: #2563eb; color: #fff; border: none; cursor: pointer; }}
+    a {{ color: #93c5fd; }}
+    .error {{ color: #fca5a5; }}
+  </style>
+</head>
+<body>
+  <main>
+    <h2>{host}</h2>
+    {error}
+    {body}
+  </main>
+</body>
+</html>
+"#,
+        host = escape_html(host),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_normalize_allowlist() {
+        let entries = vec![
+            " Alice@Example.com ".to_string(),
+            "@team.example.org".to_string(),
+            "partner.example.net".to_string(),
+            "alice@example.com".to_string(),
+        ];
+        assert_eq!(
+            normalize_allowlist(&entries).unwrap(),
+            vec!["alice@example.com", "@team.example.org", "@partner.example.net"]
+        );
+
+        assert!(normalize_allowlist(&["not an email".to_string()]).is_err());
+        assert!(normalize_allowlist(&["a@b@example.com".to_string()]).is_err());
+        assert!(normalize_allowlist(&["eve@example.com\r\nBcc: x@y.z".to_string()]).is_err());
+        assert!(normalize_allowlist(&vec!["a@example.com".to_string(); MAX_ALLOWLIST_ENTRIES + 1]).is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let allowlist = vec!["alice@example.com".to_string(), "@team.example.org".to_string()];
+        assert!(is_allowed(&allowlist, "alice@example.com"));
+        assert!(is_allowed(&allowlist, "bob@team.example.org"));
+        assert!(!is_allowed(&allowlist, "bob@example.com"));
+        assert!(!is_allowed(&allowlist, "bob@evil-team.example.org"));
+        assert!(!is_allowed(&allowlist, "bob@sub.team.example.org"));
+    }
+
+    #[test]
+    fn test_session_is_bound_to_host() {
+        let secret = "0123456789abcdef0123456789abcdef";
+        let token = issue_session(secret, "demo.tnnl.to", "alice@example.com", 1).unwrap();
+        assert_eq!(verify_session(secret, "demo.tnnl.to", &token).unwrap(), "alice@example.com");
+        assert!(verify_session(secret, "other.tnnl.to", &token).is_err());
+        assert!(verify_session("another-secret-another-secret-xx", "demo.tnnl.to", &token).is_err());
+
+        let header = format!("theme=dark; {}={}; other=1", SESSION_COOKIE, token);
+        assert_eq!(session_cookie(&header), Some(token.as_str()));
+        assert_eq!(session_cookie("tnnl_viewer_old=x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_viewer_allowlist_and_sign_in() {
+        // Allowlists are refused while viewer auth is disabled
+        let (state, _proxy) = test_state();
+        let (client_id, mut rx, _) = authed_client(&state).await;
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "allowed_viewers": ["alice@example.com"] }),
+            &state,
+        )
+        .await;
+        assert!(next_message(&mut rx)["message"].as_str().unwrap().contains("not enabled"));
+
+        let mut config = test_config();
+        config.viewer_auth.enabled = true;
+        config.viewer_auth.cookie_secret = "0123456789abcdef0123456789abcdef".to_string();
+        let (state, mailer) = test_state_with_mailer(config);
+        let (client_id, mut rx, _) = authed_client(&state).await;
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "request_tunnel", "allowed_viewers": ["Alice@Example.com"] }),
+            &state,
+        )
+        .await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["tunnel"]["allowed_viewers"], serde_json::json!(["alice@example.com"]));
+        let subdomain = response["tunnel"]["subdomain"].as_str().unwrap().to_string();
+        let host = state.config.server.tunnel_host(&subdomain);
+
+        assert_eq!(check_access(&state, &host, None).await, Access::Denied);
+        assert_eq!(
+            check_access(&state, "nope.tunnels.example.com", None).await,
+            Access::UnknownHost
+        );
+
+        // Addresses off the list get no code
+        request_code(&state, &host, "mallory@example.com").await.unwrap();
+        assert!(mailer.sent.lock().unwrap().is_empty());
+
+        request_code(&state, &host, "alice@example.com").await.unwrap();
+        let code = mailer.sent.lock().unwrap()[0].2.clone();
+        assert!(verify_code(&state, &host, "alice@example.com", "not-it").await.is_err());
+        let session = verify_code(&state, &host, "alice@example.com", &code).await.unwrap();
+        // Codes are single use
+        assert!(verify_code(&state, &host, "alice@example.com", &code).await.is_err());
+
+        let cookie = format!("{}={}", SESSION_COOKIE, session);
+        assert_eq!(
+            check_access(&state, &host, Some(&cookie)).await,
+            Access::Viewer("alice@example.com".to_string())
+        );
+
+        // Editing the list while the tunnel is live takes effect immediately
+        send(
+            client_id,
+            serde_json::json!({
+                "type": "set_viewer_allowlist",
+                "subdomain": subdomain,
+                "allowed_viewers": ["@partner.example.org"]
+            }),
+            &state,
+        )
+        .await;
+        let response = next_message(&mut rx);
+        assert_eq!(response["type"], "viewer_allowlist");
+        assert_eq!(response["allowed_viewers"], serde_json::json!(["@partner.example.org"]));
+        assert_eq!(check_access(&state, &host, Some(&cookie)).await, Access::Denied);
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "set_viewer_allowlist", "subdomain": subdomain, "allowed_viewers": [] }),
+            &state,
+        )
+        .await;
+        next_message(&mut rx);
+        assert_eq!(check_access(&state, &host, None).await, Access::Open);
+
+        send(
+            client_id,
+            serde_json::json!({ "type": "set_viewer_allowlist", "subdomain": "someone-else-1234", "allowed_viewers": [] }),
+            &state,
+        )
+        .await;
+        assert_eq!(next_message(&mut rx)["message"], "Tunnel not found");
+    }
+}
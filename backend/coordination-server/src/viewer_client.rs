@@ -0,0 +1,320 @@
+// Viewer client
+// The browser page viewers of screen tunnels load. nginx proxies `/` of every
+// screen tunnel here, so all tunnels get the same copy, versioned by its
+// content, and the page fetches its tunnel's settings from /config on load.
+// When the host cannot be reached nginx falls back to /offline here instead of
+// a bare 502, and both pages poll /status until the host is back
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::error;
+
+use crate::readiness::is_listening;
+use crate::viewer_auth::{escape_html, request_host, tunnel_for_host};
+use crate::webhooks::to_hex;
+use crate::AppState;
+
+/// Client built into the server, from the repository's client.html
+const BUILT_IN_CLIENT: &str = include_str!("../../../client.html");
+
+/// Header carrying the client version on every response
+const VERSION_HEADER: &str = "x-tnnl-client-version";
+
+/// Seconds the offline page asks browsers and crawlers to wait before retrying
+const OFFLINE_RETRY_AFTER_SECS: u64 = 5;
+
+/// The one copy of the viewer client served to every tunnel
+pub struct ViewerClient {
+    html: String,
+    version: String,
+}
+
+impl ViewerClient {
+    /// Page and version for `html`: the server version plus a content hash, so
+    /// a changed page gets a new version even without a release
+    pub fn new(html: String) -> Self {
+        let digest = to_hex(&Sha256::digest(html.as_bytes()));
+        let version = format!("{}+{}", env!("CARGO_PKG_VERSION"), &digest[..12]);
+        Self { html, version }
+    }
+
+    /// The page at `path` when configured, the built-in one otherwise
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let html = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Cannot read viewer client {}: {}", path.display(), e))?,
+            None => BUILT_IN_CLIENT.to_string(),
+        };
+        Ok(Self::new(html))
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+#[derive(Clone)]
+struct ClientState {
+    app: Arc<AppState>,
+    client: Arc<ViewerClient>,
+}
+
+/// Serve the viewer client and its per-tunnel config on an already-bound listener
+pub async fn serve(listener: TcpListener, app: Arc<AppState>, client: ViewerClient) {
+    let state = ClientState {
+        app,
+        client: Arc::new(client),
+    };
+
+    let router = Router::new()
+        .route("/client", get(page))
+        .route("/config", get(config))
+        .route("/status", get(status))
+        .route("/offline", get(offline))
+        .with_state(state);
+
+    if let Err(e) = axum::serve(listener, router).await {
+        error!("Viewer client server error: {}", e);
+    }
+}
+
+/// GET /client
+/// Browsers revalidate on every load, so an upgrade reaches open tunnels at once
+async fn page(State(state): State<ClientState>, headers: HeaderMap) -> Response {
+    let etag = format!("\"{}\"", state.client.version);
+    let cached = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == etag);
+    let headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+        (header::HeaderName::from_static(VERSION_HEADER), state.client.version.clone()),
+    ];
+
+    if cached {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    (
+        headers,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        state.client.html.clone(),
+    )
+        .into_response()
+}
+
+/// GET /config
+/// Settings of the tunnel the page was loaded from, found by its Host header
+async fn config(State(state): State<ClientState>, headers: HeaderMap) -> Response {
+    let host = request_host(&headers);
+    let subdomain = match tunnel_for_host(&state.app, &host).await {
+        Some(subdomain) => subdomain,
+        None => {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "Tunnel not found" }))).into_response()
+        }
+    };
+    let label = state
+        .app
+        .tunnel_manager
+        .get_tunnel(&subdomain)
+        .await
+        .and_then(|t| t.label);
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(serde_json::json!({
+            "subdomain": subdomain,
+            "label": label,
+            "ws_url": format!("wss://{}", host),
+            "client_version": state.client.version,
+        })),
+    )
+        .into_response()
+}
+
+/// Whether the host behind a tunnel URL can be reached, and when it last was
+#[derive(Debug, Serialize)]
+struct HostStatus {
+    /// None for a custom domain with no tunnel routed to it
+    subdomain: Option<String>,
+    online: bool,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+/// Status of the host serving `host`, or None when no tunnel or custom domain
+/// has that name
+async fn host_status(app: &Arc<AppState>, host: &str) -> Option<HostStatus> {
+    let tunnel = match tunnel_for_host(app, host).await {
+        Some(subdomain) => app.tunnel_manager.get_tunnel(&subdomain).await,
+        None => None,
+    };
+    if let Some(tunnel) = tunnel {
+        let last_seen = app.store.get_tunnel_last_connected(tunnel.id).await.unwrap_or_else(|e| {
+            error!("Failed to look up when tunnel {} was last seen: {}", tunnel.id, e);
+            None
+        });
+        return Some(HostStatus {
+            online: is_listening(tunnel.port).await,
+            subdomain: Some(tunnel.subdomain),
+            last_seen,
+        });
+    }
+
+    // A custom domain parked on the offline page: its owner's latest tunnel
+    let domain = app.store.get_custom_domain(host).await.ok().flatten()?;
+    if !domain.is_verified() {
+        return None;
+    }
+    let last_seen = match app.store.get_user_tunnels(domain.user_id, 1).await {
+        Ok(history) => history.first().and_then(|t| t.last_connected_at),
+        Err(e) => {
+            error!("Failed to look up tunnels of {}: {}", domain.user_id, e);
+            None
+        }
+    };
+    Some(HostStatus {
+        subdomain: None,
+        online: false,
+        last_seen,
+    })
+}
+
+/// GET /status
+/// Polled by the viewer client and the offline page to notice the host is back
+async fn status(State(state): State<ClientState>, headers: HeaderMap) -> Response {
+    match host_status(&state.app, &request_host(&headers)).await {
+        Some(status) => ([(header::CACHE_CONTROL, "no-store")], Json(status)).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "Tunnel not found" }))).into_response(),
+    }
+}
+
+/// GET /offline
+/// Served by nginx in place of a 502 when the host's forward is down, and for
+/// custom domains with no tunnel
+async fn offline(State(state): State<ClientState>, headers: HeaderMap) -> Response {
+    let host = request_host(&headers);
+    let last_seen = host_status(&state.app, &host).await.and_then(|s| s.last_seen);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [
+            (header::CACHE_CONTROL, "no-store".to_string()),
+            (header::RETRY_AFTER, OFFLINE_RETRY_AFTER_SECS.to_string()),
+        ],
+        offline_page(&host, last_seen, Utc::now()),
+    )
+        .into_response()
+}
+
+/// How long before `now` `then` was, e.g. "5 minutes ago"
+fn time_ago(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - then).num_seconds().max(0);
+    for (name, size) in [("day", 86400), ("hour", 3600), ("minute", 60)] {
+        if seconds >= size {
+            let count = seconds / size;
+            return format!("{} {}{} ago", count, name, if count == 1 { "" } else { "s" });
+        }
+    }
+    "just now".to_string()
+}
+
+fn offline_page(host: &str, last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Html<String> {
+    let seen = match last_seen {
+        Some(at) => format!(
+            r#"Last seen <time datetime="{}">{}</time>."#,
+            at.to_rfc3339(),
+            time_ago(at, now)
+        ),
+        None => "It has not been seen yet.".to_string(),
+    };
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{host} is offline</title>
+  <style>
+    body {{ font-family: -apple-system, BlinkMacSystemFont, sans-serif; background: #0f172a; color: #e2e8f0; display: flex; justify-content: center; padding-top: 15vh; }}
+    main {{ max-width: 360px; width: 100%; padding: 0 16px; }}
+    .muted {{ color: #94a3b8; }}
+  </style>
+</head>
+<body>
+  <main>
+    <h2>{host}</h2>
+    <p>The host sharing this address is offline. The computer may be asleep or its connection may have dropped.</p>
+    <p>{seen}</p>
+    <p class="muted" id="retry">This page reloads once the host is back.</p>
+  </main>
+  <script>
+    // Poll with backoff and reload once the host answers again
+    let delay = 2000;
+    function poll() {{
+      fetch('/_tnnl/status', {{ cache: 'no-store' }})
+        .then((response) => (response.ok ? response.json() : null))
+        .catch(() => null)
+        .then((status) => {{
+          if (status && status.online) {{
+            location.reload();
+            return;
+          }}
+          delay = Math.min(delay * 2, 30000);
+          setTimeout(poll, delay);
+        }});
+    }}
+    setTimeout(poll, delay);
+  </script>
+</body>
+</html>
+"#,
+        host = escape_html(host),
+        seen = seen,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_follows_content() {
+        let built_in = ViewerClient::load(None).unwrap();
+        assert!(built_in.html.contains("<html"));
+        assert!(built_in.version().starts_with(env!("CARGO_PKG_VERSION")));
+
+        let same = ViewerClient::new(built_in.html.clone());
+        assert_eq!(same.version(), built_in.version());
+        let changed = ViewerClient::new(format!("{}\n", built_in.html));
+        assert_ne!(changed.version(), built_in.version());
+
+        assert!(ViewerClient::load(Some(Path::new("/nonexistent/client.html"))).is_err());
+    }
+
+    #[test]
+    fn test_offline_page_shows_last_seen() {
+        let now = Utc::now();
+        assert_eq!(time_ago(now, now), "just now");
+        assert_eq!(time_ago(now - chrono::Duration::seconds(61), now), "1 minute ago");
+        assert_eq!(time_ago(now - chrono::Duration::hours(5), now), "5 hours ago");
+        assert_eq!(time_ago(now - chrono::Duration::days(2), now), "2 days ago");
+        assert_eq!(time_ago(now + chrono::Duration::minutes(1), now), "just now");
+
+        let page = offline_page("demo.tnnl.to", Some(now - chrono::Duration::minutes(3)), now).0;
+        assert!(page.contains("Last seen <time"));
+        assert!(page.contains("3 minutes ago"));
+        assert!(page.contains("/_tnnl/status"));
+        let page = offline_page("<b>.tnnl.to", None, now).0;
+        assert!(page.contains("not been seen"));
+        assert!(page.contains("&lt;b&gt;.tnnl.to"));
+    }
+}
@@ -0,0 +1,92 @@
+// Viewer presence: who is watching a host's tunnels
+// The host app reports viewers joining and leaving its local WebSocket server;
+// sessions live on the host's client entry and go away with it
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Viewers tracked per tunnel; further joins are counted but not listed
+pub const MAX_VIEWERS_PER_TUNNEL: usize = 100;
+
+/// Longest user agent or identity kept, in characters
+const MAX_FIELD_LEN: usize = 256;
+
+/// A viewer connected to one of a host's tunnels
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewerSession {
+    pub id: Uuid,
+    pub subdomain: String,
+    pub remote_ip: String,
+    pub user_agent: Option<String>,
+    /// Who the viewer authenticated as, if the tunnel requires auth
+    pub identity: Option<String>,
+    pub connected_at: DateTime<Utc>,
+}
+
+impl ViewerSession {
+    /// Parse the `viewer` object of a viewer_joined message
+    /// Values come from the viewer's request headers, so they are bounded and
+    /// the address must parse as an IP
+    pub fn from_message(subdomain: &str, viewer: &serde_json::Value) -> Result<Self> {
+        let id = viewer
+            .get("id")
+            .and_then(|v| v.as_str())
+            .and_then(|v| Uuid::parse_str(v).ok())
+            .ok_or_else(|| anyhow!("Missing or invalid viewer id"))?;
+
+        let remote_ip = viewer
+            .get("remote_ip")
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.trim().parse::<std::net::IpAddr>().ok())
+            .ok_or_else(|| anyhow!("Missing or invalid viewer remote_ip"))?
+            .to_string();
+
+        let text = |field: &str| {
+            viewer
+                .get(field)
+                .and_then(|v| v.as_str())
+                .map(|v| v.trim().chars().take(MAX_FIELD_LEN).collect::<String>())
+                .filter(|v| !v.is_empty())
+        };
+
+        Ok(Self {
+            id,
+            subdomain: subdomain.to_string(),
+            remote_ip,
+            user_agent: text("user_agent"),
+            identity: text("identity"),
+            connected_at: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_message() {
+        let id = Uuid::new_v4();
+        let viewer = ViewerSession::from_message(
+            "happy-fox-1234",
+            &serde_json::json!({
+                "id": id,
+                "remote_ip": " 203.0.113.9 ",
+                "user_agent": "x".repeat(1000),
+                "identity": ""
+            }),
+        )
+        .unwrap();
+        assert_eq!(viewer.id, id);
+        assert_eq!(viewer.subdomain, "happy-fox-1234");
+        assert_eq!(viewer.remote_ip, "203.0.113.9");
+        assert_eq!(viewer.user_agent.unwrap().len(), MAX_FIELD_LEN);
+        assert!(viewer.identity.is_none());
+
+        let bad_ip = serde_json::json!({ "id": id, "remote_ip": "<script>" });
+        assert!(ViewerSession::from_message("happy-fox-1234", &bad_ip).is_err());
+        let no_id = serde_json::json!({ "remote_ip": "203.0.113.9" });
+        assert!(ViewerSession::from_message("happy-fox-1234", &no_id).is_err());
+    }
+}
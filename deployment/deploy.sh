@@ -13,7 +13,7 @@ fi
 # 1. Install dependencies
 echo "[1/8] Installing dependencies..."
 apt-get update
-apt-get install -y nginx certbot python3-certbot-nginx apache2-utils sqlite3
+apt-get install -y nginx libnginx-mod-stream certbot python3-certbot-nginx apache2-utils sqlite3
 
 # 2. Create tnnl user
 echo "[2/8] Creating tnnl system user..."
@@ -26,12 +26,19 @@ echo "[3/8] Creating directories..."
 mkdir -p /opt/tnnl
 mkdir -p /var/lib/tnnl
 mkdir -p /etc/nginx/tunnels
+mkdir -p /etc/nginx/tnnl-streams
 mkdir -p /etc/nginx/passwd
 mkdir -p /var/www/tnnl/landing
+mkdir -p /var/log/nginx/tnnl-traffic
 chown tnnl:tnnl /opt/tnnl
 chown tnnl:tnnl /var/lib/tnnl
-chown www-data:www-data /etc/nginx/tunnels
-chown www-data:www-data /etc/nginx/passwd
+# The server writes tunnel sites, stream configs and htpasswd files directly;
+# nginx only needs to read them
+chown tnnl:www-data /etc/nginx/tunnels /etc/nginx/tnnl-streams /etc/nginx/passwd
+chown tnnl:www-data /etc/nginx/sites-available /etc/nginx/sites-enabled
+# nginx writes per-tunnel traffic logs; the server reads and deletes them
+chown tnnl:www-data /var/log/nginx/tnnl-traffic
+chmod 2775 /var/log/nginx/tnnl-traffic
 chown -R www-data:www-data /var/www/tnnl
 
 # 4. Copy binary and set permissions
@@ -56,17 +63,6 @@ else
     echo "Please upload landing page files to /root/landing/"
 fi
 
-# Copy client.html template
-echo "[4.6/8] Installing client.html template..."
-if [ -f "/root/client.html" ]; then
-    cp /root/client.html /opt/tnnl/
-    chown tnnl:tnnl /opt/tnnl/client.html
-    echo "Client template installed successfully"
-else
-    echo "Warning: /root/client.html not found. Tunnel subdomains will not have client interface."
-    echo "Please upload client.html to /root/"
-fi
-
 # Create /var/www/html if it doesn't exist
 mkdir -p /var/www/html
 chown -R www-data:www-data /var/www/html
@@ -96,7 +92,7 @@ NoNewPrivileges=true
 PrivateTmp=true
 ProtectSystem=strict
 ProtectHome=true
-ReadWritePaths=/var/lib/tnnl
+ReadWritePaths=/var/lib/tnnl /etc/nginx/tunnels /etc/nginx/tnnl-streams /etc/nginx/passwd /etc/nginx/sites-available /etc/nginx/sites-enabled /var/log/nginx/tnnl-traffic
 
 [Install]
 WantedBy=multi-user.target
@@ -115,6 +111,13 @@ if ! grep -q "map \$http_upgrade \$connection_upgrade" /etc/nginx/nginx.conf; th
     sed -i '/^http {/a\    # WebSocket upgrade support for tunnel subdomains\n    map $http_upgrade $connection_upgrade {\n        default upgrade;\n        '"'"''"'"' close;\n    }\n' /etc/nginx/nginx.conf
 fi
 
+# TCP tunnels are nginx stream servers on public ports 30000-30999
+# (tunnels.tcp_port_base / tcp_port_count); open that range in the firewall
+if ! grep -q "/etc/nginx/tnnl-streams" /etc/nginx/nginx.conf; then
+    echo "Adding TCP tunnel stream block to nginx.conf..."
+    printf '\n# TCP tunnels, written by the tnnl coordination server\nstream {\n    include /etc/nginx/tnnl-streams/*.conf;\n}\n' >> /etc/nginx/nginx.conf
+fi
+
 cp /root/nginx-tnnl.conf /etc/nginx/sites-available/tnnl
 ln -sf /etc/nginx/sites-available/tnnl /etc/nginx/sites-enabled/tnnl
 rm -f /etc/nginx/sites-enabled/default
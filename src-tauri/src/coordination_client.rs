@@ -2,27 +2,202 @@ use anyhow::{anyhow, Result};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use uuid::Uuid;
-use tauri::AppHandle;
+use tauri::{AppHandle, Emitter};
 
-#[cfg(debug_assertions)]
-const COORDINATION_SERVER_URL: &str = "wss://ws.tnnl.to";
+use crate::websocket_server::ViewerSession;
 
-#[cfg(not(debug_assertions))]
-const COORDINATION_SERVER_URL: &str = "wss://ws.tnnl.to";
+const DEFAULT_COORDINATION_SERVER_URL: &str = "wss://ws.tnnl.to";
+
+/// Coordination server to connect to
+/// Self-hosted deployments point TNNL_SERVER_URL at their own server; everything
+/// else (tunnel domain, SSH host) is announced by the server after auth
+fn coordination_server_url() -> String {
+    std::env::var("TNNL_SERVER_URL")
+        .ok()
+        .filter(|url| !url.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_COORDINATION_SERVER_URL.to_string())
+}
+
+/// Host part of the configured server URL, e.g. "ws.example.com" for "wss://ws.example.com:443/ws"
+fn coordination_server_host() -> Option<String> {
+    let url = coordination_server_url();
+    let authority = url.split("://").nth(1).unwrap_or(&url).split('/').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+/// Region hint sent with auth so a clustered server can list the closest node first
+fn client_region() -> Option<String> {
+    std::env::var("TNNL_REGION")
+        .ok()
+        .filter(|region| !region.trim().is_empty())
+}
+
+/// Deployment details the server sends in auth_success
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Tunnels are published as <subdomain>.<base_domain>
+    #[serde(default)]
+    pub base_domain: String,
+    /// Host to open SSH reverse tunnels to; servers that predate it get the server URL's host
+    #[serde(default)]
+    pub ssh_host: String,
+    /// Live nodes of a clustered server, closest first; empty when standalone
+    #[serde(default)]
+    pub nodes: Vec<ClusterNode>,
+}
+
+/// A coordination server node announced in auth_success
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterNode {
+    pub id: String,
+    pub region: Option<String>,
+    pub url: Option<String>,
+}
+
+/// What a tunnel forwards to on this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelKind {
+    /// The screen-sharing WebSocket server on port 9001
+    #[default]
+    Screen,
+    /// A local HTTP server, e.g. a dev server
+    Http,
+    /// Any local TCP service, exposed on a public port
+    Tcp,
+}
+
+/// A tunnel to ask the server for
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    #[serde(default)]
+    pub kind: TunnelKind,
+    /// Service to forward to; required for HTTP and TCP tunnels
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Shown next to the tunnel, e.g. "dev server"
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Close the tunnel automatically after this many seconds
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+}
+
+impl TunnelRequest {
+    fn validate(&self) -> Result<()> {
+        if self.kind != TunnelKind::Screen && self.local_port.is_none() {
+            return Err(anyhow!("A local port is required to share an HTTP or TCP service"));
+        }
+        if self.ttl_seconds == Some(0) {
+            return Err(anyhow!("The time to live must be positive"));
+        }
+        Ok(())
+    }
+
+    /// The request_tunnel message for this request
+    fn to_message(&self) -> serde_json::Value {
+        let mut request = serde_json::json!({
+            "type": "request_tunnel",
+            "kind": self.kind
+        });
+        if let Some(port) = self.local_port {
+            request["local_port"] = serde_json::json!(port);
+        }
+        if let Some(pwd) = &self.password {
+            request["password"] = serde_json::json!(pwd);
+        }
+        if let Some(label) = &self.label {
+            request["label"] = serde_json::json!(label);
+        }
+        if let Some(ttl) = self.ttl_seconds {
+            request["ttl_seconds"] = serde_json::json!(ttl);
+        }
+        request
+    }
+}
+
+/// Whether a tunnel's SSH forward is up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelStatus {
+    /// Assigned by the server, not confirmed reachable yet
+    #[default]
+    Connecting,
+    Active,
+    /// The local side could not be set up; see `error`
+    Failed,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelInfo {
     pub id: Uuid,
     pub subdomain: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Absent from older servers, which only do screen sharing
+    #[serde(default)]
+    pub kind: TunnelKind,
     pub url: String,
     pub port: u16,
+    /// Local port forwarded to; None for screen tunnels
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    /// Public port of TCP tunnels
+    #[serde(default)]
+    pub public_port: Option<u16>,
     pub password: Option<String>,
+    /// Emails and @domains allowed to view; empty when open to anyone
+    #[serde(default)]
+    pub allowed_viewers: Vec<String>,
     pub created_at: String,
+    /// When the server closes the tunnel; None when it does not expire
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub status: TunnelStatus,
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
+/// A past or current tunnel session from the server's history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelHistoryEntry {
+    pub id: Uuid,
+    pub subdomain: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub is_custom: bool,
+    #[serde(default)]
+    pub kind: TunnelKind,
+    pub port: u16,
+    pub password_protected: bool,
+    pub created_at: String,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    pub last_connected_at: Option<String>,
+    pub closed_at: Option<String>,
+    pub close_reason: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub viewer_sessions: i32,
+    /// Bytes moved through the proxy; absent from older servers
+    #[serde(default)]
+    pub bytes_in: i64,
+    #[serde(default)]
+    pub bytes_out: i64,
+}
+
+/// Interval between heartbeats sent to the coordination server
+const HEARTBEAT_INTERVAL_SECS: u64 = 30;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ConnectionStatus {
     Disconnected,
@@ -36,45 +211,89 @@ pub enum ConnectionStatus {
 #[derive(Clone)]
 pub struct CoordinationClient {
     status: Arc<RwLock<ConnectionStatus>>,
-    tunnel: Arc<RwLock<Option<TunnelInfo>>>,
+    tunnels: Arc<RwLock<Vec<TunnelInfo>>>,
+    server_info: Arc<RwLock<Option<ServerInfo>>>,
     access_token: Arc<RwLock<Option<String>>>,
+    outgoing: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>>,
+    pending_history: Arc<Mutex<Option<oneshot::Sender<Vec<TunnelHistoryEntry>>>>>,
 }
 
 impl CoordinationClient {
     pub fn new() -> Self {
         Self {
             status: Arc::new(RwLock::new(ConnectionStatus::Disconnected)),
-            tunnel: Arc::new(RwLock::new(None)),
+            tunnels: Arc::new(RwLock::new(Vec::new())),
+            server_info: Arc::new(RwLock::new(None)),
             access_token: Arc::new(RwLock::new(None)),
+            outgoing: Arc::new(RwLock::new(None)),
+            pending_history: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Server URLs to try in order: nodes announced by the last server we
+    /// authenticated with (closest first), then the configured server
+    async fn candidate_urls(&self) -> Vec<String> {
+        let mut urls: Vec<String> = self
+            .server_info
+            .read()
+            .await
+            .as_ref()
+            .map(|info| info.nodes.iter().filter_map(|node| node.url.clone()).collect())
+            .unwrap_or_default();
+
+        let configured = coordination_server_url();
+        if !urls.contains(&configured) {
+            urls.push(configured);
         }
+        urls
     }
 
-    /// Connect to coordination server with authentication token
-    pub async fn connect(&self, app_handle: AppHandle, access_token: String, password: Option<String>) -> Result<()> {
+    /// Connect to coordination server with authentication token, and request a
+    /// first tunnel; more can be added with `request_tunnel`
+    pub async fn connect(
+        &self,
+        app_handle: AppHandle,
+        access_token: String,
+        initial_tunnel: TunnelRequest,
+    ) -> Result<()> {
+        initial_tunnel.validate()?;
+
         // Store token for reconnection
         *self.access_token.write().await = Some(access_token.clone());
 
         *self.status.write().await = ConnectionStatus::Connecting;
 
-        // Connect to WebSocket server with timeout
-        let connect_result = tokio::time::timeout(
-            std::time::Duration::from_secs(10),
-            connect_async(COORDINATION_SERVER_URL)
-        ).await;
-
-        let (ws_stream, response) = match connect_result {
-            Ok(Ok((stream, resp))) => {
-                eprintln!("==> [Coordination] Connection successful, response: {:?}\n", resp);
-                (stream, resp)
-            },
-            Ok(Err(e)) => {
-                eprintln!("==> [Coordination] WebSocket connection failed: {:?}\n", e);
-                return Err(anyhow!("Failed to connect to coordination server: {}", e));
-            },
-            Err(_) => {
-                eprintln!("==> [Coordination] Connection timeout after 10 seconds\n");
-                return Err(anyhow!("Connection timeout"));
+        // Connect to WebSocket server with timeout, failing over through the
+        // nodes a clustered server announced last time
+        let mut connected = None;
+        let mut last_error = anyhow!("No coordination server to connect to");
+        for server_url in self.candidate_urls().await {
+            println!("[Coordination] Connecting to {}", server_url);
+            let connect_result = tokio::time::timeout(
+                std::time::Duration::from_secs(10),
+                connect_async(server_url.as_str())
+            ).await;
+
+            match connect_result {
+                Ok(Ok((stream, resp))) => {
+                    eprintln!("==> [Coordination] Connection successful, response: {:?}\n", resp);
+                    connected = Some(stream);
+                    break;
+                },
+                Ok(Err(e)) => {
+                    eprintln!("==> [Coordination] WebSocket connection to {} failed: {:?}\n", server_url, e);
+                    last_error = anyhow!("Failed to connect to coordination server: {}", e);
+                },
+                Err(_) => {
+                    eprintln!("==> [Coordination] Connection to {} timed out after 10 seconds\n", server_url);
+                    last_error = anyhow!("Connection timeout");
+                }
             }
+        }
+
+        let ws_stream = match connected {
+            Some(stream) => stream,
+            None => return Err(last_error),
         };
 
         *self.status.write().await = ConnectionStatus::Connected;
@@ -82,29 +301,59 @@ impl CoordinationClient {
 
         let (mut write, mut read) = ws_stream.split();
 
+        // All outgoing messages go through a channel so commands can send requests
+        // while the reader task owns the connection
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+        let writer_task = tokio::spawn(async move {
+            while let Some(msg) = out_rx.recv().await {
+                if let Err(e) = write.send(msg).await {
+                    eprintln!("[Coordination] Failed to send message: {}", e);
+                    break;
+                }
+            }
+        });
+
         // Send authentication message
         let auth_msg = serde_json::json!({
             "type": "auth",
-            "token": access_token
+            "token": access_token,
+            "region": client_region()
         });
 
-        write
+        out_tx
             .send(Message::Text(auth_msg.to_string()))
-            .await
             .map_err(|e| anyhow!("Failed to send auth message: {}", e))?;
 
         println!("[Coordination] Sent auth message");
 
+        *self.outgoing.write().await = Some(out_tx.clone());
+
+        // Keep the connection (and the server's last-seen time for our tunnels) fresh
+        let heartbeat_tx = out_tx.clone();
+        let heartbeat_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let heartbeat = serde_json::json!({ "type": "heartbeat" });
+                if heartbeat_tx.send(Message::Text(heartbeat.to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+
         // Clone for the message handler
         let status = self.status.clone();
-        let tunnel = self.tunnel.clone();
-        let password_clone = password.clone();
+        let tunnels = self.tunnels.clone();
+        let server_info = self.server_info.clone();
+        let outgoing = self.outgoing.clone();
+        let pending_history = self.pending_history.clone();
         let app_handle_clone = app_handle.clone();
 
         // Spawn task to handle incoming messages
         tokio::spawn(async move {
             let mut authenticated = false;
-            let mut write_handle = write;
+            let write_handle = out_tx;
 
             while let Some(msg) = read.next().await {
                 match msg {
@@ -125,6 +374,30 @@ impl CoordinationClient {
                         match msg_type {
                             Some("auth_success") => {
                                 println!("[Coordination] Authentication successful");
+
+                                let mut info: ServerInfo = match serde_json::from_value(value.clone()) {
+                                    Ok(info) => info,
+                                    Err(e) => {
+                                        eprintln!("[Coordination] Invalid server info in auth_success: {}", e);
+                                        *status.write().await = ConnectionStatus::Error(format!("Invalid auth_success: {}", e));
+                                        continue;
+                                    }
+                                };
+                                // Older servers don't announce their domain; assume SSH and
+                                // tunnels live on the host we were pointed at
+                                if info.ssh_host.is_empty() || info.base_domain.is_empty() {
+                                    let host = coordination_server_host().unwrap_or_default();
+                                    eprintln!("[Coordination] Server did not announce its domain, falling back to {}", host);
+                                    if info.ssh_host.is_empty() {
+                                        info.ssh_host = host.clone();
+                                    }
+                                    if info.base_domain.is_empty() {
+                                        info.base_domain = host;
+                                    }
+                                }
+                                println!("[Coordination] Server domain: {}, SSH host: {}", info.base_domain, info.ssh_host);
+                                *server_info.write().await = Some(info);
+
                                 *status.write().await = ConnectionStatus::Authenticated;
                                 authenticated = true;
 
@@ -145,7 +418,6 @@ impl CoordinationClient {
 
                                 if let Err(e) = write_handle
                                     .send(Message::Text(ssh_key_msg.to_string()))
-                                    .await
                                 {
                                     eprintln!("[Coordination] Failed to register SSH key: {}", e);
                                     *status.write().await = ConnectionStatus::Error(format!("Failed to register SSH key: {}", e));
@@ -156,21 +428,11 @@ impl CoordinationClient {
                             Some("ssh_key_registered") => {
                                 println!("[Coordination] SSH key registered successfully");
 
-                                // Request tunnel
-                                let tunnel_request = if let Some(pwd) = &password_clone {
-                                    serde_json::json!({
-                                        "type": "request_tunnel",
-                                        "password": pwd
-                                    })
-                                } else {
-                                    serde_json::json!({
-                                        "type": "request_tunnel"
-                                    })
-                                };
+                                // Request the first tunnel
+                                let tunnel_request = initial_tunnel.to_message();
 
                                 if let Err(e) = write_handle
                                     .send(Message::Text(tunnel_request.to_string()))
-                                    .await
                                 {
                                     eprintln!("[Coordination] Failed to request tunnel: {}", e);
                                     *status.write().await = ConnectionStatus::Error(format!("Failed to request tunnel: {}", e));
@@ -192,36 +454,87 @@ impl CoordinationClient {
 
                                     println!("[Coordination] Tunnel URL: {}", tunnel_info.url);
 
-                                    // Start WebSocket server on port 9001 if not already running
-                                    let local_port = 9001;
-                                    println!("[Coordination] Starting WebSocket server on port {}...", local_port);
-                                    let ws_result = crate::websocket_server::start_server(local_port).await
-                                        .map_err(|e| e.to_string());
-                                    if let Err(error_msg) = ws_result {
-                                        eprintln!("[Coordination] Failed to start WebSocket server: {}", error_msg);
-                                        *status.write().await = ConnectionStatus::Error(format!("Failed to start WebSocket server: {}", error_msg));
-                                        continue;
-                                    }
-                                    println!("[Coordination] WebSocket server started on port {}", local_port);
+                                    // Newer servers confirm with tunnel_ready once they see the forward
+                                    let awaits_ready = tunnel_data.get("ready") == Some(&serde_json::Value::Bool(false));
 
-                                    // Establish SSH tunnel
+                                    let tunnel_id = tunnel_info.id;
+                                    let kind = tunnel_info.kind;
                                     let remote_port = tunnel_info.port;
+                                    let local_port = tunnel_info.local_port;
+                                    tunnels.write().await.push(tunnel_info);
+                                    *status.write().await = ConnectionStatus::TunnelAssigned;
 
-                                    if let Err(e) = crate::ssh_tunnel::establish_ssh_tunnel(
-                                        &app_handle_clone,
-                                        remote_port,
-                                        local_port
-                                    ).await {
-                                        eprintln!("[Coordination] Failed to establish SSH tunnel: {}", e);
-                                        *status.write().await = ConnectionStatus::Error(format!("Failed to establish SSH tunnel: {}", e));
-                                        continue;
+                                    let result = forward_tunnel(&app_handle_clone, &server_info, tunnel_id, kind, remote_port, local_port).await;
+                                    if let Some(info) = tunnels.write().await.iter_mut().find(|t| t.id == tunnel_id) {
+                                        match result {
+                                            Ok(()) if awaits_ready => {}
+                                            Ok(()) => info.status = TunnelStatus::Active,
+                                            Err(e) => {
+                                                eprintln!("[Coordination] Failed to set up tunnel {}: {}", tunnel_id, e);
+                                                info.status = TunnelStatus::Failed;
+                                                info.error = Some(e.to_string());
+                                            }
+                                        }
                                     }
+                                    emit_tunnels_changed(&app_handle_clone, &tunnels).await;
+                                }
+                            }
+                            Some("tunnel_ready") => {
+                                let id = value.get("id").and_then(|v| v.as_str()).and_then(|v| Uuid::parse_str(v).ok());
+                                if let Some(info) = tunnels.write().await.iter_mut().find(|t| Some(t.id) == id) {
+                                    println!("[Coordination] Tunnel {} is ready", info.subdomain);
+                                    if info.status == TunnelStatus::Connecting {
+                                        info.status = TunnelStatus::Active;
+                                    }
+                                }
+                                emit_tunnels_changed(&app_handle_clone, &tunnels).await;
+                            }
+                            Some("tunnel_failed") => {
+                                // The server saw no SSH forward; tunnel_closed follows
+                                let id = value.get("id").and_then(|v| v.as_str()).and_then(|v| Uuid::parse_str(v).ok());
+                                let reason = value.get("reason").and_then(|v| v.as_str()).unwrap_or("unknown");
+                                eprintln!("[Coordination] Tunnel {:?} failed: {}", id, reason);
+                                if let Some(info) = tunnels.write().await.iter_mut().find(|t| Some(t.id) == id) {
+                                    info.status = TunnelStatus::Failed;
+                                    info.error = Some(reason.to_string());
+                                }
+                                if let Err(e) = app_handle_clone.emit("tunnel-failed", value.clone()) {
+                                    eprintln!("[Coordination] Failed to emit tunnel failure: {}", e);
+                                }
+                                emit_tunnels_changed(&app_handle_clone, &tunnels).await;
+                            }
+                            Some("tunnel_closed") => {
+                                // Older servers only send the subdomain
+                                let id = value.get("id").and_then(|v| v.as_str()).and_then(|v| Uuid::parse_str(v).ok());
+                                let subdomain = value.get("subdomain").and_then(|v| v.as_str());
+                                let reason = value.get("reason").and_then(|v| v.as_str()).unwrap_or("unknown");
+
+                                let closed = {
+                                    let mut tunnels = tunnels.write().await;
+                                    let index = tunnels.iter().position(|t| {
+                                        Some(t.id) == id || (id.is_none() && Some(t.subdomain.as_str()) == subdomain)
+                                    });
+                                    index.map(|i| tunnels.remove(i))
+                                };
+                                let Some(closed) = closed else {
+                                    continue;
+                                };
+                                println!("[Coordination] Tunnel {} closed ({})", closed.subdomain, reason);
 
-                                    println!("[Coordination] SSH tunnel established: {}:localhost:{}", remote_port, local_port);
+                                if let Err(e) = crate::ssh_tunnel::close_ssh_tunnel(&app_handle_clone, closed.id).await {
+                                    eprintln!("[Coordination] Failed to close SSH tunnel: {}", e);
+                                }
 
-                                    *tunnel.write().await = Some(tunnel_info);
-                                    *status.write().await = ConnectionStatus::TunnelAssigned;
+                                let remaining = tunnels.read().await.clone();
+                                if closed.kind == TunnelKind::Screen
+                                    && !remaining.iter().any(|t| t.kind == TunnelKind::Screen)
+                                {
+                                    let _ = crate::websocket_server::stop_server().await;
+                                }
+                                if remaining.is_empty() {
+                                    *status.write().await = ConnectionStatus::Authenticated;
                                 }
+                                emit_tunnels_changed(&app_handle_clone, &tunnels).await;
                             }
                             Some("error") => {
                                 let error_msg = value
@@ -229,7 +542,51 @@ impl CoordinationClient {
                                     .and_then(|v| v.as_str())
                                     .unwrap_or("Unknown error");
                                 eprintln!("[Coordination] Server error: {}", error_msg);
-                                *status.write().await = ConnectionStatus::Error(error_msg.to_string());
+                                if tunnels.read().await.is_empty() {
+                                    *status.write().await = ConnectionStatus::Error(error_msg.to_string());
+                                } else if let Err(e) = app_handle_clone.emit("coordination-error", error_msg) {
+                                    // A failed extra request must not mark live tunnels as broken
+                                    eprintln!("[Coordination] Failed to emit server error: {}", e);
+                                }
+                            }
+                            Some("tunnel_history") => {
+                                let entries: Vec<TunnelHistoryEntry> = value
+                                    .get("tunnels")
+                                    .cloned()
+                                    .and_then(|t| serde_json::from_value(t).ok())
+                                    .unwrap_or_default();
+
+                                if let Some(sender) = pending_history.lock().await.take() {
+                                    let _ = sender.send(entries);
+                                }
+                            }
+                            Some("viewer_allowlist") => {
+                                let allowed: Vec<String> = value
+                                    .get("allowed_viewers")
+                                    .cloned()
+                                    .and_then(|v| serde_json::from_value(v).ok())
+                                    .unwrap_or_default();
+                                println!("[Coordination] Viewer allowlist updated ({} entries)", allowed.len());
+
+                                let subdomain = value.get("subdomain").and_then(|v| v.as_str());
+                                if let Some(info) = tunnels.write().await.iter_mut().find(|t| Some(t.subdomain.as_str()) == subdomain) {
+                                    info.allowed_viewers = allowed;
+                                }
+                                emit_tunnels_changed(&app_handle_clone, &tunnels).await;
+                            }
+                            Some("bandwidth_warning") | Some("bandwidth_limited") => {
+                                // Monthly transfer cap nearly or fully used; the UI shows it
+                                println!("[Coordination] Bandwidth notice: {}", value);
+                                if let Err(e) = app_handle_clone.emit("bandwidth-notice", value.clone()) {
+                                    eprintln!("[Coordination] Failed to emit bandwidth notice: {}", e);
+                                }
+                            }
+                            Some("tunnel_expiring") => {
+                                // The tunnel's time to live is nearly up; the UI warns the host
+                                println!("[Coordination] Tunnel expiring: {}", value);
+                                if let Err(e) = app_handle_clone.emit("tunnel-expiring", value.clone()) {
+                                    eprintln!("[Coordination] Failed to emit expiry notice: {}", e);
+                                }
                             }
                             Some("heartbeat_ack") => {
                                 // Heartbeat acknowledged, connection is alive
@@ -239,8 +596,16 @@ impl CoordinationClient {
                             }
                         }
                     }
-                    Ok(Message::Close(_)) => {
-                        println!("[Coordination] Server closed connection");
+                    Ok(Message::Close(frame)) => {
+                        // Limit violations come with a close code and reason, e.g. 4029 "too many messages"
+                        match frame {
+                            Some(frame) => println!(
+                                "[Coordination] Server closed connection: {} {}",
+                                u16::from(frame.code),
+                                frame.reason
+                            ),
+                            None => println!("[Coordination] Server closed connection"),
+                        }
                         *status.write().await = ConnectionStatus::Disconnected;
                         break;
                     }
@@ -253,20 +618,143 @@ impl CoordinationClient {
                 }
             }
 
+            heartbeat_task.abort();
+            writer_task.abort();
+            *outgoing.write().await = None;
+            // The server dropped our tunnels with the connection; stop their
+            // forwards so the remote ports are free when we reconnect
+            if let Err(e) = crate::ssh_tunnel::close_all_ssh_tunnels(&app_handle_clone).await {
+                eprintln!("[Coordination] Failed to close SSH tunnels: {}", e);
+            }
+            tunnels.write().await.clear();
             *status.write().await = ConnectionStatus::Disconnected;
         });
 
         Ok(())
     }
 
+    /// Send a message to the coordination server
+    async fn send_message(&self, value: serde_json::Value) -> Result<()> {
+        let outgoing = self.outgoing.read().await;
+        let sender = outgoing
+            .as_ref()
+            .ok_or_else(|| anyhow!("Not connected to coordination server"))?;
+        sender
+            .send(Message::Text(value.to_string()))
+            .map_err(|_| anyhow!("Coordination connection closed"))
+    }
+
+    /// Fetch the user's tunnel session history from the server
+    pub async fn request_tunnel_history(&self, limit: Option<i64>) -> Result<Vec<TunnelHistoryEntry>> {
+        let (tx, rx) = oneshot::channel();
+        *self.pending_history.lock().await = Some(tx);
+
+        let mut request = serde_json::json!({ "type": "get_tunnel_history" });
+        if let Some(limit) = limit {
+            request["limit"] = serde_json::json!(limit);
+        }
+        self.send_message(request).await?;
+
+        match tokio::time::timeout(std::time::Duration::from_secs(10), rx).await {
+            Ok(Ok(entries)) => Ok(entries),
+            Ok(Err(_)) => Err(anyhow!("History request was superseded")),
+            Err(_) => Err(anyhow!("Timed out waiting for tunnel history")),
+        }
+    }
+
+    /// Ask the server for another tunnel on this connection
+    pub async fn request_tunnel(&self, request: TunnelRequest) -> Result<()> {
+        request.validate()?;
+        self.send_message(request.to_message()).await
+    }
+
+    /// Ask the server to close one tunnel; its SSH forward is torn down when
+    /// the server confirms with tunnel_closed
+    pub async fn close_tunnel(&self, tunnel_id: Uuid) -> Result<()> {
+        if !self.tunnels.read().await.iter().any(|t| t.id == tunnel_id) {
+            return Err(anyhow!("No such tunnel"));
+        }
+
+        self.send_message(serde_json::json!({
+            "type": "close_tunnel",
+            "id": tunnel_id
+        }))
+        .await
+    }
+
+    /// Subdomain of the screen-sharing tunnel, which viewers connect through
+    async fn screen_subdomain(&self) -> Option<String> {
+        self.tunnels
+            .read()
+            .await
+            .iter()
+            .find(|t| t.kind == TunnelKind::Screen)
+            .map(|t| t.subdomain.clone())
+    }
+
+    /// Replace the emails and domains allowed to view one of our tunnels
+    pub async fn set_viewer_allowlist(&self, tunnel_id: Uuid, allowed_viewers: Vec<String>) -> Result<()> {
+        let subdomain = match self.tunnels.read().await.iter().find(|t| t.id == tunnel_id) {
+            Some(t) => t.subdomain.clone(),
+            None => return Err(anyhow!("No such tunnel")),
+        };
+
+        self.send_message(serde_json::json!({
+            "type": "set_viewer_allowlist",
+            "subdomain": subdomain,
+            "allowed_viewers": allowed_viewers
+        }))
+        .await
+    }
+
+    /// Tell the server a viewer connected to our tunnel
+    pub async fn report_viewer_joined(&self, viewer: &ViewerSession) -> Result<()> {
+        let subdomain = match self.screen_subdomain().await {
+            Some(subdomain) => subdomain,
+            None => return Ok(()),
+        };
+
+        self.send_message(serde_json::json!({
+            "type": "viewer_joined",
+            "subdomain": subdomain,
+            "viewer": {
+                "id": viewer.id,
+                "remote_ip": viewer.remote_ip,
+                "user_agent": viewer.user_agent,
+                "identity": viewer.identity
+            }
+        }))
+        .await
+    }
+
+    /// Tell the server a viewer disconnected from our tunnel
+    pub async fn report_viewer_left(&self, viewer_id: Uuid) -> Result<()> {
+        let subdomain = match self.screen_subdomain().await {
+            Some(subdomain) => subdomain,
+            None => return Ok(()),
+        };
+
+        self.send_message(serde_json::json!({
+            "type": "viewer_left",
+            "subdomain": subdomain,
+            "viewer_id": viewer_id
+        }))
+        .await
+    }
+
     /// Get current connection status
     pub async fn get_status(&self) -> ConnectionStatus {
         self.status.read().await.clone()
     }
 
-    /// Get assigned tunnel info
-    pub async fn get_tunnel(&self) -> Option<TunnelInfo> {
-        self.tunnel.read().await.clone()
+    /// Get the tunnels held by this connection, oldest first
+    pub async fn get_tunnels(&self) -> Vec<TunnelInfo> {
+        self.tunnels.read().await.clone()
+    }
+
+    /// Get the deployment details announced by the server
+    pub async fn get_server_info(&self) -> Option<ServerInfo> {
+        self.server_info.read().await.clone()
     }
 
     /// Check if connected and tunnel is assigned
@@ -281,9 +769,14 @@ impl CoordinationClient {
     pub async fn disconnect(&self) -> Result<()> {
         println!("[Coordination] Disconnecting...");
 
+        // Close the connection so the server tears down our tunnels
+        if let Some(sender) = self.outgoing.write().await.take() {
+            let _ = sender.send(Message::Close(None));
+        }
+
         // Reset all state
         *self.status.write().await = ConnectionStatus::Disconnected;
-        *self.tunnel.write().await = None;
+        self.tunnels.write().await.clear();
 
         println!("[Coordination] Disconnected and state cleared");
         Ok(())
@@ -299,10 +792,62 @@ pub async fn get_or_init_client() -> Arc<Mutex<Option<CoordinationClient>>> {
     COORDINATION_CLIENT.clone()
 }
 
+/// Forward an assigned tunnel: screen tunnels go to our WebSocket server on
+/// port 9001, started here if not already running; HTTP and TCP tunnels go to
+/// the service the user picked
+async fn forward_tunnel(
+    app_handle: &AppHandle,
+    server_info: &RwLock<Option<ServerInfo>>,
+    tunnel_id: Uuid,
+    kind: TunnelKind,
+    remote_port: u16,
+    local_port: Option<u16>,
+) -> Result<()> {
+    let local_port = match (kind, local_port) {
+        (TunnelKind::Screen, _) => {
+            let local_port = 9001;
+            if !crate::websocket_server::is_running().await {
+                println!("[Coordination] Starting WebSocket server on port {}...", local_port);
+                crate::websocket_server::start_server(local_port)
+                    .await
+                    .map_err(|e| anyhow!("Failed to start WebSocket server: {}", e))?;
+                println!("[Coordination] WebSocket server started on port {}", local_port);
+            }
+            local_port
+        }
+        (_, Some(port)) => port,
+        (_, None) => return Err(anyhow!("Server assigned a {:?} tunnel without a local port", kind)),
+    };
+
+    let ssh_host = match server_info.read().await.as_ref() {
+        Some(info) => info.ssh_host.clone(),
+        None => return Err(anyhow!("Unknown SSH host")),
+    };
+
+    crate::ssh_tunnel::establish_ssh_tunnel(app_handle, &ssh_host, tunnel_id, remote_port, local_port)
+        .await
+        .map_err(|e| anyhow!("Failed to establish SSH tunnel: {}", e))?;
+
+    println!("[Coordination] SSH tunnel established: {}:localhost:{}", remote_port, local_port);
+    Ok(())
+}
+
+/// Let the UI know the tunnel list changed
+async fn emit_tunnels_changed(app_handle: &AppHandle, tunnels: &RwLock<Vec<TunnelInfo>>) {
+    let tunnels = tunnels.read().await.clone();
+    if let Err(e) = app_handle.emit("tunnels-changed", tunnels) {
+        eprintln!("[Coordination] Failed to emit tunnel list: {}", e);
+    }
+}
+
 /// Connect to coordination server
-pub async fn connect_to_coordination(app_handle: AppHandle, access_token: String, password: Option<String>) -> Result<()> {
+pub async fn connect_to_coordination(
+    app_handle: AppHandle,
+    access_token: String,
+    initial_tunnel: TunnelRequest,
+) -> Result<()> {
     let client = CoordinationClient::new();
-    client.connect(app_handle, access_token, password).await?;
+    client.connect(app_handle, access_token, initial_tunnel).await?;
 
     let mut global_client = COORDINATION_CLIENT.lock().await;
     *global_client = Some(client);
@@ -310,13 +855,82 @@ pub async fn connect_to_coordination(app_handle: AppHandle, access_token: String
     Ok(())
 }
 
-/// Get tunnel info from global client
+/// Get the tunnels held by the global client
+pub async fn get_tunnels() -> Vec<TunnelInfo> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.get_tunnels().await,
+        None => Vec::new(),
+    }
+}
+
+/// Get the first tunnel of the global client
 pub async fn get_tunnel_info() -> Option<TunnelInfo> {
-    let client_lock = COORDINATION_CLIENT.lock().await;
-    if let Some(client) = client_lock.as_ref() {
-        client.get_tunnel().await
-    } else {
-        None
+    get_tunnels().await.into_iter().next()
+}
+
+/// Add a tunnel to the existing connection
+pub async fn request_tunnel(request: TunnelRequest) -> Result<()> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.request_tunnel(request).await,
+        None => Err(anyhow!("Not connected to coordination server")),
+    }
+}
+
+/// Close one tunnel, keeping the connection and any other tunnels
+pub async fn close_tunnel(tunnel_id: Uuid) -> Result<()> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.close_tunnel(tunnel_id).await,
+        None => Err(anyhow!("Not connected to coordination server")),
+    }
+}
+
+/// Get the server's announced domain and SSH host, once authenticated
+pub async fn get_server_info() -> Option<ServerInfo> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.get_server_info().await,
+        None => None,
+    }
+}
+
+/// Get tunnel session history from the server
+pub async fn get_tunnel_history(limit: Option<i64>) -> Result<Vec<TunnelHistoryEntry>> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.request_tunnel_history(limit).await,
+        None => Err(anyhow!("Not connected to coordination server")),
+    }
+}
+
+/// Restrict a tunnel to the given viewer emails and @domains
+pub async fn set_viewer_allowlist(tunnel_id: Uuid, allowed_viewers: Vec<String>) -> Result<()> {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    match client {
+        Some(client) => client.set_viewer_allowlist(tunnel_id, allowed_viewers).await,
+        None => Err(anyhow!("Not connected to coordination server")),
+    }
+}
+
+/// Report a new viewer connection to the server, if a tunnel is active
+pub async fn report_viewer_joined(viewer: ViewerSession) {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    if let Some(client) = client {
+        if let Err(e) = client.report_viewer_joined(&viewer).await {
+            eprintln!("[Coordination] Failed to report viewer join: {}", e);
+        }
+    }
+}
+
+/// Report a viewer disconnect to the server, if a tunnel is active
+pub async fn report_viewer_left(viewer_id: Uuid) {
+    let client = COORDINATION_CLIENT.lock().await.clone();
+    if let Some(client) = client {
+        if let Err(e) = client.report_viewer_left(viewer_id).await {
+            eprintln!("[Coordination] Failed to report viewer leave: {}", e);
+        }
     }
 }
 
@@ -332,9 +946,9 @@ pub async fn get_connection_status() -> ConnectionStatus {
 
 /// Disconnect from coordination server and clean up
 pub async fn disconnect_from_coordination(app_handle: &AppHandle) -> Result<()> {
-    // Close SSH tunnel first
-    if let Err(e) = crate::ssh_tunnel::close_ssh_tunnel(app_handle).await {
-        eprintln!("[Coordination] Failed to close SSH tunnel: {}", e);
+    // Close SSH tunnels first
+    if let Err(e) = crate::ssh_tunnel::close_all_ssh_tunnels(app_handle).await {
+        eprintln!("[Coordination] Failed to close SSH tunnels: {}", e);
     }
 
     // Disconnect coordination client
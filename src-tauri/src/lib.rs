@@ -32,6 +32,7 @@ pub fn run() {
             start_websocket_server,
             stop_websocket_server,
             get_websocket_info,
+            get_viewer_sessions,
             get_running_apps,
             get_foreground_app,
             focus_app,
@@ -49,11 +50,20 @@ pub fn run() {
             connect_to_coordination_server,
             get_coordination_status,
             get_tunnel_info,
+            get_tunnels,
+            request_tunnel,
+            close_tunnel,
+            get_server_info,
+            get_tunnel_history,
+            set_viewer_allowlist,
             disconnect_tunnel,
             is_tunnel_active,
             show_and_activate_window,
         ])
         .setup(|app| {
+            // Let the WebSocket server push viewer join/leave events to the frontend
+            websocket_server::set_app_handle(app.handle().clone());
+
             // Clean up orphaned processes from previous sessions (e.g., after force quit)
             println!("[tnnl] Running startup cleanup...");
             if let Err(e) = websocket_server::cleanup_orphaned_port_9001() {
@@ -356,6 +366,11 @@ async fn get_websocket_info() -> Result<websocket_server::ServerInfo, String> {
         .map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+async fn get_viewer_sessions() -> Vec<websocket_server::ViewerSession> {
+    websocket_server::get_viewer_sessions().await
+}
+
 // Window management commands
 #[tauri::command]
 fn get_running_apps() -> Result<Vec<window_manager::AppInfo>, String> {
@@ -479,13 +494,28 @@ async fn workos_verify_code(code: String, auth_id: String) -> Result<workos_auth
 
 // Coordination server commands
 #[tauri::command]
-async fn connect_to_coordination_server(app: tauri::AppHandle, access_token: String, password: Option<String>) -> Result<String, String> {
+async fn connect_to_coordination_server(
+    app: tauri::AppHandle,
+    access_token: String,
+    password: Option<String>,
+    kind: Option<coordination_client::TunnelKind>,
+    local_port: Option<u16>,
+    label: Option<String>,
+    ttl_seconds: Option<u64>,
+) -> Result<String, String> {
     // Disconnect first if already connected
     if let Err(e) = coordination_client::disconnect_from_coordination(&app).await {
         eprintln!("[Connect] Warning: Failed to disconnect existing connection: {}", e);
     }
 
-    coordination_client::connect_to_coordination(app, access_token, password)
+    let initial_tunnel = coordination_client::TunnelRequest {
+        kind: kind.unwrap_or_default(),
+        local_port,
+        password,
+        label,
+        ttl_seconds,
+    };
+    coordination_client::connect_to_coordination(app, access_token, initial_tunnel)
         .await
         .map_err(|e| e.to_string())?;
     Ok("Connected to coordination server".to_string())
@@ -510,6 +540,44 @@ async fn get_tunnel_info() -> Result<Option<coordination_client::TunnelInfo>, St
     Ok(coordination_client::get_tunnel_info().await)
 }
 
+#[tauri::command]
+async fn get_tunnels() -> Result<Vec<coordination_client::TunnelInfo>, String> {
+    Ok(coordination_client::get_tunnels().await)
+}
+
+#[tauri::command]
+async fn request_tunnel(request: coordination_client::TunnelRequest) -> Result<(), String> {
+    coordination_client::request_tunnel(request)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn close_tunnel(tunnel_id: uuid::Uuid) -> Result<(), String> {
+    coordination_client::close_tunnel(tunnel_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_server_info() -> Result<Option<coordination_client::ServerInfo>, String> {
+    Ok(coordination_client::get_server_info().await)
+}
+
+#[tauri::command]
+async fn get_tunnel_history(limit: Option<i64>) -> Result<Vec<coordination_client::TunnelHistoryEntry>, String> {
+    coordination_client::get_tunnel_history(limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_viewer_allowlist(tunnel_id: uuid::Uuid, allowed_viewers: Vec<String>) -> Result<(), String> {
+    coordination_client::set_viewer_allowlist(tunnel_id, allowed_viewers)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn disconnect_tunnel(app: tauri::AppHandle) -> Result<String, String> {
     coordination_client::disconnect_from_coordination(&app)
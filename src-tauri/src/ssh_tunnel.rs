@@ -1,27 +1,32 @@
 // SSH tunnel management for establishing reverse tunnels to the server
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tauri::{AppHandle, Manager};
 use tauri_plugin_shell::ShellExt;
+use uuid::Uuid;
 
-#[cfg(debug_assertions)]
-const SSH_SERVER: &str = "tnnl.to";
-
-#[cfg(not(debug_assertions))]
-const SSH_SERVER: &str = "tnnl.to";
-
+// The SSH host comes from the coordination server (auth_success.ssh_host)
 const SSH_USER: &str = "tnnl";
 const SSH_KEY_FILENAME: &str = "id_ed25519";
+/// PIDs of the ssh processes we spawned, one per line, so a crashed session can be cleaned up
+const SSH_PIDS_FILENAME: &str = "ssh_pids";
 
-/// SSH tunnel state
+/// One SSH reverse forward, owned by a coordination server tunnel
 #[derive(Clone)]
+pub struct SshForward {
+    ssh_process: u32, // Process ID
+    remote_port: u16,
+    local_port: u16,
+}
+
+/// SSH tunnel state: one forward per tunnel id
+#[derive(Clone, Default)]
 pub struct SshTunnelState {
-    ssh_process: Option<u32>, // Process ID
-    remote_port: Option<u16>,
-    local_port: Option<u16>,
+    forwards: HashMap<Uuid, SshForward>,
 }
 
 /// Global SSH tunnel manager
@@ -29,6 +34,7 @@ pub struct SshTunnelManager {
     state: Arc<RwLock<SshTunnelState>>,
     ssh_key_path: PathBuf,
     ssh_pub_key_path: PathBuf,
+    pids_path: PathBuf,
 }
 
 impl SshTunnelManager {
@@ -43,71 +49,77 @@ impl SshTunnelManager {
 
         let ssh_key_path = tnnl_dir.join(SSH_KEY_FILENAME);
         let ssh_pub_key_path = tnnl_dir.join(format!("{}.pub", SSH_KEY_FILENAME));
+        let pids_path = tnnl_dir.join(SSH_PIDS_FILENAME);
 
         Ok(Self {
-            state: Arc::new(RwLock::new(SshTunnelState {
-                ssh_process: None,
-                remote_port: None,
-                local_port: None,
-            })),
+            state: Arc::new(RwLock::new(SshTunnelState::default())),
             ssh_key_path,
             ssh_pub_key_path,
+            pids_path,
         })
     }
 
+    /// PIDs recorded in the pid file
+    fn read_pids(&self) -> Vec<u32> {
+        std::fs::read_to_string(&self.pids_path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect()
+    }
+
+    fn write_pids(&self, pids: &[u32]) {
+        let content: String = pids.iter().map(|pid| format!("{}\n", pid)).collect();
+        if let Err(e) = std::fs::write(&self.pids_path, content) {
+            eprintln!("[SSH Tunnel] Failed to write {}: {}", self.pids_path.display(), e);
+        }
+    }
+
+    fn record_pid(&self, pid: u32) {
+        let mut pids = self.read_pids();
+        pids.push(pid);
+        self.write_pids(&pids);
+    }
+
+    fn forget_pid(&self, pid: u32) {
+        let pids: Vec<u32> = self.read_pids().into_iter().filter(|p| *p != pid).collect();
+        self.write_pids(&pids);
+    }
+
     /// Clean up any orphaned SSH tunnels from previous sessions
     /// This is especially important after force quits or crashes
     pub fn cleanup_orphaned_tunnels(&self) -> Result<()> {
         println!("[SSH Tunnel] Cleaning up orphaned SSH tunnels from previous sessions...");
 
-        #[cfg(unix)]
-        {
-            // Find all SSH processes connecting to our server
-            let output = Command::new("sh")
-                .arg("-c")
-                .arg(format!("ps aux | grep 'ssh.*-R.*{}@{}' | grep -v grep || true", SSH_USER, SSH_SERVER))
-                .output()?;
-
-            if output.status.success() {
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                let lines: Vec<&str> = stdout.lines().collect();
-
-                if lines.is_empty() {
-                    println!("[SSH Tunnel] No orphaned tunnels found");
-                    return Ok(());
-                }
-
-                println!("[SSH Tunnel] Found {} orphaned tunnel(s)", lines.len());
-
-                for line in lines {
-                    // Extract PID (second column in ps aux output)
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() >= 2 {
-                        if let Ok(pid) = parts[1].parse::<i32>() {
-                            println!("[SSH Tunnel] Killing orphaned tunnel PID: {}", pid);
+        // Only processes this app spawned are recorded, so ssh sessions the user
+        // started themselves are never touched
+        let pids = self.read_pids();
+        if pids.is_empty() {
+            println!("[SSH Tunnel] No orphaned tunnels found");
+            return Ok(());
+        }
 
-                            use nix::sys::signal::{kill, Signal};
-                            use nix::unistd::Pid;
+        println!("[SSH Tunnel] Found {} orphaned tunnel(s)", pids.len());
 
-                            let pid_obj = Pid::from_raw(pid);
-                            if let Err(e) = kill(pid_obj, Signal::SIGTERM) {
-                                eprintln!("[SSH Tunnel] Failed to kill PID {}: {}", pid, e);
-                            } else {
-                                println!("[SSH Tunnel] ✓ Killed orphaned tunnel PID: {}", pid);
-                            }
-                        }
-                    }
+        for pid in pids {
+            #[cfg(unix)]
+            {
+                // The PID may have been reused since the crash; only kill it if it
+                // is still one of our reverse tunnels
+                let output = Command::new("ps")
+                    .args(&["-p", &pid.to_string(), "-o", "command="])
+                    .output()?;
+                let command = String::from_utf8_lossy(&output.stdout);
+                if !(command.contains("ssh") && command.contains(&format!("{}@", SSH_USER))) {
+                    continue;
                 }
             }
-        }
 
-        #[cfg(windows)]
-        {
-            // On Windows, use tasklist and taskkill
-            // TODO: Implement Windows cleanup
-            eprintln!("[SSH Tunnel] Orphaned tunnel cleanup not yet implemented for Windows");
+            println!("[SSH Tunnel] Killing orphaned tunnel PID: {}", pid);
+            Self::kill_process(pid);
         }
 
+        self.write_pids(&[]);
         Ok(())
     }
 
@@ -151,18 +163,20 @@ impl SshTunnelManager {
         Ok(content.trim().to_string())
     }
 
-    /// Establish SSH reverse tunnel
+    /// Establish the SSH reverse tunnel for one coordination server tunnel
     /// Example: ssh -R remote_port:localhost:local_port -N tnnl@server
     pub async fn establish_tunnel(
         &self,
         app_handle: &AppHandle,
+        ssh_host: &str,
+        tunnel_id: Uuid,
         remote_port: u16,
         local_port: u16,
     ) -> Result<()> {
-        // Check if already connected
+        // Check if this tunnel is already forwarded
         {
             let mut state = self.state.write().await;
-            if let Some(pid) = state.ssh_process {
+            if let Some(pid) = state.forwards.get(&tunnel_id).map(|f| f.ssh_process) {
                 // Verify the process is actually running
                 #[cfg(unix)]
                 {
@@ -177,19 +191,17 @@ impl SshTunnelManager {
 
                     // Process doesn't exist, clear stale state
                     eprintln!("[SSH Tunnel] Clearing stale tunnel state (PID {} not running)", pid);
-                    state.ssh_process = None;
-                    state.remote_port = None;
-                    state.local_port = None;
+                    state.forwards.remove(&tunnel_id);
+                    self.forget_pid(pid);
                 }
 
                 #[cfg(windows)]
                 {
                     // On Windows, just try to establish new tunnel
                     // TODO: Implement proper process checking on Windows
-                    eprintln!("[SSH Tunnel] Clearing stale tunnel state (Windows)");
-                    state.ssh_process = None;
-                    state.remote_port = None;
-                    state.local_port = None;
+                    eprintln!("[SSH Tunnel] Clearing stale tunnel state (Windows, PID {})", pid);
+                    state.forwards.remove(&tunnel_id);
+                    self.forget_pid(pid);
                 }
             }
         }
@@ -197,12 +209,12 @@ impl SshTunnelManager {
         // Ensure SSH keys exist
         self.ensure_ssh_keys()?;
 
-        println!("[SSH Tunnel] Establishing tunnel: remote_port={}, local_port={}", remote_port, local_port);
+        println!("[SSH Tunnel] Establishing tunnel {}: remote_port={}, local_port={}", tunnel_id, remote_port, local_port);
 
         // Build SSH command
         // ssh -R remote_port:localhost:local_port -N -o StrictHostKeyChecking=no -i key_path user@server
         eprintln!("[SSH Tunnel] SSH command: ssh -R {}:localhost:{} -N -o StrictHostKeyChecking=no -o ServerAliveInterval=30 -o ServerAliveCountMax=3 -i {} {}@{}",
-            remote_port, local_port, self.ssh_key_path.display(), SSH_USER, SSH_SERVER);
+            remote_port, local_port, self.ssh_key_path.display(), SSH_USER, ssh_host);
 
         // Try using Tauri shell plugin first (works in sandbox), fallback to std::process::Command
         let ssh_result = app_handle.shell()
@@ -214,7 +226,7 @@ impl SshTunnelManager {
                 "-o", "ServerAliveInterval=30",
                 "-o", "ServerAliveCountMax=3",
                 "-i", &self.ssh_key_path.to_string_lossy(),
-                &format!("{}@{}", SSH_USER, SSH_SERVER),
+                &format!("{}@{}", SSH_USER, ssh_host),
             ])
             .spawn();
 
@@ -258,7 +270,7 @@ impl SshTunnelManager {
                         "-o", "ServerAliveInterval=30",
                         "-o", "ServerAliveCountMax=3",
                         "-i", &self.ssh_key_path.to_string_lossy(),
-                        &format!("{}@{}", SSH_USER, SSH_SERVER),
+                        &format!("{}@{}", SSH_USER, ssh_host),
                     ])
                     .stdout(Stdio::piped())
                     .stderr(Stdio::piped())
@@ -272,69 +284,83 @@ impl SshTunnelManager {
             }
         };
         println!("[SSH Tunnel] SSH process started with PID: {}", pid);
+        self.record_pid(pid);
 
         // Update state
         {
             let mut state = self.state.write().await;
-            state.ssh_process = Some(pid);
-            state.remote_port = Some(remote_port);
-            state.local_port = Some(local_port);
+            state.forwards.insert(tunnel_id, SshForward {
+                ssh_process: pid,
+                remote_port,
+                local_port,
+            });
         }
 
         Ok(())
     }
 
-    /// Close the SSH tunnel
-    pub async fn close_tunnel(&self) -> Result<()> {
-        let pid = {
-            let mut state = self.state.write().await;
-            let pid = state.ssh_process.take();
-            state.remote_port = None;
-            state.local_port = None;
-            pid
-        };
-
-        if let Some(pid) = pid {
-            println!("[SSH Tunnel] Closing SSH tunnel (PID: {})", pid);
+    /// Close the SSH tunnel of one coordination server tunnel
+    pub async fn close_tunnel(&self, tunnel_id: Uuid) -> Result<()> {
+        let forward = self.state.write().await.forwards.remove(&tunnel_id);
 
-            // Kill the SSH process
-            #[cfg(unix)]
-            {
-                use nix::sys::signal::{kill, Signal};
-                use nix::unistd::Pid;
+        if let Some(forward) = forward {
+            self.kill_forward(forward.ssh_process);
+        }
 
-                let pid = Pid::from_raw(pid as i32);
-                if let Err(e) = kill(pid, Signal::SIGTERM) {
-                    eprintln!("[SSH Tunnel] Failed to kill SSH process: {}", e);
-                }
-            }
+        Ok(())
+    }
 
-            #[cfg(windows)]
-            {
-                let _ = Command::new("taskkill")
-                    .args(&["/PID", &pid.to_string(), "/F"])
-                    .output();
-            }
+    /// Close every SSH tunnel, e.g. when disconnecting
+    pub async fn close_all_tunnels(&self) -> Result<()> {
+        let forwards: Vec<SshForward> = self.state.write().await.forwards.drain().map(|(_, f)| f).collect();
 
-            println!("[SSH Tunnel] SSH tunnel closed");
+        for forward in forwards {
+            self.kill_forward(forward.ssh_process);
         }
 
         Ok(())
     }
 
-    /// Check if tunnel is active
+    fn kill_forward(&self, pid: u32) {
+        println!("[SSH Tunnel] Closing SSH tunnel (PID: {})", pid);
+        Self::kill_process(pid);
+        self.forget_pid(pid);
+        println!("[SSH Tunnel] SSH tunnel closed");
+    }
+
+    fn kill_process(pid: u32) {
+        #[cfg(unix)]
+        {
+            use nix::sys::signal::{kill, Signal};
+            use nix::unistd::Pid;
+
+            let pid = Pid::from_raw(pid as i32);
+            if let Err(e) = kill(pid, Signal::SIGTERM) {
+                eprintln!("[SSH Tunnel] Failed to kill SSH process: {}", e);
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let _ = Command::new("taskkill")
+                .args(&["/PID", &pid.to_string(), "/F"])
+                .output();
+        }
+    }
+
+    /// Check if any tunnel is active
     pub async fn is_active(&self) -> bool {
         let state = self.state.read().await;
-        state.ssh_process.is_some()
+        !state.forwards.is_empty()
     }
 
-    /// Get current tunnel info
-    pub async fn get_tunnel_info(&self) -> Option<(u16, u16)> {
+    /// Get the (remote, local) ports forwarded for a tunnel
+    pub async fn get_tunnel_info(&self, tunnel_id: Uuid) -> Option<(u16, u16)> {
         let state = self.state.read().await;
-        match (state.remote_port, state.local_port) {
-            (Some(remote), Some(local)) => Some((remote, local)),
-            _ => None,
-        }
+        state
+            .forwards
+            .get(&tunnel_id)
+            .map(|forward| (forward.remote_port, forward.local_port))
     }
 }
 
@@ -375,9 +401,11 @@ pub async fn get_ssh_public_key(app_handle: &AppHandle) -> Result<String> {
     }
 }
 
-/// Establish SSH tunnel
+/// Establish the SSH tunnel for one coordination server tunnel
 pub async fn establish_ssh_tunnel(
     app_handle: &AppHandle,
+    ssh_host: &str,
+    tunnel_id: Uuid,
     remote_port: u16,
     local_port: u16,
 ) -> Result<()> {
@@ -385,18 +413,29 @@ pub async fn establish_ssh_tunnel(
     let manager = manager_lock.lock().await;
 
     match manager.as_ref() {
-        Some(mgr) => mgr.establish_tunnel(app_handle, remote_port, local_port).await,
+        Some(mgr) => mgr.establish_tunnel(app_handle, ssh_host, tunnel_id, remote_port, local_port).await,
+        None => Err(anyhow!("Tunnel manager not initialized")),
+    }
+}
+
+/// Close the SSH tunnel of one coordination server tunnel
+pub async fn close_ssh_tunnel(app_handle: &AppHandle, tunnel_id: Uuid) -> Result<()> {
+    let manager_lock = get_or_init_manager(app_handle).await?;
+    let manager = manager_lock.lock().await;
+
+    match manager.as_ref() {
+        Some(mgr) => mgr.close_tunnel(tunnel_id).await,
         None => Err(anyhow!("Tunnel manager not initialized")),
     }
 }
 
-/// Close SSH tunnel
-pub async fn close_ssh_tunnel(app_handle: &AppHandle) -> Result<()> {
+/// Close every SSH tunnel
+pub async fn close_all_ssh_tunnels(app_handle: &AppHandle) -> Result<()> {
     let manager_lock = get_or_init_manager(app_handle).await?;
     let manager = manager_lock.lock().await;
 
     match manager.as_ref() {
-        Some(mgr) => mgr.close_tunnel().await,
+        Some(mgr) => mgr.close_all_tunnels().await,
         None => Err(anyhow!("Tunnel manager not initialized")),
     }
 }
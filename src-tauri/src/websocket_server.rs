@@ -1,19 +1,111 @@
+use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, RwLock};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderMap;
 use tokio_tungstenite::tungstenite::Message;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use uuid::Uuid;
 
 /// Global WebSocket server state using tokio's async RwLock
 static WS_STATE: Lazy<Arc<RwLock<Option<ServerState>>>> =
     Lazy::new(|| Arc::new(RwLock::new(None)));
 
+/// App handle used to push viewer events to the frontend, set once at startup
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+
+type ViewerMap = Arc<RwLock<HashMap<Uuid, ViewerSession>>>;
+
 struct ServerState {
     address: SocketAddr,
     frame_tx: broadcast::Sender<Vec<u8>>,
     shutdown_tx: broadcast::Sender<()>,
+    viewers: ViewerMap,
+}
+
+/// A viewer connected to the local server
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ViewerSession {
+    pub id: Uuid,
+    /// Viewer address as seen by the tunnel's nginx, or the socket peer for direct connections
+    pub remote_ip: String,
+    pub user_agent: Option<String>,
+    /// Email the viewer signed in with, or the Basic Auth username
+    pub identity: Option<String>,
+    /// Unix timestamp (seconds)
+    pub connected_at: u64,
+}
+
+impl ViewerSession {
+    /// Build a session from the WebSocket upgrade request headers
+    fn from_headers(headers: &HeaderMap, peer_addr: SocketAddr) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        // nginx appends the address it saw to X-Forwarded-For, so the last entry
+        // is the one we can trust; earlier entries are whatever the viewer sent
+        let remote_ip = header("x-forwarded-for")
+            .and_then(|v| v.rsplit(',').next().map(|ip| ip.trim().to_string()))
+            .filter(|ip| !ip.is_empty())
+            .unwrap_or_else(|| peer_addr.ip().to_string());
+
+        // Viewers signed in through an email allowlist are named by nginx,
+        // which always overwrites the header; otherwise use the Basic Auth user
+        let identity = header("x-tnnl-viewer")
+            .or_else(|| header("authorization").and_then(|v| basic_auth_username(&v)));
+
+        let connected_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            id: Uuid::new_v4(),
+            remote_ip,
+            user_agent: header("user-agent"),
+            identity,
+            connected_at,
+        }
+    }
+}
+
+/// Username from an `Authorization: Basic ...` header value
+fn basic_auth_username(value: &str) -> Option<String> {
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    let username = credentials.split(':').next()?;
+    if username.is_empty() {
+        None
+    } else {
+        Some(username.to_string())
+    }
+}
+
+/// Remember the app handle so viewer join/leave events reach the frontend
+pub fn set_app_handle(app: AppHandle) {
+    let _ = APP_HANDLE.set(app);
+}
+
+/// Emit a viewer event to the frontend, if the app handle is set
+fn emit_viewer_event<S: serde::Serialize + Clone>(event: &str, payload: S) {
+    if let Some(app) = APP_HANDLE.get() {
+        if let Err(e) = app.emit(event, payload) {
+            eprintln!("[tnnl] Failed to emit {}: {}", event, e);
+        }
+    }
 }
 
 /// Start the WebSocket server on a specific port
@@ -64,6 +156,8 @@ pub async fn start_server(port: u16) -> Result<String, Box<dyn std::error::Error
     // Create shutdown channel
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
 
+    let viewers: ViewerMap = Arc::new(RwLock::new(HashMap::new()));
+
     // Store server state
     {
         let mut state = WS_STATE.write().await;
@@ -71,6 +165,7 @@ pub async fn start_server(port: u16) -> Result<String, Box<dyn std::error::Error
             address: local_addr,
             frame_tx: frame_tx.clone(),
             shutdown_tx: shutdown_tx.clone(),
+            viewers: viewers.clone(),
         });
     }
 
@@ -85,7 +180,7 @@ pub async fn start_server(port: u16) -> Result<String, Box<dyn std::error::Error
                         Ok((stream, peer_addr)) => {
                             println!("[tnnl] New connection from: {}", peer_addr);
                             let frame_rx = frame_tx.subscribe();
-                            tokio::spawn(handle_connection(stream, peer_addr, frame_rx));
+                            tokio::spawn(handle_connection(stream, peer_addr, frame_rx, viewers.clone()));
                         }
                         Err(e) => {
                             eprintln!("[tnnl] Accept error: {}", e);
@@ -381,8 +476,19 @@ async fn handle_connection(
     stream: TcpStream,
     peer_addr: SocketAddr,
     mut frame_rx: broadcast::Receiver<Vec<u8>>,
+    viewers: ViewerMap,
 ) {
-    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+    // Keep the upgrade request headers to identify the viewer
+    let mut request_headers = HeaderMap::new();
+    let ws_stream = match tokio_tungstenite::accept_hdr_async(
+        stream,
+        |req: &Request, resp: Response| -> Result<Response, ErrorResponse> {
+            request_headers = req.headers().clone();
+            Ok(resp)
+        },
+    )
+    .await
+    {
         Ok(ws) => ws,
         Err(e) => {
             eprintln!("[tnnl] WebSocket handshake error: {}", e);
@@ -390,7 +496,18 @@ async fn handle_connection(
         }
     };
 
-    println!("[tnnl] WebSocket connected: {}", peer_addr);
+    let session = ViewerSession::from_headers(&request_headers, peer_addr);
+    let viewer_id = session.id;
+    println!(
+        "[tnnl] WebSocket connected: {} (viewer {} from {})",
+        peer_addr, viewer_id, session.remote_ip
+    );
+
+    viewers.write().await.insert(viewer_id, session.clone());
+    emit_viewer_event("viewer-joined", session.clone());
+
+    // Tell the coordination server who is watching the active tunnel
+    tokio::spawn(crate::coordination_client::report_viewer_joined(session));
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
@@ -402,6 +519,7 @@ async fn handle_connection(
         .await
     {
         eprintln!("[tnnl] Failed to send welcome: {}", e);
+        viewer_left(&viewers, viewer_id).await;
         return;
     }
 
@@ -477,9 +595,19 @@ async fn handle_connection(
 
     // Clean up
     receiver_task.abort();
+    viewer_left(&viewers, viewer_id).await;
     println!("[tnnl] Client disconnected: {}", peer_addr);
 }
 
+/// Drop a viewer from the session list and report it gone
+async fn viewer_left(viewers: &ViewerMap, viewer_id: Uuid) {
+    if viewers.write().await.remove(&viewer_id).is_none() {
+        return;
+    }
+    emit_viewer_event("viewer-left", serde_json::json!({ "id": viewer_id }));
+    tokio::spawn(crate::coordination_client::report_viewer_left(viewer_id));
+}
+
 /// Broadcast a frame to all connected clients
 pub async fn broadcast_frame(frame_data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
     let state = WS_STATE.read().await;
@@ -544,6 +672,23 @@ pub async fn get_server_info() -> Result<ServerInfo, Box<dyn std::error::Error>>
     }
 }
 
+/// Viewers currently connected to the local server, oldest first
+pub async fn get_viewer_sessions() -> Vec<ViewerSession> {
+    let viewers = match WS_STATE.read().await.as_ref() {
+        Some(server_state) => server_state.viewers.clone(),
+        None => return Vec::new(),
+    };
+
+    let mut sessions: Vec<ViewerSession> = viewers.read().await.values().cloned().collect();
+    sessions.sort_by_key(|s| s.connected_at);
+    sessions
+}
+
+/// Whether the WebSocket server is running
+pub async fn is_running() -> bool {
+    WS_STATE.read().await.is_some()
+}
+
 /// Stop the WebSocket server
 pub async fn stop_server() -> Result<(), Box<dyn std::error::Error>> {
     let mut state = WS_STATE.write().await;
@@ -1,4 +1,5 @@
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import { getCurrentWindow } from '@tauri-apps/api/window';
 
 // Types
@@ -9,13 +10,91 @@ interface CaptureStatus {
   average_fps: number;
 }
 
+type TunnelKind = 'screen' | 'http' | 'tcp';
+
+type TunnelStatus = 'connecting' | 'active' | 'failed';
+
+interface TunnelRequest {
+  kind: TunnelKind;
+  local_port: number | null;
+  password: string | null;
+  label: string | null;
+  ttl_seconds: number | null;
+}
+
 interface TunnelInfo {
   id: string;
   subdomain: string;
+  label: string | null;
+  kind: TunnelKind;
   url: string;
   port: number;
+  local_port: number | null;
+  public_port: number | null;
   password: string | null;
+  allowed_viewers: string[];
   created_at: string;
+  expires_at: string | null;
+  status: TunnelStatus;
+  error: string | null;
+}
+
+interface ClusterNode {
+  id: string;
+  region: string | null;
+  url: string | null;
+}
+
+interface ServerInfo {
+  base_domain: string;
+  ssh_host: string;
+  nodes: ClusterNode[];
+}
+
+interface TunnelHistoryEntry {
+  id: string;
+  subdomain: string;
+  label?: string | null;
+  is_custom: boolean;
+  kind?: TunnelKind;
+  port: number;
+  password_protected: boolean;
+  created_at: string;
+  last_connected_at: string | null;
+  closed_at: string | null;
+  close_reason: string | null;
+  duration_seconds: number | null;
+  viewer_sessions: number;
+  bytes_in: number;
+  bytes_out: number;
+}
+
+interface TunnelExpiringNotice {
+  id: string;
+  subdomain: string;
+  expires_at: string;
+  seconds_left: number;
+}
+
+interface TunnelFailedNotice {
+  id: string;
+  subdomain: string;
+  reason: string;
+}
+
+interface BandwidthNotice {
+  type: 'bandwidth_warning' | 'bandwidth_limited';
+  action?: 'throttle' | 'close';
+  used_bytes: number;
+  cap_bytes: number;
+}
+
+interface ViewerSession {
+  id: string;
+  remote_ip: string;
+  user_agent: string | null;
+  identity: string | null;
+  connected_at: number;
 }
 
 interface User {
@@ -49,8 +128,27 @@ const statsEl = document.getElementById('stats')!;
 const connectTunnelBtn = document.getElementById('connectTunnel') as HTMLButtonElement;
 const disconnectTunnelBtn = document.getElementById('disconnectTunnel') as HTMLButtonElement;
 const tunnelPasswordInput = document.getElementById('tunnel-password') as HTMLInputElement;
+const tunnelPasswordGroup = document.getElementById('tunnel-password-group')!;
+const tunnelKindSelect = document.getElementById('tunnel-kind') as HTMLSelectElement;
+const localPortGroup = document.getElementById('local-port-group')!;
+const localPortInput = document.getElementById('local-port') as HTMLInputElement;
+const tunnelLabelInput = document.getElementById('tunnel-label') as HTMLInputElement;
+const tunnelTtlSelect = document.getElementById('tunnel-ttl') as HTMLSelectElement;
+const allowlistTunnelSelect = document.getElementById('allowlist-tunnel') as HTMLSelectElement;
+const allowedViewersInput = document.getElementById('allowed-viewers') as HTMLInputElement;
+const saveAllowlistBtn = document.getElementById('saveAllowlist') as HTMLButtonElement;
 const togglePasswordBtn = document.getElementById('toggle-password') as HTMLButtonElement;
 const tunnelInfoEl = document.getElementById('tunnelInfo')!;
+const loadHistoryBtn = document.getElementById('loadHistory') as HTMLButtonElement;
+const tunnelHistoryEl = document.getElementById('tunnelHistory')!;
+const viewersEl = document.getElementById('viewers')!;
+const bandwidthNoticeEl = document.getElementById('bandwidthNotice')!;
+
+// Viewers connected to the local WebSocket server, by session id
+const viewers = new Map<string, ViewerSession>();
+
+// Tunnels held by this connection, as last reported by the backend
+let tunnels: TunnelInfo[] = [];
 
 // State
 let statusInterval: number | null = null;
@@ -286,51 +384,74 @@ async function stopCapture() {
   }
 }
 
+// Read the tunnel form; throws a message when the local port is missing
+function readTunnelRequest(): TunnelRequest {
+  const kind = tunnelKindSelect.value as TunnelKind;
+  const localPort = kind === 'screen' ? null : parseInt(localPortInput.value, 10);
+  if (localPort !== null && !(localPort >= 1 && localPort <= 65535)) {
+    throw 'Enter the local port to share (1-65535)';
+  }
+
+  // TCP tunnels carry no HTTP, so they cannot be password protected
+  const password = kind === 'tcp' ? '' : tunnelPasswordInput.value.trim();
+  const label = tunnelLabelInput.value.trim();
+  const ttl = parseInt(tunnelTtlSelect.value, 10);
+
+  return {
+    kind,
+    local_port: localPort,
+    password: password ? password : null,
+    label: label ? label : null,
+    ttl_seconds: ttl > 0 ? ttl : null
+  };
+}
+
+// Connect with a first tunnel, or add one to the existing connection
 async function connectToTunnel() {
   if (!authToken) {
     tunnelInfoEl.innerHTML = '<em style="color: #dc2626;">Not authenticated</em>';
     return;
   }
 
+  const adding = tunnels.length > 0;
+
   try {
+    const request = readTunnelRequest();
     connectTunnelBtn.disabled = true;
-    connectTunnelBtn.textContent = 'Connecting...';
-    tunnelInfoEl.innerHTML = '<em>Connecting to coordination server...</em>';
+    connectTunnelBtn.textContent = adding ? 'Adding...' : 'Connecting...';
 
-    const password = tunnelPasswordInput.value.trim();
-    const passwordParam = password ? password : null;
+    if (adding) {
+      await invoke('request_tunnel', { request });
+      // The new tunnel shows up through the tunnels-changed event
+      resetTunnelForm();
+      return;
+    }
 
+    tunnelInfoEl.innerHTML = '<em>Connecting to coordination server...</em>';
     await invoke<string>('connect_to_coordination_server', {
       accessToken: authToken,
-      password: passwordParam
+      password: request.password,
+      kind: request.kind,
+      localPort: request.local_port,
+      label: request.label,
+      ttlSeconds: request.ttl_seconds
     });
 
-    // Poll for tunnel info
+    // Poll for the first tunnel
     let attempts = 0;
     const maxAttempts = 30;
     const pollInterval = setInterval(async () => {
       attempts++;
       try {
-        const tunnelInfo = await invoke<TunnelInfo | null>('get_tunnel_info');
+        const current = await invoke<TunnelInfo[]>('get_tunnels');
 
-        if (tunnelInfo) {
+        if (current.length > 0) {
           clearInterval(pollInterval);
-
-          // Show disconnect button, hide connect button
-          connectTunnelBtn.classList.add('hidden');
-          disconnectTunnelBtn.classList.remove('hidden');
-          tunnelPasswordInput.disabled = true;
-
-          tunnelInfoEl.innerHTML = `
-            <strong>✓ Connected</strong><br>
-            <strong>URL:</strong> <a href="${tunnelInfo.url}" target="_blank" style="color: #ffffff; text-decoration: underline;">${tunnelInfo.url}</a><br>
-            <strong>Port:</strong> ${tunnelInfo.port}<br>
-            ${tunnelInfo.password ? '<strong>Password:</strong> Protected (username: tnnl)<br>' : '<em>No password required</em>'}
-          `;
+          resetTunnelForm();
+          await renderTunnels(current);
         } else if (attempts >= maxAttempts) {
           clearInterval(pollInterval);
-          connectTunnelBtn.disabled = false;
-          connectTunnelBtn.textContent = 'Connect to tnnl.to';
+          resetTunnelForm();
           tunnelInfoEl.innerHTML = '<em style="color: #dc2626;">Connection timeout. Please try again.</em>';
         }
       } catch (error) {
@@ -340,9 +461,47 @@ async function connectToTunnel() {
 
   } catch (error: any) {
     console.error('[Tunnel] Connection failed:', error);
-    connectTunnelBtn.disabled = false;
-    connectTunnelBtn.textContent = 'Connect to tnnl.to';
-    tunnelInfoEl.innerHTML = `<em style="color: #dc2626;">Error: ${error}</em>`;
+    resetTunnelForm();
+    if (adding) {
+      updateStatus(`Failed to add tunnel: ${error}`);
+    } else {
+      tunnelInfoEl.innerHTML = `<em style="color: #dc2626;">Error: ${error}</em>`;
+    }
+  }
+}
+
+function resetTunnelForm() {
+  connectTunnelBtn.disabled = false;
+  connectTunnelBtn.textContent = tunnels.length > 0 ? 'Add Tunnel' : 'Connect Tunnel';
+  tunnelLabelInput.value = '';
+}
+
+// Show the local port field for HTTP/TCP tunnels, and the password field only
+// where nginx can ask for one
+function updateTunnelKindFields() {
+  const kind = tunnelKindSelect.value as TunnelKind;
+  localPortGroup.classList.toggle('hidden', kind === 'screen');
+  tunnelPasswordGroup.classList.toggle('hidden', kind === 'tcp');
+}
+
+function describeTunnelTarget(tunnelInfo: TunnelInfo): string {
+  switch (tunnelInfo.kind) {
+    case 'http':
+      return `<strong>Sharing:</strong> http://localhost:${tunnelInfo.local_port}<br>`;
+    case 'tcp':
+      return `<strong>Sharing:</strong> localhost:${tunnelInfo.local_port} on public port ${tunnelInfo.public_port}<br>`;
+    default:
+      return '';
+  }
+}
+
+async function closeTunnel(tunnelId: string) {
+  try {
+    await invoke('close_tunnel', { tunnelId });
+    // Removed from the list once the server confirms with tunnel_closed
+  } catch (error) {
+    console.error('[Tunnel] Close failed:', error);
+    updateStatus(`Failed to close tunnel: ${error}`);
   }
 }
 
@@ -354,42 +513,207 @@ async function disconnectFromTunnel() {
     await invoke<string>('disconnect_tunnel');
 
     // Reset UI
-    connectTunnelBtn.classList.remove('hidden');
-    disconnectTunnelBtn.classList.add('hidden');
-    connectTunnelBtn.disabled = false;
-    connectTunnelBtn.textContent = 'Connect to tnnl.to';
-    tunnelPasswordInput.disabled = false;
     tunnelPasswordInput.value = '';
-    tunnelInfoEl.innerHTML = '<em>Not connected</em>';
+    await renderTunnels([]);
   } catch (error: any) {
     console.error('[Tunnel] Disconnect failed:', error);
+  } finally {
     disconnectTunnelBtn.disabled = false;
-    disconnectTunnelBtn.textContent = 'Disconnect';
+    disconnectTunnelBtn.textContent = 'Disconnect All';
+  }
+}
+
+function describeTunnelStatus(tunnelInfo: TunnelInfo): string {
+  switch (tunnelInfo.status) {
+    case 'active':
+      return '✓';
+    case 'failed':
+      return `<span style="color: #dc2626;">✗ ${escapeHtml(tunnelInfo.error ?? 'Failed')}</span>`;
+    default:
+      return '<em>connecting...</em>';
+  }
+}
+
+// One entry per tunnel, each with its own Close button
+async function renderTunnels(current: TunnelInfo[]) {
+  tunnels = current;
+  const connected = tunnels.length > 0;
+
+  connectTunnelBtn.textContent = connected ? 'Add Tunnel' : 'Connect Tunnel';
+  disconnectTunnelBtn.classList.toggle('hidden', !connected);
+  saveAllowlistBtn.disabled = !connected;
+  allowlistTunnelSelect.disabled = !connected;
+
+  const selected = allowlistTunnelSelect.value;
+  allowlistTunnelSelect.innerHTML = tunnels
+    .map((t) => `<option value="${t.id}">${escapeHtml(t.label ?? t.subdomain)}</option>`)
+    .join('');
+  if (tunnels.some((t) => t.id === selected)) {
+    allowlistTunnelSelect.value = selected;
   }
+
+  if (!connected) {
+    tunnelInfoEl.innerHTML = '<em>Not connected</em>';
+    return;
+  }
+
+  const serverInfo = await invoke<ServerInfo | null>('get_server_info');
+  tunnelInfoEl.innerHTML = tunnels.map((tunnelInfo) => `
+    <div style="margin-bottom: 10px;">
+      ${describeTunnelStatus(tunnelInfo)} <strong>${escapeHtml(tunnelInfo.label ?? tunnelInfo.kind)}</strong>
+      <button type="button" class="btn-secondary" data-close-tunnel="${tunnelInfo.id}" style="width: auto; padding: 2px 10px; margin: 0 0 0 8px;">Close</button><br>
+      ${tunnelInfo.status === 'active'
+        ? `<strong>URL:</strong> <a href="${tunnelInfo.url}" target="_blank" style="color: #ffffff; text-decoration: underline;">${tunnelInfo.url}</a><br>`
+        : '<em>URL appears once the tunnel is ready</em><br>'}
+      <strong>Port:</strong> ${tunnelInfo.port}<br>
+      ${describeTunnelTarget(tunnelInfo)}
+      ${tunnelInfo.expires_at ? `<strong>Closes at:</strong> ${new Date(tunnelInfo.expires_at).toLocaleString()}<br>` : ''}
+      ${tunnelInfo.password ? '<strong>Password:</strong> Protected (username: tnnl)<br>' : '<em>No password required</em>'}
+      ${tunnelInfo.allowed_viewers.length > 0 ? `<br><strong>Viewers:</strong> ${escapeHtml(tunnelInfo.allowed_viewers.join(', '))}` : ''}
+    </div>
+  `).join('') + (serverInfo ? `<strong>Server:</strong> ${serverInfo.base_domain}` : '');
 }
 
 async function updateTunnelInfo() {
   try {
-    const tunnelInfo = await invoke<TunnelInfo | null>('get_tunnel_info');
-
-    if (tunnelInfo && tunnelInfoEl) {
-      // Show disconnect button, hide connect button
-      connectTunnelBtn.classList.add('hidden');
-      disconnectTunnelBtn.classList.remove('hidden');
-      tunnelPasswordInput.disabled = true;
-
-      tunnelInfoEl.innerHTML = `
-        <strong>✓ Connected</strong><br>
-        <strong>URL:</strong> <a href="${tunnelInfo.url}" target="_blank" style="color: #ffffff; text-decoration: underline;">${tunnelInfo.url}</a><br>
-        <strong>Port:</strong> ${tunnelInfo.port}<br>
-        ${tunnelInfo.password ? '<strong>Password:</strong> Protected (username: tnnl)<br>' : '<em>No password required</em>'}
-      `;
-    }
+    await renderTunnels(await invoke<TunnelInfo[]>('get_tunnels'));
   } catch (error) {
     // Not connected yet, ignore
   }
 }
 
+async function saveAllowlist() {
+  const tunnelId = allowlistTunnelSelect.value;
+  if (!tunnelId) {
+    return;
+  }
+
+  const allowedViewers = allowedViewersInput.value
+    .split(/[\s,]+/)
+    .map((entry) => entry.trim())
+    .filter((entry) => entry.length > 0);
+
+  try {
+    saveAllowlistBtn.disabled = true;
+    await invoke('set_viewer_allowlist', { tunnelId, allowedViewers });
+    updateStatus(allowedViewers.length > 0 ? '✓ Only the listed viewers can watch' : '✓ Anyone with the link can watch');
+    // The server answers with the normalized list
+    setTimeout(updateTunnelInfo, 500);
+  } catch (error) {
+    console.error('[Tunnel] Failed to set viewer allowlist:', error);
+    updateStatus(`Failed to update viewers: ${error}`);
+  } finally {
+    saveAllowlistBtn.disabled = false;
+  }
+}
+
+async function initTunnels() {
+  await listen<TunnelInfo[]>('tunnels-changed', (event) => {
+    renderTunnels(event.payload);
+  });
+  // Errors for an extra tunnel request; the connection itself is fine
+  await listen<string>('coordination-error', (event) => {
+    updateStatus(`Tunnel request failed: ${event.payload}`);
+  });
+  await listen<TunnelFailedNotice>('tunnel-failed', (event) => {
+    const notice = event.payload;
+    const tunnel = tunnels.find((t) => t.id === notice.id);
+    updateStatus(`Tunnel ${tunnel?.label ?? notice.subdomain} failed: ${notice.reason}`);
+  });
+  await listen<TunnelExpiringNotice>('tunnel-expiring', (event) => {
+    const notice = event.payload;
+    const tunnel = tunnels.find((t) => t.id === notice.id);
+    const name = tunnel?.label ?? notice.subdomain;
+    const minutes = Math.max(1, Math.round(notice.seconds_left / 60));
+    updateStatus(`Tunnel ${name} closes in ${minutes} minute${minutes === 1 ? '' : 's'}`);
+  });
+}
+
+async function loadTunnelHistory() {
+  try {
+    loadHistoryBtn.disabled = true;
+    const entries = await invoke<TunnelHistoryEntry[]>('get_tunnel_history', { limit: 10 });
+
+    if (entries.length === 0) {
+      tunnelHistoryEl.innerHTML = '<em>No tunnels yet</em>';
+      return;
+    }
+
+    tunnelHistoryEl.innerHTML = entries.map((entry) => {
+      const started = new Date(entry.created_at).toLocaleString();
+      const state = entry.closed_at
+        ? `closed (${entry.close_reason ?? 'unknown'}) after ${formatDuration(entry.duration_seconds ?? 0)}`
+        : '<strong>active</strong>';
+      const viewers = `${entry.viewer_sessions} viewer session${entry.viewer_sessions === 1 ? '' : 's'}`;
+      const transfer = `${formatBytes(entry.bytes_out ?? 0)} sent`;
+      const name = entry.label ? `${escapeHtml(entry.label)} (${entry.subdomain})` : entry.subdomain;
+      return `<strong>${name}</strong><br>${started} · ${state} · ${viewers} · ${transfer}`;
+    }).join('<br><br>');
+  } catch (error) {
+    console.error('[Tunnel] Failed to load history:', error);
+    tunnelHistoryEl.innerHTML = `<em style="color: #dc2626;">${error}</em>`;
+  } finally {
+    loadHistoryBtn.disabled = false;
+  }
+}
+
+function escapeHtml(text: string): string {
+  const div = document.createElement('div');
+  div.textContent = text;
+  return div.innerHTML;
+}
+
+function renderViewers() {
+  if (viewers.size === 0) {
+    viewersEl.innerHTML = '<em>Nobody is watching</em>';
+    return;
+  }
+
+  viewersEl.innerHTML = [...viewers.values()]
+    .sort((a, b) => a.connected_at - b.connected_at)
+    .map((viewer) => {
+      const since = new Date(viewer.connected_at * 1000).toLocaleTimeString();
+      const who = viewer.identity ? `<strong>${escapeHtml(viewer.identity)}</strong> · ` : '';
+      const agent = viewer.user_agent ? `<br><small>${escapeHtml(viewer.user_agent)}</small>` : '';
+      return `${who}${escapeHtml(viewer.remote_ip)} · since ${since}${agent}`;
+    }).join('<br><br>');
+}
+
+async function initViewers() {
+  await listen<ViewerSession>('viewer-joined', (event) => {
+    viewers.set(event.payload.id, event.payload);
+    renderViewers();
+  });
+  await listen<{ id: string }>('viewer-left', (event) => {
+    viewers.delete(event.payload.id);
+    renderViewers();
+  });
+
+  // Pick up viewers that connected before the listeners were registered
+  try {
+    const sessions = await invoke<ViewerSession[]>('get_viewer_sessions');
+    sessions.forEach((session) => viewers.set(session.id, session));
+    renderViewers();
+  } catch (error) {
+    console.error('[Viewers] Failed to load sessions:', error);
+  }
+}
+
+async function initBandwidthNotices() {
+  await listen<BandwidthNotice>('bandwidth-notice', (event) => {
+    const notice = event.payload;
+    const usage = `${formatBytes(notice.used_bytes)} of ${formatBytes(notice.cap_bytes)}`;
+    if (notice.type === 'bandwidth_warning') {
+      bandwidthNoticeEl.textContent = `${usage} of this month's transfer used`;
+    } else if (notice.action === 'close') {
+      bandwidthNoticeEl.textContent = `Monthly transfer cap reached (${usage}); tunnels are closed until next month`;
+    } else {
+      bandwidthNoticeEl.textContent = `Monthly transfer cap reached (${usage}); tunnels are throttled until next month`;
+    }
+    bandwidthNoticeEl.classList.remove('hidden');
+  });
+}
+
 async function syncUIState() {
   let captureActive = false;
   let tunnelActive = false;
@@ -416,11 +740,11 @@ async function syncUIState() {
 
   // Check tunnel status
   try {
-    const tunnelInfo = await invoke<TunnelInfo | null>('get_tunnel_info');
-    tunnelActive = tunnelInfo !== null;
+    const current = await invoke<TunnelInfo[]>('get_tunnels');
+    tunnelActive = current.length > 0;
 
-    if (tunnelInfo) {
-      await updateTunnelInfo();
+    if (tunnelActive || tunnels.length > 0) {
+      await renderTunnels(current);
     }
   } catch (error) {
     console.error('Failed to check tunnel status:', error);
@@ -432,6 +756,16 @@ startBtn.addEventListener('click', startCapture);
 stopBtn.addEventListener('click', stopCapture);
 connectTunnelBtn.addEventListener('click', connectToTunnel);
 disconnectTunnelBtn.addEventListener('click', disconnectFromTunnel);
+tunnelKindSelect.addEventListener('change', updateTunnelKindFields);
+loadHistoryBtn.addEventListener('click', loadTunnelHistory);
+saveAllowlistBtn.addEventListener('click', saveAllowlist);
+tunnelInfoEl.addEventListener('click', (event) => {
+  const target = event.target as HTMLElement;
+  const tunnelId = target.dataset.closeTunnel;
+  if (tunnelId) {
+    closeTunnel(tunnelId);
+  }
+});
 togglePasswordBtn.addEventListener('click', () => {
   const isPassword = tunnelPasswordInput.type === 'password';
   tunnelPasswordInput.type = isPassword ? 'text' : 'password';
@@ -443,6 +777,9 @@ togglePasswordBtn.addEventListener('click', () => {
 async function init() {
   updateStatus('Initializing...');
   await checkPermissions();
+  await initViewers();
+  await initBandwidthNotices();
+  await initTunnels();
   await syncUIState();
 
   // Poll for state changes every 2 seconds